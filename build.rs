@@ -5,7 +5,7 @@ use std::path::PathBuf;
 fn main() {
     let out_dir = env::var("OUT_DIR").unwrap();
     let path: PathBuf = [out_dir.clone(), "mod.rs".to_string()].iter().collect();
-    fs::write(path, "pub mod ttrpc;").unwrap();
+    fs::write(path, "pub mod ttrpc;\npub mod admin;").unwrap();
 
     let customize = protobuf_codegen::Customize::default()
         .gen_mod_rs(false)
@@ -14,7 +14,7 @@ fn main() {
     protobuf_codegen::Codegen::new()
         .pure()
         .out_dir(out_dir)
-        .inputs(["src/ttrpc.proto"])
+        .inputs(["src/ttrpc.proto", "src/admin.proto"])
         .include("src")
         .customize(customize)
         .run()
@@ -36,7 +36,7 @@
 
 #![allow(dead_code)]
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
 
 use crate::Customize;
 use protobuf::{
@@ -50,12 +50,13 @@ use protobuf::{
     Message,
 };
 use protobuf_codegen::code_writer::CodeWriter;
-use std::fs::File;
+use std::fs::{self, File};
 use std::io::{self, stdin, stdout, Write};
 use std::path::Path;
 
 use super::util::{
-    self, async_on, def_async_fn, fq_grpc, pub_async_fn, to_camel_case, to_snake_case, MethodType,
+    self, async_on, def_async_fn, fq_ttrpc, pub_async_fn, to_camel_case, to_snake_case,
+    ttrpc_crate, MethodType,
 };
 
 struct MethodGen<'a> {
@@ -84,20 +85,38 @@ impl<'a> MethodGen<'a> {
     }
 
     fn input(&self) -> String {
+        self.message_path(self.proto.get_input_type())
+    }
+
+    fn output(&self) -> String {
+        self.message_path(self.proto.get_output_type())
+    }
+
+    fn message_path(&self, fqn: &str) -> String {
+        let message = self.root_scope.find_message(fqn);
         format!(
-            "super::{}",
-            self.root_scope
-                .find_message(self.proto.get_input_type())
-                .rust_fq_name()
+            "{}::{}",
+            file_mod_path(
+                message.get_scope().get_file_descriptor(),
+                &self.package_name,
+                self.customize
+            ),
+            message.rust_name()
         )
     }
 
-    fn output(&self) -> String {
+    /// The arguments of the handler macros naming the request type: its module and
+    /// name, or its path if its module isn't a sibling of the generated one.
+    fn input_macro_args(&self) -> String {
+        let message = self.root_scope.find_message(self.proto.get_input_type());
+        let file = message.get_scope().get_file_descriptor();
+        if self.customize.package_modules && file.get_package() != self.package_name {
+            return self.input();
+        }
         format!(
-            "super::{}",
-            self.root_scope
-                .find_message(self.proto.get_output_type())
-                .rust_fq_name()
+            "{}, {}",
+            proto_path_to_rust_mod(file.get_name()),
+            message.rust_name()
         )
     }
 
@@ -106,16 +125,22 @@ impl<'a> MethodGen<'a> {
             self.proto.get_client_streaming(),
             self.proto.get_server_streaming(),
         ) {
-            (false, false) => (MethodType::Unary, fq_grpc("MethodType::Unary")),
+            (false, false) => (
+                MethodType::Unary,
+                fq_ttrpc(self.customize, "MethodType::Unary"),
+            ),
             (true, false) => (
                 MethodType::ClientStreaming,
-                fq_grpc("MethodType::ClientStreaming"),
+                fq_ttrpc(self.customize, "MethodType::ClientStreaming"),
             ),
             (false, true) => (
                 MethodType::ServerStreaming,
-                fq_grpc("MethodType::ServerStreaming"),
+                fq_ttrpc(self.customize, "MethodType::ServerStreaming"),
+            ),
+            (true, true) => (
+                MethodType::Duplex,
+                fq_ttrpc(self.customize, "MethodType::Duplex"),
             ),
-            (true, true) => (MethodType::Duplex, fq_grpc("MethodType::Duplex")),
         }
     }
 
@@ -159,13 +184,13 @@ impl<'a> MethodGen<'a> {
     }
 
     fn write_handler_impl(&self, w: &mut CodeWriter) {
-        w.block(&format!("impl ::ttrpc::MethodHandler for {}Method {{", self.struct_name()), "}",
+        let ttrpc = ttrpc_crate(self.customize);
+        w.block(&format!("impl {ttrpc}::MethodHandler for {}Method {{", self.struct_name()), "}",
         |w| {
-            w.block("fn handler(&self, ctx: ::ttrpc::TtrpcContext, req: ::ttrpc::Request) -> ::ttrpc::Result<()> {", "}",
+            w.block(&format!("fn handler(&self, ctx: {ttrpc}::TtrpcContext, req: {ttrpc}::Request) -> {ttrpc}::Result<()> {{"), "}",
             |w| {
-                w.write_line(&format!("::ttrpc::request_handler!(self, ctx, req, {}, {}, {});",
-                                        proto_path_to_rust_mod(self.root_scope.find_message(self.proto.get_input_type()).get_scope().get_file_descriptor().get_name()),
-                                        self.root_scope.find_message(self.proto.get_input_type()).rust_name(),
+                w.write_line(&format!("{ttrpc}::request_handler!(self, ctx, req, {}, {});",
+                                        self.input_macro_args(),
                                         self.name()));
                 w.write_line("Ok(())");
             });
@@ -173,51 +198,50 @@ impl<'a> MethodGen<'a> {
     }
 
     fn write_handler_impl_async(&self, w: &mut CodeWriter) {
+        let ttrpc = ttrpc_crate(self.customize);
         w.write_line("#[async_trait]");
         match self.method_type().0 {
             MethodType::Unary => {
-                w.block(&format!("impl ::ttrpc::r#async::MethodHandler for {}Method {{", self.struct_name()), "}",
+                w.block(&format!("impl {ttrpc}::r#async::MethodHandler for {}Method {{", self.struct_name()), "}",
                 |w| {
-                    w.block("async fn handler(&self, ctx: ::ttrpc::r#async::TtrpcContext, req: ::ttrpc::Request) -> ::ttrpc::Result<::ttrpc::Response> {", "}",
+                    w.block(&format!("async fn handler(&self, ctx: {ttrpc}::r#async::TtrpcContext, req: {ttrpc}::Request) -> {ttrpc}::Result<{ttrpc}::Response> {{"), "}",
                         |w| {
-                            w.write_line(&format!("::ttrpc::async_request_handler!(self, ctx, req, {}, {}, {});",
-                                        proto_path_to_rust_mod(self.root_scope.find_message(self.proto.get_input_type()).get_scope().get_file_descriptor().get_name()),
-                                        self.root_scope.find_message(self.proto.get_input_type()).rust_name(),
+                            w.write_line(&format!("{ttrpc}::async_request_handler!(self, ctx, req, {}, {});",
+                                        self.input_macro_args(),
                                         self.name()));
                     });
             });
             }
             // only receive
             MethodType::ClientStreaming => {
-                w.block(&format!("impl ::ttrpc::r#async::StreamHandler for {}Method {{", self.struct_name()), "}",
+                w.block(&format!("impl {ttrpc}::r#async::StreamHandler for {}Method {{", self.struct_name()), "}",
                 |w| {
-                    w.block("async fn handler(&self, ctx: ::ttrpc::r#async::TtrpcContext, inner: ::ttrpc::r#async::StreamInner) -> ::ttrpc::Result<Option<::ttrpc::Response>> {", "}",
+                    w.block(&format!("async fn handler(&self, ctx: {ttrpc}::r#async::TtrpcContext, inner: {ttrpc}::r#async::StreamInner) -> {ttrpc}::Result<Option<{ttrpc}::Response>> {{"), "}",
                         |w| {
-                            w.write_line(&format!("::ttrpc::async_client_streamimg_handler!(self, ctx, inner, {});",
+                            w.write_line(&format!("{ttrpc}::async_client_streamimg_handler!(self, ctx, inner, {});",
                                         self.name()));
                     });
             });
             }
             // only send
             MethodType::ServerStreaming => {
-                w.block(&format!("impl ::ttrpc::r#async::StreamHandler for {}Method {{", self.struct_name()), "}",
+                w.block(&format!("impl {ttrpc}::r#async::StreamHandler for {}Method {{", self.struct_name()), "}",
                 |w| {
-                    w.block("async fn handler(&self, ctx: ::ttrpc::r#async::TtrpcContext, mut inner: ::ttrpc::r#async::StreamInner) -> ::ttrpc::Result<Option<::ttrpc::Response>> {", "}",
+                    w.block(&format!("async fn handler(&self, ctx: {ttrpc}::r#async::TtrpcContext, mut inner: {ttrpc}::r#async::StreamInner) -> {ttrpc}::Result<Option<{ttrpc}::Response>> {{"), "}",
                         |w| {
-                            w.write_line(&format!("::ttrpc::async_server_streamimg_handler!(self, ctx, inner, {}, {}, {});",
-                                        proto_path_to_rust_mod(self.root_scope.find_message(self.proto.get_input_type()).get_scope().get_file_descriptor().get_name()),
-                                        self.root_scope.find_message(self.proto.get_input_type()).rust_name(),
+                            w.write_line(&format!("{ttrpc}::async_server_streamimg_handler!(self, ctx, inner, {}, {});",
+                                        self.input_macro_args(),
                                         self.name()));
                     });
             });
             }
             // receive and send
             MethodType::Duplex => {
-                w.block(&format!("impl ::ttrpc::r#async::StreamHandler for {}Method {{", self.struct_name()), "}",
+                w.block(&format!("impl {ttrpc}::r#async::StreamHandler for {}Method {{", self.struct_name()), "}",
                 |w| {
-                    w.block("async fn handler(&self, ctx: ::ttrpc::r#async::TtrpcContext, inner: ::ttrpc::r#async::StreamInner) -> ::ttrpc::Result<Option<::ttrpc::Response>> {", "}",
+                    w.block(&format!("async fn handler(&self, ctx: {ttrpc}::r#async::TtrpcContext, inner: {ttrpc}::r#async::StreamInner) -> {ttrpc}::Result<Option<{ttrpc}::Response>> {{"), "}",
                         |w| {
-                            w.write_line(&format!("::ttrpc::async_duplex_streamimg_handler!(self, ctx, inner, {});",
+                            w.write_line(&format!("{ttrpc}::async_duplex_streamimg_handler!(self, ctx, inner, {});",
                                         self.name()));
                     });
             });
@@ -227,129 +251,335 @@ impl<'a> MethodGen<'a> {
 
     // Method signatures
     fn unary(&self, method_name: &str) -> String {
+        let ttrpc = ttrpc_crate(self.customize);
         format!(
-            "{}(&self, ctx: ttrpc::context::Context, req: &{}) -> {}<{}>",
+            "{}(&self, ctx: {ttrpc}::context::Context, req: &{}) -> {}<{}>",
             method_name,
             self.input(),
-            fq_grpc("Result"),
+            fq_ttrpc(self.customize, "Result"),
             self.output()
         )
     }
 
-    fn client_streaming(&self, method_name: &str) -> String {
+    fn unary_with_metadata(&self, method_name: &str) -> String {
+        let ttrpc = ttrpc_crate(self.customize);
         format!(
-            "{}(&self, ctx: ttrpc::context::Context) -> {}<{}<{}, {}>>",
+            "{}_with_metadata(&self, ctx: {ttrpc}::context::Context, req: &{}) -> {}<({}, ::std::collections::HashMap<String, Vec<String>>)>",
             method_name,
-            fq_grpc("Result"),
-            fq_grpc("r#async::ClientStreamSender"),
             self.input(),
+            fq_ttrpc(self.customize, "Result"),
             self.output()
         )
     }
 
-    fn server_streaming(&self, method_name: &str) -> String {
+    // The stream types are those of `module`, `r#async` or `sync`.
+    fn client_streaming(&self, method_name: &str, module: &str) -> String {
+        let ttrpc = ttrpc_crate(self.customize);
         format!(
-            "{}(&self, ctx: ttrpc::context::Context, req: &{}) -> {}<{}<{}>>",
+            "{}(&self, ctx: {ttrpc}::context::Context) -> {}<{}<{}, {}>>",
             method_name,
+            fq_ttrpc(self.customize, "Result"),
+            fq_ttrpc(self.customize, &format!("{module}::ClientStreamSender")),
             self.input(),
-            fq_grpc("Result"),
-            fq_grpc("r#async::ClientStreamReceiver"),
             self.output()
         )
     }
 
-    fn duplex_streaming(&self, method_name: &str) -> String {
+    fn server_streaming(&self, method_name: &str, module: &str) -> String {
+        let ttrpc = ttrpc_crate(self.customize);
         format!(
-            "{}(&self, ctx: ttrpc::context::Context) -> {}<{}<{}, {}>>",
+            "{}(&self, ctx: {ttrpc}::context::Context, req: &{}) -> {}<{}<{}>>",
             method_name,
-            fq_grpc("Result"),
-            fq_grpc("r#async::ClientStream"),
+            self.input(),
+            fq_ttrpc(self.customize, "Result"),
+            fq_ttrpc(self.customize, &format!("{module}::ClientStreamReceiver")),
+            self.output()
+        )
+    }
+
+    fn duplex_streaming(&self, method_name: &str, module: &str) -> String {
+        let ttrpc = ttrpc_crate(self.customize);
+        format!(
+            "{}(&self, ctx: {ttrpc}::context::Context) -> {}<{}<{}, {}>>",
+            method_name,
+            fq_ttrpc(self.customize, "Result"),
+            fq_ttrpc(self.customize, &format!("{module}::ClientStream")),
             self.input(),
             self.output()
         )
     }
 
     fn write_client(&self, w: &mut CodeWriter) {
+        let ttrpc = ttrpc_crate(self.customize);
         let method_name = self.name();
-        if let MethodType::Unary = self.method_type().0 {
-            w.pub_fn(&self.unary(&method_name), |w| {
+        let path = format!(
+            "\"{}.{}\", \"{}\"",
+            self.package_name,
+            self.service_name,
+            &self.proto.get_name()
+        );
+        match self.method_type().0 {
+            MethodType::Unary => w.pub_fn(&self.unary(&method_name), |w| {
                 w.write_line(&format!("let mut cres = {}::new();", self.output()));
                 w.write_line(&format!(
-                    "::ttrpc::client_request!(self, ctx, req, \"{}.{}\", \"{}\", cres);",
-                    self.package_name,
-                    self.service_name,
-                    &self.proto.get_name(),
+                    "{ttrpc}::client_request!(self, ctx, req, {path}, cres);"
                 ));
                 w.write_line("Ok(cres)");
-            });
+            }),
+            MethodType::ClientStreaming => {
+                w.pub_fn(&self.client_streaming(&method_name, "sync"), |w| {
+                    w.write_line(&format!("{ttrpc}::client_stream_send!(self, ctx, {path});"));
+                })
+            }
+            MethodType::ServerStreaming => {
+                w.pub_fn(&self.server_streaming(&method_name, "sync"), |w| {
+                    w.write_line(&format!(
+                        "{ttrpc}::client_stream_receive!(self, ctx, req, {path});"
+                    ));
+                })
+            }
+            MethodType::Duplex => w.pub_fn(&self.duplex_streaming(&method_name, "sync"), |w| {
+                w.write_line(&format!("{ttrpc}::client_stream!(self, ctx, {path});"));
+            }),
         }
     }
 
     fn write_async_client(&self, w: &mut CodeWriter) {
+        let ttrpc = ttrpc_crate(self.customize);
         let method_name = self.name();
-        match self.method_type().0 {
+        let path = format!(
+            "\"{}.{}\", \"{}\"",
+            self.package_name,
+            self.service_name,
+            &self.proto.get_name()
+        );
+        let (signature, call) = match self.method_type().0 {
             // Unary RPC
-            MethodType::Unary => {
-                pub_async_fn(w, &self.unary(&method_name), |w| {
-                    w.write_line(&format!("let mut cres = {}::new();", self.output()));
-                    w.write_line(&format!(
-                        "::ttrpc::async_client_request!(self, ctx, req, \"{}.{}\", \"{}\", cres);",
-                        self.package_name,
-                        self.service_name,
-                        &self.proto.get_name(),
-                    ));
-                });
-            }
+            MethodType::Unary => (
+                self.unary(&method_name),
+                format!("async_client_request!(self, ctx, req, {path}, cres"),
+            ),
             // Client Streaming RPC
-            MethodType::ClientStreaming => {
-                pub_async_fn(w, &self.client_streaming(&method_name), |w| {
-                    w.write_line(&format!(
-                        "::ttrpc::async_client_stream_send!(self, ctx, \"{}.{}\", \"{}\");",
-                        self.package_name,
-                        self.service_name,
-                        &self.proto.get_name(),
-                    ));
-                });
-            }
+            MethodType::ClientStreaming => (
+                self.client_streaming(&method_name, "r#async"),
+                format!("async_client_stream_send!(self, ctx, {path}"),
+            ),
             // Server Streaming RPC
-            MethodType::ServerStreaming => {
-                pub_async_fn(w, &self.server_streaming(&method_name), |w| {
+            MethodType::ServerStreaming => (
+                self.server_streaming(&method_name, "r#async"),
+                format!("async_client_stream_receive!(self, ctx, req, {path}"),
+            ),
+            // Bidirectional streaming RPC
+            MethodType::Duplex => (
+                self.duplex_streaming(&method_name, "r#async"),
+                format!("async_client_stream!(self, ctx, {path}"),
+            ),
+        };
+        let unary = matches!(self.method_type().0, MethodType::Unary);
+        // Only unary calls are retried.
+        let idempotent = unary && self.idempotent();
+        pub_async_fn(w, &signature, |w| {
+            if unary {
+                w.write_line(&format!("let mut cres = {}::new();", self.output()));
+            }
+            if idempotent {
+                w.write_line(&format!(
+                    "{ttrpc}::{call}, &{ttrpc}::r#async::CallOptions::new().idempotent(true));"
+                ));
+            } else {
+                w.write_line(&format!("{ttrpc}::{call});"));
+            }
+        });
+        w.write_line("");
+        pub_async_fn(w, &self.with_options(&signature), |w| {
+            if unary {
+                w.write_line(&format!("let mut cres = {}::new();", self.output()));
+            }
+            if idempotent {
+                w.write_line(&format!(
+                    "{ttrpc}::{call}, &opts.clone().idempotent(true));"
+                ));
+            } else {
+                w.write_line(&format!("{ttrpc}::{call}, opts);"));
+            }
+        });
+        if unary {
+            let call = format!("async_client_request_with_metadata!(self, ctx, req, {path}, cres");
+            let signature = self.unary_with_metadata(&method_name);
+            w.write_line("");
+            pub_async_fn(w, &signature, |w| {
+                w.write_line(&format!("let mut cres = {}::new();", self.output()));
+                if idempotent {
                     w.write_line(&format!(
-                        "::ttrpc::async_client_stream_receive!(self, ctx, req, \"{}.{}\", \"{}\");",
-                        self.package_name,
-                        self.service_name,
-                        &self.proto.get_name(),
+                        "{ttrpc}::{call}, &{ttrpc}::r#async::CallOptions::new().idempotent(true));"
                     ));
-                });
-            }
-            // Bidirectional streaming RPC
-            MethodType::Duplex => {
-                pub_async_fn(w, &self.duplex_streaming(&method_name), |w| {
+                } else {
+                    w.write_line(&format!("{ttrpc}::{call});"));
+                }
+            });
+            w.write_line("");
+            pub_async_fn(w, &self.with_options(&signature), |w| {
+                w.write_line(&format!("let mut cres = {}::new();", self.output()));
+                if idempotent {
                     w.write_line(&format!(
-                        "::ttrpc::async_client_stream!(self, ctx, \"{}.{}\", \"{}\");",
-                        self.package_name,
-                        self.service_name,
-                        &self.proto.get_name(),
+                        "{ttrpc}::{call}, &opts.clone().idempotent(true));"
                     ));
-                });
+                } else {
+                    w.write_line(&format!("{ttrpc}::{call}, opts);"));
+                }
+            });
+        }
+    }
+
+    fn write_method_info(&self, w: &mut CodeWriter) {
+        let ttrpc = ttrpc_crate(self.customize);
+        w.block(&format!("{ttrpc}::MethodInfo {{"), "},", |w| {
+            w.field_entry(
+                "service",
+                &format!("\"{}.{}\"", self.package_name, self.service_name),
+            );
+            w.field_entry("method", &format!("\"{}\"", self.proto.get_name()));
+            w.field_entry("method_type", &self.method_type().1);
+            w.field_entry("schema_digest", &format!("{:#018x}", self.schema_digest()));
+        });
+    }
+
+    /// A digest of the descriptors of the request and response, and of the messages
+    /// and enums they refer to, so peers generated from different revisions of them
+    /// can tell.
+    fn schema_digest(&self) -> u64 {
+        let mut digest = util::FNV_OFFSET;
+        let mut seen = HashSet::new();
+        let mut pending: VecDeque<String> =
+            [self.proto.get_input_type(), self.proto.get_output_type()]
+                .iter()
+                .map(|fqn| fqn.to_string())
+                .collect();
+        while let Some(fqn) = pending.pop_front() {
+            if !seen.insert(fqn.clone()) {
+                continue;
             }
-        };
+            digest = util::fnv1a(digest, fqn.as_bytes());
+            let bytes = match self.root_scope.find_message_or_enum(&fqn) {
+                MessageOrEnumWithScope::Message(m) => {
+                    pending.extend(referenced_types(m.message));
+                    m.message.write_to_bytes()
+                }
+                MessageOrEnumWithScope::Enum(e) => e.en.write_to_bytes(),
+            };
+            digest = util::fnv1a(digest, &bytes.unwrap());
+        }
+        digest
+    }
+
+    fn idempotent(&self) -> bool {
+        let path = format!(
+            "{}.{}/{}",
+            self.package_name,
+            self.service_name,
+            self.proto.get_name()
+        );
+        self.customize.idempotent_methods.contains(&path)
+            || self.proto.get_options().get_idempotency_level()
+                != MethodOptions_IdempotencyLevel::IDEMPOTENCY_UNKNOWN
+    }
+
+    /// The signature of the variant of a client method taking call options.
+    fn with_options(&self, signature: &str) -> String {
+        let ttrpc = ttrpc_crate(self.customize);
+        let (name, rest) = signature.split_once('(').unwrap();
+        let (args, ret) = rest.split_once(") ->").unwrap();
+        format!("{name}_with_options({args}, opts: &{ttrpc}::r#async::CallOptions) ->{ret}")
+    }
+
+    fn tower_service_name(&self) -> String {
+        format!("{}{}Service", self.service_name, self.struct_name())
+    }
+
+    fn write_tower_service(&self, w: &mut CodeWriter, client_name: &str) {
+        let ttrpc = ttrpc_crate(self.customize);
+        if !matches!(self.method_type().0, MethodType::Unary) {
+            return;
+        }
+
+        let name = self.tower_service_name();
+        w.write_line(&format!(
+            "/// A `tower::Service` calling `{}.{}/{}` with a fixed context.",
+            self.package_name,
+            self.service_name,
+            self.proto.get_name(),
+        ));
+        w.write_line("#[derive(Clone)]");
+        w.pub_struct(&name, |w| {
+            w.field_decl("client", client_name);
+            w.field_decl("ctx", &format!("{ttrpc}::context::Context"));
+        });
+
+        w.write_line("");
+        w.impl_self_block(&name, |w| {
+            w.pub_fn(
+                &format!("new(client: {client_name}, ctx: {ttrpc}::context::Context) -> Self"),
+                |w| {
+                    w.expr_block(&name, |w| {
+                        w.field_entry("client", "client");
+                        w.field_entry("ctx", "ctx");
+                    });
+                },
+            );
+        });
+
+        w.write_line("");
+        w.block(
+            &format!("impl ::tower::Service<{}> for {} {{", self.input(), name),
+            "}",
+            |w| {
+                w.write_line(&format!("type Response = {};", self.output()));
+                w.write_line(format!("type Error = {ttrpc}::Error;"));
+                w.write_line(&format!(
+                    "type Future = ::std::pin::Pin<::std::boxed::Box<dyn ::std::future::Future<Output = {}<{}>> + Send>>;",
+                    fq_ttrpc(self.customize, "Result"),
+                    self.output()
+                ));
+                w.write_line("");
+                w.def_fn(
+                    &format!(
+                        "poll_ready(&mut self, _cx: &mut ::std::task::Context<'_>) -> ::std::task::Poll<{}<()>>",
+                        fq_ttrpc(self.customize, "Result")
+                    ),
+                    |w| {
+                        w.write_line("::std::task::Poll::Ready(Ok(()))");
+                    },
+                );
+                w.write_line("");
+                w.def_fn(
+                    &format!("call(&mut self, req: {}) -> Self::Future", self.input()),
+                    |w| {
+                        w.write_line("let client = self.client.clone();");
+                        w.write_line("let ctx = self.ctx.clone();");
+                        w.write_line(&format!(
+                            "::std::boxed::Box::pin(async move {{ client.{}(ctx, &req).await }})",
+                            self.name()
+                        ));
+                    },
+                );
+            },
+        );
     }
 
     fn write_service(&self, w: &mut CodeWriter) {
+        let ttrpc = ttrpc_crate(self.customize);
         let (_req, req_type, resp_type) = match self.method_type().0 {
             MethodType::Unary => ("req", self.input(), self.output()),
             MethodType::ClientStreaming => (
                 "stream",
-                format!("::ttrpc::r#async::ServerStreamReceiver<{}>", self.input()),
+                format!("{ttrpc}::r#async::ServerStreamReceiver<{}>", self.input()),
                 self.output(),
             ),
             MethodType::ServerStreaming => (
                 "req",
                 format!(
-                    "{}, _: {}<{}>",
+                    "{}, _: {ttrpc}::r#async::ServerStreamSender<{}>",
                     self.input(),
-                    "::ttrpc::r#async::ServerStreamSender",
                     self.output()
                 ),
                 "()".to_string(),
@@ -357,8 +587,7 @@ impl<'a> MethodGen<'a> {
             MethodType::Duplex => (
                 "stream",
                 format!(
-                    "{}<{}, {}>",
-                    "::ttrpc::r#async::ServerStream",
+                    "{ttrpc}::r#async::ServerStream<{}, {}>",
                     self.output(),
                     self.input(),
                 ),
@@ -366,18 +595,27 @@ impl<'a> MethodGen<'a> {
             ),
         };
 
+        let resp_type = match self.method_type().0 {
+            MethodType::Unary | MethodType::ClientStreaming
+                if self.customize.encoded_responses && async_on(self.customize, "server") =>
+            {
+                format!("{ttrpc}::r#async::EncodedResponse<{}>", resp_type)
+            }
+            _ => resp_type,
+        };
+
         let get_sig = |context_name| {
             format!(
-                "{}(&self, _ctx: &{}, _: {}) -> ::ttrpc::Result<{}>",
+                "{}(&self, _ctx: &{}, _: {}) -> {ttrpc}::Result<{}>",
                 self.name(),
-                fq_grpc(context_name),
+                fq_ttrpc(self.customize, context_name),
                 req_type,
                 resp_type,
             )
         };
 
         let cb = |w: &mut CodeWriter| {
-            w.write_line(format!("Err(::ttrpc::Error::RpcStatus(::ttrpc::get_status(::ttrpc::Code::NOT_FOUND, \"/{}.{}/{} is not supported\".to_string())))",
+            w.write_line(format!("Err({ttrpc}::Error::RpcStatus({ttrpc}::get_status({ttrpc}::Code::NOT_FOUND, \"/{}.{}/{} is not supported\".to_string())))",
             self.package_name,
             self.service_name, self.proto.get_name(),));
         };
@@ -392,7 +630,8 @@ impl<'a> MethodGen<'a> {
     }
 
     fn write_bind(&self, w: &mut CodeWriter) {
-        let method_handler_name = "::ttrpc::MethodHandler";
+        let ttrpc = ttrpc_crate(self.customize);
+        let method_handler_name = format!("{ttrpc}::MethodHandler");
 
         let s = format!(
             "methods.insert(\"/{}.{}/{}\".to_string(),
@@ -407,21 +646,20 @@ impl<'a> MethodGen<'a> {
     }
 
     fn write_async_bind(&self, w: &mut CodeWriter) {
+        let ttrpc = ttrpc_crate(self.customize);
         let s = if matches!(self.method_type().0, MethodType::Unary) {
             format!(
                 "methods.insert(\"{}\".to_string(),
-                    Box::new({}Method{{service: service.clone()}}) as {});",
+                    Box::new({}Method{{service: service.clone()}}) as Box<dyn {ttrpc}::r#async::MethodHandler + Send + Sync>);",
                 self.proto.get_name(),
                 self.struct_name(),
-                "Box<dyn ::ttrpc::r#async::MethodHandler + Send + Sync>"
             )
         } else {
             format!(
                 "streams.insert(\"{}\".to_string(),
-                    Arc::new({}Method{{service: service.clone()}}) as {});",
+                    Arc::new({}Method{{service: service.clone()}}) as Arc<dyn {ttrpc}::r#async::StreamHandler + Send + Sync>);",
                 self.proto.get_name(),
                 self.struct_name(),
-                "Arc<dyn ::ttrpc::r#async::StreamHandler + Send + Sync>"
             )
         };
         w.write_line(&s);
@@ -491,15 +729,16 @@ impl<'a> ServiceGen<'a> {
     }
 
     fn write_sync_client(&self, w: &mut CodeWriter) {
+        let ttrpc = ttrpc_crate(self.customize);
         w.write_line("#[derive(Clone)]");
         w.pub_struct(&self.client_name(), |w| {
-            w.field_decl("client", "::ttrpc::Client");
+            w.field_decl("client", &format!("{ttrpc}::Client"));
         });
 
         w.write_line("");
 
         w.impl_self_block(&self.client_name(), |w| {
-            w.pub_fn("new(client: ::ttrpc::Client) -> Self", |w| {
+            w.pub_fn(&format!("new(client: {ttrpc}::Client) -> Self"), |w| {
                 w.expr_block(&self.client_name(), |w| {
                     w.field_entry("client", "client");
                 });
@@ -513,25 +752,36 @@ impl<'a> ServiceGen<'a> {
     }
 
     fn write_async_client(&self, w: &mut CodeWriter) {
+        let ttrpc = ttrpc_crate(self.customize);
         w.write_line("#[derive(Clone)]");
         w.pub_struct(&self.client_name(), |w| {
-            w.field_decl("client", "::ttrpc::r#async::Client");
+            w.field_decl("client", &format!("{ttrpc}::r#async::Client"));
         });
 
         w.write_line("");
 
         w.impl_self_block(&self.client_name(), |w| {
-            w.pub_fn("new(client: ::ttrpc::r#async::Client) -> Self", |w| {
-                w.expr_block(&self.client_name(), |w| {
-                    w.field_entry("client", "client");
-                });
-            });
+            w.pub_fn(
+                &format!("new(client: {ttrpc}::r#async::Client) -> Self"),
+                |w| {
+                    w.expr_block(&self.client_name(), |w| {
+                        w.field_entry("client", "client");
+                    });
+                },
+            );
 
             for method in &self.methods {
                 w.write_line("");
                 method.write_async_client(w);
             }
         });
+
+        if self.customize.tower_client {
+            for method in &self.methods {
+                w.write_line("");
+                method.write_tower_service(w, &self.client_name());
+            }
+        }
     }
 
     fn write_server(&self, w: &mut CodeWriter) {
@@ -556,7 +806,8 @@ impl<'a> ServiceGen<'a> {
     }
 
     fn write_sync_server_create(&self, w: &mut CodeWriter) {
-        let method_handler_name = "::ttrpc::MethodHandler";
+        let ttrpc = ttrpc_crate(self.customize);
+        let method_handler_name = format!("{ttrpc}::MethodHandler");
         let s = format!(
             "create_{}(service: Arc<Box<dyn {} + Send + Sync>>) -> HashMap<String, Box<dyn {} + Send + Sync>>",
             to_snake_case(&self.service_name()),
@@ -576,11 +827,11 @@ impl<'a> ServiceGen<'a> {
     }
 
     fn write_async_server_create(&self, w: &mut CodeWriter) {
+        let ttrpc = ttrpc_crate(self.customize);
         let s = format!(
-            "create_{}(service: Arc<Box<dyn {} + Send + Sync>>) -> HashMap<String, {}>",
+            "create_{}(service: Arc<Box<dyn {} + Send + Sync>>) -> HashMap<String, {ttrpc}::r#async::Service>",
             to_snake_case(&self.service_name()),
             self.service_name(),
-            "::ttrpc::r#async::Service"
         );
 
         let has_stream_method = self.has_stream_method();
@@ -598,9 +849,8 @@ impl<'a> ServiceGen<'a> {
             }
             w.write_line("");
             w.write_line(format!(
-                "ret.insert(\"{}\".to_string(), {});",
+                "ret.insert(\"{}\".to_string(), {ttrpc}::r#async::Service{{ methods, streams }});",
                 self.service_path(),
-                "::ttrpc::r#async::Service{ methods, streams }"
             ));
             w.write_line("ret");
         });
@@ -616,6 +866,12 @@ impl<'a> ServiceGen<'a> {
         }
     }
 
+    fn write_method_infos(&self, w: &mut CodeWriter) {
+        for method in &self.methods {
+            method.write_method_info(w);
+        }
+    }
+
     fn write(&self, w: &mut CodeWriter) {
         self.write_client(w);
         w.write_line("");
@@ -625,6 +881,224 @@ impl<'a> ServiceGen<'a> {
     }
 }
 
+struct BuilderGen<'a> {
+    message: MessageWithScope<'a>,
+    root_scope: &'a RootScope<'a>,
+    customize: &'a Customize,
+}
+
+impl<'a> BuilderGen<'a> {
+    fn message_type(&self) -> String {
+        self.rust_type_path(&self.message.scope, self.message.message.get_name())
+    }
+
+    fn rust_type_path(&self, scope: &Scope, name: &str) -> String {
+        let package = self.message.scope.get_file_descriptor().get_package();
+        rust_type_path(scope, name, package, self.customize)
+    }
+
+    fn builder_name(&self) -> String {
+        let mut name: String = self
+            .message
+            .scope
+            .path
+            .iter()
+            .map(|m| m.get_name())
+            .collect();
+        name.push_str(self.message.message.get_name());
+        name + "Builder"
+    }
+
+    fn type_path(&self, fqn: &str) -> String {
+        let t = self.root_scope.find_message_or_enum(fqn);
+        self.rust_type_path(t.get_scope(), t.get_name())
+    }
+
+    /// The parameter type of the setter of `field`, and the expression converting
+    /// the parameter `v` to the type of the message field.
+    fn setter(&self, field: &FieldDescriptorProto) -> Option<(String, String)> {
+        let repeated = field.get_label() == FieldDescriptorProto_Label::LABEL_REPEATED;
+        let (ty, expr) = match field.get_field_type() {
+            FieldDescriptorProto_Type::TYPE_DOUBLE => ("f64".to_string(), "v"),
+            FieldDescriptorProto_Type::TYPE_FLOAT => ("f32".to_string(), "v"),
+            FieldDescriptorProto_Type::TYPE_INT64
+            | FieldDescriptorProto_Type::TYPE_SINT64
+            | FieldDescriptorProto_Type::TYPE_SFIXED64 => ("i64".to_string(), "v"),
+            FieldDescriptorProto_Type::TYPE_UINT64 | FieldDescriptorProto_Type::TYPE_FIXED64 => {
+                ("u64".to_string(), "v")
+            }
+            FieldDescriptorProto_Type::TYPE_INT32
+            | FieldDescriptorProto_Type::TYPE_SINT32
+            | FieldDescriptorProto_Type::TYPE_SFIXED32 => ("i32".to_string(), "v"),
+            FieldDescriptorProto_Type::TYPE_UINT32 | FieldDescriptorProto_Type::TYPE_FIXED32 => {
+                ("u32".to_string(), "v")
+            }
+            FieldDescriptorProto_Type::TYPE_BOOL => ("bool".to_string(), "v"),
+            FieldDescriptorProto_Type::TYPE_STRING if repeated => ("String".to_string(), "v"),
+            FieldDescriptorProto_Type::TYPE_STRING => ("impl Into<String>".to_string(), "v.into()"),
+            FieldDescriptorProto_Type::TYPE_BYTES if repeated => ("Vec<u8>".to_string(), "v"),
+            FieldDescriptorProto_Type::TYPE_BYTES => ("impl Into<Vec<u8>>".to_string(), "v.into()"),
+            FieldDescriptorProto_Type::TYPE_ENUM if repeated => (
+                self.type_path(field.get_type_name()),
+                "v.into_iter().map(::protobuf::EnumOrUnknown::new).collect()",
+            ),
+            FieldDescriptorProto_Type::TYPE_ENUM => (
+                self.type_path(field.get_type_name()),
+                "::protobuf::EnumOrUnknown::new(v)",
+            ),
+            FieldDescriptorProto_Type::TYPE_MESSAGE => {
+                let t = self.root_scope.find_message(field.get_type_name());
+                if t.message.get_options().get_map_entry() {
+                    return None;
+                }
+                let expr = if repeated {
+                    "v"
+                } else {
+                    "::protobuf::MessageField::some(v)"
+                };
+                (self.rust_type_path(&t.scope, t.message.get_name()), expr)
+            }
+            FieldDescriptorProto_Type::TYPE_GROUP => return None,
+        };
+        if repeated {
+            return Some((format!("Vec<{}>", ty), expr.to_string()));
+        }
+
+        // Singular fields with presence are options, except for messages.
+        let proto2 = self.message.scope.get_file_descriptor().get_syntax() != "proto3";
+        let message = field.get_field_type() == FieldDescriptorProto_Type::TYPE_MESSAGE;
+        let mut expr = expr.to_string();
+        if !message && (proto2 || field.get_proto3_optional()) {
+            expr = format!("Some({})", expr);
+        }
+        Some((ty, expr))
+    }
+
+    fn write(&self, w: &mut CodeWriter) {
+        let message_type = self.message_type();
+        let builder_name = self.builder_name();
+
+        w.impl_self_block(&message_type, |w| {
+            w.pub_fn(&format!("builder() -> {}", builder_name), |w| {
+                w.write_line(&format!("{}::default()", builder_name));
+            });
+        });
+
+        w.write_line("");
+        w.write_line("#[derive(Default)]");
+        w.pub_struct(&builder_name, |w| {
+            w.field_decl("inner", &message_type);
+        });
+
+        w.write_line("");
+        w.impl_self_block(&builder_name, |w| {
+            for field in self.message.message.get_field() {
+                // Oneof members, but not proto3 optional fields which are in a
+                // synthetic oneof.
+                if field.has_oneof_index() && !field.get_proto3_optional() {
+                    continue;
+                }
+                let name = util::escape_keyword(field.get_name());
+                if name == "build" {
+                    continue;
+                }
+                if let Some((ty, expr)) = self.setter(field) {
+                    w.pub_fn(&format!("{}(mut self, v: {}) -> Self", name, ty), |w| {
+                        w.write_line(&format!("self.inner.{} = {};", name, expr));
+                        w.write_line("self");
+                    });
+                    w.write_line("");
+                }
+            }
+            w.pub_fn(&format!("build(self) -> {}", message_type), |w| {
+                w.write_line("self.inner");
+            });
+        });
+    }
+}
+
+/// The messages and enums the fields of `message` and of its nested messages are of.
+fn referenced_types(message: &DescriptorProto) -> Vec<String> {
+    let mut types: Vec<String> = message
+        .get_field()
+        .iter()
+        .filter(|field| field.has_type_name())
+        .map(|field| field.get_type_name().to_string())
+        .collect();
+    for nested in message.get_nested_type() {
+        types.extend(referenced_types(nested));
+    }
+    types
+}
+
+/// Path of the module generated by rust-protobuf for `file`, from the module generated
+/// for a file of `package`.
+fn file_mod_path(file: &FileDescriptorProto, package: &str, customize: &Customize) -> String {
+    let mut path = "super::".to_string();
+    if customize.package_modules && file.get_package() != package {
+        for _ in package_parts(package) {
+            path.push_str("super::");
+        }
+        for part in package_parts(file.get_package()) {
+            path.push_str(&util::escape_keyword(part));
+            path.push_str("::");
+        }
+    }
+    path.push_str(&proto_path_to_rust_mod(file.get_name()));
+    path
+}
+
+fn package_parts(package: &str) -> impl Iterator<Item = &str> {
+    package.split('.').filter(|p| !p.is_empty())
+}
+
+/// Path of a message or enum as generated by rust-protobuf, where nested types live
+/// in a module named after their parent message.
+fn rust_type_path(scope: &Scope, name: &str, package: &str, customize: &Customize) -> String {
+    let mut path = file_mod_path(scope.get_file_descriptor(), package, customize);
+    for parent in &scope.path {
+        path.push_str("::");
+        path.push_str(&util::escape_keyword(&util::to_nested_mod_name(
+            parent.get_name(),
+        )));
+    }
+    path.push_str("::");
+    path.push_str(name);
+    path
+}
+
+/// Write a builder for every request message of the services of `file` which is
+/// defined in `file` as well, so that each message gets a single builder.
+fn write_request_builders(
+    w: &mut CodeWriter,
+    file: &FileDescriptorProto,
+    root_scope: &RootScope,
+    customize: &Customize,
+) {
+    let mut seen = Vec::new();
+    for service in file.get_service() {
+        for method in service.get_method() {
+            let input = method.get_input_type();
+            if seen.contains(&input) {
+                continue;
+            }
+            seen.push(input);
+
+            let message = root_scope.find_message(input);
+            if message.scope.get_file_descriptor().get_name() != file.get_name() {
+                continue;
+            }
+            w.write_line("");
+            BuilderGen {
+                message,
+                root_scope,
+                customize,
+            }
+            .write(w);
+        }
+    }
+}
+
 pub fn write_generated_by(w: &mut CodeWriter, pkg: &str, version: &str) {
     w.write_line(format!(
         "// This file is generated by {pkg} {version}. Do not edit",
@@ -664,7 +1138,15 @@ fn gen_file(
         return None;
     }
 
-    let base = protobuf::descriptorx::proto_path_to_rust_mod(file.get_name());
+    let mut base = proto_path_to_rust_mod(file.get_name());
+    if customize.package_modules {
+        let dir: Vec<_> = package_parts(file.get_package())
+            .map(util::escape_keyword)
+            .collect();
+        if !dir.is_empty() {
+            base = format!("{}/{}", dir.join("/"), base);
+        }
+    }
 
     let mut v = Vec::new();
     {
@@ -683,6 +1165,21 @@ fn gen_file(
             w.write_line("");
             ServiceGen::new(service, file, root_scope, customize).write(&mut w);
         }
+
+        let ttrpc = ttrpc_crate(customize);
+        w.write_line("");
+        w.write_line("/// The methods of the services defined in this file.");
+        w.write_line(format!("pub const METHODS: &[{ttrpc}::MethodInfo] = &["));
+        w.indented(|w| {
+            for service in file.get_service() {
+                ServiceGen::new(service, file, root_scope, customize).write_method_infos(w);
+            }
+        });
+        w.write_line("];");
+
+        if customize.request_builders {
+            write_request_builders(&mut w, file, root_scope, customize);
+        }
     }
 
     Some(GenResult {
@@ -727,6 +1224,9 @@ pub fn gen_and_write(
     for r in &results {
         let mut file_path = out_dir.to_owned();
         file_path.push(&r.name);
+        if let Some(dir) = file_path.parent() {
+            fs::create_dir_all(dir)?;
+        }
         let mut file_writer = File::create(&file_path)?;
         file_writer.write_all(&r.content)?;
         file_writer.flush()?;
@@ -36,4 +36,28 @@ pub struct Customize {
     pub async_client: bool,
     /// Indicates whether to generate async code for server.
     pub async_server: bool,
+    /// Indicates whether to generate a `tower::Service` for every unary method of
+    /// the async client. The generated code depends on the `tower` crate.
+    pub tower_client: bool,
+    /// Indicates whether unary and client streaming methods of the async server return
+    /// `ttrpc::r#async::EncodedResponse`, which may hold an already encoded response.
+    pub encoded_responses: bool,
+    /// Indicates whether to generate builders for the request messages defined in the
+    /// same file, e.g. `CreateTaskRequest::builder().id(..).bundle(..).build()`.
+    /// Oneof and map fields have no setter.
+    pub request_builders: bool,
+    /// Path of the ttrpc crate in generated code, `::ttrpc` if unset. Set it when ttrpc is
+    /// renamed or re-exported by another crate, e.g. `::my_runtime::ttrpc`.
+    pub crate_path: Option<String>,
+    /// Unary methods the async client may retry, as `package.Service/Method`, see
+    /// `ttrpc::r#async::ClientBuilder::retry`. Methods whose `idempotency_level`
+    /// option is `NO_SIDE_EFFECTS` or `IDEMPOTENT` are retried too.
+    pub idempotent_methods: Vec<String>,
+    /// Indicates whether to generate files in directories matching their package, e.g.
+    /// `containerd/services/tasks/v1/tasks_ttrpc.rs` for `containerd.services.tasks.v1`,
+    /// to be included in the matching module hierarchy. Messages of other packages are
+    /// referenced through that hierarchy, so the modules generated by rust-protobuf must
+    /// be laid out the same way. rust-protobuf refers to the files imported as siblings
+    /// though, so these need to be re-exported next to the importing one.
+    pub package_modules: bool,
 }
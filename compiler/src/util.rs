@@ -94,10 +94,50 @@ pub fn to_camel_case(name: &str) -> String {
     camel_case_name
 }
 
+/// Name of the module rust-protobuf generates for the nested types of a message.
+pub fn to_nested_mod_name(message_name: &str) -> String {
+    let mut name = String::with_capacity(message_name.len());
+    let mut last_lower = false;
+    for c in message_name.chars() {
+        if c.is_ascii_uppercase() && last_lower {
+            name.push('_');
+        }
+        name.push(c.to_ascii_lowercase());
+        last_lower = c.is_lowercase();
+    }
+    name
+}
+
+const RUST_KEYWORDS: &[&str] = &[
+    "abstract", "as", "async", "await", "become", "box", "break", "const", "continue", "crate",
+    "do", "dyn", "else", "enum", "extern", "false", "final", "fn", "for", "if", "impl", "in",
+    "let", "loop", "macro", "match", "mod", "move", "mut", "override", "priv", "pub", "ref",
+    "return", "self", "Self", "static", "struct", "super", "trait", "true", "try", "type",
+    "typeof", "unsafe", "unsized", "use", "virtual", "where", "while", "yield",
+];
+
+/// Escape `ident` the way rust-protobuf does if it is a keyword.
+pub fn escape_keyword(ident: &str) -> String {
+    if RUST_KEYWORDS.contains(&ident) {
+        format!("{}_", ident)
+    } else {
+        ident.to_string()
+    }
+}
+
+/// Path of the ttrpc crate in generated code, see [`Customize::crate_path`](crate::Customize::crate_path).
+pub fn ttrpc_crate(customize: &crate::Customize) -> &str {
+    customize.crate_path.as_deref().unwrap_or("::ttrpc")
+}
+
 pub fn fq_grpc(item: &str) -> String {
     format!("::ttrpc::{}", item)
 }
 
+pub fn fq_ttrpc(customize: &crate::Customize, item: &str) -> String {
+    format!("{}::{}", ttrpc_crate(customize), item)
+}
+
 pub fn async_on(customize: &crate::Customize, r#type: &str) -> bool {
     if r#type == "server" {
         customize.async_all || customize.async_server
@@ -131,6 +171,15 @@ where
     async_fn_block(w, false, sig, cb);
 }
 
+pub const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+
+/// Folds `bytes` into the 64-bit FNV-1a hash `hash`, which starts at [`FNV_OFFSET`].
+pub fn fnv1a(hash: u64, bytes: &[u8]) -> u64 {
+    bytes.iter().fold(hash, |hash, b| {
+        (hash ^ *b as u64).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
 pub enum MethodType {
     Unary,
     ClientStreaming,
@@ -197,4 +246,31 @@ mod test {
             assert_eq!(res, exp);
         }
     }
+
+    #[test]
+    fn test_nested_mod_name() {
+        let cases = vec![
+            ("Mount", "mount"),
+            ("ContainerInfo", "container_info"),
+            ("CreateIDForReq", "create_idfor_req"),
+            ("Type", "type"),
+        ];
+
+        for (origin, exp) in cases {
+            let res = super::to_nested_mod_name(origin);
+            assert_eq!(res, exp);
+        }
+        assert_eq!(super::escape_keyword("type"), "type_");
+        assert_eq!(super::escape_keyword("id"), "id");
+    }
+
+    #[test]
+    fn test_fnv1a() {
+        assert_eq!(super::fnv1a(super::FNV_OFFSET, b""), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(super::fnv1a(super::FNV_OFFSET, b"a"), 0xaf63_dc4c_8601_ec8c);
+        assert_eq!(
+            super::fnv1a(super::fnv1a(super::FNV_OFFSET, b"foo"), b"bar"),
+            super::fnv1a(super::FNV_OFFSET, b"foobar")
+        );
+    }
 }
@@ -0,0 +1,152 @@
+// Copyright (c) 2023 Ant Group
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Socket addresses of the supported transports.
+
+use std::convert::TryFrom;
+use std::fmt;
+use std::net::SocketAddr;
+use std::str::FromStr;
+
+use crate::error::Error;
+
+/// Address of a [vsock](https://man7.org/linux/man-pages/man7/vsock.7.html) socket.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct VsockAddr {
+    pub cid: u32,
+    pub port: u32,
+}
+
+impl VsockAddr {
+    pub fn new(cid: u32, port: u32) -> VsockAddr {
+        VsockAddr { cid, port }
+    }
+}
+
+impl fmt::Display for VsockAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "vsock://{}:{}", self.cid, self.port)
+    }
+}
+
+/// Parses the `cid:port` part of a `vsock://cid:port` socket address.
+impl FromStr for VsockAddr {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.strip_prefix("vsock://").unwrap_or(s);
+        let invalid = || Error::Others(format!("sockaddr {s} is not right for vsock"));
+        let (cid, port) = s.split_once(':').ok_or_else(invalid)?;
+        // The cid is traditionally given as -1 (VMADDR_CID_ANY) by servers.
+        let cid = match cid.parse::<i64>().map_err(|_| invalid())? {
+            -1 => u32::MAX,
+            cid => u32::try_from(cid).map_err(|_| invalid())?,
+        };
+        let port = port.parse().map_err(|_| invalid())?;
+        Ok(VsockAddr { cid, port })
+    }
+}
+
+/// Address of a socket of any supported transport.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum Address {
+    /// Path of a Unix domain socket, starting with `@` for an abstract socket. It is
+    /// empty for an unnamed socket, which is what clients of a socket in the file
+    /// system connect from. Clients of an abstract socket connect from an abstract
+    /// name the kernel picks, which a server binding to `@` alone gets too.
+    Unix(String),
+    Vsock(VsockAddr),
+    /// Path of a Unix domain socket of type `SOCK_SEQPACKET`, see [`Address::Unix`].
+    UnixPacket(String),
+    /// TCP address, with an IP rather than a host name.
+    Tcp(SocketAddr),
+}
+
+impl From<VsockAddr> for Address {
+    fn from(addr: VsockAddr) -> Self {
+        Address::Vsock(addr)
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Address::Unix(path) => write!(f, "unix://{path}"),
+            Address::Vsock(addr) => addr.fmt(f),
+            Address::UnixPacket(path) => write!(f, "unixpacket://{path}"),
+            Address::Tcp(addr) => write!(f, "tcp://{addr}"),
+        }
+    }
+}
+
+impl FromStr for Address {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(path) = s.strip_prefix("unix://") {
+            return Ok(Address::Unix(path.to_string()));
+        }
+        if let Some(path) = s.strip_prefix("unixpacket://") {
+            return Ok(Address::UnixPacket(path.to_string()));
+        }
+        if let Some(addr) = s.strip_prefix("tcp://") {
+            return addr
+                .parse()
+                .map(Address::Tcp)
+                .map_err(|_| Error::Others(format!("invalid tcp address {s:?}")));
+        }
+        if s.starts_with("vsock://") {
+            return s.parse().map(Address::Vsock);
+        }
+        Err(Error::Others(format!("Scheme {s:?} is not supported")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_address() {
+        for (input, expected) in [
+            (
+                "unix:///run/a.sock",
+                Some(Address::Unix("/run/a.sock".into())),
+            ),
+            (
+                "unix://@/run/b.sock",
+                Some(Address::Unix("@/run/b.sock".into())),
+            ),
+            ("vsock://8:1024", Some(VsockAddr::new(8, 1024).into())),
+            (
+                "vsock://-1:1024",
+                Some(VsockAddr::new(u32::MAX, 1024).into()),
+            ),
+            (
+                "unixpacket:///run/c.sock",
+                Some(Address::UnixPacket("/run/c.sock".into())),
+            ),
+            (
+                "tcp://127.0.0.1:1024",
+                Some(Address::Tcp("127.0.0.1:1024".parse().unwrap())),
+            ),
+            (
+                "tcp://[::1]:1024",
+                Some(Address::Tcp("[::1]:1024".parse().unwrap())),
+            ),
+            ("tcp://localhost:1024", None),
+            ("vsock://8", None),
+            ("vsock://8:port", None),
+            ("abc:///run/c.sock", None),
+        ] {
+            let addr = input.parse::<Address>().ok();
+            assert_eq!(addr, expected, "{input}");
+            if let Some(addr) = addr.filter(|_| !input.contains("-1")) {
+                assert_eq!(addr.to_string(), input);
+            }
+        }
+    }
+}
@@ -0,0 +1,240 @@
+// Copyright (c) 2023 Ant Group
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Adaptive limit of the calls in progress on a client, see
+//! [`ClientBuilder::adaptive_in_flight`](crate::r#async::ClientBuilder::adaptive_in_flight).
+
+use std::cmp::Ordering;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::error::{Error, Result};
+use crate::proto::Code;
+use crate::r#async::clock::Clock;
+
+/// Adapts the number of calls a client makes at once to how fast the server
+/// answers them, so an aggressive caller doesn't overwhelm a small server, e.g. the
+/// agent of a guest.
+///
+/// The limit grows by one every time as many calls as the limit were answered
+/// within the latency, and is multiplied by the backoff as soon as a call was
+/// slower, timed out or was rejected with `RESOURCE_EXHAUSTED`.
+#[derive(Clone, Debug)]
+pub struct AdaptiveLimit {
+    min: usize,
+    max: usize,
+    latency: Duration,
+    backoff: f64,
+}
+
+impl AdaptiveLimit {
+    /// Allows between `min` and `max` calls at once, starting with `min`, and
+    /// backs off when a call takes longer than `latency`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `min` is 0 or greater than `max`.
+    pub fn new(min: usize, max: usize, latency: Duration) -> AdaptiveLimit {
+        assert!(
+            min > 0 && min <= max,
+            "adaptive limit bounds must satisfy 0 < min <= max"
+        );
+        AdaptiveLimit {
+            min,
+            max,
+            latency,
+            backoff: 0.9,
+        }
+    }
+
+    /// Multiply the limit by `backoff` when the server is overloaded. Defaults to
+    /// 0.9.
+    ///
+    /// # Panics
+    ///
+    /// Panics unless `backoff` is between 0 and 1, exclusive.
+    pub fn backoff(mut self, backoff: f64) -> AdaptiveLimit {
+        assert!(
+            backoff > 0.0 && backoff < 1.0,
+            "adaptive limit backoff must be between 0 and 1"
+        );
+        self.backoff = backoff;
+        self
+    }
+}
+
+/// An [`AdaptiveLimit`] at work, which resizes the semaphore of the calls in
+/// progress, shared by the clones of a client.
+pub(crate) struct AdaptiveLimiter {
+    config: AdaptiveLimit,
+    permits: Arc<Semaphore>,
+    state: Mutex<State>,
+    clock: Arc<dyn Clock>,
+}
+
+struct State {
+    limit: f64,
+    /// The permits in use to forget once given back, when the limit shrank below
+    /// the calls in progress.
+    debt: usize,
+}
+
+impl AdaptiveLimiter {
+    pub(crate) fn new(config: AdaptiveLimit, clock: Arc<dyn Clock>) -> AdaptiveLimiter {
+        AdaptiveLimiter {
+            permits: Arc::new(Semaphore::new(config.min)),
+            state: Mutex::new(State {
+                limit: config.min as f64,
+                debt: 0,
+            }),
+            config,
+            clock,
+        }
+    }
+
+    pub(crate) fn permits(&self) -> Arc<Semaphore> {
+        self.permits.clone()
+    }
+
+    /// The number of calls allowed at once.
+    pub(crate) fn limit(&self) -> usize {
+        self.state.lock().unwrap().limit as usize
+    }
+
+    /// Gives back a permit of the semaphore.
+    pub(crate) fn release(&self, permit: OwnedSemaphorePermit) {
+        let mut state = self.state.lock().unwrap();
+        if state.debt > 0 {
+            state.debt -= 1;
+            permit.forget();
+        }
+    }
+
+    /// Starts measuring a call which holds a permit.
+    pub(crate) fn sample(self: &Arc<Self>) -> Sample {
+        Sample {
+            limiter: self.clone(),
+            started: self.clock.now(),
+            done: false,
+        }
+    }
+
+    fn record(&self, overloaded: bool) {
+        let mut state = self.state.lock().unwrap();
+        let old = state.limit as usize;
+        state.limit = if overloaded {
+            (state.limit * self.config.backoff).max(self.config.min as f64)
+        } else {
+            (state.limit + 1.0 / state.limit).min(self.config.max as f64)
+        };
+        let new = state.limit as usize;
+        match new.cmp(&old) {
+            Ordering::Greater => {
+                let grown = new - old;
+                let paid = grown.min(state.debt);
+                state.debt -= paid;
+                self.permits.add_permits(grown - paid);
+            }
+            Ordering::Less => {
+                debug!("Adaptive in-flight limit shrinks to {}", new);
+                for _ in new..old {
+                    match self.permits.try_acquire() {
+                        Ok(permit) => permit.forget(),
+                        Err(_) => state.debt += 1,
+                    }
+                }
+            }
+            Ordering::Equal => {}
+        }
+    }
+}
+
+/// Measures the latency of a unary call, see [`AdaptiveLimiter::sample`]. A call
+/// dropped before it finished, e.g. because it timed out, counts as overloaded.
+pub(crate) struct Sample {
+    limiter: Arc<AdaptiveLimiter>,
+    started: Instant,
+    done: bool,
+}
+
+impl Sample {
+    /// Accounts for the outcome of the call.
+    pub(crate) fn finish<T>(mut self, res: &Result<T>) {
+        self.done = true;
+        let overloaded = match res {
+            // Says nothing about the load of the server.
+            Err(e) if e.is_connection_error() => return,
+            Err(Error::RpcStatus(status))
+                if status.code() == Code::DEADLINE_EXCEEDED
+                    || status.code() == Code::RESOURCE_EXHAUSTED =>
+            {
+                true
+            }
+            _ => {
+                let latency = self
+                    .limiter
+                    .clock
+                    .now()
+                    .saturating_duration_since(self.started);
+                latency > self.limiter.config.latency
+            }
+        };
+        self.limiter.record(overloaded);
+    }
+}
+
+impl Drop for Sample {
+    fn drop(&mut self) {
+        if !self.done {
+            self.limiter.record(true);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::r#async::MockClock;
+
+    #[test]
+    fn test_adaptive_limit() {
+        let clock = MockClock::new();
+        let limiter = Arc::new(AdaptiveLimiter::new(
+            AdaptiveLimit::new(2, 4, Duration::from_millis(10)).backoff(0.5),
+            Arc::new(clock.clone()),
+        ));
+        let ok: Result<()> = Ok(());
+        limiter.sample().finish(&ok);
+        limiter.sample().finish(&ok);
+        assert_eq!(limiter.limit(), 2);
+        for _ in 0..10 {
+            limiter.sample().finish(&ok);
+        }
+        assert_eq!(limiter.limit(), 4);
+        assert_eq!(limiter.permits.available_permits(), 4);
+
+        // Shrinks below the calls in progress, which are forgotten once finished.
+        let held: Vec<_> = (0..3)
+            .map(|_| limiter.permits.clone().try_acquire_owned().unwrap())
+            .collect();
+        let slow = limiter.sample();
+        clock.advance(Duration::from_millis(20));
+        slow.finish(&ok);
+        assert_eq!(limiter.limit(), 2);
+        assert_eq!(limiter.permits.available_permits(), 0);
+        for permit in held {
+            limiter.release(permit);
+        }
+        assert_eq!(limiter.permits.available_permits(), 2);
+
+        drop(limiter.sample());
+        assert_eq!(limiter.limit(), 2);
+        let e: Result<()> = Err(Error::PeerClosed);
+        limiter.sample().finish(&e);
+        assert_eq!(limiter.limit(), 2);
+    }
+}
@@ -0,0 +1,196 @@
+// Copyright (c) 2023 Ant Group
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! The built-in admin service, see [`Server::admin_service`](crate::r#async::Server::admin_service).
+
+use std::collections::HashMap;
+
+use async_trait::async_trait;
+
+use crate::error::{get_status, Error, Result};
+use crate::proto::admin::{
+    CloseConnectionRequest, CloseConnectionResponse, Connection, ListConnectionsRequest,
+    ListConnectionsResponse,
+};
+use crate::proto::{Code, Request, Response};
+use crate::r#async::server::{self, ConnectionMap};
+use crate::r#async::{MethodHandler, Service, TtrpcContext};
+
+pub(crate) const ADMIN_SERVICE: &str = "ttrpc.admin.Admin";
+
+pub(crate) fn service(connections: ConnectionMap) -> HashMap<String, Service> {
+    let mut methods: HashMap<String, Box<dyn MethodHandler + Send + Sync>> = HashMap::new();
+    methods.insert(
+        "ListConnections".to_string(),
+        Box::new(ListConnections {
+            connections: connections.clone(),
+        }),
+    );
+    methods.insert(
+        "CloseConnection".to_string(),
+        Box::new(CloseConnection { connections }),
+    );
+
+    let mut services = HashMap::new();
+    services.insert(
+        ADMIN_SERVICE.to_string(),
+        Service {
+            methods,
+            streams: HashMap::new(),
+        },
+    );
+    services
+}
+
+struct ListConnections {
+    connections: ConnectionMap,
+}
+
+#[async_trait]
+impl MethodHandler for ListConnections {
+    async fn handler(&self, _ctx: TtrpcContext, req: Request) -> Result<Response> {
+        let _: ListConnectionsRequest = decode(&req)?;
+
+        let mut resp = ListConnectionsResponse::new();
+        resp.connections = server::list_connections(&self.connections)
+            .into_iter()
+            .map(|info| {
+                let mut c = Connection::new();
+                c.id = info.id;
+                c.peer = info.peer;
+                c.age_nano = info.age.as_nanos() as u64;
+                c.open_streams = info.open_streams as u64;
+                c.bytes_read = info.bytes_read;
+                c.bytes_written = info.bytes_written;
+                c
+            })
+            .collect();
+        respond(resp)
+    }
+}
+
+struct CloseConnection {
+    connections: ConnectionMap,
+}
+
+#[async_trait]
+impl MethodHandler for CloseConnection {
+    async fn handler(&self, _ctx: TtrpcContext, req: Request) -> Result<Response> {
+        let req: CloseConnectionRequest = decode(&req)?;
+
+        match server::close_connection(&self.connections, req.id, &req.reason) {
+            Ok(()) => respond(CloseConnectionResponse::new()),
+            Err(e) => Ok(e.into()),
+        }
+    }
+}
+
+fn decode<M: protobuf::Message>(req: &Request) -> Result<M> {
+    M::parse_from_bytes(&req.payload).map_err(err_to_others_err!(e, "Decode request failed."))
+}
+
+fn respond(msg: impl protobuf::Message) -> Result<Response> {
+    let mut resp = Response::new();
+    resp.set_status(get_status(Code::OK, ""));
+    resp.payload = msg
+        .write_to_bytes()
+        .map_err(err_to_others_err!(e, "Encode response failed."))?;
+    Ok(resp)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::proto::Codec;
+    use crate::r#async::{testing, Client, Server};
+
+    struct Echo;
+
+    #[async_trait]
+    impl MethodHandler for Echo {
+        async fn handler(&self, _ctx: TtrpcContext, req: Request) -> Result<Response> {
+            Ok(Response {
+                payload: req.payload,
+                ..Default::default()
+            })
+        }
+    }
+
+    fn request(service: &str, method: &str, payload: Vec<u8>) -> Request {
+        Request {
+            service: service.to_string(),
+            method: method.to_string(),
+            payload,
+            timeout_nano: 5_000_000_000,
+            ..Default::default()
+        }
+    }
+
+    async fn list(admin: &Client) -> Vec<Connection> {
+        let payload = ListConnectionsRequest::new().encode().unwrap();
+        let req = request(ADMIN_SERVICE, "ListConnections", payload);
+        let res = admin.request(req).await.unwrap();
+        ListConnectionsResponse::decode(res.payload)
+            .unwrap()
+            .connections
+    }
+
+    async fn close(admin: &Client, id: i32) -> Result<Response> {
+        let mut close = CloseConnectionRequest::new();
+        close.id = id;
+        close.reason = "test".to_string();
+        let req = request(ADMIN_SERVICE, "CloseConnection", close.encode().unwrap());
+        admin.request(req).await
+    }
+
+    #[tokio::test]
+    async fn test_admin_service() {
+        let server = Server::new();
+        let mut services = server.admin_service();
+        let mut methods: HashMap<String, Box<dyn MethodHandler + Send + Sync>> = HashMap::new();
+        methods.insert("Echo".to_string(), Box::new(Echo));
+        services.insert(
+            "test.Test".to_string(),
+            Service {
+                methods,
+                streams: HashMap::new(),
+            },
+        );
+        let server = testing::start(server.register_service(services))
+            .await
+            .unwrap();
+
+        let client = Client::connect(&server.address()).unwrap();
+        client
+            .request(request("test.Test", "Echo", b"ping".to_vec()))
+            .await
+            .unwrap();
+        let id = server.server().connections()[0].id;
+
+        let admin = Client::connect(&server.address()).unwrap();
+        let connections = list(&admin).await;
+        assert_eq!(connections.len(), 2);
+        let listed = connections.iter().find(|c| c.id == id).unwrap();
+        // The request and response of the echo.
+        assert!(listed.bytes_read > 0 && listed.bytes_written > 0);
+        assert_eq!(listed.open_streams, 0);
+
+        close(&admin, id).await.unwrap();
+        while server.server().connections().len() != 1 {
+            tokio::time::sleep(std::time::Duration::from_millis(1)).await;
+        }
+        assert!(client
+            .request(request("test.Test", "Echo", b"ping".to_vec()))
+            .await
+            .is_err());
+        assert!(list(&admin).await.iter().all(|c| c.id != id));
+
+        match close(&admin, id).await {
+            Err(Error::RpcStatus(status)) => assert_eq!(status.code(), Code::NOT_FOUND),
+            res => panic!("unexpected {:?}", res),
+        }
+    }
+}
@@ -0,0 +1,212 @@
+// Copyright (c) 2023 Ant Group
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Blocking calls over the connection of an async client.
+
+use std::future::Future;
+use std::thread::{self, JoinHandle};
+
+use tokio::runtime::{Builder, Handle};
+use tokio::sync::oneshot;
+
+use crate::error::{Error, Result};
+use crate::proto::{Request, Response};
+use crate::r#async::{CallOptions, CallTiming, Client, ClientBuilder};
+
+/// Makes blocking calls through an async [`Client`], so blocking and async code
+/// may share one connection instead of using the sync client alongside.
+///
+/// The calls run on a runtime owned by the client, or on the runtime of an
+/// existing client, see [`BlockingClient::with_handle`]. Calls must not be made
+/// from an async context, as blocking a runtime thread panics.
+///
+/// Generated async clients are used through [`BlockingClient::block_on`], e.g.
+/// `blocking.block_on(AgentServiceClient::new(blocking.client().clone()).list(ctx, &req))`.
+pub struct BlockingClient {
+    client: Client,
+    handle: Handle,
+    _runtime: Option<RuntimeThread>,
+}
+
+impl BlockingClient {
+    /// Connect to `sockaddr` on a runtime owned by the returned client.
+    pub fn connect(sockaddr: &str) -> Result<BlockingClient> {
+        Self::build(ClientBuilder::new(sockaddr))
+    }
+
+    /// Build the client of `builder` on a runtime owned by the returned client.
+    pub fn build(builder: ClientBuilder) -> Result<BlockingClient> {
+        let runtime = RuntimeThread::start()?;
+        let handle = runtime.handle.clone();
+        let client = {
+            let _guard = handle.enter();
+            builder.build()?
+        };
+        Ok(BlockingClient {
+            client,
+            handle,
+            _runtime: Some(runtime),
+        })
+    }
+
+    /// Make blocking calls through `client`, which was created on the runtime of
+    /// `handle`. The runtime must be driven by other threads, e.g. a multi-thread
+    /// runtime, or a current-thread runtime running `Runtime::block_on`.
+    pub fn with_handle(client: Client, handle: Handle) -> BlockingClient {
+        BlockingClient {
+            client,
+            handle,
+            _runtime: None,
+        }
+    }
+
+    /// The async client, whose clones share the connection.
+    pub fn client(&self) -> &Client {
+        &self.client
+    }
+
+    /// Runs `future` to completion on the runtime of the client.
+    pub fn block_on<F: Future>(&self, future: F) -> F::Output {
+        self.handle.block_on(future)
+    }
+
+    /// Requests a unary request and returns with response.
+    pub fn request(&self, req: Request) -> Result<Response> {
+        self.block_on(self.client.request(req))
+    }
+
+    /// Blocking version of [`Client::request_with_options`].
+    pub fn request_with_options(
+        &self,
+        req: Request,
+        opts: &CallOptions,
+    ) -> Result<(Response, Option<CallTiming>)> {
+        self.block_on(self.client.request_with_options(req, opts))
+    }
+}
+
+/// A current-thread runtime driven by a dedicated thread until dropped.
+struct RuntimeThread {
+    handle: Handle,
+    stop: Option<oneshot::Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl RuntimeThread {
+    fn start() -> Result<RuntimeThread> {
+        let runtime = Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .map_err(err_to_others_err!(e, "Build runtime failed: "))?;
+        let handle = runtime.handle().clone();
+        let (stop, stopped) = oneshot::channel::<()>();
+        let thread = thread::Builder::new()
+            .name("ttrpc-blocking-client".to_string())
+            .spawn(move || {
+                runtime.block_on(stopped).ok();
+            })
+            .map_err(|e| Error::Others(format!("Spawn runtime thread failed: {e}")))?;
+        Ok(RuntimeThread {
+            handle,
+            stop: Some(stop),
+            thread: Some(thread),
+        })
+    }
+}
+
+impl Drop for RuntimeThread {
+    fn drop(&mut self) {
+        if let Some(stop) = self.stop.take() {
+            stop.send(()).ok();
+        }
+        if let Some(thread) = self.thread.take() {
+            thread.join().ok();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::HashMap;
+
+    use async_trait::async_trait;
+
+    use crate::r#async::{testing, MethodHandler, Server, Service, TtrpcContext};
+
+    struct Echo;
+
+    #[async_trait]
+    impl MethodHandler for Echo {
+        async fn handler(&self, _ctx: TtrpcContext, req: Request) -> Result<Response> {
+            Ok(Response {
+                payload: req.payload,
+                ..Default::default()
+            })
+        }
+    }
+
+    fn echo(payload: &[u8]) -> Request {
+        Request {
+            service: "test.Test".to_string(),
+            method: "Echo".to_string(),
+            payload: payload.to_vec(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_blocking_client() {
+        let runtime = RuntimeThread::start().unwrap();
+        let mut methods: HashMap<String, Box<dyn MethodHandler + Send + Sync>> = HashMap::new();
+        methods.insert("Echo".to_string(), Box::new(Echo));
+        let mut services = HashMap::new();
+        services.insert(
+            "test.Test".to_string(),
+            Service {
+                methods,
+                streams: HashMap::new(),
+            },
+        );
+        let server = runtime
+            .handle
+            .block_on(testing::start(Server::new().register_service(services)))
+            .unwrap();
+
+        // A client on a runtime of its own.
+        let blocking = BlockingClient::connect(&server.address()).unwrap();
+        assert_eq!(blocking.request(echo(b"owned")).unwrap().payload, b"owned");
+        let res = blocking.block_on(blocking.client().request(echo(b"async")));
+        assert_eq!(res.unwrap().payload, b"async");
+        // Dropping it stops its runtime, which closes the connection.
+        drop(blocking);
+        while !server.server().connections().is_empty() {
+            thread::sleep(std::time::Duration::from_millis(1));
+        }
+
+        // Blocking threads sharing the connection of an async client.
+        let client = {
+            let _guard = runtime.handle.enter();
+            Client::connect(&server.address()).unwrap()
+        };
+        let threads: Vec<_> = (0..4)
+            .map(|i| {
+                let blocking = BlockingClient::with_handle(client.clone(), runtime.handle.clone());
+                thread::spawn(move || {
+                    let payload = format!("thread {i}");
+                    let res = blocking.request(echo(payload.as_bytes())).unwrap();
+                    assert_eq!(res.payload, payload.as_bytes());
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        let blocking = BlockingClient::with_handle(client, runtime.handle.clone());
+        blocking.request(echo(b"shared")).unwrap();
+        assert_eq!(server.server().connections().len(), 1);
+    }
+}
@@ -0,0 +1,238 @@
+// Copyright (c) 2023 Ant Group
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Circuit breaker of a client, see
+//! [`ClientBuilder::circuit_breaker`](crate::r#async::ClientBuilder::circuit_breaker).
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::error::{get_rpc_status, Error, Result};
+use crate::proto::Code;
+use crate::r#async::clock::Clock;
+
+/// The state of a [`CircuitBreaker`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BreakerState {
+    /// Calls are made.
+    Closed,
+    /// Calls fail fast with `UNAVAILABLE` until the cool-down elapsed.
+    Open,
+    /// The cool-down elapsed, a single trial call is made and the others fail fast.
+    /// The breaker closes if it succeeds, and opens again otherwise.
+    HalfOpen,
+}
+
+/// Receives the state changes of a [`CircuitBreaker`].
+pub trait BreakerHook {
+    fn on_state_change(&self, state: BreakerState);
+}
+
+/// Stops a client from piling up calls on a wedged server: after a number of
+/// unary calls in a row failed because of the connection or of their deadline,
+/// calls fail fast for a cool-down, then a trial call tells whether the server
+/// is back.
+///
+/// Calls which failed with any other error count as successes, the server
+/// answered them.
+#[derive(Clone)]
+pub struct CircuitBreaker {
+    failures: u32,
+    cool_down: Duration,
+    hook: Option<Arc<dyn BreakerHook + Send + Sync>>,
+}
+
+impl CircuitBreaker {
+    /// Opens after `failures` calls in a row failed, for `cool_down`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `failures` is 0.
+    pub fn new(failures: u32, cool_down: Duration) -> CircuitBreaker {
+        assert!(failures > 0, "breaker failures must be greater than 0");
+        CircuitBreaker {
+            failures,
+            cool_down,
+            hook: None,
+        }
+    }
+
+    /// Run `hook` whenever the state of the breaker changes.
+    pub fn on_state_change(mut self, hook: Arc<dyn BreakerHook + Send + Sync>) -> CircuitBreaker {
+        self.hook = Some(hook);
+        self
+    }
+}
+
+/// A [`CircuitBreaker`] at work, shared by the clones of a client.
+pub(crate) struct Breaker {
+    config: CircuitBreaker,
+    state: Mutex<State>,
+    clock: Arc<dyn Clock>,
+}
+
+struct State {
+    breaker: BreakerState,
+    failures: u32,
+    /// When the breaker opened, or when the trial call started.
+    since: Instant,
+}
+
+impl Breaker {
+    pub(crate) fn new(config: CircuitBreaker, clock: Arc<dyn Clock>) -> Breaker {
+        Breaker {
+            config,
+            state: Mutex::new(State {
+                breaker: BreakerState::Closed,
+                failures: 0,
+                since: clock.now(),
+            }),
+            clock,
+        }
+    }
+
+    pub(crate) fn state(&self) -> BreakerState {
+        self.state.lock().unwrap().breaker
+    }
+
+    /// Fails fast unless the breaker is closed, for the calls which are not
+    /// accounted for, e.g. streams.
+    pub(crate) fn check(&self) -> Result<()> {
+        if self.state() != BreakerState::Closed {
+            return Err(open_error());
+        }
+        Ok(())
+    }
+
+    /// Lets a call through, or fails it fast while the breaker is open.
+    pub(crate) fn admit(&self) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        match state.breaker {
+            BreakerState::Closed => return Ok(()),
+            // A trial call which never finished, e.g. it was dropped, doesn't hold
+            // the breaker forever.
+            BreakerState::Open | BreakerState::HalfOpen
+                if self.clock.now().saturating_duration_since(state.since)
+                    >= self.config.cool_down => {}
+            _ => return Err(open_error()),
+        }
+        state.since = self.clock.now();
+        self.set(state, BreakerState::HalfOpen);
+        Ok(())
+    }
+
+    /// Accounts for a call which was let through.
+    pub(crate) fn record(&self, failed: bool) {
+        let mut state = self.state.lock().unwrap();
+        if !failed {
+            state.failures = 0;
+            self.set(state, BreakerState::Closed);
+            return;
+        }
+        state.failures += 1;
+        // The calls let through before the breaker opened don't extend the cool-down.
+        let open = match state.breaker {
+            BreakerState::Closed => state.failures >= self.config.failures,
+            BreakerState::Open => false,
+            BreakerState::HalfOpen => true,
+        };
+        if open {
+            state.since = self.clock.now();
+            self.set(state, BreakerState::Open);
+        }
+    }
+
+    /// Changes the state, and runs the hook once the lock is released.
+    fn set(&self, mut state: std::sync::MutexGuard<State>, breaker: BreakerState) {
+        if state.breaker == breaker {
+            return;
+        }
+        state.breaker = breaker;
+        drop(state);
+        debug!("Circuit breaker is {:?}", breaker);
+        if let Some(hook) = self.config.hook.as_ref() {
+            hook.on_state_change(breaker);
+        }
+    }
+}
+
+fn open_error() -> Error {
+    get_rpc_status(Code::UNAVAILABLE, "circuit breaker is open")
+}
+
+/// Returns `true` if `e` is a failure of the connection or the deadline of a call,
+/// rather than an answer of the server.
+pub(crate) fn is_breaker_failure(e: &Error) -> bool {
+    e.is_connection_error() || e.code() == Code::DEADLINE_EXCEEDED
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::r#async::MockClock;
+
+    struct Record(Mutex<Vec<BreakerState>>);
+
+    impl BreakerHook for Record {
+        fn on_state_change(&self, state: BreakerState) {
+            self.0.lock().unwrap().push(state);
+        }
+    }
+
+    #[test]
+    fn test_breaker() {
+        let record = Arc::new(Record(Mutex::new(Vec::new())));
+        let clock = MockClock::new();
+        let breaker = Breaker::new(
+            CircuitBreaker::new(2, Duration::from_millis(50)).on_state_change(record.clone()),
+            Arc::new(clock.clone()),
+        );
+        breaker.admit().unwrap();
+        breaker.record(true);
+        breaker.record(false);
+        breaker.record(true);
+        assert_eq!(breaker.state(), BreakerState::Closed);
+        breaker.record(true);
+        assert_eq!(breaker.state(), BreakerState::Open);
+        let e = breaker.admit().unwrap_err();
+        assert_eq!(e.code(), Code::UNAVAILABLE);
+
+        // A failed trial opens the breaker again, a successful one closes it.
+        clock.advance(Duration::from_millis(49));
+        assert!(breaker.admit().is_err());
+        clock.advance(Duration::from_millis(1));
+        breaker.admit().unwrap();
+        assert!(breaker.admit().is_err());
+        breaker.record(true);
+        assert_eq!(breaker.state(), BreakerState::Open);
+        clock.advance(Duration::from_millis(50));
+        breaker.admit().unwrap();
+        breaker.record(false);
+        breaker.admit().unwrap();
+        assert_eq!(
+            *record.0.lock().unwrap(),
+            vec![
+                BreakerState::Open,
+                BreakerState::HalfOpen,
+                BreakerState::Open,
+                BreakerState::HalfOpen,
+                BreakerState::Closed,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_breaker_failure() {
+        assert!(is_breaker_failure(&Error::PeerClosed));
+        assert!(is_breaker_failure(&get_rpc_status(
+            Code::DEADLINE_EXCEEDED,
+            "timeout"
+        )));
+        assert!(!is_breaker_failure(&get_rpc_status(
+            Code::NOT_FOUND,
+            "no such sandbox"
+        )));
+    }
+}
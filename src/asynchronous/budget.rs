@@ -0,0 +1,94 @@
+// Copyright (c) 2023 Ant Group
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Accounting of the memory held by calls in progress.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// A cap on the bytes held by the requests and responses a server is handling,
+/// shared by all its connections.
+///
+/// Requests which don't fit in the budget are rejected with `RESOURCE_EXHAUSTED`
+/// before they are handled. Clones share the budget, so a clone kept by the
+/// caller reports the current usage.
+#[derive(Clone, Debug)]
+pub struct MemoryBudget {
+    inner: Arc<BudgetInner>,
+}
+
+#[derive(Debug)]
+struct BudgetInner {
+    limit: usize,
+    used: AtomicUsize,
+}
+
+impl MemoryBudget {
+    pub fn new(limit: usize) -> MemoryBudget {
+        assert!(limit > 0, "memory budget must be positive");
+        MemoryBudget {
+            inner: Arc::new(BudgetInner {
+                limit,
+                used: AtomicUsize::new(0),
+            }),
+        }
+    }
+
+    pub fn limit(&self) -> usize {
+        self.inner.limit
+    }
+
+    /// Bytes currently held by calls in progress.
+    pub fn used(&self) -> usize {
+        self.inner.used.load(Ordering::Relaxed)
+    }
+
+    /// Reserves `bytes` until the returned reservation is dropped, or returns
+    /// `None` if they don't fit in the budget.
+    pub(crate) fn try_reserve(&self, bytes: usize) -> Option<Reservation> {
+        let limit = self.inner.limit;
+        self.inner
+            .used
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |used| {
+                used.checked_add(bytes).filter(|used| *used <= limit)
+            })
+            .ok()?;
+        Some(Reservation {
+            budget: self.inner.clone(),
+            bytes,
+        })
+    }
+}
+
+/// Bytes held against a [`MemoryBudget`], released when dropped.
+pub(crate) struct Reservation {
+    budget: Arc<BudgetInner>,
+    bytes: usize,
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        self.budget.used.fetch_sub(self.bytes, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_memory_budget() {
+        let budget = MemoryBudget::new(100);
+        let a = budget.try_reserve(60).unwrap();
+        assert!(budget.try_reserve(41).is_none());
+        let b = budget.try_reserve(40).unwrap();
+        assert_eq!(budget.used(), 100);
+        drop(a);
+        assert_eq!(budget.used(), 40);
+        assert!(budget.try_reserve(usize::MAX).is_none());
+        drop(b);
+        assert_eq!(budget.used(), 0);
+    }
+}
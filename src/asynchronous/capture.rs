@@ -0,0 +1,174 @@
+// Copyright (c) 2023 Ant Group
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Capture of the messages exchanged on connections into a pcapng file.
+//!
+//! Every message is recorded as a packet of link type `LINKTYPE_USER0` (147),
+//! which Wireshark hands to the dissector configured for `DLT_USER 0`. A packet
+//! holds:
+//!
+//! - the direction, 0 for a received message and 1 for a sent one,
+//! - 3 reserved bytes,
+//! - the file descriptor of the connection, as a big endian `u32`,
+//! - the message as written on the connection, header included.
+//!
+//! Requires the `capture` feature.
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::os::unix::io::RawFd;
+use std::path::Path;
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crossbeam::channel::{self, Receiver, Sender, TrySendError};
+
+use crate::error::{Error, Result};
+use crate::proto::{GenMessage, MESSAGE_HEADER_LENGTH};
+
+const BLOCK_SECTION_HEADER: u32 = 0x0A0D_0D0A;
+const BLOCK_INTERFACE_DESCRIPTION: u32 = 1;
+const BLOCK_ENHANCED_PACKET: u32 = 6;
+const BYTE_ORDER_MAGIC: u32 = 0x1A2B_3C4D;
+const LINKTYPE_USER0: u16 = 147;
+/// Direction, reserved bytes and file descriptor.
+const ENCAPSULATION_LENGTH: usize = 8;
+/// Packets waiting to be written, beyond which new ones are dropped.
+const CAPTURE_QUEUE: usize = 4096;
+
+/// Whether a captured message was received or sent on the connection.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Direction {
+    Received = 0,
+    Sent = 1,
+}
+
+/// A pcapng file recording the messages of the connections it is given to, see
+/// [`Server::set_capture`](crate::r#async::Server::set_capture) and
+/// [`ClientBuilder::capture`](crate::r#async::ClientBuilder::capture).
+///
+/// Clones write to the same file, so a server and its clients may share one.
+/// The file is written by a thread of its own, so the connections don't block
+/// on it. Packets are dropped while it lags too far behind, and the thread
+/// exits once the last clone is dropped.
+///
+/// Capturing slows down every message, it is meant for debugging only.
+#[derive(Clone, Debug)]
+pub struct Capture {
+    tx: Sender<Packet>,
+}
+
+/// A captured message, and when it was captured.
+#[derive(Debug)]
+struct Packet {
+    timestamp: u64,
+    data: Vec<u8>,
+}
+
+impl Capture {
+    /// Creates or truncates the file at `path`.
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<Capture> {
+        let path = path.as_ref();
+        let file = File::create(path)
+            .map_err(|e| Error::Others(format!("Create capture {path:?} failed: {e}")))?;
+        let mut file = BufWriter::new(file);
+        write_headers(&mut file)
+            .map_err(|e| Error::Others(format!("Write capture {path:?} failed: {e}")))?;
+        let (tx, rx) = channel::bounded(CAPTURE_QUEUE);
+        thread::Builder::new()
+            .name("ttrpc-capture".to_string())
+            .spawn(move || write_packets(file, rx))
+            .map_err(|e| Error::Others(format!("Spawn capture writer failed: {e}")))?;
+        Ok(Capture { tx })
+    }
+
+    pub(crate) fn record(&self, fd: RawFd, direction: Direction, msg: &GenMessage) {
+        let mut data =
+            Vec::with_capacity(ENCAPSULATION_LENGTH + MESSAGE_HEADER_LENGTH + msg.payload.len());
+        data.extend_from_slice(&[direction as u8, 0, 0, 0]);
+        data.extend_from_slice(&(fd as u32).to_be_bytes());
+        data.extend_from_slice(&Vec::<u8>::from(msg.header));
+        data.extend_from_slice(&msg.payload);
+
+        // Microseconds, the default resolution of the interface.
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_micros() as u64;
+        match self.tx.try_send(Packet { timestamp, data }) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => warn!("Capture is lagging, message dropped"),
+            // The writer gave up on the file.
+            Err(TrySendError::Disconnected(_)) => {}
+        }
+    }
+}
+
+/// Writes the packets received on `rx` until all the captures are dropped.
+fn write_packets(mut file: BufWriter<File>, rx: Receiver<Packet>) {
+    for packet in rx {
+        // Flushed right away, so the capture survives a crash of the process.
+        if let Err(e) =
+            write_packet(&mut file, packet.timestamp, &packet.data).and_then(|_| file.flush())
+        {
+            warn!("Capture message failed: {:?}", e);
+        }
+    }
+}
+
+fn write_headers(w: &mut impl Write) -> std::io::Result<()> {
+    // Section header, with an unspecified section length.
+    w.write_all(&BLOCK_SECTION_HEADER.to_le_bytes())?;
+    w.write_all(&28u32.to_le_bytes())?;
+    w.write_all(&BYTE_ORDER_MAGIC.to_le_bytes())?;
+    w.write_all(&1u16.to_le_bytes())?;
+    w.write_all(&0u16.to_le_bytes())?;
+    w.write_all(&(-1i64).to_le_bytes())?;
+    w.write_all(&28u32.to_le_bytes())?;
+
+    // Interface description, without a snapshot length limit.
+    w.write_all(&BLOCK_INTERFACE_DESCRIPTION.to_le_bytes())?;
+    w.write_all(&20u32.to_le_bytes())?;
+    w.write_all(&LINKTYPE_USER0.to_le_bytes())?;
+    w.write_all(&0u16.to_le_bytes())?;
+    w.write_all(&0u32.to_le_bytes())?;
+    w.write_all(&20u32.to_le_bytes())?;
+    w.flush()
+}
+
+fn write_packet(w: &mut impl Write, timestamp: u64, data: &[u8]) -> std::io::Result<()> {
+    let padding = (4 - data.len() % 4) % 4;
+    let block_length = (32 + data.len() + padding) as u32;
+
+    w.write_all(&BLOCK_ENHANCED_PACKET.to_le_bytes())?;
+    w.write_all(&block_length.to_le_bytes())?;
+    // Interface id.
+    w.write_all(&0u32.to_le_bytes())?;
+    w.write_all(&((timestamp >> 32) as u32).to_le_bytes())?;
+    w.write_all(&(timestamp as u32).to_le_bytes())?;
+    w.write_all(&(data.len() as u32).to_le_bytes())?;
+    w.write_all(&(data.len() as u32).to_le_bytes())?;
+    w.write_all(data)?;
+    w.write_all(&[0u8; 3][..padding])?;
+    w.write_all(&block_length.to_le_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_packet() {
+        let mut buf = vec![];
+        write_packet(&mut buf, (7 << 32) | 9, &[1, 2, 3, 4, 5]).unwrap();
+        assert_eq!(buf.len(), 40);
+        assert_eq!(buf[4..8], 40u32.to_le_bytes());
+        assert_eq!(buf[12..16], 7u32.to_le_bytes());
+        assert_eq!(buf[16..20], 9u32.to_le_bytes());
+        assert_eq!(buf[20..24], 5u32.to_le_bytes());
+        assert_eq!(buf[28..36], [1, 2, 3, 4, 5, 0, 0, 0]);
+        assert_eq!(buf[36..40], 40u32.to_le_bytes());
+    }
+}
@@ -0,0 +1,192 @@
+// Copyright (c) 2023 Ant Group
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Child processes owned by the call which spawned them.
+
+use std::process::{Child, Command, ExitStatus};
+use std::time::Duration;
+
+use crate::error::{Error, Result};
+
+const MIN_POLL_INTERVAL: Duration = Duration::from_millis(1);
+const MAX_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// A child process which is killed and reaped once dropped, unless it exited
+/// and was waited for.
+///
+/// Handlers are dropped when their call is cancelled, e.g. because it timed out
+/// or the client went away, so a handler keeping its child in a `ScopedChild`
+/// doesn't leak the process, nor leave a zombie behind.
+#[derive(Debug)]
+pub struct ScopedChild {
+    child: Option<Child>,
+}
+
+impl ScopedChild {
+    pub fn new(child: Child) -> ScopedChild {
+        ScopedChild { child: Some(child) }
+    }
+
+    /// Spawns `command` as a scoped child.
+    pub fn spawn(command: &mut Command) -> Result<ScopedChild> {
+        let child = command
+            .spawn()
+            .map_err(|e| Error::Others(format!("Spawn {command:?} failed: {e}")))?;
+        Ok(ScopedChild::new(child))
+    }
+
+    pub fn id(&self) -> u32 {
+        self.child().id()
+    }
+
+    /// The child, e.g. to take its stdin and stdout.
+    pub fn child(&self) -> &Child {
+        self.child.as_ref().unwrap()
+    }
+
+    pub fn child_mut(&mut self) -> &mut Child {
+        self.child.as_mut().unwrap()
+    }
+
+    /// Waits for the child to exit, and reaps it.
+    ///
+    /// Dropping the returned future, e.g. because the call was cancelled, leaves
+    /// the child running until the `ScopedChild` itself is dropped.
+    pub async fn wait(&mut self) -> Result<ExitStatus> {
+        let mut interval = MIN_POLL_INTERVAL;
+        loop {
+            let status = self
+                .child_mut()
+                .try_wait()
+                .map_err(|e| Error::Others(format!("Wait child failed: {e}")))?;
+            if let Some(status) = status {
+                return Ok(status);
+            }
+            tokio::time::sleep(interval).await;
+            interval = (interval * 2).min(MAX_POLL_INTERVAL);
+        }
+    }
+
+    /// Lets the child run past the drop of the `ScopedChild`.
+    pub fn into_inner(mut self) -> Child {
+        self.child.take().unwrap()
+    }
+}
+
+impl Drop for ScopedChild {
+    fn drop(&mut self) {
+        let mut child = match self.child.take() {
+            Some(child) => child,
+            None => return,
+        };
+        // Already reaped.
+        if let Ok(Some(_)) = child.try_wait() {
+            return;
+        }
+        if let Err(e) = child.kill() {
+            warn!("Kill child {} failed: {:?}", child.id(), e);
+        }
+        // The child exits right away, but don't block the runtime meanwhile.
+        let mut reap = move || {
+            if let Err(e) = child.wait() {
+                warn!("Reap child {} failed: {:?}", child.id(), e);
+            }
+        };
+        match tokio::runtime::Handle::try_current() {
+            Ok(handle) => {
+                handle.spawn_blocking(reap);
+            }
+            Err(_) => reap(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::HashMap;
+    use std::path::Path;
+    use std::sync::{Arc, Mutex};
+
+    use async_trait::async_trait;
+    use tokio::sync::Notify;
+
+    use crate::r#async::{testing, MethodHandler, Service, TtrpcContext};
+    use crate::{Request, Response};
+
+    /// Runs `sleep` for the seconds of the payload, and answers with its exit code.
+    struct Sleep {
+        pid: Arc<Mutex<Option<u32>>>,
+        started: Arc<Notify>,
+    }
+
+    #[async_trait]
+    impl MethodHandler for Sleep {
+        async fn handler(&self, _ctx: TtrpcContext, req: Request) -> Result<Response> {
+            let seconds = String::from_utf8_lossy(&req.payload).into_owned();
+            let mut child = ScopedChild::spawn(Command::new("sleep").arg(seconds))?;
+            *self.pid.lock().unwrap() = Some(child.id());
+            self.started.notify_one();
+            let status = child.wait().await?;
+            Ok(Response {
+                payload: status.code().unwrap_or(-1).to_string().into_bytes(),
+                ..Default::default()
+            })
+        }
+    }
+
+    fn sleep(seconds: &str) -> Request {
+        Request {
+            service: "test.Test".to_string(),
+            method: "Sleep".to_string(),
+            payload: seconds.as_bytes().to_vec(),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_call() {
+        let pid = Arc::new(Mutex::new(None));
+        let started = Arc::new(Notify::new());
+        let mut methods: HashMap<String, Box<dyn MethodHandler + Send + Sync>> = HashMap::new();
+        let handler = Sleep {
+            pid: pid.clone(),
+            started: started.clone(),
+        };
+        methods.insert("Sleep".to_string(), Box::new(handler));
+        let mut services = HashMap::new();
+        services.insert(
+            "test.Test".to_string(),
+            Service {
+                methods,
+                streams: HashMap::new(),
+            },
+        );
+        let (client, _server) = testing::serve(services).await.unwrap();
+
+        // The child of a cancelled call is killed and reaped.
+        let call = tokio::spawn({
+            let client = client.clone();
+            async move { client.request(sleep("1000")).await }
+        });
+        started.notified().await;
+        call.abort();
+        let proc = format!("/proc/{}", pid.lock().unwrap().unwrap());
+        let mut reaped = false;
+        for _ in 0..500 {
+            reaped = !Path::new(&proc).exists();
+            if reaped {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(reaped, "{} was not reaped", proc);
+
+        // The child of a call which waited for it is left alone.
+        let res = client.request(sleep("0")).await.unwrap();
+        assert_eq!(res.payload, b"0");
+    }
+}
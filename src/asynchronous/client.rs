@@ -4,99 +4,772 @@
 // SPDX-License-Identifier: Apache-2.0
 //
 
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::convert::TryInto;
-use std::os::unix::io::RawFd;
-use std::sync::atomic::{AtomicU32, Ordering};
+use std::io;
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, OwnedFd, RawFd};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
 
 use async_trait::async_trait;
+use futures::future::{BoxFuture, Future, FutureExt, Shared, WeakShared};
 use nix::unistd::close;
-use tokio::{self, sync::mpsc, task};
+use tokio::{
+    self,
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    select,
+    sync::{mpsc, watch, Mutex as AsyncMutex, OwnedSemaphorePermit, Semaphore},
+    task,
+};
+#[cfg(feature = "tls")]
+use tokio_rustls::rustls::ClientConfig;
+#[cfg(feature = "tls-keylog")]
+use tokio_rustls::rustls::KeyLog;
 
-use crate::common::client_connect;
+use crate::address::Address;
+use crate::common::{client_connect, client_connect_timeout};
+use crate::context;
 use crate::error::{get_rpc_status, Error, Result};
 use crate::proto::{
-    Code, Codec, GenMessage, Message, MessageHeader, Request, Response, FLAG_NO_DATA,
-    FLAG_REMOTE_CLOSED, FLAG_REMOTE_OPEN, MESSAGE_TYPE_DATA, MESSAGE_TYPE_RESPONSE,
+    Code, Codec, GenMessage, KeyValue, Message, MessageHeader, Request, Response, FLAG_ACK,
+    FLAG_NO_DATA, FLAG_REMOTE_CLOSED, FLAG_REMOTE_OPEN, MESSAGE_TYPE_DATA, MESSAGE_TYPE_GOAWAY,
+    MESSAGE_TYPE_PING, MESSAGE_TYPE_PONG, MESSAGE_TYPE_REQUEST, MESSAGE_TYPE_RESPONSE,
 };
+use crate::r#async::adaptive::{AdaptiveLimit, AdaptiveLimiter};
+use crate::r#async::breaker::{is_breaker_failure, Breaker, BreakerState, CircuitBreaker};
+#[cfg(feature = "capture")]
+use crate::r#async::capture::Capture;
+use crate::r#async::clock::{self, default_clock, Clock, Elapsed};
 use crate::r#async::connection::*;
-use crate::r#async::shutdown;
+use crate::r#async::connectivity::{ConnectivityState, StateSender, StateWatcher};
+use crate::r#async::dial::{connect_tcp, tcp_host};
+use crate::r#async::handshake::{
+    ClientHandshake, ServerHello, ServerHelloReceiver, CAPABILITY_CANCEL,
+    CAPABILITY_UNARY_OVER_STREAM,
+};
+use crate::r#async::metrics::{record_timing, CallTiming, CallTimings, DebugState, MetricsHook};
+use crate::r#async::notifications::{ClientSubscription, Notifications};
+use crate::r#async::ping::{self, PingHandler, PingHandlerSlot, Pings};
+use crate::r#async::resolver::Resolver;
+use crate::r#async::seqpacket::ClientSocket;
+use crate::r#async::server::{Priority, PRIORITY_METADATA_KEY};
+use crate::r#async::shutdown::{self, ShutdownReport};
 use crate::r#async::stream::{
-    Kind, MessageReceiver, MessageSender, ResultReceiver, ResultSender, StreamInner,
+    notify_ack, AckWaiters, Kind, MessageReceiver, MessageSender, ResultReceiver, ResultSender,
+    StreamInner, DEFAULT_STREAM_BUFFER,
 };
-use crate::r#async::utils;
+#[cfg(feature = "tls")]
+use crate::r#async::tls::{self, Tls};
+use crate::r#async::Compression;
+use crate::MethodInfo;
+
+const DEFAULT_RECONNECT_INTERVAL: Duration = Duration::from_secs(1);
+/// Calls queued while reconnecting, when no offline queue is configured.
+const DEFAULT_RECONNECT_QUEUE: usize = 100;
+
+type SharedReceiver = Arc<AsyncMutex<MessageReceiver>>;
 
 /// A ttrpc Client (async).
+///
+/// A client is a cheap handle to one connection: all clones share the connection,
+/// which is closed once the last clone and the last stream created from it are
+/// dropped.
 #[derive(Clone)]
 pub struct Client {
     req_tx: MessageSender,
+    /// Requests of the high priority calls, written before the queued messages.
+    urgent_tx: MessageSender,
     next_stream_id: Arc<AtomicU32>,
     streams: Arc<Mutex<HashMap<u32, ResultSender>>>,
+    acks: AckWaiters,
+    timings: CallTimings,
+    server_hello: ServerHelloReceiver,
+    subscription: Option<ClientSubscription>,
+    metrics_hook: Option<Arc<dyn MetricsHook + Send + Sync>>,
+    close_tx: Arc<watch::Sender<bool>>,
+    /// Counts the connections still running.
+    exits: Arc<shutdown::Notifier>,
+    /// Counts the sockets of the connections closed.
+    closed_fds: Arc<AtomicUsize>,
+    /// Wakes up the dialer of a lazy client.
+    dial_tx: Option<Arc<watch::Sender<()>>>,
+    retry: Option<Arc<RetryPolicy>>,
+    state: StateSender,
+    interceptors: Arc<Vec<Arc<dyn Interceptor + Send + Sync>>>,
+    in_flight: Option<InFlight>,
+    /// Counts the calls in progress, and stops new ones once shut down.
+    calls: Arc<shutdown::Notifier>,
+    pings: Pings,
+    ping_handler: PingHandlerSlot,
+    coalesced: Coalesced,
+    breaker: Option<Arc<Breaker>>,
+    clock: Arc<dyn Clock>,
+    /// Set while the server drains the connection, see [`MESSAGE_TYPE_GOAWAY`].
+    draining: Arc<AtomicBool>,
+}
+
+type CallFuture = BoxFuture<'static, Result<(Response, Option<CallTiming>)>>;
+
+/// The calls in progress by service, method and idempotency key, see
+/// [`CallOptions::idempotency_key`].
+type Coalesced = Arc<Mutex<HashMap<(String, String, String), CoalescedCall>>>;
+
+struct CoalescedCall {
+    /// The payload of the request, which the calls joining it must send too.
+    payload: Vec<u8>,
+    call: WeakShared<CallFuture>,
 }
 
+/// A connection shared by the clients of several services, e.g.
+/// `FooClient::new(channel.clone())` and `BarClient::new(channel.clone())`.
+///
+/// The connection is configured once, via [`ClientBuilder`], and closed when the
+/// last client created from it is dropped.
+pub type Channel = Client;
+
 impl Client {
+    /// Connects to `sockaddr`, see [`ClientBuilder::build`].
     pub fn connect(sockaddr: &str) -> Result<Client> {
-        let fd = unsafe { client_connect(sockaddr)? };
-        Ok(Self::new(fd))
+        ClientBuilder::new(sockaddr).build()
+    }
+
+    /// Returns a client which connects to `sockaddr` when the first call is
+    /// made, see [`ClientBuilder::lazy`].
+    pub fn connect_lazy(sockaddr: &str) -> Result<Client> {
+        ClientBuilder::new(sockaddr).lazy().build()
     }
 
     /// Initialize a new [`Client`].
     pub fn new(fd: RawFd) -> Client {
-        let stream = utils::new_unix_stream_from_raw_fd(fd);
+        Self::with_options(fd, &ClientBuilder::new(""))
+    }
 
+    fn with_options(fd: RawFd, opts: &ClientBuilder) -> Client {
+        Self::with_dialer(async move { Ok(ClientSocket::from_raw_fd(fd)) }, opts)
+    }
+
+    /// Like [`Client::with_options`], with the connection made by `dial` in the
+    /// background. Calls fail if it fails.
+    fn with_dialer<D>(dial: D, opts: &ClientBuilder) -> Client
+    where
+        D: Future<Output = Result<ClientSocket>> + Send + 'static,
+    {
         let (req_tx, rx): (MessageSender, MessageReceiver) = mpsc::channel(100);
 
-        let req_map = Arc::new(Mutex::new(HashMap::new()));
-        let delegate = ClientBuilder {
-            rx: Some(rx),
-            streams: req_map.clone(),
-        };
+        let (client, delegate) = Self::with_sender(req_tx, rx, opts);
+        let hook = opts.connect_hook.clone();
+        tokio::spawn(async move {
+            let mut stream = match dial.await {
+                Ok(stream) => stream,
+                Err(e) => {
+                    error!("Connect failed: {:?}", e);
+                    delegate.fail_calls().await;
+                    delegate.state.set(ConnectivityState::Shutdown);
+                    return;
+                }
+            };
+            if let Some(hook) = hook {
+                // The calls made from now on fail once the delegate is dropped.
+                if let Err(e) = hook.on_connect(&mut stream).await {
+                    error!("Connect hook failed: {:?}", e);
+                    delegate.fail_calls().await;
+                    delegate.state.set(ConnectivityState::Shutdown);
+                    return;
+                }
+            }
+            delegate.state.set(ConnectivityState::Ready);
+            let stream = delegate.own(stream);
+            Connection::new(stream, delegate.clone()).run().await.ok();
+            delegate.state.set(ConnectivityState::Shutdown);
+        });
 
-        let conn = Connection::new(stream, delegate);
-        tokio::spawn(async move { conn.run().await });
+        client
+    }
 
-        Client {
+    fn with_sender(
+        req_tx: MessageSender,
+        rx: MessageReceiver,
+        opts: &ClientBuilder,
+    ) -> (Client, ClientDelegateBuilder) {
+        let (urgent_tx, urgent_rx) = mpsc::channel(req_tx.max_capacity());
+        let (handshake, server_hello) =
+            ClientHandshake::new(opts.schema_version.as_deref(), &opts.schema_digests);
+        let subscription = opts.notifications.then(ClientSubscription::new);
+        let (close_tx, close_rx) = watch::channel(false);
+        let (exits, _) = shutdown::new();
+        let client = Client {
             req_tx,
+            urgent_tx,
             next_stream_id: Arc::new(AtomicU32::new(1)),
-            streams: req_map,
+            streams: Arc::new(Mutex::new(HashMap::new())),
+            acks: Arc::new(Mutex::new(HashMap::new())),
+            timings: Arc::new(Mutex::new(HashMap::new())),
+            server_hello,
+            subscription: subscription.clone(),
+            metrics_hook: None,
+            close_tx: Arc::new(close_tx),
+            exits: Arc::new(exits),
+            closed_fds: Arc::new(AtomicUsize::new(0)),
+            dial_tx: None,
+            retry: opts.retry.clone().map(Arc::new),
+            state: StateSender::new(if opts.lazy {
+                ConnectivityState::Idle
+            } else {
+                ConnectivityState::Connecting
+            }),
+            interceptors: Arc::new(Vec::new()),
+            in_flight: InFlight::new(opts),
+            calls: Arc::new(shutdown::new().0),
+            pings: Pings::default(),
+            ping_handler: Arc::new(Mutex::new(None)),
+            coalesced: Arc::new(Mutex::new(HashMap::new())),
+            breaker: opts
+                .breaker
+                .clone()
+                .map(|b| Arc::new(Breaker::new(b, opts.clock.clone()))),
+            clock: opts.clock.clone(),
+            draining: Arc::new(AtomicBool::new(false)),
+        };
+        let delegate = ClientDelegateBuilder {
+            rx: Arc::new(AsyncMutex::new(rx)),
+            urgent_rx: Arc::new(AsyncMutex::new(urgent_rx)),
+            next_stream_id: client.next_stream_id.clone(),
+            streams: client.streams.clone(),
+            acks: client.acks.clone(),
+            timings: client.timings.clone(),
+            handshake,
+            subscription,
+            write_timeout: opts.write_timeout,
+            #[cfg(feature = "capture")]
+            capture: opts.capture.clone(),
+            yield_budget: opts.yield_budget,
+            write_batch: opts.write_batch,
+            close_rx,
+            exits: client.exits.clone(),
+            closed_fds: client.closed_fds.clone(),
+            idle_timeout: opts.idle_timeout,
+            idled: Arc::new(AtomicBool::new(false)),
+            state: client.state.clone(),
+            req_tx: client.req_tx.downgrade(),
+            pings: client.pings.clone(),
+            ping_handler: client.ping_handler.clone(),
+            keepalive: opts.keepalive,
+            clock: opts.clock.clone(),
+            draining: client.draining.clone(),
+        };
+        (client, delegate)
+    }
+
+    /// Returns the schema version of the server, see [`ClientBuilder::schema_version`].
+    ///
+    /// Waits for the handshake of the connection to finish. Returns `None` if the
+    /// client has no schema version, or the server did not answer the handshake.
+    pub async fn peer_version(&self) -> Option<String> {
+        self.server_hello().await.map(|hello| hello.version)
+    }
+
+    /// Returns the restart epoch of the server the client is connected to, see
+    /// [`Server::set_restart_epoch`](crate::r#async::Server::set_restart_epoch).
+    ///
+    /// Every connection made by the client repeats the handshake, so a client
+    /// which reconnected, e.g. once [`Client::watch_state`] reported
+    /// [`ConnectivityState::Ready`] again, compares the epoch with the one it had
+    /// to tell whether to drop its cached state and make its watches again.
+    ///
+    /// Waits for the handshake of the connection to finish. Returns `None` if the
+    /// client has no schema version, or the server has no restart epoch.
+    pub async fn peer_restart_epoch(&self) -> Option<u64> {
+        self.server_hello().await?.restart_epoch
+    }
+
+    /// Returns the methods the server found generated from another revision of
+    /// their schema than the client's, see [`ClientBuilder::schema_digests`].
+    ///
+    /// Waits for the handshake of the connection to finish. Returns nothing if the
+    /// client sent no digests, or the server doesn't check them.
+    pub async fn peer_schema_drift(&self) -> Vec<String> {
+        self.server_hello()
+            .await
+            .map(|hello| hello.drifted)
+            .unwrap_or_default()
+    }
+
+    /// Whether the server accepts calls of unary methods made as streams, with
+    /// one message each way, see
+    /// [`Server::set_unary_over_stream`](crate::r#async::Server::set_unary_over_stream).
+    ///
+    /// Such calls are opened with `new_stream(req, true, true)` and an empty
+    /// request payload, followed by the request message and `close_send()`. The
+    /// server sends the response message, then closes the stream.
+    ///
+    /// Waits for the handshake of the connection to finish, so the client needs a
+    /// [schema version](ClientBuilder::schema_version). Returns `false` otherwise.
+    pub async fn peer_supports_unary_over_stream(&self) -> bool {
+        self.server_hello().await.map_or(false, |hello| {
+            hello
+                .capabilities
+                .iter()
+                .any(|c| c == CAPABILITY_UNARY_OVER_STREAM)
+        })
+    }
+
+    /// Returns `true` if the handshake finished and the server didn't list
+    /// `capability`. Servers which don't answer the handshake may support it.
+    fn peer_lacks(&self, capability: &str) -> bool {
+        match &*self.server_hello.borrow() {
+            Some(Some(hello)) => !hello.capabilities.iter().any(|c| c == capability),
+            _ => false,
+        }
+    }
+
+    async fn server_hello(&self) -> Option<ServerHello> {
+        let mut rx = self.server_hello.clone();
+        loop {
+            if let Some(hello) = rx.borrow().clone() {
+                return hello;
+            }
+            if rx.changed().await.is_err() {
+                return None;
+            }
+        }
+    }
+
+    /// Returns a receiver of the notifications pushed by the server, or `None` if the
+    /// client did not subscribe, see [`ClientBuilder::notifications`].
+    ///
+    /// Every receiver gets all notifications which arrive after it was created.
+    pub fn notifications(&self) -> Option<Notifications> {
+        self.subscription.as_ref().map(|s| s.receiver())
+    }
+
+    /// Set a hook which receives a [`DebugState`] sample every time a call is issued.
+    pub fn set_metrics_hook(mut self, hook: Arc<dyn MetricsHook + Send + Sync>) -> Self {
+        self.metrics_hook = Some(hook);
+        self
+    }
+
+    /// Run `interceptor` on every unary call, after the interceptors added before
+    /// it. Streams are not intercepted.
+    pub fn add_interceptor(mut self, interceptor: Arc<dyn Interceptor + Send + Sync>) -> Self {
+        Arc::make_mut(&mut self.interceptors).push(interceptor);
+        self
+    }
+
+    /// Answer the pings of the server with the payload returned by `handler`, see
+    /// [`Server::ping`](crate::r#async::Server::ping). The handler is shared by all
+    /// the clones of the client. Pings are answered with an empty payload by
+    /// default.
+    pub fn on_ping(self, handler: Arc<dyn PingHandler + Send + Sync>) -> Self {
+        *self.ping_handler.lock().unwrap() = Some(handler);
+        self
+    }
+
+    /// Pings the server with `payload`, and returns the payload of its answer, see
+    /// [`Server::on_ping`](crate::r#async::Server::on_ping).
+    ///
+    /// Servers which don't support pings never answer, so callers should bound
+    /// the wait with a timeout.
+    pub async fn ping(&self, payload: &[u8]) -> Result<Vec<u8>> {
+        self.wake_dialer();
+        self.pings.ping(&self.req_tx, payload).await
+    }
+
+    /// Returns the state of the [circuit breaker](ClientBuilder::circuit_breaker)
+    /// of the client, if it has one.
+    pub fn breaker_state(&self) -> Option<BreakerState> {
+        self.breaker.as_ref().map(|breaker| breaker.state())
+    }
+
+    /// Returns the number of calls and streams the client makes at once, if it has
+    /// a limit, which changes with the latency of the server under
+    /// [`ClientBuilder::adaptive_in_flight`].
+    pub fn in_flight_limit(&self) -> Option<usize> {
+        self.in_flight
+            .as_ref()
+            .map(|in_flight| match &in_flight.adaptive {
+                Some(adaptive) => adaptive.limit(),
+                None => in_flight.max,
+            })
+    }
+
+    /// Returns the state of the connection of the client.
+    pub fn state(&self) -> ConnectivityState {
+        self.state.get()
+    }
+
+    /// Returns a receiver of the state transitions of the client, e.g. to report
+    /// the health of the connection before calls fail.
+    pub fn watch_state(&self) -> StateWatcher {
+        self.state.watch()
+    }
+
+    /// Returns the current depth of the internal queues of the connection.
+    pub fn debug_state(&self) -> DebugState {
+        DebugState::collect(&self.req_tx, &self.streams)
+    }
+
+    fn wake_dialer(&self) {
+        if let Some(dial_tx) = self.dial_tx.as_ref() {
+            dial_tx.send_replace(());
+        }
+    }
+
+    /// Registers a new call, which fails once the client is shutting down or shut
+    /// down, see [`Client::shutdown`] and [`ClientBuilder::max_in_flight`].
+    ///
+    /// Fails with `UNAVAILABLE` without sending the call while the server drains
+    /// the connection, which the default [`RetryPolicy`] retries on the next one.
+    async fn start_call(&self) -> Result<CallGuard> {
+        let call = self.calls.subscribe();
+        // Nothing sends the calls of a closed client, nor of one which gave up
+        // reconnecting.
+        if call.is_shutdown() || self.state.get() == ConnectivityState::Shutdown {
+            return Err(Error::LocalClosed);
+        }
+        if self.draining.load(Ordering::Relaxed) {
+            return Err(get_rpc_status(
+                Code::UNAVAILABLE,
+                "server is draining the connection",
+            ));
+        }
+        let permit = match &self.in_flight {
+            Some(in_flight) => Some(in_flight.acquire().await?),
+            None => None,
+        };
+        Ok(CallGuard {
+            _call: call,
+            _permit: permit,
+        })
+    }
+
+    /// Whether the connection is gone, and calls fail without being sent.
+    fn is_closed(&self) -> bool {
+        self.req_tx.is_closed()
+    }
+
+    fn record_metrics(&self) {
+        if let Some(hook) = self.metrics_hook.as_ref() {
+            hook.on_state(&self.debug_state());
+        }
+    }
+
+    /// Stops making new calls, waits up to `timeout` for the calls and streams in
+    /// progress to finish, then closes the connection like [`Client::close`].
+    ///
+    /// New calls of all the clones of the client fail with [`Error::LocalClosed`].
+    /// The report counts the calls which didn't finish in time.
+    pub async fn shutdown(self, timeout: Duration) -> ShutdownReport {
+        self.calls.shutdown();
+        if clock::timeout(&*self.clock, timeout, self.calls.wait_all_exit())
+            .await
+            .is_err()
+        {
+            debug!(
+                "{} calls still in progress, close anyway",
+                self.calls.waiters()
+            );
+        }
+        self.close().await
+    }
+
+    /// Closes the connection, failing the calls in progress of all the clones of
+    /// the client, and waits until the connection has been torn down.
+    ///
+    /// Dropping the clients only closes the connection once the last clone and
+    /// stream are dropped.
+    pub async fn close(self) -> ShutdownReport {
+        self.state.set(ConnectivityState::Shutdown);
+        let connections = self.exits.waiters();
+        let closed_fds = self.closed_fds.load(Ordering::Relaxed);
+        self.close_tx.send_replace(true);
+        self.exits.wait_all_exit().await.ok();
+
+        let streams = std::mem::take(&mut *self.streams.lock().unwrap());
+        self.acks.lock().unwrap().clear();
+        for (_, tx) in streams.iter() {
+            tx.send(Err(Error::LocalClosed)).await.ok();
+        }
+        ShutdownReport {
+            connections,
+            calls_in_progress: streams.len(),
+            detached: 0,
+            fds_closed: self.closed_fds.load(Ordering::Relaxed) - closed_fds,
         }
     }
 
     /// Requsts a unary request and returns with response.
     pub async fn request(&self, req: Request) -> Result<Response> {
-        let timeout_nano = req.timeout_nano;
+        let (res, _) = self.request_with_options(req, &CallOptions::new()).await?;
+        Ok(res)
+    }
+
+    /// Requests a unary request with the given [`CallOptions`], and returns the
+    /// response and, if asked for, the [`CallTiming`] of the call.
+    pub async fn request_with_options(
+        &self,
+        mut req: Request,
+        opts: &CallOptions,
+    ) -> Result<(Response, Option<CallTiming>)> {
+        opts.apply(&mut req);
+        match &opts.idempotency_key {
+            Some(key) => self.coalesce(key, req, opts).await,
+            None => self.intercept(req, opts).await,
+        }
+    }
+
+    /// Joins the call in progress with the same idempotency key, or makes it.
+    async fn coalesce(
+        &self,
+        key: &str,
+        req: Request,
+        opts: &CallOptions,
+    ) -> Result<(Response, Option<CallTiming>)> {
+        let key = (req.service.clone(), req.method.clone(), key.to_string());
+        let call = {
+            let mut coalesced = self.coalesced.lock().unwrap();
+            let current = coalesced
+                .get(&key)
+                .and_then(|c| Some((c.call.upgrade()?, &c.payload)));
+            match current {
+                Some((call, payload)) if *payload == req.payload => call,
+                Some(_) => {
+                    return Err(get_rpc_status(
+                        Code::INVALID_ARGUMENT,
+                        format!(
+                            "idempotency key {:?} of {}.{} is used by a different request",
+                            key.2, key.0, key.1
+                        ),
+                    ))
+                }
+                None => {
+                    let payload = req.payload.clone();
+                    let client = self.clone();
+                    let opts = opts.clone();
+                    let call: Shared<CallFuture> =
+                        async move { client.intercept(req, &opts).await }
+                            .boxed()
+                            .shared();
+                    // Forget the calls all their callers gave up on.
+                    coalesced.retain(|_, c| c.call.upgrade().is_some());
+                    let coalesced_call = CoalescedCall {
+                        payload,
+                        call: call.downgrade().unwrap(),
+                    };
+                    coalesced.insert(key.clone(), coalesced_call);
+                    call
+                }
+            }
+        };
+        let res = call.clone().await;
+        // Later calls with the key are made again.
+        let mut coalesced = self.coalesced.lock().unwrap();
+        if let Some(current) = coalesced.get(&key).and_then(|c| c.call.upgrade()) {
+            if current.ptr_eq(&call) {
+                coalesced.remove(&key);
+            }
+        }
+        res
+    }
+
+    /// Runs the interceptors, then sends the unary request.
+    async fn intercept(
+        &self,
+        req: Request,
+        opts: &CallOptions,
+    ) -> Result<(Response, Option<CallTiming>)> {
+        if self.interceptors.is_empty() {
+            return self.send(req, opts).await;
+        }
+
+        let timing = Mutex::new(None);
+        let next = Next {
+            client: self,
+            interceptors: &self.interceptors,
+            opts,
+            timing: &timing,
+        };
+        let res = next.run(req).await?;
+        Ok((res, timing.into_inner().unwrap()))
+    }
+
+    /// Sends a unary request, once the interceptors ran.
+    async fn send(
+        &self,
+        req: Request,
+        opts: &CallOptions,
+    ) -> Result<(Response, Option<CallTiming>)> {
+        match opts.retry_policy(self.retry.as_deref()) {
+            Some(policy) => {
+                self.request_with_retry(req, opts.timing, opts.urgent(), policy)
+                    .await
+            }
+            None => self
+                .attempt(req, opts.timing, opts.urgent())
+                .await
+                .unwrap_or_else(|e| Err(timeout_error(e))),
+        }
+    }
+
+    async fn request_with_retry(
+        &self,
+        req: Request,
+        timing: bool,
+        urgent: bool,
+        policy: &RetryPolicy,
+    ) -> Result<(Response, Option<CallTiming>)> {
+        // The timeout of the request bounds all the attempts.
+        let deadline = context::deadline_after(self.clock.now(), req.timeout_nano);
+        let mut attempts = 0;
+        loop {
+            attempts += 1;
+            let remaining =
+                deadline.map(|deadline| deadline.saturating_duration_since(self.clock.now()));
+            let timeout = match (remaining, policy.per_try_timeout) {
+                (Some(remaining), Some(per_try)) => Some(remaining.min(per_try)),
+                (remaining, per_try) => remaining.or(per_try),
+            };
+            let mut attempt = req.clone();
+            if let Some(timeout) = timeout {
+                // 0 would be no timeout at all.
+                attempt.timeout_nano = (timeout.as_nanos().min(i64::MAX as u128) as i64).max(1);
+            }
+
+            let err = match self.attempt(attempt, timing, urgent).await {
+                Ok(Err(e)) if policy.retryable(&e) => e,
+                Ok(res) => return res,
+                // The attempt exceeded the per-try timeout, or the call its timeout.
+                Err(e) => timeout_error(e),
+            };
+            let delay = policy.delay(attempts);
+            let expired =
+                matches!(deadline, Some(deadline) if self.clock.now() + delay >= deadline);
+            if attempts >= policy.max_attempts || expired {
+                return Err(err);
+            }
+            debug!(
+                "Retry {}.{} after {:?}: {:?}",
+                req.service, req.method, delay, err
+            );
+            clock::sleep(&*self.clock, delay).await;
+        }
+    }
+
+    /// Makes one attempt of a unary call, or returns `Err` once the timeout of the
+    /// request elapsed.
+    async fn attempt(
+        &self,
+        req: Request,
+        timing: bool,
+        urgent: bool,
+    ) -> std::result::Result<Result<(Response, Option<CallTiming>)>, Elapsed> {
+        if let Some(breaker) = self.breaker.as_ref() {
+            if let Err(e) = breaker.admit() {
+                return Ok(Err(e));
+            }
+        }
         let stream_id = self.next_stream_id.fetch_add(2, Ordering::Relaxed);
+        if timing {
+            let timing = CallTiming::new();
+            self.timings.lock().unwrap().insert(stream_id, timing);
+        }
+
+        let res = self.call(stream_id, req, urgent).await;
+        let timing = self.timings.lock().unwrap().remove(&stream_id);
+        if let Some(breaker) = self.breaker.as_ref() {
+            breaker.record(match &res {
+                Ok(Err(e)) => is_breaker_failure(e),
+                Ok(Ok(_)) => false,
+                Err(_) => true,
+            });
+        }
+        let res = match res? {
+            Ok(res) => res,
+            Err(e) => return Ok(Err(e)),
+        };
+
+        let timing = timing.map(|timing| CallTiming {
+            decoded: Some(Instant::now()),
+            ..timing
+        });
+        Ok(Ok((res, timing)))
+    }
+
+    async fn call(
+        &self,
+        stream_id: u32,
+        req: Request,
+        urgent: bool,
+    ) -> std::result::Result<Result<Response>, Elapsed> {
+        let timeout_nano = req.timeout_nano;
+        let call = self.call_without_timeout(stream_id, req, urgent);
+        if timeout_nano == 0 {
+            return Ok(call.await);
+        }
 
+        // The deadline also covers the time spent in the send queue, which can be
+        // long when the client is offline.
+        clock::timeout(
+            &*self.clock,
+            Duration::from_nanos(timeout_nano as u64),
+            call,
+        )
+        .await
+    }
+
+    async fn call_without_timeout(
+        &self,
+        stream_id: u32,
+        req: Request,
+        urgent: bool,
+    ) -> Result<Response> {
         let msg: GenMessage = Message::new_request(stream_id, req)?
             .try_into()
             .map_err(|e: protobuf::Error| Error::Others(e.to_string()))?;
 
+        let _guard = self.start_call().await?;
+        let sample = self
+            .in_flight
+            .as_ref()
+            .and_then(|in_flight| in_flight.adaptive.as_ref())
+            .map(|adaptive| adaptive.sample());
+        let res = self.exchange(stream_id, msg, urgent).await;
+        if let Some(sample) = sample {
+            sample.finish(&res);
+        }
+        res
+    }
+
+    /// Sends the request of a unary call and waits for its response.
+    async fn exchange(&self, stream_id: u32, msg: GenMessage, urgent: bool) -> Result<Response> {
         let (tx, mut rx): (ResultSender, ResultReceiver) = mpsc::channel(100);
 
         // TODO: check return.
         self.streams.lock().unwrap().insert(stream_id, tx);
+        let mut abandon = AbandonOnDrop {
+            streams: &self.streams,
+            stream_id,
+            cancel: None,
+        };
+        self.wake_dialer();
 
-        self.req_tx
+        self.sender(urgent)
             .send(msg)
             .await
             .map_err(|e| Error::Others(format!("Send packet to sender error {e:?}")))?;
+        self.record_metrics();
+        // Servers which don't know cancel messages ignore them, like other
+        // unknown message types.
+        if !self.peer_lacks(CAPABILITY_CANCEL) {
+            abandon.cancel = Some(&self.req_tx);
+        }
 
-        let result = if timeout_nano == 0 {
-            rx.recv()
-                .await
-                .ok_or_else(|| Error::Others("Receive packet from receiver error".to_string()))?
-        } else {
-            tokio::time::timeout(
-                std::time::Duration::from_nanos(timeout_nano as u64),
-                rx.recv(),
-            )
-            .await
-            .map_err(|e| Error::Others(format!("Receive packet timeout {e:?}")))?
-            .ok_or_else(|| Error::Others("Receive packet from receiver error".to_string()))?
-        };
-
-        let msg = result?;
+        let msg = rx.recv().await;
+        abandon.cancel = None;
+        let msg =
+            msg.ok_or_else(|| Error::Others("Receive packet from receiver error".to_string()))??;
 
         let res = Response::decode(msg.payload)
             .map_err(err_to_others_err!(e, "Unpack response error "))?;
@@ -109,6 +782,16 @@ impl Client {
         Ok(res)
     }
 
+    /// The queue of the requests of the calls, the urgent one for high priority
+    /// calls.
+    fn sender(&self, urgent: bool) -> &MessageSender {
+        if urgent {
+            &self.urgent_tx
+        } else {
+            &self.req_tx
+        }
+    }
+
     /// Creates a StreamInner instance.
     pub async fn new_stream(
         &self,
@@ -116,6 +799,19 @@ impl Client {
         streaming_client: bool,
         streaming_server: bool,
     ) -> Result<StreamInner> {
+        self.new_stream_with_options(req, streaming_client, streaming_server, &CallOptions::new())
+            .await
+    }
+
+    /// Creates a StreamInner instance with the given [`CallOptions`].
+    pub async fn new_stream_with_options(
+        &self,
+        mut req: Request,
+        streaming_client: bool,
+        streaming_server: bool,
+        opts: &CallOptions,
+    ) -> Result<StreamInner> {
+        opts.apply(&mut req);
         let stream_id = self.next_stream_id.fetch_add(2, Ordering::Relaxed);
         let is_req_payload_empty = req.payload.is_empty();
 
@@ -135,13 +831,19 @@ impl Client {
             msg.header.add_flags(FLAG_REMOTE_CLOSED);
         }
 
-        let (tx, rx): (ResultSender, ResultReceiver) = mpsc::channel(100);
+        if let Some(breaker) = self.breaker.as_ref() {
+            breaker.check()?;
+        }
+        let guard = self.start_call().await?;
+        let (tx, rx): (ResultSender, ResultReceiver) = mpsc::channel(opts.stream_buffer);
         // TODO: check return
         self.streams.lock().unwrap().insert(stream_id, tx);
-        self.req_tx
+        self.wake_dialer();
+        self.sender(opts.urgent())
             .send(msg)
             .await
             .map_err(|e| Error::Others(format!("Send packet to sender error {e:?}")))?;
+        self.record_metrics();
 
         Ok(StreamInner::new(
             stream_id,
@@ -151,98 +853,1621 @@ impl Client {
             streaming_server,
             Kind::Client,
             self.streams.clone(),
-        ))
+            self.acks.clone(),
+        )
+        .with_compression(opts.compression.clone())
+        .with_guard(guard))
     }
 }
 
-struct ClientClose {
-    fd: RawFd,
-    close_fd: RawFd,
+/// Held by a call of a client until it finished.
+#[derive(Debug)]
+pub(crate) struct CallGuard {
+    _call: shutdown::Waiter,
+    _permit: Option<InFlightPermit>,
 }
 
-impl Drop for ClientClose {
-    fn drop(&mut self) {
-        close(self.close_fd).unwrap();
-        close(self.fd).unwrap();
-        trace!("All client is droped");
-    }
+/// Bounds the calls in progress on a client, see [`ClientBuilder::max_in_flight`]
+/// and [`ClientBuilder::adaptive_in_flight`].
+#[derive(Clone)]
+struct InFlight {
+    permits: Arc<Semaphore>,
+    /// Wait for a slot rather than failing when all are taken.
+    wait: bool,
+    /// The size of `permits`, unless it's resized by `adaptive`.
+    max: usize,
+    adaptive: Option<Arc<AdaptiveLimiter>>,
 }
 
-#[derive(Debug)]
-struct ClientBuilder {
-    rx: Option<MessageReceiver>,
-    streams: Arc<Mutex<HashMap<u32, ResultSender>>>,
+impl InFlight {
+    fn new(opts: &ClientBuilder) -> Option<InFlight> {
+        if let Some((limit, wait)) = opts.adaptive_in_flight.clone() {
+            let adaptive = Arc::new(AdaptiveLimiter::new(limit, opts.clock.clone()));
+            return Some(InFlight {
+                permits: adaptive.permits(),
+                wait,
+                max: 0,
+                adaptive: Some(adaptive),
+            });
+        }
+        opts.max_in_flight.map(|(max, wait)| InFlight {
+            permits: Arc::new(Semaphore::new(max)),
+            wait,
+            max,
+            adaptive: None,
+        })
+    }
+
+    async fn acquire(&self) -> Result<InFlightPermit> {
+        let permit = if self.wait {
+            // The semaphore is never closed.
+            self.permits.clone().acquire_owned().await.unwrap()
+        } else {
+            self.permits
+                .clone()
+                .try_acquire_owned()
+                .map_err(|_| Error::ResourceExhausted)?
+        };
+        Ok(InFlightPermit {
+            permit: Some(permit),
+            adaptive: self.adaptive.clone(),
+        })
+    }
 }
 
-impl Builder for ClientBuilder {
-    type Reader = ClientReader;
-    type Writer = ClientWriter;
+/// A slot of [`InFlight`], given back once dropped.
+struct InFlightPermit {
+    permit: Option<OwnedSemaphorePermit>,
+    adaptive: Option<Arc<AdaptiveLimiter>>,
+}
 
-    fn build(&mut self) -> (Self::Reader, Self::Writer) {
-        let (notifier, waiter) = shutdown::new();
-        (
-            ClientReader {
-                shutdown_waiter: waiter,
-                streams: self.streams.clone(),
-            },
-            ClientWriter {
-                rx: self.rx.take().unwrap(),
-                shutdown_notifier: notifier,
+impl std::fmt::Debug for InFlightPermit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("InFlightPermit").finish_non_exhaustive()
+    }
+}
 
-                streams: self.streams.clone(),
-            },
-        )
+impl Drop for InFlightPermit {
+    fn drop(&mut self) {
+        if let (Some(permit), Some(adaptive)) = (self.permit.take(), self.adaptive.as_ref()) {
+            adaptive.release(permit);
+        }
     }
 }
 
-struct ClientWriter {
-    rx: MessageReceiver,
-    shutdown_notifier: shutdown::Notifier,
+/// Forgets a unary call once its caller is done with it, e.g. because it timed
+/// out: its request is not sent if still queued, and its response is dropped.
+///
+/// A call dropped before its response arrived is also cancelled on the server,
+/// unless the handshake showed it doesn't support it, so the server stops handling
+/// it.
+struct AbandonOnDrop<'a> {
+    streams: &'a Mutex<HashMap<u32, ResultSender>>,
+    stream_id: u32,
+    /// Set while the call waits for its response.
+    cancel: Option<&'a MessageSender>,
+}
 
-    streams: Arc<Mutex<HashMap<u32, ResultSender>>>,
+impl Drop for AbandonOnDrop<'_> {
+    fn drop(&mut self) {
+        self.streams.lock().unwrap().remove(&self.stream_id);
+        if let Some(tx) = self.cancel {
+            let msg = GenMessage {
+                header: MessageHeader::new_cancel(self.stream_id),
+                payload: Vec::new(),
+            };
+            // Best effort, the send queue may be full.
+            if tx.try_send(msg).is_err() {
+                debug!("Failed to cancel call {}", self.stream_id);
+            }
+        }
+    }
 }
 
+/// Runs around the unary calls of a client, e.g. to add an authentication token to
+/// the requests, or to log the calls, see [`Client::add_interceptor`].
 #[async_trait]
-impl WriterDelegate for ClientWriter {
-    async fn recv(&mut self) -> Option<GenMessage> {
-        self.rx.recv().await
-    }
+pub trait Interceptor {
+    /// Returns the response to `req`, usually the one `next` returns. Returning
+    /// without running `next` fails or answers the call without sending it.
+    async fn call(&self, req: Request, next: Next<'_>) -> Result<Response>;
+}
 
-    async fn disconnect(&self, msg: &GenMessage, e: Error) {
-        // TODO:
-        // At this point, a new request may have been received.
-        let resp_tx = {
-            let mut map = self.streams.lock().unwrap();
-            map.remove(&msg.header.stream_id)
-        };
+/// The interceptors which run after the current one, and the sending of the
+/// request.
+pub struct Next<'a> {
+    client: &'a Client,
+    interceptors: &'a [Arc<dyn Interceptor + Send + Sync>],
+    opts: &'a CallOptions,
+    timing: &'a Mutex<Option<CallTiming>>,
+}
 
-        // TODO: if None
-        if let Some(resp_tx) = resp_tx {
-            let e = Error::Socket(format!("{e:?}"));
-            resp_tx
-                .send(Err(e))
-                .await
-                .unwrap_or_else(|_e| error!("The request has returned"));
+impl Next<'_> {
+    /// Passes `req` to the next interceptor, or sends it once all ran.
+    pub async fn run(self, req: Request) -> Result<Response> {
+        match self.interceptors.split_first() {
+            Some((interceptor, interceptors)) => {
+                let next = Next {
+                    interceptors,
+                    ..self
+                };
+                interceptor.call(req, next).await
+            }
+            None => {
+                let (res, timing) = self.client.send(req, self.opts).await?;
+                *self.timing.lock().unwrap() = timing;
+                Ok(res)
+            }
         }
     }
 
-    async fn exit(&self) {
-        self.shutdown_notifier.shutdown();
+    /// The clock of the client, see [`ClientBuilder::clock`].
+    pub(crate) fn clock(&self) -> Arc<dyn Clock> {
+        self.client.clock.clone()
     }
 }
 
-async fn get_resp_tx(
-    req_map: Arc<Mutex<HashMap<u32, ResultSender>>>,
-    header: &MessageHeader,
-) -> Option<ResultSender> {
-    let resp_tx = match header.type_ {
-        MESSAGE_TYPE_RESPONSE => match req_map.lock().unwrap().remove(&header.stream_id) {
-            Some(tx) => tx,
-            None => {
-                debug!("Receiver got unknown response packet {:?}", header);
-                return None;
-            }
-        },
+/// Options of a single call.
+///
+/// Generated clients take them in their `*_with_options` methods.
+#[derive(Clone, Debug)]
+pub struct CallOptions {
+    stream_buffer: usize,
+    compression: Option<Compression>,
+    timing: bool,
+    timeout: Option<Duration>,
+    metadata: HashMap<String, Vec<String>>,
+    priority: Option<Priority>,
+    retry: Option<RetryPolicy>,
+    idempotent: bool,
+    idempotency_key: Option<String>,
+}
+
+impl Default for CallOptions {
+    fn default() -> Self {
+        CallOptions {
+            stream_buffer: DEFAULT_STREAM_BUFFER,
+            compression: None,
+            timing: false,
+            timeout: None,
+            metadata: HashMap::new(),
+            priority: None,
+            retry: None,
+            idempotent: false,
+            idempotency_key: None,
+        }
+    }
+}
+
+impl CallOptions {
+    pub fn new() -> CallOptions {
+        CallOptions::default()
+    }
+
+    /// Set how many messages from the server are buffered on the stream, 100 by
+    /// default. Further messages wait for the stream to be read.
+    ///
+    /// Log streaming benefits from a larger buffer, while a small one keeps
+    /// thousands of idle watches cheap.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `capacity` is 0.
+    pub fn stream_buffer(mut self, capacity: usize) -> CallOptions {
+        assert!(
+            capacity > 0,
+            "stream buffer capacity must be greater than 0"
+        );
+        self.stream_buffer = capacity;
+        self
+    }
+
+    /// Compress the large messages sent on the stream, see [`Compression`].
+    ///
+    /// The server must be configured with the same compressor, see
+    /// [`Server::set_compression`](crate::r#async::Server::set_compression).
+    pub fn compression(mut self, compression: Compression) -> CallOptions {
+        self.compression = Some(compression);
+        self
+    }
+
+    /// Record when the steps of a unary call happened, and return them alongside
+    /// the response, see [`Client::request_with_options`].
+    pub fn timing(mut self, enabled: bool) -> CallOptions {
+        self.timing = enabled;
+        self
+    }
+
+    /// Fail the call if it didn't finish within `timeout`, instead of the
+    /// timeout of the request. The server gets the deadline too.
+    pub fn with_timeout(mut self, timeout: Duration) -> CallOptions {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Add `value` to the metadata `key` of the request.
+    pub fn with_metadata(mut self, key: &str, value: &str) -> CallOptions {
+        self.metadata
+            .entry(key.to_string())
+            .or_default()
+            .push(value.to_string());
+        self
+    }
+
+    /// Add the binary `value` to the metadata `key` of the request, see
+    /// [`Context::set_bin`](crate::context::Context::set_bin). Like all binary
+    /// metadata, it travels base64 encoded, see
+    /// [`BINARY_SUFFIX`](crate::context::BINARY_SUFFIX) for why.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `key` doesn't end with [`BINARY_SUFFIX`](crate::context::BINARY_SUFFIX).
+    pub fn with_bin_metadata(mut self, key: &str, value: &[u8]) -> CallOptions {
+        let value =
+            crate::context::encode_bin(key, &[value.to_vec()]).unwrap_or_else(|e| panic!("{}", e));
+        self.metadata
+            .entry(key.to_string())
+            .or_default()
+            .extend(value);
+        self
+    }
+
+    /// Ask the server to run the call in the lane of `priority`, which it only
+    /// does if it trusts the hints of its clients and didn't set the priority of the
+    /// method, see [`Server::set_priority_hints`](crate::r#async::Server::set_priority_hints).
+    ///
+    /// The request of a [`Priority::High`] call is also written to the connection
+    /// ahead of the messages queued by the other calls, e.g. bulk streams, so
+    /// control calls like `Kill` or `Shutdown` aren't stuck behind them. Only the
+    /// request jumps the queue, not the later messages of a stream.
+    pub fn with_priority(mut self, priority: Priority) -> CallOptions {
+        self.priority = Some(priority);
+        self
+    }
+
+    /// Retry the unary call with `policy` instead of the policy of the client,
+    /// whether the call is idempotent or not.
+    pub fn with_retry(mut self, policy: RetryPolicy) -> CallOptions {
+        self.retry = Some(policy);
+        self
+    }
+
+    /// Mark the unary call as safe to make more than once, so the retry policy
+    /// of the client applies to it, see [`ClientBuilder::retry`].
+    ///
+    /// Generated clients mark the methods the compiler was told are idempotent.
+    pub fn idempotent(mut self, idempotent: bool) -> CallOptions {
+        self.idempotent = idempotent;
+        self
+    }
+
+    /// Coalesce the unary call with the call in progress on the client to the
+    /// same method with the same `key`, if any: a single request is sent, and every
+    /// call gets its response, so the retries of callers whose first call is still
+    /// running don't add load to the server.
+    ///
+    /// A call whose payload differs from the one of the call in progress fails with
+    /// `INVALID_ARGUMENT`. The options of the call which was made are used for all.
+    /// Calls are not coalesced across clients.
+    pub fn idempotency_key(mut self, key: &str) -> CallOptions {
+        self.idempotency_key = Some(key.to_string());
+        self
+    }
+
+    fn retry_policy<'a>(&'a self, client: Option<&'a RetryPolicy>) -> Option<&'a RetryPolicy> {
+        self.retry
+            .as_ref()
+            .or_else(|| client.filter(|_| self.idempotent))
+    }
+
+    fn urgent(&self) -> bool {
+        self.priority == Some(Priority::High)
+    }
+
+    fn apply(&self, req: &mut Request) {
+        if let Some(timeout) = self.timeout {
+            req.timeout_nano = timeout.as_nanos().min(i64::MAX as u128) as i64;
+        }
+        req.metadata.extend(context::to_pb(self.metadata.clone()));
+        if let Some(priority) = self.priority {
+            req.metadata.push(KeyValue {
+                key: PRIORITY_METADATA_KEY.to_string(),
+                value: priority.as_str().to_string(),
+                ..Default::default()
+            });
+        }
+    }
+}
+
+/// How failed unary calls are made again, see [`ClientBuilder::retry`] and
+/// [`CallOptions::with_retry`].
+///
+/// A call is retried when the server failed it with one of the retryable codes,
+/// or when an attempt exceeded the per-try timeout. The timeout of the call
+/// bounds all its attempts. Streams are never retried.
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    max_attempts: u32,
+    codes: Vec<Code>,
+    per_try_timeout: Option<Duration>,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 3,
+            codes: vec![Code::UNAVAILABLE],
+            per_try_timeout: None,
+            initial_backoff: Duration::from_millis(50),
+            max_backoff: Duration::from_secs(1),
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn new() -> RetryPolicy {
+        RetryPolicy::default()
+    }
+
+    /// Make at most `attempts` attempts, the first one included. 3 by default.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `attempts` is 0.
+    pub fn max_attempts(mut self, attempts: u32) -> RetryPolicy {
+        assert!(attempts > 0, "max attempts must be greater than 0");
+        self.max_attempts = attempts;
+        self
+    }
+
+    /// Retry the calls failed with one of `codes`, only `UNAVAILABLE` by default.
+    pub fn retryable_codes(mut self, codes: Vec<Code>) -> RetryPolicy {
+        self.codes = codes;
+        self
+    }
+
+    /// Give up on an attempt after `timeout`, and retry. Attempts are only
+    /// bounded by the timeout of the call by default.
+    pub fn per_try_timeout(mut self, timeout: Duration) -> RetryPolicy {
+        self.per_try_timeout = Some(timeout);
+        self
+    }
+
+    /// Wait `initial` before the first retry, doubling the wait up to `max` for
+    /// the next ones. 50ms and 1s by default.
+    pub fn backoff(mut self, initial: Duration, max: Duration) -> RetryPolicy {
+        self.initial_backoff = initial;
+        self.max_backoff = max.max(initial);
+        self
+    }
+
+    fn retryable(&self, e: &Error) -> bool {
+        matches!(e, Error::RpcStatus(status) if self.codes.contains(&status.code()))
+    }
+
+    /// The wait before the next attempt, after `failures` attempts failed.
+    fn delay(&self, failures: u32) -> Duration {
+        backoff_delay(self.initial_backoff, self.max_backoff, failures)
+    }
+}
+
+/// How a client redials once its connection is lost, see
+/// [`ClientBuilder::reconnect`].
+#[derive(Clone, Debug)]
+pub struct ReconnectPolicy {
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    max_attempts: Option<u32>,
+    jitter: f64,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        ReconnectPolicy {
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(10),
+            max_attempts: None,
+            jitter: 0.2,
+        }
+    }
+}
+
+impl ReconnectPolicy {
+    pub fn new() -> ReconnectPolicy {
+        ReconnectPolicy::default()
+    }
+
+    /// Wait `initial` before redialing, doubling the wait up to `max` while
+    /// dialing keeps failing. 100ms and 10s by default.
+    pub fn backoff(mut self, initial: Duration, max: Duration) -> ReconnectPolicy {
+        self.initial_backoff = initial;
+        self.max_backoff = max.max(initial);
+        self
+    }
+
+    /// Give up after `attempts` dials failed in a row, failing the queued calls
+    /// and the later ones with [`Error::LocalClosed`]. Unlimited by default.
+    pub fn max_attempts(mut self, attempts: u32) -> ReconnectPolicy {
+        self.max_attempts = Some(attempts);
+        self
+    }
+
+    /// Shorten every wait by a random fraction of up to `jitter`, so clients
+    /// which lost their connections together don't redial together. 0.2 by
+    /// default.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `jitter` is not between 0 and 1.
+    pub fn jitter(mut self, jitter: f64) -> ReconnectPolicy {
+        assert!(
+            (0.0..=1.0).contains(&jitter),
+            "jitter must be between 0 and 1"
+        );
+        self.jitter = jitter;
+        self
+    }
+
+    /// Redial every second forever, what clients with an offline queue do by
+    /// default.
+    fn fixed() -> ReconnectPolicy {
+        ReconnectPolicy {
+            initial_backoff: DEFAULT_RECONNECT_INTERVAL,
+            max_backoff: DEFAULT_RECONNECT_INTERVAL,
+            max_attempts: None,
+            jitter: 0.0,
+        }
+    }
+
+    fn gave_up(&self, failures: u32) -> bool {
+        matches!(self.max_attempts, Some(max) if failures >= max)
+    }
+
+    /// The wait before the next dial, after `failures` dials failed in a row.
+    fn delay(&self, failures: u32) -> Duration {
+        backoff_delay(self.initial_backoff, self.max_backoff, failures)
+            .mul_f64(1.0 - self.jitter * random_fraction())
+    }
+}
+
+/// `initial` doubled for every failure but the first, up to `max`.
+fn backoff_delay(initial: Duration, max: Duration, failures: u32) -> Duration {
+    let exp = failures.saturating_sub(1).min(31);
+    initial
+        .checked_mul(1 << exp)
+        .map_or(max, |delay| delay.min(max))
+}
+
+fn timeout_error(e: Elapsed) -> Error {
+    Error::Others(format!("Receive packet timeout {e:?}"))
+}
+
+/// A random number in `[0, 1)`, good enough to spread redials.
+pub(crate) fn random_fraction() -> f64 {
+    use std::collections::hash_map::RandomState;
+    use std::hash::{BuildHasher, Hasher};
+
+    let bits = RandomState::new().build_hasher().finish();
+    (bits >> 11) as f64 / (1u64 << 53) as f64
+}
+
+struct ClientClose {
+    fd: RawFd,
+    close_fd: RawFd,
+}
+
+impl Drop for ClientClose {
+    fn drop(&mut self) {
+        close(self.close_fd).unwrap();
+        close(self.fd).unwrap();
+        trace!("All client is droped");
+    }
+}
+
+/// Builder of a [`Client`] connected to a socket address.
+#[derive(Clone)]
+pub struct ClientBuilder {
+    sockaddr: String,
+    offline_queue: Option<usize>,
+    write_timeout: Option<Duration>,
+    schema_version: Option<String>,
+    schema_digests: Vec<MethodInfo>,
+    notifications: bool,
+    connect_hook: Option<Arc<dyn ConnectHook + Send + Sync>>,
+    resolver: Option<Arc<dyn Resolver + Send + Sync>>,
+    #[cfg(feature = "capture")]
+    capture: Option<Capture>,
+    reconnect: Option<ReconnectPolicy>,
+    retry: Option<RetryPolicy>,
+    lazy: bool,
+    idle_timeout: Option<Duration>,
+    yield_budget: Option<usize>,
+    write_batch: Option<usize>,
+    max_in_flight: Option<(usize, bool)>,
+    adaptive_in_flight: Option<(AdaptiveLimit, bool)>,
+    keepalive: Option<(Duration, Duration)>,
+    connect_timeout: Option<Duration>,
+    breaker: Option<CircuitBreaker>,
+    #[cfg(feature = "tls")]
+    tls: Option<Tls>,
+    #[cfg(feature = "tls")]
+    tls_server_name: Option<String>,
+    #[cfg(feature = "tls-keylog")]
+    tls_key_log: Option<Arc<dyn KeyLog>>,
+    clock: Arc<dyn Clock>,
+}
+
+impl ClientBuilder {
+    pub fn new(sockaddr: &str) -> ClientBuilder {
+        ClientBuilder {
+            sockaddr: sockaddr.to_string(),
+            offline_queue: None,
+            write_timeout: None,
+            schema_version: None,
+            schema_digests: Vec::new(),
+            notifications: false,
+            connect_hook: None,
+            resolver: None,
+            #[cfg(feature = "capture")]
+            capture: None,
+            reconnect: None,
+            retry: None,
+            lazy: false,
+            idle_timeout: None,
+            yield_budget: None,
+            write_batch: None,
+            max_in_flight: None,
+            adaptive_in_flight: None,
+            keepalive: None,
+            connect_timeout: None,
+            breaker: None,
+            #[cfg(feature = "tls")]
+            tls: None,
+            #[cfg(feature = "tls")]
+            tls_server_name: None,
+            #[cfg(feature = "tls-keylog")]
+            tls_key_log: None,
+            clock: default_clock(),
+        }
+    }
+
+    /// Queue up to `capacity` calls while the client is disconnected, and flush them
+    /// once the connection is (re)established.
+    ///
+    /// The client is returned immediately, even if the server is not listening yet,
+    /// and redials in the background whenever the connection is lost. A queued call
+    /// is dropped if its timeout expires before it could be sent.
+    pub fn offline_queue(mut self, capacity: usize) -> ClientBuilder {
+        self.offline_queue = Some(capacity);
+        self
+    }
+
+    /// Give up connecting if the server doesn't accept the connection within `timeout`,
+    /// instead of blocking until it does. Applies to every address dialed.
+    ///
+    /// For a `tcp://` target named by host name, bounds the resolution and all the
+    /// attempts to connect to its addresses together.
+    pub fn connect_timeout(mut self, timeout: Duration) -> ClientBuilder {
+        self.connect_timeout = Some(timeout);
+        self
+    }
+
+    /// Measure and wait for the deadlines of calls, the retry and reconnect
+    /// backoffs, the keepalive of the connection, the cool-down of the circuit
+    /// breaker, the [hedging](ClientPool::hedge) delay, the injected faults and
+    /// the timeout of [`Client::shutdown`] with `clock` instead of the
+    /// [`TokioClock`], e.g. a [`MockClock`](crate::r#async::MockClock) in tests.
+    ///
+    /// [`TokioClock`]: crate::r#async::TokioClock
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> ClientBuilder {
+        self.clock = clock;
+        self
+    }
+
+    /// Secure the connection with TLS, e.g. to reach a server on another host over
+    /// `tcp://` or `vsock://`. Give `config` a client certificate for mutual TLS.
+    ///
+    /// The certificate of the server is verified for the host of a `tcp://` target,
+    /// or the name set by [`ClientBuilder::tls_server_name`]. The connect hook runs
+    /// over the secured connection.
+    #[cfg(feature = "tls")]
+    pub fn tls_config(mut self, config: ClientConfig) -> ClientBuilder {
+        let tls = Tls::new(config);
+        #[cfg(feature = "tls-keylog")]
+        let tls = match &self.tls_key_log {
+            Some(key_log) => tls.with_key_log(key_log.clone()),
+            None => tls,
+        };
+        self.tls = Some(tls);
+        self
+    }
+
+    /// Verify the certificate of the server for `name` rather than for the host of
+    /// the target, which a `vsock://` target requires.
+    #[cfg(feature = "tls")]
+    pub fn tls_server_name(mut self, name: &str) -> ClientBuilder {
+        self.tls_server_name = Some(name.to_string());
+        self
+    }
+
+    /// Pass the TLS secrets of the connections to `key_log`, so that captured
+    /// traffic can be decrypted while debugging, e.g. with Wireshark. Give it a
+    /// [`KeyLogFile`](crate::r#async::rustls::KeyLogFile) to append them to the
+    /// file named by `SSLKEYLOGFILE`, in the NSS key log format.
+    ///
+    /// Anyone reading the secrets can decrypt the connections, so this is meant
+    /// for development only. Requires [`ClientBuilder::tls_config`].
+    #[cfg(feature = "tls-keylog")]
+    pub fn tls_key_log(mut self, key_log: Arc<dyn KeyLog>) -> ClientBuilder {
+        self.tls = self.tls.map(|tls| tls.with_key_log(key_log.clone()));
+        self.tls_key_log = Some(key_log);
+        self
+    }
+
+    /// Consider the connection dead if writing a single message takes longer than
+    /// `timeout`, e.g. because the server stopped reading, and fail the calls in
+    /// progress instead of blocking them forever.
+    pub fn write_timeout(mut self, timeout: Duration) -> ClientBuilder {
+        self.write_timeout = Some(timeout);
+        self
+    }
+
+    /// Exchange `version` with the version of the server when connecting.
+    ///
+    /// The server exposes the version to its handlers in
+    /// [`TtrpcContext::peer_version`](crate::r#async::TtrpcContext::peer_version),
+    /// and the version of the server is returned by [`Client::peer_version`]. This
+    /// lets both sides gate newer fields and methods without probing.
+    pub fn schema_version(mut self, version: &str) -> ClientBuilder {
+        self.schema_version = Some(version.to_string());
+        self
+    }
+
+    /// Send the schema digests of `methods`, e.g. the `METHODS` of the generated
+    /// modules the client uses, in the handshake of every connection.
+    ///
+    /// A server checking them, see
+    /// [`Server::check_schema_digests`](crate::r#async::Server::check_schema_digests),
+    /// reports the methods the client and the server were generated from different
+    /// revisions of, which the client logs and returns from
+    /// [`Client::peer_schema_drift`].
+    pub fn schema_digests(mut self, methods: &[MethodInfo]) -> ClientBuilder {
+        self.schema_digests.extend_from_slice(methods);
+        self
+    }
+
+    /// Subscribe to the notifications pushed by the server on every connection,
+    /// see [`Client::notifications`].
+    pub fn notifications(mut self) -> ClientBuilder {
+        self.notifications = true;
+        self
+    }
+
+    /// Run `hook` on every connection before any call is sent on it.
+    pub fn connect_hook(mut self, hook: Arc<dyn ConnectHook + Send + Sync>) -> ClientBuilder {
+        self.connect_hook = Some(hook);
+        self
+    }
+
+    /// Resolve the socket address given to [`ClientBuilder::new`] with `resolver`
+    /// before every dial, and connect to the first address which accepts the
+    /// connection.
+    ///
+    /// Requires an [offline queue](ClientBuilder::offline_queue), as the client
+    /// dials in the background.
+    pub fn resolver(mut self, resolver: Arc<dyn Resolver + Send + Sync>) -> ClientBuilder {
+        self.resolver = Some(resolver);
+        self
+    }
+
+    /// Record the messages exchanged on every connection into `capture`, to
+    /// analyze the traffic with Wireshark.
+    #[cfg(feature = "capture")]
+    pub fn capture(mut self, capture: Capture) -> ClientBuilder {
+        self.capture = Some(capture);
+        self
+    }
+
+    /// Redial with `policy` whenever the connection is lost. Calls made while
+    /// the client is disconnected wait for the new connection, the calls in
+    /// progress when the connection was lost fail.
+    ///
+    /// Unlike with an [offline queue](ClientBuilder::offline_queue) alone, the
+    /// first connection is made by [`ClientBuilder::build`], which fails if the
+    /// server is not listening. Clients with an offline queue redial every second
+    /// by default.
+    pub fn reconnect(mut self, policy: ReconnectPolicy) -> ClientBuilder {
+        self.reconnect = Some(policy);
+        self
+    }
+
+    /// Retry the failed unary calls marked [idempotent](CallOptions::idempotent)
+    /// with `policy`. Calls are not retried by default.
+    pub fn retry(mut self, policy: RetryPolicy) -> ClientBuilder {
+        self.retry = Some(policy);
+        self
+    }
+
+    /// Fail calls fast with `UNAVAILABLE` once `breaker` opened, instead of piling
+    /// them up on a server which stopped answering. The clones of the client share
+    /// the breaker. Clients have no breaker by default.
+    pub fn circuit_breaker(mut self, breaker: CircuitBreaker) -> ClientBuilder {
+        self.breaker = Some(breaker);
+        self
+    }
+
+    /// Yield to the other tasks of the runtime after handling `messages` messages
+    /// read in a row, so a burst of responses or stream messages doesn't starve
+    /// the tasks sharing the worker. The reader doesn't yield by default.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `messages` is 0.
+    pub fn yield_budget(mut self, messages: usize) -> ClientBuilder {
+        assert!(messages > 0, "yield budget must be greater than 0");
+        self.yield_budget = Some(messages);
+        self
+    }
+
+    /// Write up to `messages` queued messages with a single vectored write, rather
+    /// than a write per message, which saves syscalls when many small calls are
+    /// made concurrently. The messages are written one by one by default.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `messages` is 0.
+    pub fn write_batch(mut self, messages: usize) -> ClientBuilder {
+        assert!(messages > 0, "write batch must be greater than 0");
+        self.write_batch = Some(messages);
+        self
+    }
+
+    /// Allow at most `limit` unary calls and streams in progress at a time on
+    /// the connection. Once all are taken, new calls wait for one to finish if
+    /// `wait` is `true`, and fail with [`Error::ResourceExhausted`] otherwise.
+    ///
+    /// Without a limit, a flood of calls grows the queue of messages to send and
+    /// the calls waiting for their responses without bounds. Waiting for a slot
+    /// counts against the timeout of the call. Unlimited by default.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `limit` is 0.
+    pub fn max_in_flight(mut self, limit: usize, wait: bool) -> ClientBuilder {
+        assert!(limit > 0, "max in flight must be greater than 0");
+        self.max_in_flight = Some((limit, wait));
+        self
+    }
+
+    /// Like [`ClientBuilder::max_in_flight`], with a limit which adapts to the
+    /// latency of the unary calls, see [`AdaptiveLimit`], and replaces a fixed one.
+    ///
+    /// Protects a server which slows down under load, e.g. a small agent in a
+    /// guest, from a caller which makes many calls at once. The clones of the client
+    /// share the limit, see [`Client::in_flight_limit`].
+    pub fn adaptive_in_flight(mut self, limit: AdaptiveLimit, wait: bool) -> ClientBuilder {
+        self.adaptive_in_flight = Some((limit, wait));
+        self
+    }
+
+    /// Ping the server once nothing was read from the connection for `interval`,
+    /// and consider the connection dead if the server doesn't answer within
+    /// `timeout`, e.g. a vsock peer which went away silently.
+    ///
+    /// The calls in progress on a dead connection fail with
+    /// [`Error::KeepaliveTimeout`], and the client reconnects if it has a
+    /// [reconnect policy](ClientBuilder::reconnect). The server must support
+    /// [pings](Client::ping).
+    pub fn keepalive(mut self, interval: Duration, timeout: Duration) -> ClientBuilder {
+        self.keepalive = Some((interval, timeout));
+        self
+    }
+
+    /// Close the connection once it carried no call for `timeout`, and connect
+    /// again when the next call is made, see [`ClientBuilder::lazy`].
+    ///
+    /// Hosts with many mostly idle clients, e.g. one per sandbox, hold sockets
+    /// and tasks only for the clients in use. Clients receiving
+    /// [notifications](ClientBuilder::notifications) can't have an idle timeout.
+    pub fn idle_timeout(mut self, timeout: Duration) -> ClientBuilder {
+        self.idle_timeout = Some(timeout);
+        self.lazy = true;
+        self
+    }
+
+    /// Don't connect until the first call is made, e.g. to create the client
+    /// before the server is started.
+    ///
+    /// Unless the client has an [offline queue](ClientBuilder::offline_queue) or
+    /// a [reconnect policy](ClientBuilder::reconnect), the calls waiting for the
+    /// connection fail if it can't be made, and the client connects again for the
+    /// next call.
+    pub fn lazy(mut self) -> ClientBuilder {
+        self.lazy = true;
+        self
+    }
+
+    /// Connects to the server and returns the client.
+    ///
+    /// A `tcp://` target named by host name, e.g. `tcp://sandbox.local:1024`, is
+    /// resolved without blocking and its IPv6 and IPv4 addresses are dialed in
+    /// turn, a new attempt starting every 250ms until one connects. This happens
+    /// in the background: the client is returned at once, and its calls fail if
+    /// no address accepts.
+    pub fn build(self) -> Result<Client> {
+        if self.idle_timeout.is_some() && self.notifications {
+            return Err(Error::Others(
+                "a client receiving notifications can't have an idle timeout".to_string(),
+            ));
+        }
+        #[cfg(feature = "tls")]
+        if self.tls.is_some() {
+            tls::server_name(&self.sockaddr, self.tls_server_name.as_deref())?;
+        }
+        #[cfg(feature = "tls-keylog")]
+        if self.tls_key_log.is_some() {
+            if self.tls.is_none() {
+                return Err(Error::Others(
+                    "a TLS key log requires ClientBuilder::tls_config".to_string(),
+                ));
+            }
+            warn!(
+                "Logging the TLS secrets of the connections to {}",
+                self.sockaddr
+            );
+        }
+        if self.resolver.is_some() && self.offline_queue.is_none() {
+            return Err(Error::Others(
+                "a client with a resolver requires an offline queue".to_string(),
+            ));
+        }
+        let (capacity, mut first) = match (self.offline_queue, &self.reconnect) {
+            (Some(0), _) => {
+                return Err(Error::Others(
+                    "offline queue capacity must be greater than 0".to_string(),
+                ))
+            }
+            (Some(capacity), _) => (capacity, None),
+            (None, _) if self.lazy => (DEFAULT_RECONNECT_QUEUE, None),
+            // Host names are resolved and dialed in the background, the first
+            // calls wait for the connection.
+            (None, Some(_)) if tcp_host(&self.sockaddr).is_some() => {
+                (DEFAULT_RECONNECT_QUEUE, None)
+            }
+            (None, Some(_)) => {
+                let fd = self.connect(&self.sockaddr)?;
+                (DEFAULT_RECONNECT_QUEUE, Some(fd))
+            }
+            (None, None) => {
+                let fd = match tcp_host(&self.sockaddr) {
+                    Some(_) => None,
+                    None => Some(self.connect(&self.sockaddr)?),
+                };
+                let opts = self.clone();
+                let dial = async move {
+                    let fd = match fd {
+                        Some(fd) => fd,
+                        None => opts.dial().await?,
+                    };
+                    opts.secure(ClientSocket::from_raw_fd(fd)).await
+                };
+                return Ok(Client::with_dialer(dial, &self));
+            }
+        };
+        // Lazy clients without a queue nor a policy only dial for calls.
+        let on_demand = self.offline_queue.is_none() && self.reconnect.is_none();
+
+        let (req_tx, rx): (MessageSender, MessageReceiver) = mpsc::channel(capacity);
+        let weak_tx = req_tx.downgrade();
+        let (mut client, delegate) = Client::with_sender(req_tx, rx, &self);
+        let (dial_tx, mut dial_rx) = watch::channel(());
+        if self.lazy {
+            client.dial_tx = Some(Arc::new(dial_tx));
+        }
+        let policy = self
+            .reconnect
+            .clone()
+            .unwrap_or_else(ReconnectPolicy::fixed);
+        tokio::spawn(async move {
+            let mut failures = 0;
+            let mut idle = self.lazy;
+            let mut close_rx = delegate.close_rx.clone();
+            // Stop redialing once all clients and streams are dropped.
+            while weak_tx.upgrade().is_some() && !*delegate.close_rx.borrow() {
+                if idle {
+                    delegate.state.set(ConnectivityState::Idle);
+                    select! {
+                        res = dial_rx.changed() => if res.is_err() {
+                            break;
+                        },
+                        _ = closed(&mut close_rx) => break,
+                    }
+                    idle = false;
+                }
+                delegate.state.set(ConnectivityState::Connecting);
+                let res = match first.take() {
+                    Some(fd) => Ok(fd),
+                    None => self.dial().await,
+                };
+                let res = match res {
+                    Ok(fd) => self.secure(ClientSocket::from_raw_fd(fd)).await,
+                    Err(e) => Err(e),
+                };
+                match res {
+                    Ok(mut stream) => {
+                        let hooked = match &self.connect_hook {
+                            Some(hook) => hook.on_connect(&mut stream).await.map_err(|e| {
+                                error!("Connect hook of {} failed: {:?}", self.sockaddr, e);
+                            }),
+                            None => Ok(()),
+                        };
+                        if hooked.is_ok() {
+                            delegate.state.set(ConnectivityState::Ready);
+                            let conn = Connection::new(delegate.own(stream), delegate.clone());
+                            conn.run().await.ok();
+                            trace!("Connection to {} closed", self.sockaddr);
+                            failures = 0;
+                            if delegate.idled.swap(false, Ordering::Relaxed) {
+                                // Only the calls made from now on wake up the dialer
+                                // again, those made meanwhile are still queued.
+                                dial_rx.borrow_and_update();
+                                idle = delegate.streams.lock().unwrap().is_empty();
+                                continue;
+                            }
+                        } else {
+                            failures += 1;
+                        }
+                    }
+                    Err(e) => {
+                        trace!("Connect to {} failed: {:?}", self.sockaddr, e);
+                        failures += 1;
+                    }
+                }
+                if on_demand {
+                    // Only the calls made from now on wake up the dialer again.
+                    dial_rx.borrow_and_update();
+                    delegate.fail_calls().await;
+                    idle = true;
+                    continue;
+                }
+                if policy.gave_up(failures) {
+                    error!(
+                        "Gave up connecting to {} after {} attempts",
+                        self.sockaddr, failures
+                    );
+                    delegate.fail_calls().await;
+                    break;
+                }
+                delegate.state.set(ConnectivityState::TransientFailure);
+                clock::sleep(&*self.clock, policy.delay(failures)).await;
+            }
+            delegate.state.set(ConnectivityState::Shutdown);
+        });
+
+        Ok(client)
+    }
+
+    fn connect(&self, sockaddr: &str) -> Result<RawFd> {
+        connect(sockaddr, self.connect_timeout)
+    }
+
+    /// Like [`ClientBuilder::connect`], on a blocking thread so that a peer slow
+    /// to accept, e.g. over vsock, doesn't stall the runtime.
+    async fn connect_blocking(&self, sockaddr: &str) -> Result<RawFd> {
+        let sockaddr = sockaddr.to_string();
+        let timeout = self.connect_timeout;
+        let fd = task::spawn_blocking(move || {
+            // Closed if the dial is given up meanwhile.
+            connect(&sockaddr, timeout).map(|fd| unsafe { OwnedFd::from_raw_fd(fd) })
+        })
+        .await
+        .map_err(|e| Error::Others(format!("Connect task failed: {e}")))??;
+        Ok(fd.into_raw_fd())
+    }
+
+    /// Runs the TLS handshake over `stream` if the connection is to be secured.
+    async fn secure(&self, stream: ClientSocket) -> Result<ClientSocket> {
+        #[cfg(feature = "tls")]
+        if let Some(tls) = self.tls.as_ref() {
+            let name = tls::server_name(&self.sockaddr, self.tls_server_name.as_deref())?;
+            return tls.connect(name, stream).await;
+        }
+        Ok(stream)
+    }
+
+    async fn dial(&self) -> Result<RawFd> {
+        let resolver = match &self.resolver {
+            Some(resolver) => resolver,
+            None => match tcp_host(&self.sockaddr) {
+                Some(host_port) => return connect_tcp(host_port, self.connect_timeout).await,
+                None => return self.connect_blocking(&self.sockaddr).await,
+            },
+        };
+        let addresses = resolver.resolve(&self.sockaddr).await?;
+        let mut last_err = Error::Others(format!("{} resolved to no address", self.sockaddr));
+        for addr in addresses {
+            match self.connect_blocking(&addr.to_string()).await {
+                Ok(fd) => return Ok(fd),
+                Err(e) => {
+                    trace!("Connect to {} failed: {:?}", addr, e);
+                    last_err = e;
+                }
+            }
+        }
+        Err(last_err)
+    }
+}
+
+fn connect(sockaddr: &str, timeout: Option<Duration>) -> Result<RawFd> {
+    match timeout {
+        Some(timeout) => unsafe { client_connect_timeout(sockaddr, timeout) },
+        None => unsafe { client_connect(sockaddr) },
+    }
+}
+
+/// Several connections to the same server, which calls are spread over
+/// round-robin, so concurrent calls are not serialized on a single connection.
+/// Or one connection to each of several servers, see [`ClientPool::with_endpoints`].
+///
+/// Connections are made when first needed, and made again once lost. Clones
+/// share the connections.
+#[derive(Clone)]
+pub struct ClientPool {
+    inner: Arc<PoolInner>,
+}
+
+struct PoolInner {
+    builder: ClientBuilder,
+    slots: Vec<Mutex<Option<PoolSlot>>>,
+    /// The address of the connection of each slot, or empty if all connect to
+    /// the address of `builder`.
+    endpoints: Vec<String>,
+    balancing: Balancing,
+    next: AtomicUsize,
+    idle_timeout: Option<Duration>,
+    hedge_delay: Option<Duration>,
+}
+
+/// How a [`ClientPool`] over several endpoints picks the connection of a call.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Balancing {
+    /// Use the first endpoint which is up, in the order given, so the others are
+    /// only used while it is down.
+    PickFirst,
+    /// Spread the calls over the endpoints which are up.
+    RoundRobin,
+}
+
+struct PoolSlot {
+    client: Client,
+    last_used: Instant,
+}
+
+impl ClientPool {
+    /// Spread the calls over up to `size` connections made by `builder`.
+    pub fn new(builder: ClientBuilder, size: usize) -> ClientPool {
+        assert!(size > 0, "pool size must be greater than 0");
+        ClientPool {
+            inner: Arc::new(PoolInner {
+                builder,
+                slots: (0..size).map(|_| Mutex::new(None)).collect(),
+                endpoints: Vec::new(),
+                balancing: Balancing::RoundRobin,
+                next: AtomicUsize::new(0),
+                idle_timeout: None,
+                hedge_delay: None,
+            }),
+        }
+    }
+
+    /// Connect to each of `endpoints`, e.g. replicas of an agent on several vsock
+    /// cids, with `builder` and the address of the endpoint, and pick the connection
+    /// of every call as `balancing` says.
+    ///
+    /// An endpoint is down while its connection fails, or is waiting to reconnect,
+    /// and calls fail over to the other endpoints meanwhile. The calls in progress
+    /// on an endpoint which goes down fail, as they may have been handled.
+    pub fn with_endpoints(
+        builder: ClientBuilder,
+        endpoints: Vec<Address>,
+        balancing: Balancing,
+    ) -> ClientPool {
+        assert!(!endpoints.is_empty(), "endpoints must not be empty");
+        ClientPool {
+            inner: Arc::new(PoolInner {
+                builder,
+                slots: endpoints.iter().map(|_| Mutex::new(None)).collect(),
+                endpoints: endpoints.iter().map(Address::to_string).collect(),
+                balancing,
+                next: AtomicUsize::new(0),
+                idle_timeout: None,
+                hedge_delay: None,
+            }),
+        }
+    }
+
+    /// Close the connections which were not used for `timeout`, when next
+    /// getting a client from the pool. They are made again when needed.
+    pub fn idle_timeout(mut self, timeout: Duration) -> ClientPool {
+        let inner = Arc::get_mut(&mut self.inner).expect("pool is shared");
+        inner.idle_timeout = Some(timeout);
+        self
+    }
+
+    /// Send a copy of the unary calls still waiting for their response after
+    /// `delay` on the next connection, and return the first successful response.
+    ///
+    /// The copy which lost is forgotten, but the server may still handle it, so
+    /// only hedge calls which are safe to make twice. Hedging needs a pool of at
+    /// least 2 connections to avoid a slow one.
+    pub fn hedge(mut self, delay: Duration) -> ClientPool {
+        let inner = Arc::get_mut(&mut self.inner).expect("pool is shared");
+        inner.hedge_delay = Some(delay);
+        self
+    }
+
+    /// Returns the client of the next connection, connecting it if needed.
+    ///
+    /// The connection stays open while the client is in use, even if the pool
+    /// reaps it.
+    pub fn get(&self) -> Result<Client> {
+        let now = Instant::now();
+        let len = self.inner.slots.len();
+        let first = match self.inner.balancing {
+            Balancing::PickFirst => 0,
+            Balancing::RoundRobin => self.inner.next.fetch_add(1, Ordering::Relaxed) % len,
+        };
+        if let Some(timeout) = self.inner.idle_timeout {
+            self.reap(first, now, timeout);
+        }
+        if self.inner.endpoints.is_empty() {
+            return self.get_slot(first, now);
+        }
+
+        // Fail over to the next endpoints, or settle for the first one which is
+        // reconnecting if all are down.
+        let mut reconnecting = None;
+        let mut last_err = None;
+        for index in (first..len).chain(0..first) {
+            match self.get_slot(index, now) {
+                Ok(client) if client.state() == ConnectivityState::TransientFailure => {
+                    reconnecting.get_or_insert(client);
+                }
+                Ok(client) => return Ok(client),
+                Err(e) => {
+                    trace!("Connect to {} failed: {:?}", self.inner.endpoints[index], e);
+                    last_err = Some(e);
+                }
+            }
+        }
+        match (reconnecting, last_err) {
+            (Some(client), _) => Ok(client),
+            (None, Some(e)) => Err(e),
+            (None, None) => unreachable!("a pool has at least one slot"),
+        }
+    }
+
+    fn get_slot(&self, index: usize, now: Instant) -> Result<Client> {
+        if let Some(client) = self.use_slot(index, now) {
+            return Ok(client);
+        }
+        // Building may connect, which blocks, so the slot isn't held meanwhile.
+        let mut builder = self.inner.builder.clone();
+        if let Some(endpoint) = self.inner.endpoints.get(index) {
+            builder.sockaddr = endpoint.clone();
+        }
+        let client = builder.build()?;
+        let mut slot = self.inner.slots[index].lock().unwrap();
+        match slot.as_mut() {
+            // Another caller connected first, the new connection is dropped.
+            Some(slot) if !slot.client.is_closed() => {
+                slot.last_used = now;
+                Ok(slot.client.clone())
+            }
+            _ => {
+                *slot = Some(PoolSlot {
+                    client: client.clone(),
+                    last_used: now,
+                });
+                Ok(client)
+            }
+        }
+    }
+
+    /// Returns the client of the slot `index`, unless it has none or it is closed.
+    fn use_slot(&self, index: usize, now: Instant) -> Option<Client> {
+        let mut slot = self.inner.slots[index].lock().unwrap();
+        let slot = slot.as_mut().filter(|slot| !slot.client.is_closed())?;
+        slot.last_used = now;
+        Some(slot.client.clone())
+    }
+
+    /// Requests a unary request on the next connection, see [`ClientPool::hedge`].
+    pub async fn request(&self, req: Request) -> Result<Response> {
+        let delay = match self.inner.hedge_delay {
+            Some(delay) => delay,
+            None => return self.get()?.request(req).await,
+        };
+
+        let mut hedged_req = req.clone();
+        let primary = self.get()?;
+        let first = primary.request(req);
+        tokio::pin!(first);
+        select! {
+            res = &mut first => return res,
+            _ = clock::sleep(&*self.inner.builder.clock, delay) => {}
+        }
+
+        let client = match self.get() {
+            Ok(client) => client,
+            Err(_) => return first.await,
+        };
+        if hedged_req.timeout_nano > 0 {
+            // Both copies share the deadline of the call.
+            let elapsed = delay.as_nanos().min(i64::MAX as u128) as i64;
+            hedged_req.timeout_nano = (hedged_req.timeout_nano - elapsed).max(1);
+        }
+        let hedged = client.request(hedged_req);
+        tokio::pin!(hedged);
+        // Dropping the other copy forgets it.
+        select! {
+            res = &mut first => match res {
+                Ok(res) => Ok(res),
+                Err(_) => hedged.await,
+            },
+            res = &mut hedged => match res {
+                Ok(res) => Ok(res),
+                Err(_) => first.await,
+            },
+        }
+    }
+
+    /// Number of the connections currently held by the pool.
+    pub fn connections(&self) -> usize {
+        self.inner
+            .slots
+            .iter()
+            .filter(|slot| slot.lock().unwrap().is_some())
+            .count()
+    }
+
+    fn reap(&self, except: usize, now: Instant, timeout: Duration) {
+        for (index, slot) in self.inner.slots.iter().enumerate() {
+            if index == except {
+                continue;
+            }
+            let mut slot = slot.lock().unwrap();
+            if matches!(&*slot, Some(s) if now.duration_since(s.last_used) >= timeout) {
+                *slot = None;
+            }
+        }
+    }
+}
+
+#[derive(Clone)]
+struct ClientDelegateBuilder {
+    rx: SharedReceiver,
+    urgent_rx: SharedReceiver,
+    next_stream_id: Arc<AtomicU32>,
+    streams: Arc<Mutex<HashMap<u32, ResultSender>>>,
+    acks: AckWaiters,
+    timings: CallTimings,
+    handshake: Option<ClientHandshake>,
+    subscription: Option<ClientSubscription>,
+    write_timeout: Option<Duration>,
+    #[cfg(feature = "capture")]
+    capture: Option<Capture>,
+    yield_budget: Option<usize>,
+    write_batch: Option<usize>,
+    close_rx: watch::Receiver<bool>,
+    exits: Arc<shutdown::Notifier>,
+    closed_fds: Arc<AtomicUsize>,
+    idle_timeout: Option<Duration>,
+    /// Set once a connection was closed for being idle.
+    idled: Arc<AtomicBool>,
+    state: StateSender,
+    /// Answers the pings of the server, without keeping the client alive.
+    req_tx: mpsc::WeakSender<GenMessage>,
+    pings: Pings,
+    ping_handler: PingHandlerSlot,
+    keepalive: Option<(Duration, Duration)>,
+    clock: Arc<dyn Clock>,
+    draining: Arc<AtomicBool>,
+}
+
+impl ClientDelegateBuilder {
+    /// Fails the calls waiting for a connection which won't come.
+    async fn fail_calls(&self) {
+        // Queued messages of the failed calls.
+        while self.rx.lock().await.try_recv().is_ok() {}
+        while self.urgent_rx.lock().await.try_recv().is_ok() {}
+        let streams = std::mem::take(&mut *self.streams.lock().unwrap());
+        self.acks.lock().unwrap().clear();
+        for (_, tx) in streams {
+            tx.send(Err(Error::LocalClosed)).await.ok();
+        }
+    }
+
+    /// Hands `socket` to a connection, which closes it once done.
+    fn own(&self, socket: ClientSocket) -> OwnedSocket {
+        OwnedSocket {
+            socket: Some(socket),
+            closed: self.closed_fds.clone(),
+            _exit: self.exits.subscribe(),
+        }
+    }
+}
+
+/// The socket of a connection of a client, closed once both the reader and the
+/// writer of the connection are done with it.
+struct OwnedSocket {
+    socket: Option<ClientSocket>,
+    /// Counts the sockets closed, see [`Client::close`].
+    closed: Arc<AtomicUsize>,
+    /// Dropped once the socket is closed.
+    _exit: shutdown::Waiter,
+}
+
+impl OwnedSocket {
+    fn get(self: Pin<&mut Self>) -> Pin<&mut ClientSocket> {
+        Pin::new(self.get_mut().socket.as_mut().unwrap())
+    }
+}
+
+impl Drop for OwnedSocket {
+    fn drop(&mut self) {
+        if let Some(socket) = self.socket.take() {
+            match socket.close() {
+                Ok(()) => {
+                    self.closed.fetch_add(1, Ordering::Relaxed);
+                }
+                Err(e) => warn!("Failed to close client socket: {}", e),
+            }
+        }
+    }
+}
+
+impl AsRawFd for OwnedSocket {
+    fn as_raw_fd(&self) -> RawFd {
+        self.socket.as_ref().unwrap().as_raw_fd()
+    }
+}
+
+impl AsyncRead for OwnedSocket {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        self.get().poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for OwnedSocket {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.get().poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.get().poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.get().poll_shutdown(cx)
+    }
+}
+
+impl Builder for ClientDelegateBuilder {
+    type Reader = ClientReader;
+    type Writer = ClientWriter;
+
+    fn write_timeout(&self) -> Option<Duration> {
+        self.write_timeout
+    }
+
+    #[cfg(feature = "capture")]
+    fn capture(&self) -> Option<Capture> {
+        self.capture.clone()
+    }
+
+    fn yield_budget(&self) -> Option<usize> {
+        self.yield_budget
+    }
+
+    fn write_batch(&self) -> Option<usize> {
+        self.write_batch
+    }
+
+    fn build(&mut self) -> (Self::Reader, Self::Writer) {
+        let (notifier, waiter) = shutdown::new();
+        // Every connection starts with a handshake and the subscription.
+        let handshake = self.handshake.as_ref().and_then(|handshake| {
+            handshake
+                .start(&self.next_stream_id, &self.streams)
+                .map_err(|e| error!("Failed to start handshake: {:?}", e))
+                .ok()
+        });
+        let subscription = self.subscription.as_ref().and_then(|subscription| {
+            subscription
+                .start(&self.next_stream_id, &self.streams)
+                .map_err(|e| error!("Failed to subscribe to notifications: {:?}", e))
+                .ok()
+        });
+        (
+            ClientReader {
+                shutdown_waiter: waiter,
+                streams: self.streams.clone(),
+                acks: self.acks.clone(),
+                timings: self.timings.clone(),
+                req_tx: self.req_tx.clone(),
+                pings: self.pings.clone(),
+                ping_handler: self.ping_handler.clone(),
+                keepalive: self.keepalive,
+                last_read: Mutex::new(self.clock.now()),
+                clock: self.clock.clone(),
+                draining: self.draining.clone(),
+            },
+            ClientWriter {
+                rx: self.rx.clone(),
+                urgent_rx: self.urgent_rx.clone(),
+                shutdown_notifier: notifier,
+                setup: handshake.into_iter().chain(subscription).collect(),
+                close_rx: self.close_rx.clone(),
+                idle_timeout: self.idle_timeout,
+                idled: self.idled.clone(),
+
+                streams: self.streams.clone(),
+                timings: self.timings.clone(),
+            },
+        )
+    }
+}
+
+struct ClientWriter {
+    rx: SharedReceiver,
+    urgent_rx: SharedReceiver,
+    shutdown_notifier: shutdown::Notifier,
+    /// Sent before any queued message.
+    setup: VecDeque<GenMessage>,
+    close_rx: watch::Receiver<bool>,
+    idle_timeout: Option<Duration>,
+    idled: Arc<AtomicBool>,
+
+    streams: Arc<Mutex<HashMap<u32, ResultSender>>>,
+    timings: CallTimings,
+}
+
+impl ClientWriter {
+    /// The caller may have given up while the request was queued.
+    fn abandoned(&self, msg: &GenMessage) -> bool {
+        if msg.header.type_ == MESSAGE_TYPE_REQUEST
+            && !self
+                .streams
+                .lock()
+                .unwrap()
+                .contains_key(&msg.header.stream_id)
+        {
+            trace!("Drop abandoned request {:?}", msg.header);
+            return true;
+        }
+        false
+    }
+}
+
+#[async_trait]
+impl WriterDelegate for ClientWriter {
+    async fn recv(&mut self) -> Option<GenMessage> {
+        if let Some(msg) = self.setup.pop_front() {
+            return Some(msg);
+        }
+        let mut rx = self.rx.lock().await;
+        let mut urgent_rx = self.urgent_rx.lock().await;
+        loop {
+            let msg = select! {
+                biased;
+                _ = closed(&mut self.close_rx) => return None,
+                // Closed once the clients are dropped, the streams may still send.
+                Some(msg) = urgent_rx.recv() => msg,
+                msg = rx.recv() => msg?,
+                _ = idle(self.idle_timeout) => {
+                    if !self.streams.lock().unwrap().is_empty() {
+                        continue;
+                    }
+                    trace!("Close idle connection");
+                    self.idled.store(true, Ordering::Relaxed);
+                    return None;
+                }
+            };
+            if self.abandoned(&msg) {
+                continue;
+            }
+            return Some(msg);
+        }
+    }
+
+    fn try_recv(&mut self) -> Option<GenMessage> {
+        if let Some(msg) = self.setup.pop_front() {
+            return Some(msg);
+        }
+        // Only the writer receives, but for the calls failed on a lost connection.
+        let mut rx = self.rx.try_lock().ok()?;
+        let mut urgent_rx = self.urgent_rx.try_lock().ok()?;
+        loop {
+            let msg = urgent_rx.try_recv().or_else(|_| rx.try_recv()).ok()?;
+            if !self.abandoned(&msg) {
+                return Some(msg);
+            }
+        }
+    }
+
+    async fn disconnect(&self, msg: &GenMessage, e: Error) {
+        // TODO:
+        // At this point, a new request may have been received.
+        let resp_tx = {
+            let mut map = self.streams.lock().unwrap();
+            map.remove(&msg.header.stream_id)
+        };
+
+        // TODO: if None
+        if let Some(resp_tx) = resp_tx {
+            let e = match e {
+                Error::PeerClosed => e,
+                e => Error::Socket(format!("{e:?}")),
+            };
+            resp_tx
+                .send(Err(e))
+                .await
+                .unwrap_or_else(|_e| error!("The request has returned"));
+        }
+    }
+
+    async fn exit(&self) {
+        self.shutdown_notifier.shutdown();
+    }
+
+    fn written(&self, header: &MessageHeader) {
+        if header.type_ == MESSAGE_TYPE_REQUEST {
+            record_timing(&self.timings, header.stream_id, |t| &mut t.written);
+        }
+    }
+}
+
+/// Completes after `timeout`, or never without a timeout.
+async fn idle(timeout: Option<Duration>) {
+    match timeout {
+        Some(timeout) => tokio::time::sleep(timeout).await,
+        None => futures::future::pending().await,
+    }
+}
+
+/// Waits until [`Client::close`] is called.
+async fn closed(close_rx: &mut watch::Receiver<bool>) {
+    while !*close_rx.borrow_and_update() {
+        if close_rx.changed().await.is_err() {
+            // All the clients were dropped without closing.
+            futures::future::pending::<()>().await;
+        }
+    }
+}
+
+async fn get_resp_tx(
+    req_map: Arc<Mutex<HashMap<u32, ResultSender>>>,
+    header: &MessageHeader,
+) -> Option<ResultSender> {
+    let resp_tx = match header.type_ {
+        MESSAGE_TYPE_RESPONSE => match req_map.lock().unwrap().remove(&header.stream_id) {
+            Some(tx) => tx,
+            None => {
+                debug!("Receiver got unknown response packet {:?}", header);
+                return None;
+            }
+        },
         MESSAGE_TYPE_DATA => {
             if (header.flags & FLAG_REMOTE_CLOSED) == FLAG_REMOTE_CLOSED {
                 match req_map.lock().unwrap().remove(&header.stream_id) {
@@ -262,77 +2487,1724 @@ async fn get_resp_tx(
                 }
             }
         }
-        _ => {
-            let resp_tx = match req_map.lock().unwrap().remove(&header.stream_id) {
-                Some(tx) => tx,
-                None => {
-                    debug!("Receiver got unknown packet {:?}", header);
-                    return None;
-                }
-            };
-            resp_tx
-                .send(Err(Error::Others(format!(
-                    "Receiver got malformed packet {header:?}"
-                ))))
+        _ => {
+            let resp_tx = match req_map.lock().unwrap().remove(&header.stream_id) {
+                Some(tx) => tx,
+                None => {
+                    debug!("Receiver got unknown packet {:?}", header);
+                    return None;
+                }
+            };
+            resp_tx
+                .send(Err(Error::Others(format!(
+                    "Receiver got malformed packet {header:?}"
+                ))))
+                .await
+                .unwrap_or_else(|_e| error!("The request has returned"));
+            return None;
+        }
+    };
+
+    Some(resp_tx)
+}
+
+struct ClientReader {
+    streams: Arc<Mutex<HashMap<u32, ResultSender>>>,
+    acks: AckWaiters,
+    timings: CallTimings,
+    req_tx: mpsc::WeakSender<GenMessage>,
+    pings: Pings,
+    ping_handler: PingHandlerSlot,
+    /// Interval and timeout of the keepalive pings.
+    keepalive: Option<(Duration, Duration)>,
+    last_read: Mutex<Instant>,
+    clock: Arc<dyn Clock>,
+    /// See [`Client::draining`].
+    draining: Arc<AtomicBool>,
+    shutdown_waiter: shutdown::Waiter,
+}
+
+#[async_trait]
+impl ReaderDelegate for ClientReader {
+    async fn wait_shutdown(&self) {
+        self.shutdown_waiter.wait_shutdown().await
+    }
+
+    async fn disconnect(&self, e: Error, sender: &mut task::JoinHandle<()>) {
+        // Abort the request sender task to prevent incoming RPC requests
+        // from being processed.
+        sender.abort();
+        let _ = sender.await;
+
+        // Fail the sends waiting for an acknowledgement, and the pings.
+        self.acks.lock().unwrap().clear();
+        self.pings.clear();
+        // Take all items out of `req_map`.
+        let mut map = std::mem::take(&mut *self.streams.lock().unwrap());
+        // Terminate undone RPC requests with the error.
+        for (_stream_id, resp_tx) in map.drain() {
+            if let Err(_e) = resp_tx.send(Err(e.clone())).await {
+                warn!("Failed to terminate pending RPC: the request has returned");
+            }
+        }
+    }
+
+    async fn exit(&self) {
+        // The calls made from now on wait for the next connection.
+        self.draining.store(false, Ordering::Relaxed);
+    }
+
+    async fn check_alive(&self) -> Error {
+        let (interval, timeout) = match self.keepalive {
+            Some(keepalive) => keepalive,
+            None => return std::future::pending().await,
+        };
+        let last_read = *self.last_read.lock().unwrap();
+        self.clock.sleep_until(last_read + interval).await;
+        // The client is gone, the connection is closing anyway.
+        let tx = match self.req_tx.upgrade() {
+            Some(tx) => tx,
+            None => return std::future::pending().await,
+        };
+        trace!("Connection idle for {:?}, ping", interval);
+        match clock::timeout(&*self.clock, timeout, self.pings.ping(&tx, &[])).await {
+            // Reading the pong dropped this future already.
+            Ok(_) => std::future::pending().await,
+            Err(_) => Error::KeepaliveTimeout,
+        }
+    }
+
+    async fn handle_err(&self, header: MessageHeader, e: Error) {
+        *self.last_read.lock().unwrap() = self.clock.now();
+        let req_map = self.streams.clone();
+        tokio::spawn(async move {
+            if let Some(resp_tx) = get_resp_tx(req_map, &header).await {
+                resp_tx
+                    .send(Err(e))
+                    .await
+                    .unwrap_or_else(|_e| error!("The request has returned"));
+            }
+        });
+    }
+
+    async fn handle_msg(&self, msg: GenMessage) {
+        *self.last_read.lock().unwrap() = self.clock.now();
+        let stream_id = msg.header.stream_id;
+        match msg.header.type_ {
+            MESSAGE_TYPE_DATA if (msg.header.flags & FLAG_ACK) == FLAG_ACK => {
+                notify_ack(&self.acks, stream_id);
+                return;
+            }
+            // The server won't acknowledge anything after the response.
+            MESSAGE_TYPE_RESPONSE => {
+                self.acks.lock().unwrap().remove(&stream_id);
+                record_timing(&self.timings, stream_id, |t| &mut t.received);
+            }
+            MESSAGE_TYPE_PING => {
+                let handler = self.ping_handler.lock().unwrap().clone();
+                let pong = ping::pong(handler.as_deref(), &msg);
+                if let Some(tx) = self.req_tx.upgrade() {
+                    tx.send(pong).await.ok();
+                }
+                return;
+            }
+            MESSAGE_TYPE_PONG => {
+                self.pings.pong(msg);
+                return;
+            }
+            // The calls in progress go on until the server closes the connection.
+            MESSAGE_TYPE_GOAWAY => {
+                info!("Server is shutting down, fail new calls until reconnected");
+                self.draining.store(true, Ordering::Relaxed);
+                return;
+            }
+            _ => {}
+        }
+
+        let req_map = self.streams.clone();
+        tokio::spawn(async move {
+            if let Some(resp_tx) = get_resp_tx(req_map, &msg.header).await {
+                resp_tx
+                    .send(Ok(msg))
+                    .await
+                    .unwrap_or_else(|_e| error!("The request has returned"));
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::sync::Notify;
+
+    use crate::r#async::handshake::HANDSHAKE_SERVICE;
+    use crate::r#async::{
+        testing, MethodHandler, MockClock, SchemaDrift, Server, Service, TtrpcContext,
+    };
+
+    struct Echo;
+
+    #[async_trait]
+    impl MethodHandler for Echo {
+        async fn handler(&self, _ctx: TtrpcContext, req: Request) -> Result<Response> {
+            Ok(Response {
+                payload: req.payload,
+                ..Default::default()
+            })
+        }
+    }
+
+    /// Never answers.
+    struct Hang;
+
+    #[async_trait]
+    impl MethodHandler for Hang {
+        async fn handler(&self, _ctx: TtrpcContext, _req: Request) -> Result<Response> {
+            std::future::pending().await
+        }
+    }
+
+    /// Never answers, and notifies `dropped` once the server drops it.
+    struct Watch {
+        started: Arc<Notify>,
+        dropped: Arc<Notify>,
+    }
+
+    struct NotifyOnDrop(Arc<Notify>);
+
+    impl Drop for NotifyOnDrop {
+        fn drop(&mut self) {
+            self.0.notify_one();
+        }
+    }
+
+    #[async_trait]
+    impl MethodHandler for Watch {
+        async fn handler(&self, _ctx: TtrpcContext, _req: Request) -> Result<Response> {
+            let _guard = NotifyOnDrop(self.dropped.clone());
+            self.started.notify_one();
+            std::future::pending().await
+        }
+    }
+
+    /// Answers once `release` is notified, and notifies `started` before.
+    struct Gate {
+        started: Arc<Notify>,
+        release: Arc<Notify>,
+    }
+
+    #[async_trait]
+    impl MethodHandler for Gate {
+        async fn handler(&self, _ctx: TtrpcContext, _req: Request) -> Result<Response> {
+            self.started.notify_one();
+            self.release.notified().await;
+            Ok(Response::default())
+        }
+    }
+
+    /// Answers every call, and counts them.
+    struct Counted(Arc<AtomicUsize>);
+
+    #[async_trait]
+    impl MethodHandler for Counted {
+        async fn handler(&self, _ctx: TtrpcContext, _req: Request) -> Result<Response> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            Ok(Response::default())
+        }
+    }
+
+    /// Fails every call with `UNAVAILABLE`, and counts them.
+    struct Unavailable(Arc<AtomicUsize>);
+
+    #[async_trait]
+    impl MethodHandler for Unavailable {
+        async fn handler(&self, _ctx: TtrpcContext, _req: Request) -> Result<Response> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            let mut res = Response::new();
+            res.set_status(crate::get_status(Code::UNAVAILABLE, "try again"));
+            Ok(res)
+        }
+    }
+
+    fn services() -> HashMap<String, Service> {
+        let mut methods: HashMap<String, Box<dyn MethodHandler + Send + Sync>> = HashMap::new();
+        methods.insert("Echo".to_string(), Box::new(Echo));
+        methods.insert("Hang".to_string(), Box::new(Hang));
+        let mut services = HashMap::new();
+        services.insert(
+            "test.Test".to_string(),
+            Service {
+                methods,
+                streams: HashMap::new(),
+            },
+        );
+        services
+    }
+
+    fn request(method: &str) -> Request {
+        Request {
+            service: "test.Test".to_string(),
+            method: method.to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// Waits until `client` has `calls` calls waiting for their response.
+    async fn wait_calls(client: &Client, calls: usize) {
+        while client.streams.lock().unwrap().len() != calls {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_close_report() {
+        let (client, _server) = testing::serve(services()).await.unwrap();
+        let call = tokio::spawn({
+            let client = client.clone();
+            async move { client.request(request("Hang")).await }
+        });
+        wait_calls(&client, 1).await;
+
+        let report = client.close().await;
+        assert_eq!(report.connections, 1);
+        assert_eq!(report.calls_in_progress, 1);
+        assert_eq!(report.detached, 0);
+        assert_eq!(report.fds_closed, 1);
+        assert!(matches!(call.await.unwrap(), Err(Error::LocalClosed)));
+    }
+
+    #[tokio::test]
+    async fn test_shutdown() {
+        let started = Arc::new(Notify::new());
+        let release = Arc::new(Notify::new());
+        let mut services = services();
+        services.get_mut("test.Test").unwrap().methods.insert(
+            "Gate".to_string(),
+            Box::new(Gate {
+                started: started.clone(),
+                release: release.clone(),
+            }),
+        );
+        let server = testing::start(Server::new().register_service(services))
+            .await
+            .unwrap();
+
+        // The call in progress gets its response.
+        let client = Client::connect(&server.address()).unwrap();
+        let call = tokio::spawn({
+            let client = client.clone();
+            async move { client.request(request("Gate")).await }
+        });
+        started.notified().await;
+        let other = client.clone();
+        let shutdown = tokio::spawn(client.shutdown(Duration::from_secs(5)));
+        while !matches!(
+            other.request(request("Echo")).await,
+            Err(Error::LocalClosed)
+        ) {}
+        assert!(!shutdown.is_finished());
+        release.notify_one();
+        call.await.unwrap().unwrap();
+        let report = shutdown.await.unwrap();
+        assert_eq!(report.connections, 1);
+        assert_eq!(report.calls_in_progress, 0);
+
+        // Unless it doesn't finish in time.
+        let client = Client::connect(&server.address()).unwrap();
+        let call = tokio::spawn({
+            let client = client.clone();
+            async move { client.request(request("Hang")).await }
+        });
+        wait_calls(&client, 1).await;
+        let report = client.shutdown(Duration::from_millis(50)).await;
+        assert_eq!(report.calls_in_progress, 1);
+        assert!(matches!(call.await.unwrap(), Err(Error::LocalClosed)));
+    }
+
+    #[tokio::test]
+    async fn test_mock_clock_timeout() {
+        let server = testing::start(Server::new().register_service(services()))
+            .await
+            .unwrap();
+        let clock = MockClock::new();
+        let client = ClientBuilder::new(&server.address())
+            .clock(Arc::new(clock.clone()))
+            .build()
+            .unwrap();
+        let mut req = request("Hang");
+        req.timeout_nano = Duration::from_secs(3600).as_nanos() as i64;
+        let call = tokio::spawn({
+            let client = client.clone();
+            async move { client.request(req).await }
+        });
+        wait_calls(&client, 1).await;
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!call.is_finished());
+        clock.advance(Duration::from_secs(3600));
+        let res = tokio::time::timeout(Duration::from_secs(5), call)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(matches!(res, Err(Error::Others(e)) if e.contains("timeout")));
+    }
+
+    #[tokio::test]
+    async fn test_max_in_flight() {
+        let (_, server) = testing::serve(services()).await.unwrap();
+        for wait in [false, true] {
+            let client = ClientBuilder::new(&server.address())
+                .max_in_flight(2, wait)
+                .build()
+                .unwrap();
+            let mut hangs: Vec<_> = (0..2)
+                .map(|_| {
+                    let client = client.clone();
+                    tokio::spawn(async move { client.request(request("Hang")).await })
+                })
+                .collect();
+            wait_calls(&client, 2).await;
+
+            let mut echo = tokio::spawn({
+                let client = client.clone();
+                async move { client.request(request("Echo")).await }
+            });
+            if wait {
+                // Nothing is sent until a call gives its slot back.
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                assert!(!echo.is_finished());
+                assert_eq!(client.streams.lock().unwrap().len(), 2);
+            } else {
+                assert!(matches!(
+                    (&mut echo).await.unwrap(),
+                    Err(Error::ResourceExhausted)
+                ));
+            }
+
+            // Dropping a call gives its slot back.
+            hangs[0].abort();
+            let _ = (&mut hangs[0]).await;
+            if wait {
+                echo.await.unwrap().unwrap();
+            } else {
+                client.request(request("Echo")).await.unwrap();
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_reconnect() {
+        let server = testing::start(Server::new().register_service(services()))
+            .await
+            .unwrap();
+        let backoff = Duration::from_millis(10);
+        let client = ClientBuilder::new(&server.address())
+            .reconnect(ReconnectPolicy::new().backoff(backoff, backoff))
+            .build()
+            .unwrap();
+        client.request(request("Echo")).await.unwrap();
+
+        let mut states = client.watch_state();
+        let id = server.server().connections()[0].id;
+        server.server().close_connection(id, "test").unwrap();
+        let mut seen = Vec::new();
+        while let Some(state) = states.recv().await {
+            seen.push(state);
+            if state == ConnectivityState::Ready {
+                break;
+            }
+        }
+        assert!(seen.contains(&ConnectivityState::TransientFailure));
+        client.request(request("Echo")).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_state() {
+        let path = std::env::temp_dir().join(format!("ttrpc-state-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let backoff = Duration::from_millis(10);
+        let client = ClientBuilder::new(&format!("unix://{}", path.display()))
+            .lazy()
+            .reconnect(ReconnectPolicy::new().backoff(backoff, backoff))
+            .build()
+            .unwrap();
+        assert_eq!(client.state(), ConnectivityState::Idle);
+
+        // The first call dials, and fails while nothing listens.
+        let mut states = client.watch_state();
+        let call = tokio::spawn({
+            let client = client.clone();
+            async move { client.request(request("Echo")).await }
+        });
+        while states.recv().await != Some(ConnectivityState::TransientFailure) {}
+
+        let listener = std::os::unix::net::UnixListener::bind(&path).unwrap();
+        let mut server = Server::new()
+            .register_service(services())
+            .add_listener(listener.into_raw_fd())
+            .unwrap()
+            .set_domain_unix();
+        server.start().await.unwrap();
+        while states.recv().await != Some(ConnectivityState::Ready) {}
+        assert_eq!(client.state(), ConnectivityState::Ready);
+        let _ = call.await.unwrap();
+        client.request(request("Echo")).await.unwrap();
+
+        client.clone().close().await;
+        assert_eq!(client.state(), ConnectivityState::Shutdown);
+        while states.recv().await.is_some() {}
+        assert!(client.request(request("Echo")).await.is_err());
+        server.shutdown().await.unwrap();
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn test_connect_lazy() {
+        let path = std::env::temp_dir().join(format!("ttrpc-lazy-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let address = format!("unix://{}", path.display());
+        assert!(Client::connect(&address).is_err());
+        let client = Client::connect_lazy(&address).unwrap();
+
+        // Without a reconnect policy, the call fails and the next one dials again.
+        assert!(client.request(request("Echo")).await.is_err());
+        let listener = std::os::unix::net::UnixListener::bind(&path).unwrap();
+        let mut server = Server::new()
+            .register_service(services())
+            .add_listener(listener.into_raw_fd())
+            .unwrap()
+            .set_domain_unix();
+        server.start().await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(server.connections().is_empty());
+        client.request(request("Echo")).await.unwrap();
+        assert_eq!(server.connections().len(), 1);
+
+        // Nor does a lazy client dial a server which is up before the first call.
+        let other = Client::connect_lazy(&address).unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(server.connections().len(), 1);
+        other.request(request("Echo")).await.unwrap();
+        assert_eq!(server.connections().len(), 2);
+
+        server.shutdown().await.unwrap();
+        let _ = std::fs::remove_file(&path);
+    }
+
+    /// Sends `banner` on every connection, and counts them.
+    struct SendBanner {
+        banner: &'static [u8],
+        runs: Arc<AtomicUsize>,
+    }
+
+    #[async_trait]
+    impl ConnectHook for SendBanner {
+        async fn on_connect(&self, stream: &mut dyn RawStream) -> Result<()> {
+            self.runs.fetch_add(1, Ordering::SeqCst);
+            stream
+                .write_all(self.banner)
+                .await
+                .map_err(err_to_others_err!(e, ""))?;
+            let mut ok = [0; 3];
+            stream
+                .read_exact(&mut ok)
+                .await
+                .map_err(err_to_others_err!(e, ""))?;
+            Ok(())
+        }
+    }
+
+    /// Accepts the connections which send the banner `HELLO\n`.
+    struct CheckBanner;
+
+    #[async_trait]
+    impl ConnectHook for CheckBanner {
+        async fn on_connect(&self, stream: &mut dyn RawStream) -> Result<()> {
+            let mut banner = [0; 6];
+            stream
+                .read_exact(&mut banner)
+                .await
+                .map_err(err_to_others_err!(e, ""))?;
+            if &banner != b"HELLO\n" {
+                return Err(Error::Others("unexpected banner".to_string()));
+            }
+            stream
+                .write_all(b"OK\n")
+                .await
+                .map_err(err_to_others_err!(e, ""))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_connect_hook() {
+        let server = testing::start(
+            Server::new()
+                .register_service(services())
+                .set_connect_hook(Arc::new(CheckBanner)),
+        )
+        .await
+        .unwrap();
+
+        // The banner is sent before the first call, and once per connection.
+        let runs = Arc::new(AtomicUsize::new(0));
+        let client = ClientBuilder::new(&server.address())
+            .lazy()
+            .connect_hook(Arc::new(SendBanner {
+                banner: b"HELLO\n",
+                runs: runs.clone(),
+            }))
+            .build()
+            .unwrap();
+        assert_eq!(runs.load(Ordering::SeqCst), 0);
+        client.request(request("Echo")).await.unwrap();
+        client.request(request("Echo")).await.unwrap();
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
+
+        // The server closes the connections without the banner.
+        let client = Client::connect(&server.address()).unwrap();
+        assert!(client.request(request("Echo")).await.is_err());
+
+        // A failing hook is a failed attempt to connect.
+        let runs = Arc::new(AtomicUsize::new(0));
+        let backoff = Duration::from_millis(10);
+        let client = ClientBuilder::new(&server.address())
+            .connect_hook(Arc::new(SendBanner {
+                banner: b"HOWDY\n",
+                runs: runs.clone(),
+            }))
+            .reconnect(
+                ReconnectPolicy::new()
+                    .backoff(backoff, backoff)
+                    .max_attempts(3),
+            )
+            .build()
+            .unwrap();
+        let mut states = client.watch_state();
+        while states.recv().await.is_some() {}
+        assert_eq!(client.state(), ConnectivityState::Shutdown);
+        assert_eq!(runs.load(Ordering::SeqCst), 3);
+        assert!(matches!(
+            client.request(request("Echo")).await,
+            Err(Error::LocalClosed)
+        ));
+    }
+
+    /// Records the ticks of a task sharing the worker at every ping.
+    struct Ticks {
+        ticker: Arc<AtomicUsize>,
+        seen: Mutex<Vec<usize>>,
+    }
+
+    impl PingHandler for Ticks {
+        fn on_ping(&self, _payload: &[u8]) -> Vec<u8> {
+            self.seen
+                .lock()
+                .unwrap()
+                .push(self.ticker.load(Ordering::SeqCst));
+            Vec::new()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_yield_budget() {
+        let path = std::env::temp_dir().join(format!("ttrpc-burst-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = tokio::net::UnixListener::bind(&path).unwrap();
+        let client = ClientBuilder::new(&format!("unix://{}", path.display()))
+            .yield_budget(4)
+            .build()
+            .unwrap();
+        let ticker = Arc::new(AtomicUsize::new(0));
+        let ticks = Arc::new(Ticks {
+            ticker: ticker.clone(),
+            seen: Mutex::new(Vec::new()),
+        });
+        let client = client.on_ping(ticks.clone());
+        let (mut conn, _) = listener.accept().await.unwrap();
+        let ticking = tokio::spawn(async move {
+            loop {
+                ticker.fetch_add(1, Ordering::SeqCst);
+                tokio::task::yield_now().await;
+            }
+        });
+
+        // A burst of pings, read by the client at once.
+        let mut burst = Vec::new();
+        for id in 0..1000 {
+            ping::frame(MESSAGE_TYPE_PING, id, Vec::new())
+                .write_to(&mut burst)
                 .await
-                .unwrap_or_else(|_e| error!("The request has returned"));
-            return None;
+                .unwrap();
         }
-    };
+        conn.write_all(&burst).await.unwrap();
+        for _ in 0..1000 {
+            GenMessage::read_from(&mut conn).await.unwrap();
+        }
+        ticking.abort();
 
-    Some(resp_tx)
-}
+        // The other task ran during the burst: the yielded ticker is only woken
+        // again on the next tick of the scheduler, so it runs every other budget.
+        let seen = ticks.seen.lock().unwrap();
+        assert_eq!(seen.len(), 1000);
+        assert!(seen.windows(9).all(|w| w[0] < w[8]));
+        drop(client);
+        let _ = std::fs::remove_file(&path);
+    }
 
-struct ClientReader {
-    streams: Arc<Mutex<HashMap<u32, ResultSender>>>,
-    shutdown_waiter: shutdown::Waiter,
-}
+    #[tokio::test]
+    async fn test_idle_timeout() {
+        let server = testing::start(Server::new().register_service(services()))
+            .await
+            .unwrap();
+        let client = ClientBuilder::new(&server.address())
+            .idle_timeout(Duration::from_millis(50))
+            .build()
+            .unwrap();
+        client.request(request("Echo")).await.unwrap();
+        assert_eq!(client.state(), ConnectivityState::Ready);
+        assert_eq!(server.server().connections().len(), 1);
 
-#[async_trait]
-impl ReaderDelegate for ClientReader {
-    async fn wait_shutdown(&self) {
-        self.shutdown_waiter.wait_shutdown().await
+        let mut states = client.watch_state();
+
+        while let Some(state) = states.recv().await {
+            if state == ConnectivityState::Idle {
+                break;
+            }
+        }
+        while !server.server().connections().is_empty() {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+
+        // The next call connects again.
+        client.request(request("Echo")).await.unwrap();
+        assert_eq!(server.server().connections().len(), 1);
     }
 
-    async fn disconnect(&self, e: Error, sender: &mut task::JoinHandle<()>) {
-        // Abort the request sender task to prevent incoming RPC requests
-        // from being processed.
-        sender.abort();
-        let _ = sender.await;
+    #[tokio::test]
+    async fn test_cancel_on_drop() {
+        let started = Arc::new(Notify::new());
+        let dropped = Arc::new(Notify::new());
+        let mut services = services();
+        services.get_mut("test.Test").unwrap().methods.insert(
+            "Watch".to_string(),
+            Box::new(Watch {
+                started: started.clone(),
+                dropped: dropped.clone(),
+            }),
+        );
+        // Without a schema version, so without a handshake.
+        let (client, _server) = testing::serve(services).await.unwrap();
 
-        // Take all items out of `req_map`.
-        let mut map = std::mem::take(&mut *self.streams.lock().unwrap());
-        // Terminate undone RPC requests with the error.
-        for (_stream_id, resp_tx) in map.drain() {
-            if let Err(_e) = resp_tx.send(Err(e.clone())).await {
-                warn!("Failed to terminate pending RPC: the request has returned");
+        let call = tokio::spawn({
+            let client = client.clone();
+            async move { client.request(request("Watch")).await }
+        });
+        started.notified().await;
+        call.abort();
+
+        tokio::time::timeout(Duration::from_secs(5), dropped.notified())
+            .await
+            .expect("the server kept handling the dropped call");
+    }
+
+    #[tokio::test]
+    async fn test_goaway() {
+        let started = Arc::new(Notify::new());
+        let release = Arc::new(Notify::new());
+        let mut services = services();
+        services.get_mut("test.Test").unwrap().methods.insert(
+            "Gate".to_string(),
+            Box::new(Gate {
+                started: started.clone(),
+                release: release.clone(),
+            }),
+        );
+        let mut server = testing::start(Server::new().register_service(services))
+            .await
+            .unwrap();
+        let backoff = Duration::from_millis(10);
+        let client = ClientBuilder::new(&server.address())
+            .reconnect(ReconnectPolicy::new().backoff(backoff, backoff))
+            .build()
+            .unwrap();
+        let call = tokio::spawn({
+            let client = client.clone();
+            async move { client.request(request("Gate")).await }
+        });
+        started.notified().await;
+
+        let shutdown = tokio::spawn(async move {
+            let report = server
+                .server_mut()
+                .graceful_shutdown(Duration::from_secs(5))
+                .await;
+            (server, report)
+        });
+        while !client.draining.load(Ordering::Relaxed) {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        // Failed by the client, the server would say it's shutting down.
+        match client.request(request("Echo")).await {
+            Err(Error::RpcStatus(s)) => {
+                assert_eq!(s.code(), Code::UNAVAILABLE);
+                assert_eq!(s.message(), "server is draining the connection");
             }
+            res => panic!("unexpected {:?}", res),
+        }
+
+        // The call in progress still finishes.
+        release.notify_one();
+        call.await.unwrap().unwrap();
+        let (_server, report) = shutdown.await.unwrap();
+        assert_eq!(report.calls_in_progress, 0);
+        while client.draining.load(Ordering::Relaxed) {
+            tokio::time::sleep(Duration::from_millis(1)).await;
         }
     }
 
-    async fn exit(&self) {}
+    /// A client retrying with `policy` on `clock`, and the attempts its calls of
+    /// `Unavailable` made.
+    async fn retrying(
+        policy: RetryPolicy,
+        clock: &MockClock,
+    ) -> (Client, Arc<AtomicUsize>, testing::ServerGuard) {
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let mut services = services();
+        services.get_mut("test.Test").unwrap().methods.insert(
+            "Unavailable".to_string(),
+            Box::new(Unavailable(attempts.clone())),
+        );
+        let server = testing::start(Server::new().register_service(services))
+            .await
+            .unwrap();
+        let client = ClientBuilder::new(&server.address())
+            .clock(Arc::new(clock.clone()))
+            .retry(policy)
+            .build()
+            .unwrap();
+        (client, attempts, server)
+    }
 
-    async fn handle_err(&self, header: MessageHeader, e: Error) {
-        let req_map = self.streams.clone();
-        tokio::spawn(async move {
-            if let Some(resp_tx) = get_resp_tx(req_map, &header).await {
-                resp_tx
-                    .send(Err(e))
+    /// Moves `clock` forward in small steps, so the backoffs end one after the
+    /// other, until `attempts` were made.
+    async fn advance_until(clock: &MockClock, attempts: &AtomicUsize, n: usize) {
+        while attempts.load(Ordering::SeqCst) < n {
+            clock.advance(Duration::from_millis(100));
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+    }
+
+    fn unavailable(timeout: Duration) -> Request {
+        let mut req = request("Unavailable");
+        req.timeout_nano = timeout.as_nanos() as i64;
+        req
+    }
+
+    fn assert_unavailable(res: Result<impl std::fmt::Debug>) {
+        match res {
+            Err(Error::RpcStatus(status)) => assert_eq!(status.code(), Code::UNAVAILABLE),
+            res => panic!("unexpected {:?}", res),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_retry_idempotent_only() {
+        let clock = MockClock::new();
+        let backoff = Duration::from_secs(1);
+        let policy = RetryPolicy::new().backoff(backoff, backoff);
+        let (client, attempts, _server) = retrying(policy, &clock).await;
+
+        // Not marked idempotent, made once without waiting for the clock.
+        let timeout = Duration::from_secs(3600);
+        assert_unavailable(client.request(unavailable(timeout)).await);
+        assert_eq!(attempts.load(Ordering::SeqCst), 1);
+
+        let call = tokio::spawn({
+            let client = client.clone();
+            let opts = CallOptions::new().idempotent(true);
+            async move {
+                client
+                    .request_with_options(unavailable(timeout), &opts)
                     .await
-                    .unwrap_or_else(|_e| error!("The request has returned"));
             }
         });
+        advance_until(&clock, &attempts, 1 + 3).await;
+        assert_unavailable(call.await.unwrap());
+        assert_eq!(attempts.load(Ordering::SeqCst), 1 + 3);
     }
 
-    async fn handle_msg(&self, msg: GenMessage) {
-        let req_map = self.streams.clone();
+    #[tokio::test]
+    async fn test_retry_max_attempts() {
+        let clock = MockClock::new();
+        let backoff = Duration::from_secs(1);
+        let policy = RetryPolicy::new().max_attempts(4).backoff(backoff, backoff);
+        let (client, attempts, _server) = retrying(policy, &clock).await;
+
+        let call = tokio::spawn({
+            let client = client.clone();
+            let opts = CallOptions::new().idempotent(true);
+            let req = unavailable(Duration::from_secs(3600));
+            async move { client.request_with_options(req, &opts).await }
+        });
+        advance_until(&clock, &attempts, 4).await;
+        assert_unavailable(call.await.unwrap());
+
+        // Plenty of time left, but no attempt left.
+        clock.advance(Duration::from_secs(60));
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert_eq!(attempts.load(Ordering::SeqCst), 4);
+    }
+
+    #[tokio::test]
+    async fn test_retry_backoff_deadline() {
+        let clock = MockClock::new();
+        let start = clock.now();
+        let policy = RetryPolicy::new()
+            .max_attempts(10)
+            .backoff(Duration::from_secs(1), Duration::from_secs(60));
+        let (client, attempts, _server) = retrying(policy, &clock).await;
+
+        let call = tokio::spawn({
+            let client = client.clone();
+            let opts = CallOptions::new().idempotent(true);
+            let req = unavailable(Duration::from_millis(2500));
+            async move { client.request_with_options(req, &opts).await }
+        });
+        advance_until(&clock, &attempts, 2).await;
+        // The second backoff of 2s would end past the deadline of the call, which
+        // fails with the error of its last attempt rather than sleeping.
+        let res = tokio::time::timeout(Duration::from_secs(5), call)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_unavailable(res);
+        assert_eq!(attempts.load(Ordering::SeqCst), 2);
+        assert!(clock.now() - start < Duration::from_millis(2500));
+    }
+
+    /// Hangs on its first call, answers the next ones.
+    struct SlowFirst {
+        started: Arc<Notify>,
+        first: AtomicBool,
+    }
+
+    #[async_trait]
+    impl MethodHandler for SlowFirst {
+        async fn handler(&self, _ctx: TtrpcContext, req: Request) -> Result<Response> {
+            if self.first.swap(false, Ordering::SeqCst) {
+                self.started.notify_one();
+                std::future::pending::<()>().await;
+            }
+            Ok(Response {
+                payload: req.payload,
+                ..Default::default()
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_hedge() {
+        let started = Arc::new(Notify::new());
+        let mut services = services();
+        services.get_mut("test.Test").unwrap().methods.insert(
+            "SlowFirst".to_string(),
+            Box::new(SlowFirst {
+                started: started.clone(),
+                first: AtomicBool::new(true),
+            }),
+        );
+        let server = testing::start(Server::new().register_service(services))
+            .await
+            .unwrap();
+        let clock = MockClock::new();
+        let builder = ClientBuilder::new(&server.address()).clock(Arc::new(clock.clone()));
+        let pool = ClientPool::new(builder, 2).hedge(Duration::from_secs(1));
+
+        let call = tokio::spawn({
+            let pool = pool.clone();
+            let mut req = request("SlowFirst");
+            req.payload = b"hedged".to_vec();
+            req.timeout_nano = Duration::from_secs(3600).as_nanos() as i64;
+            async move { pool.request(req).await }
+        });
+        started.notified().await;
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        // Only hedged once the delay elapsed on the clock of the pool.
+        assert!(!call.is_finished());
+        assert_eq!(pool.connections(), 1);
+
+        clock.advance(Duration::from_secs(1));
+        let res = tokio::time::timeout(Duration::from_secs(5), call)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(res.unwrap().payload, b"hedged");
+        assert_eq!(pool.connections(), 2);
+    }
+
+    /// Starts a server on `path`, so it can be restarted on the same address.
+    async fn start_at(path: &std::path::Path) -> Server {
+        let _ = std::fs::remove_file(path);
+        let listener = std::os::unix::net::UnixListener::bind(path).unwrap();
+        let mut server = Server::new()
+            .register_service(services())
+            .add_listener(listener.into_raw_fd())
+            .unwrap()
+            .set_domain_unix();
+        server.start().await.unwrap();
+        server
+    }
+
+    #[tokio::test]
+    async fn test_pool_failover() {
+        let server = testing::start(Server::new().register_service(services()))
+            .await
+            .unwrap();
+        let down = std::env::temp_dir().join(format!("ttrpc-down-{}.sock", std::process::id()));
+        let endpoints = vec![
+            format!("unix://{}", down.display()).parse().unwrap(),
+            server.address().parse().unwrap(),
+        ];
+        let builder = ClientBuilder::new("unix://").reconnect(ReconnectPolicy::new());
+        let pool = ClientPool::with_endpoints(builder, endpoints, Balancing::RoundRobin);
+
+        // Whichever endpoint is next, the calls end up on the one which is up.
+        for _ in 0..3 {
+            pool.request(request("Echo")).await.unwrap();
+        }
+        assert_eq!(pool.connections(), 1);
+        assert_eq!(server.server().connections().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_pool_pick_first() {
+        let path = std::env::temp_dir().join(format!("ttrpc-first-{}.sock", std::process::id()));
+        let mut first = start_at(&path).await;
+        let second = testing::start(Server::new().register_service(services()))
+            .await
+            .unwrap();
+        let endpoints = vec![
+            format!("unix://{}", path.display()).parse().unwrap(),
+            second.address().parse().unwrap(),
+        ];
+        let backoff = Duration::from_millis(10);
+        let builder = ClientBuilder::new("unix://")
+            .reconnect(ReconnectPolicy::new().backoff(backoff, backoff));
+        let pool = ClientPool::with_endpoints(builder, endpoints, Balancing::PickFirst);
+
+        let client = pool.get().unwrap();
+        client.request(request("Echo")).await.unwrap();
+        assert_eq!(first.connections().len(), 1);
+
+        // The calls fall back to the second endpoint while the first one is down.
+        first.shutdown().await.unwrap();
+        let mut states = client.watch_state();
+        while states.recv().await != Some(ConnectivityState::TransientFailure) {}
+        pool.request(request("Echo")).await.unwrap();
+        assert_eq!(second.server().connections().len(), 1);
+
+        // And return to it once it is back.
+        let first = start_at(&path).await;
+        while states.recv().await != Some(ConnectivityState::Ready) {}
+        pool.request(request("Echo")).await.unwrap();
+        assert_eq!(first.connections().len(), 1);
+        assert_eq!(pool.connections(), 2);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn test_offline_queue() {
+        let path = std::env::temp_dir().join(format!("ttrpc-offline-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let clock = MockClock::new();
+        let client = ClientBuilder::new(&format!("unix://{}", path.display()))
+            .offline_queue(2)
+            .clock(Arc::new(clock.clone()))
+            .build()
+            .unwrap();
+        let call = |timeout: Duration| {
+            let client = client.clone();
+            let mut req = request("Counted");
+            req.timeout_nano = timeout.as_nanos() as i64;
+            tokio::spawn(async move { client.request(req).await })
+        };
+        let is_timeout =
+            |res: Result<Response>| matches!(res, Err(Error::Others(e)) if e.contains("timeout"));
+
+        let flushed = call(Duration::from_secs(3600));
+        wait_calls(&client, 1).await;
+        let expired = call(Duration::from_secs(10));
+        wait_calls(&client, 2).await;
+        assert_eq!(client.req_tx.capacity(), 0);
+        // The queue is full, the call waits for room until it times out.
+        let refused = call(Duration::from_secs(5));
+        wait_calls(&client, 3).await;
+        clock.advance(Duration::from_secs(5));
+        assert!(is_timeout(refused.await.unwrap()));
+
+        // Queued, but dropped once its timeout expired.
+        clock.advance(Duration::from_secs(5));
+        assert!(is_timeout(expired.await.unwrap()));
+
+        let calls = Arc::new(AtomicUsize::new(0));
+        let mut services = services();
+        services
+            .get_mut("test.Test")
+            .unwrap()
+            .methods
+            .insert("Counted".to_string(), Box::new(Counted(calls.clone())));
+        let listener = std::os::unix::net::UnixListener::bind(&path).unwrap();
+        let mut server = Server::new()
+            .register_service(services)
+            .add_listener(listener.into_raw_fd())
+            .unwrap()
+            .set_domain_unix();
+        server.start().await.unwrap();
+        // Let the client redial, the queued call is sent once connected.
+        while !flushed.is_finished() {
+            clock.advance(Duration::from_millis(100));
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        flushed.await.unwrap().unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        server.shutdown().await.unwrap();
+        let _ = std::fs::remove_file(&path);
+    }
+
+    /// Calls `Hang` with a client pinging after 10s idle on `clock`, and moves the
+    /// clock forward in steps until the call failed or `limit` elapsed.
+    async fn hang_with_keepalive(
+        address: &str,
+        clock: &MockClock,
+        limit: Duration,
+    ) -> Option<Result<Response>> {
+        let client = ClientBuilder::new(address)
+            .clock(Arc::new(clock.clone()))
+            .keepalive(Duration::from_secs(10), Duration::from_secs(5))
+            .build()
+            .unwrap();
+        let call = tokio::spawn({
+            let client = client.clone();
+            let mut req = request("Hang");
+            req.timeout_nano = Duration::from_secs(3600).as_nanos() as i64;
+            async move { client.request(req).await }
+        });
+        wait_calls(&client, 1).await;
+        let start = clock.now();
+        while !call.is_finished() && clock.now() - start < limit {
+            clock.advance(Duration::from_millis(500));
+            tokio::time::sleep(Duration::from_millis(2)).await;
+        }
+        if call.is_finished() {
+            return Some(call.await.unwrap());
+        }
+        call.abort();
+        None
+    }
+
+    #[tokio::test]
+    async fn test_keepalive() {
+        let clock = MockClock::new();
+
+        // Accepted by the kernel, but never read nor answered.
+        let path = std::env::temp_dir().join(format!("ttrpc-silent-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let _silent = std::os::unix::net::UnixListener::bind(&path).unwrap();
+        let address = format!("unix://{}", path.display());
+        let start = clock.now();
+        let res = hang_with_keepalive(&address, &clock, Duration::from_secs(60)).await;
+        assert!(matches!(res, Some(Err(Error::KeepaliveTimeout))));
+        // Idle for the interval, then unanswered for the timeout.
+        assert!(clock.now() - start >= Duration::from_secs(15));
+        let _ = std::fs::remove_file(&path);
+
+        // A server answers the pings, the call goes on.
+        let server = testing::start(Server::new().register_service(services()))
+            .await
+            .unwrap();
+        let res = hang_with_keepalive(&server.address(), &clock, Duration::from_secs(60)).await;
+        assert!(res.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_pool() {
+        let server = testing::start(Server::new().register_service(services()))
+            .await
+            .unwrap();
+        let idle = Duration::from_millis(50);
+        let pool = ClientPool::new(ClientBuilder::new(&server.address()), 3).idle_timeout(idle);
+        assert_eq!(pool.connections(), 0);
+
+        // Round robin, every connection is made once and then reused.
+        for _ in 0..6 {
+            pool.request(request("Echo")).await.unwrap();
+        }
+        assert_eq!(pool.connections(), 3);
+        assert_eq!(server.server().connections().len(), 3);
+
+        // All idle, the connections but the one picked next are reaped.
+        tokio::time::sleep(idle * 2).await;
+        let client = pool.get().unwrap();
+        assert_eq!(pool.connections(), 1);
+        while server.server().connections().len() != 1 {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        client.request(request("Echo")).await.unwrap();
+
+        // And made again when their turn comes.
+        pool.request(request("Echo")).await.unwrap();
+        assert_eq!(pool.connections(), 2);
+    }
+
+    /// Counts its calls, and echoes them once `release` is notified.
+    struct Released {
+        calls: Arc<AtomicUsize>,
+        release: Arc<Notify>,
+    }
+
+    #[async_trait]
+    impl MethodHandler for Released {
+        async fn handler(&self, _ctx: TtrpcContext, req: Request) -> Result<Response> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            self.release.notified().await;
+            Ok(Response {
+                payload: req.payload,
+                ..Default::default()
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_coalesce() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let release = Arc::new(Notify::new());
+        let mut services = services();
+        services.get_mut("test.Test").unwrap().methods.insert(
+            "Released".to_string(),
+            Box::new(Released {
+                calls: calls.clone(),
+                release: release.clone(),
+            }),
+        );
+        let (client, _server) = testing::serve(services).await.unwrap();
+        let call = |method: &str, payload: &[u8]| {
+            let client = client.clone();
+            let mut req = request(method);
+            req.payload = payload.to_vec();
+            tokio::spawn(async move {
+                let opts = CallOptions::new().idempotency_key("key");
+                let (res, _) = client.request_with_options(req, &opts).await?;
+                Ok::<_, Error>(res.payload)
+            })
+        };
+        let wait_handled = |n: usize| {
+            let calls = calls.clone();
+            async move {
+                while calls.load(Ordering::SeqCst) != n {
+                    tokio::time::sleep(Duration::from_millis(1)).await;
+                }
+            }
+        };
+
+        let first = call("Released", b"a");
+        wait_handled(1).await;
+        let joined = call("Released", b"a");
+        // The key only coalesces the calls of the same method.
+        assert_eq!(call("Echo", b"b").await.unwrap().unwrap(), b"b");
+        match call("Released", b"b").await.unwrap() {
+            Err(Error::RpcStatus(s)) => assert_eq!(s.code(), Code::INVALID_ARGUMENT),
+            res => panic!("unexpected {:?}", res),
+        }
+
+        release.notify_one();
+        assert_eq!(first.await.unwrap().unwrap(), b"a");
+        assert_eq!(joined.await.unwrap().unwrap(), b"a");
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        // Once answered, the key is free again.
+        let again = call("Released", b"b");
+        wait_handled(2).await;
+        release.notify_one();
+        assert_eq!(again.await.unwrap().unwrap(), b"b");
+    }
+
+    /// Answers with the values of the metadata `order`.
+    struct Order;
+
+    #[async_trait]
+    impl MethodHandler for Order {
+        async fn handler(&self, ctx: TtrpcContext, _req: Request) -> Result<Response> {
+            let order = ctx.metadata.get("order").cloned().unwrap_or_default();
+            Ok(Response {
+                payload: order.join(",").into_bytes(),
+                ..Default::default()
+            })
+        }
+    }
+
+    /// Adds its name to the metadata `order`, and logs when it runs.
+    struct Tag(&'static str, Arc<Mutex<Vec<String>>>);
+
+    #[async_trait]
+    impl Interceptor for Tag {
+        async fn call(&self, mut req: Request, next: Next<'_>) -> Result<Response> {
+            self.1.lock().unwrap().push(format!("{} in", self.0));
+            req.metadata.push(KeyValue {
+                key: "order".to_string(),
+                value: self.0.to_string(),
+                ..Default::default()
+            });
+            let res = next.run(req).await;
+            self.1.lock().unwrap().push(format!("{} out", self.0));
+            res
+        }
+    }
+
+    /// Answers `Hang` and fails `Echo` without sending them.
+    struct ShortCircuit;
+
+    #[async_trait]
+    impl Interceptor for ShortCircuit {
+        async fn call(&self, req: Request, next: Next<'_>) -> Result<Response> {
+            match req.method.as_str() {
+                "Hang" => Ok(Response {
+                    payload: b"cached".to_vec(),
+                    ..Default::default()
+                }),
+                "Echo" => Err(Error::RpcStatus(crate::get_status(
+                    Code::PERMISSION_DENIED,
+                    "denied",
+                ))),
+                _ => next.run(req).await,
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_interceptors() {
+        let mut services = services();
+        let methods = &mut services.get_mut("test.Test").unwrap().methods;
+        methods.insert("Order".to_string(), Box::new(Order));
+        let (client, _server) = testing::serve(services).await.unwrap();
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let client = client
+            .add_interceptor(Arc::new(Tag("a", log.clone())))
+            .add_interceptor(Arc::new(Tag("b", log.clone())))
+            .add_interceptor(Arc::new(ShortCircuit));
+
+        // They run in the order they were added, around the call.
+        let res = client.request(request("Order")).await.unwrap();
+        assert_eq!(res.payload, b"a,b");
+        assert_eq!(*log.lock().unwrap(), ["a in", "b in", "b out", "a out"]);
+
+        // One answering or failing the call stops it from being sent, e.g. `Hang`
+        // would never be answered, but the interceptors before it see the result.
+        log.lock().unwrap().clear();
+        let res = client.request(request("Hang")).await.unwrap();
+        assert_eq!(res.payload, b"cached");
+        let res = client.request(request("Echo")).await;
+        assert!(matches!(res, Err(Error::RpcStatus(s)) if s.code() == Code::PERMISSION_DENIED));
+        assert_eq!(log.lock().unwrap().len(), 8);
+    }
+
+    /// Answers with the metadata and the time left of the call once `release` is
+    /// notified, and counts the calls.
+    struct Inspect {
+        calls: Arc<AtomicUsize>,
+        release: Arc<Notify>,
+    }
+
+    #[async_trait]
+    impl MethodHandler for Inspect {
+        async fn handler(&self, ctx: TtrpcContext, _req: Request) -> Result<Response> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            self.release.notified().await;
+            let seen = format!(
+                "{:?} {:?} {:?}",
+                ctx.metadata.get("key"),
+                crate::context::get_bin(&ctx.metadata, "token-bin"),
+                ctx.remaining().map(|left| left <= Duration::from_secs(10)),
+            );
+            Ok(Response {
+                payload: seen.into_bytes(),
+                ..Default::default()
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_call_options() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let release = Arc::new(Notify::new());
+        let mut services = services();
+        services.get_mut("test.Test").unwrap().methods.insert(
+            "Inspect".to_string(),
+            Box::new(Inspect {
+                calls: calls.clone(),
+                release: release.clone(),
+            }),
+        );
+        let (client, _server) = testing::serve(services).await.unwrap();
+        let call = |opts: CallOptions| {
+            let client = client.clone();
+            tokio::spawn(async move {
+                let (res, _) = client
+                    .request_with_options(request("Inspect"), &opts)
+                    .await?;
+                Ok::<_, Error>(String::from_utf8(res.payload).unwrap())
+            })
+        };
+
+        // The server gets the metadata and the deadline.
+        let opts = CallOptions::new()
+            .with_timeout(Duration::from_secs(10))
+            .with_metadata("key", "a")
+            .with_metadata("key", "b")
+            .with_bin_metadata("token-bin", &[0, 255])
+            .idempotency_key("inspect");
+        let first = call(opts);
+        while calls.load(Ordering::SeqCst) != 1 {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        // A call joining it gets the same answer, made with the options of the first.
+        let joined = call(CallOptions::new().idempotency_key("inspect"));
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        release.notify_one();
+        let seen = r#"Some(["a", "b"]) Some([[0, 255]]) Some(true)"#;
+        assert_eq!(first.await.unwrap().unwrap(), seen);
+        assert_eq!(joined.await.unwrap().unwrap(), seen);
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        // The client fails the call once it times out, whether the server answers or not.
+        let opts = CallOptions::new().with_timeout(Duration::from_millis(50));
+        let res = client.request_with_options(request("Hang"), &opts).await;
+        assert!(matches!(res, Err(Error::Others(e)) if e.contains("timeout")));
+        // Without options, the call has no metadata nor deadline.
+        let plain = call(CallOptions::new());
+        release.notify_one();
+        assert_eq!(plain.await.unwrap().unwrap(), "None None None");
+    }
+
+    #[tokio::test]
+    async fn test_write_batch() {
+        let server = testing::start(Server::new().register_service(services()))
+            .await
+            .unwrap();
+        let client = ClientBuilder::new(&server.address())
+            .write_batch(3)
+            .build()
+            .unwrap();
+        // More calls than fit in a batch, large enough for the socket to take
+        // each batch in several partial writes, and small ones in between.
+        let calls: Vec<_> = (0..10u8)
+            .map(|i| {
+                let client = client.clone();
+                let mut req = request("Echo");
+                let len = if i % 2 == 0 { 1 << 20 } else { 7 };
+                req.payload = vec![i; len];
+                tokio::spawn(async move { (req.payload.clone(), client.request(req).await) })
+            })
+            .collect();
+        for call in calls {
+            let (payload, res) = call.await.unwrap();
+            assert_eq!(res.unwrap().payload, payload);
+        }
+    }
+
+    /// Answers with the schema version of the client.
+    struct PeerVersion;
+
+    #[async_trait]
+    impl MethodHandler for PeerVersion {
+        async fn handler(&self, ctx: TtrpcContext, _req: Request) -> Result<Response> {
+            Ok(Response {
+                payload: ctx.peer_version().unwrap_or_default().into_bytes(),
+                ..Default::default()
+            })
+        }
+    }
+
+    fn method(method: &'static str, schema_digest: u64) -> MethodInfo {
+        MethodInfo {
+            service: "test.Test",
+            method,
+            method_type: crate::MethodType::Unary,
+            schema_digest,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_handshake() {
+        let mut services = services();
+        let methods = &mut services.get_mut("test.Test").unwrap().methods;
+        methods.insert("PeerVersion".to_string(), Box::new(PeerVersion));
+        methods.insert("Drifted".to_string(), Box::new(Echo));
+        let server = testing::start(
+            Server::new()
+                .register_service(services)
+                .set_schema_version("2.0")
+                .check_schema_digests(
+                    &[
+                        method("Echo", 1),
+                        method("PeerVersion", 2),
+                        method("Drifted", 3),
+                    ],
+                    SchemaDrift::Reject,
+                ),
+        )
+        .await
+        .unwrap();
+        let client = ClientBuilder::new(&server.address())
+            .schema_version("1.0")
+            .schema_digests(&[
+                method("Echo", 1),
+                method("PeerVersion", 2),
+                method("Drifted", 4),
+            ])
+            .build()
+            .unwrap();
+
+        let res = client.request(request("PeerVersion")).await.unwrap();
+        assert_eq!(res.payload, b"1.0");
+        assert_eq!(client.peer_version().await.as_deref(), Some("2.0"));
+        assert_eq!(client.peer_schema_drift().await, ["/test.Test/Drifted"]);
+        client.request(request("Echo")).await.unwrap();
+        let res = client.request(request("Drifted")).await;
+        assert!(matches!(res, Err(Error::RpcStatus(s)) if s.code() == Code::FAILED_PRECONDITION));
+
+        // Without a schema version, the client doesn't shake hands.
+        let client = ClientBuilder::new(&server.address()).build().unwrap();
+        let res = client.request(request("PeerVersion")).await.unwrap();
+        assert!(res.payload.is_empty());
+        assert_eq!(client.peer_version().await, None);
+    }
+
+    #[tokio::test]
+    async fn test_handshake_unknown() {
+        let path = std::env::temp_dir().join(format!("ttrpc-older-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        // A server older than the handshake, which echoes every call and doesn't
+        // know the handshake method.
+        let listener = tokio::net::UnixListener::bind(&path).unwrap();
         tokio::spawn(async move {
-            if let Some(resp_tx) = get_resp_tx(req_map, &msg.header).await {
-                resp_tx
-                    .send(Ok(msg))
-                    .await
-                    .unwrap_or_else(|_e| error!("The request has returned"));
+            let (mut conn, _) = listener.accept().await.unwrap();
+            while let Ok(req) = Message::<Request>::read_from(&mut conn).await {
+                let mut res = Response::new();
+                if req.payload.service == HANDSHAKE_SERVICE {
+                    res.set_status(crate::get_status(Code::INVALID_ARGUMENT, "unknown service"));
+                } else {
+                    res.payload = req.payload.payload;
+                }
+                let res = Message {
+                    header: MessageHeader::new_response(req.header.stream_id, res.size()),
+                    payload: res,
+                };
+                res.write_to(&mut conn).await.unwrap();
             }
         });
+        let client = ClientBuilder::new(&format!("unix://{}", path.display()))
+            .schema_version("1.0")
+            .schema_digests(&[method("Echo", 1)])
+            .build()
+            .unwrap();
+
+        let mut req = request("Echo");
+        req.payload = b"hello".to_vec();
+        assert_eq!(client.request(req).await.unwrap().payload, b"hello");
+        assert_eq!(client.peer_version().await, None);
+        assert!(client.peer_schema_drift().await.is_empty());
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn test_restart_epoch() {
+        let path = std::env::temp_dir().join(format!("ttrpc-epoch-{}.sock", std::process::id()));
+        let start = |epoch| {
+            let _ = std::fs::remove_file(&path);
+            let listener = std::os::unix::net::UnixListener::bind(&path).unwrap();
+            Server::new()
+                .register_service(services())
+                .set_restart_epoch(epoch)
+                .add_listener(listener.into_raw_fd())
+                .unwrap()
+                .set_domain_unix()
+        };
+        let mut server = start(1);
+        server.start().await.unwrap();
+        let backoff = Duration::from_millis(10);
+        let client = ClientBuilder::new(&format!("unix://{}", path.display()))
+            .schema_version("1.0")
+            .reconnect(ReconnectPolicy::new().backoff(backoff, backoff))
+            .build()
+            .unwrap();
+        client.request(request("Echo")).await.unwrap();
+        assert_eq!(client.peer_restart_epoch().await, Some(1));
+
+        // The client learns the epoch of the restarted server once reconnected.
+        let mut states = client.watch_state();
+        server.shutdown().await.unwrap();
+        while states.recv().await != Some(ConnectivityState::TransientFailure) {}
+        let mut server = start(2);
+        server.start().await.unwrap();
+        while states.recv().await != Some(ConnectivityState::Ready) {}
+        assert_eq!(client.peer_restart_epoch().await, Some(2));
+        client.request(request("Echo")).await.unwrap();
+
+        // Without a schema version, the client doesn't shake hands.
+        let other = ClientBuilder::new(&format!("unix://{}", path.display()))
+            .build()
+            .unwrap();
+        other.request(request("Echo")).await.unwrap();
+        assert_eq!(other.peer_restart_epoch().await, None);
+        server.shutdown().await.unwrap();
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn test_timing() {
+        let (client, _server) = testing::serve(services()).await.unwrap();
+        let opts = CallOptions::new().timing(true);
+        let (_, timing) = client
+            .request_with_options(request("Echo"), &opts)
+            .await
+            .unwrap();
+        let timing = timing.unwrap();
+        let written = timing.written.unwrap();
+        let received = timing.received.unwrap();
+        let decoded = timing.decoded.unwrap();
+        assert!(timing.queued <= written && written <= received && received <= decoded);
+        assert!(client.timings.lock().unwrap().is_empty());
+
+        let (_, timing) = client
+            .request_with_options(request("Echo"), &CallOptions::new())
+            .await
+            .unwrap();
+        assert!(timing.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_write_timeout() {
+        let path = std::env::temp_dir().join(format!("ttrpc-stalled-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        // A server which accepts, but never reads.
+        let listener = tokio::net::UnixListener::bind(&path).unwrap();
+        let accepted = tokio::spawn(async move { listener.accept().await.unwrap() });
+        let client = ClientBuilder::new(&format!("unix://{}", path.display()))
+            .write_timeout(Duration::from_millis(100))
+            .build()
+            .unwrap();
+        // More than the socket buffers hold.
+        let calls: Vec<_> = (0..4)
+            .map(|_| {
+                let client = client.clone();
+                let mut req = request("Echo");
+                req.payload = vec![0; 1 << 20];
+                tokio::spawn(async move { client.request(req).await })
+            })
+            .collect();
+        for call in calls {
+            let res = tokio::time::timeout(Duration::from_secs(10), call)
+                .await
+                .expect("the calls in progress fail")
+                .unwrap();
+            assert!(res.is_err());
+        }
+        drop(accepted);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    /// Records the payloads of its calls, in the order they were handled.
+    struct Recorded(Arc<Mutex<Vec<Vec<u8>>>>);
+
+    #[async_trait]
+    impl MethodHandler for Recorded {
+        async fn handler(&self, _ctx: TtrpcContext, req: Request) -> Result<Response> {
+            self.0.lock().unwrap().push(req.payload);
+            Ok(Response::default())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_priority() {
+        let path = std::env::temp_dir().join(format!("ttrpc-urgent-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let clock = MockClock::new();
+        let client = ClientBuilder::new(&format!("unix://{}", path.display()))
+            .offline_queue(2)
+            .clock(Arc::new(clock.clone()))
+            .build()
+            .unwrap();
+        let call = |payload: &str, priority: Priority| {
+            let client = client.clone();
+            let mut req = request("Recorded");
+            req.payload = payload.as_bytes().to_vec();
+            let opts = CallOptions::new().with_priority(priority);
+            tokio::spawn(async move { client.request_with_options(req, &opts).await })
+        };
+
+        // Fill the queue while disconnected, and one more call waits for room.
+        let mut calls = Vec::new();
+        for (i, payload) in ["1", "2", "3"].iter().enumerate() {
+            calls.push(call(payload, Priority::Normal));
+            wait_calls(&client, i + 1).await;
+        }
+        assert_eq!(client.req_tx.capacity(), 0);
+        calls.push(call("urgent", Priority::High));
+        wait_calls(&client, 4).await;
+
+        let recorded = Arc::new(Mutex::new(Vec::new()));
+        let mut services = services();
+        services
+            .get_mut("test.Test")
+            .unwrap()
+            .methods
+            .insert("Recorded".to_string(), Box::new(Recorded(recorded.clone())));
+        let listener = std::os::unix::net::UnixListener::bind(&path).unwrap();
+        let mut server = Server::new()
+            .register_service(services)
+            .add_listener(listener.into_raw_fd())
+            .unwrap()
+            .set_domain_unix();
+        server.start().await.unwrap();
+        while !calls.iter().all(|call| call.is_finished()) {
+            clock.advance(Duration::from_millis(100));
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        for call in calls {
+            call.await.unwrap().unwrap();
+        }
+        assert_eq!(
+            *recorded.lock().unwrap(),
+            [&b"urgent"[..], b"1", b"2", b"3"]
+        );
+        server.shutdown().await.unwrap();
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn test_channel() {
+        let server = testing::start(Server::new().register_service(services()))
+            .await
+            .unwrap();
+        let channel: Channel = Client::connect(&server.address()).unwrap();
+        // As the clients of two services generated from it would.
+        let first = channel.clone();
+        let second = channel.clone();
+        drop(channel);
+        first.request(request("Echo")).await.unwrap();
+        second.request(request("Echo")).await.unwrap();
+        assert_eq!(server.server().connections().len(), 1);
+
+        // The connection stays open as long as one of them does.
+        drop(first);
+        second.request(request("Echo")).await.unwrap();
+        assert_eq!(server.server().connections().len(), 1);
+        drop(second);
+        while !server.server().connections().is_empty() {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
     }
 }
@@ -0,0 +1,159 @@
+// Copyright (c) 2023 Ant Group
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Time as seen by clients and servers, see
+//! [`ClientBuilder::clock`](crate::r#async::ClientBuilder::clock) and
+//! [`Server::set_clock`](crate::r#async::Server::set_clock).
+
+use std::fmt::Debug;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use tokio::sync::watch;
+
+/// Tells the time the deadlines of calls, the cool-down of the circuit breaker
+/// and the keepalive of a client are measured with, and waits for it: the
+/// timeouts of calls, the retry and reconnect backoffs and the keepalive pings
+/// all sleep on the clock.
+#[async_trait]
+pub trait Clock: Debug + Send + Sync {
+    fn now(&self) -> Instant;
+
+    /// Waits until [`Clock::now`] reached `deadline`. Sleeps for the time left
+    /// by default, which is only right for clocks which move with the real time.
+    async fn sleep_until(&self, deadline: Instant) {
+        tokio::time::sleep(deadline.saturating_duration_since(self.now())).await
+    }
+}
+
+/// The time of tokio: the real time, unless paused with `tokio::time::pause`,
+/// so timers and deadlines advance together in tests. The default clock.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TokioClock;
+
+#[async_trait]
+impl Clock for TokioClock {
+    fn now(&self) -> Instant {
+        tokio::time::Instant::now().into_std()
+    }
+
+    async fn sleep_until(&self, deadline: Instant) {
+        tokio::time::sleep_until(deadline.into()).await
+    }
+}
+
+/// A clock which only moves when told to, shared by its clones. Sleeping on it
+/// ends once it was advanced past the deadline.
+#[derive(Clone, Debug)]
+pub struct MockClock {
+    start: Instant,
+    elapsed: Arc<watch::Sender<Duration>>,
+}
+
+impl MockClock {
+    pub fn new() -> MockClock {
+        MockClock {
+            start: Instant::now(),
+            elapsed: Arc::new(watch::channel(Duration::ZERO).0),
+        }
+    }
+
+    /// Moves the clock `duration` forward.
+    pub fn advance(&self, duration: Duration) {
+        self.elapsed.send_modify(|elapsed| *elapsed += duration);
+    }
+}
+
+impl Default for MockClock {
+    fn default() -> Self {
+        MockClock::new()
+    }
+}
+
+#[async_trait]
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        self.start + *self.elapsed.borrow()
+    }
+
+    async fn sleep_until(&self, deadline: Instant) {
+        let mut elapsed = self.elapsed.subscribe();
+        // The sender lives as long as `self`, so this never fails.
+        let _ = elapsed
+            .wait_for(|elapsed| self.start + *elapsed >= deadline)
+            .await;
+    }
+}
+
+pub(crate) fn default_clock() -> Arc<dyn Clock> {
+    Arc::new(TokioClock)
+}
+
+/// The timeout of a [`timeout`] elapsed.
+#[derive(Debug)]
+pub(crate) struct Elapsed(());
+
+/// Sleeps `duration` on `clock`.
+pub(crate) async fn sleep(clock: &dyn Clock, duration: Duration) {
+    clock.sleep_until(clock.now() + duration).await
+}
+
+/// Like `tokio::time::timeout`, but with the time of `clock`.
+pub(crate) async fn timeout<F: Future>(
+    clock: &dyn Clock,
+    duration: Duration,
+    future: F,
+) -> Result<F::Output, Elapsed> {
+    let deadline = clock.now() + duration;
+    tokio::select! {
+        biased;
+        output = future => Ok(output),
+        _ = clock.sleep_until(deadline) => Err(Elapsed(())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::task::Poll;
+
+    use super::*;
+
+    #[test]
+    fn test_mock_clock() {
+        let clock = MockClock::new();
+        let start = clock.now();
+        clock.clone().advance(Duration::from_secs(3));
+        assert_eq!(clock.now() - start, Duration::from_secs(3));
+        assert_eq!(clock.now(), clock.now());
+    }
+
+    #[tokio::test]
+    async fn test_mock_clock_sleep() {
+        let clock = MockClock::new();
+        let mut slept = Box::pin(sleep(&clock, Duration::from_secs(3)));
+        let mut timed_out = Box::pin(timeout(
+            &clock,
+            Duration::from_secs(2),
+            std::future::pending::<()>(),
+        ));
+        assert!(futures::poll!(&mut slept).is_pending());
+        assert!(futures::poll!(&mut timed_out).is_pending());
+
+        clock.advance(Duration::from_secs(2));
+        assert!(matches!(
+            futures::poll!(&mut timed_out),
+            Poll::Ready(Err(_))
+        ));
+        assert!(futures::poll!(&mut slept).is_pending());
+
+        clock.advance(Duration::from_secs(1));
+        assert!(futures::poll!(&mut slept).is_ready());
+        assert!(timeout(&clock, Duration::from_secs(1), async { 1 })
+            .await
+            .is_ok());
+    }
+}
@@ -0,0 +1,97 @@
+// Copyright (c) 2023 Ant Group
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Compression of the messages sent on streams.
+
+use std::fmt;
+use std::sync::Arc;
+
+use crate::error::Result;
+
+/// Messages smaller than this are sent uncompressed by default.
+const DEFAULT_MIN_SIZE: usize = 1024;
+
+/// An algorithm to compress the payload of stream messages, e.g. backed by zstd.
+///
+/// ttrpc doesn't ship any algorithm, both peers must use the same one.
+pub trait Compressor {
+    fn compress(&self, buf: &[u8]) -> Result<Vec<u8>>;
+    fn decompress(&self, buf: &[u8]) -> Result<Vec<u8>>;
+}
+
+/// Compression of the data messages of a stream.
+///
+/// Every message of at least [`Compression::min_size`] bytes is compressed and
+/// flagged with [`FLAG_COMPRESSED`](crate::proto::FLAG_COMPRESSED), so e.g. log
+/// streams compress well while tiny heartbeat messages are sent as they are.
+/// Flagged messages are decompressed on receipt.
+#[derive(Clone)]
+pub struct Compression {
+    pub(crate) compressor: Arc<dyn Compressor + Send + Sync>,
+    pub(crate) min_size: usize,
+}
+
+impl Compression {
+    pub fn new(compressor: Arc<dyn Compressor + Send + Sync>) -> Compression {
+        Compression {
+            compressor,
+            min_size: DEFAULT_MIN_SIZE,
+        }
+    }
+
+    /// Send messages smaller than `size` bytes uncompressed, 1024 by default.
+    pub fn min_size(mut self, size: usize) -> Compression {
+        self.min_size = size;
+        self
+    }
+
+    /// Returns the compressed `buf`, or `None` if it is not worth compressing.
+    pub(crate) fn compress(&self, buf: &[u8]) -> Result<Option<Vec<u8>>> {
+        if buf.len() < self.min_size {
+            return Ok(None);
+        }
+        let compressed = self.compressor.compress(buf)?;
+        Ok(Some(compressed).filter(|c| c.len() < buf.len()))
+    }
+}
+
+impl fmt::Debug for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Compression")
+            .field("min_size", &self.min_size)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Keeps every other byte, good enough to tell whether a buffer was compressed.
+    struct Halve;
+
+    impl Compressor for Halve {
+        fn compress(&self, buf: &[u8]) -> Result<Vec<u8>> {
+            Ok(buf.iter().step_by(2).copied().collect())
+        }
+
+        fn decompress(&self, buf: &[u8]) -> Result<Vec<u8>> {
+            Ok(buf.iter().flat_map(|b| [*b, *b]).collect())
+        }
+    }
+
+    #[test]
+    fn test_compress() {
+        let compression = Compression::new(Arc::new(Halve)).min_size(4);
+        assert_eq!(compression.compress(&[1, 2, 3]).unwrap(), None);
+        assert_eq!(
+            compression.compress(&[1, 2, 3, 4]).unwrap(),
+            Some(vec![1, 3])
+        );
+        // Not smaller once compressed.
+        let compression = compression.min_size(0);
+        assert_eq!(compression.compress(&[1]).unwrap(), None);
+    }
+}
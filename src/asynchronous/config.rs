@@ -0,0 +1,398 @@
+// Copyright (c) 2023 Ant Group
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Options of a server loaded from a file or the environment.
+
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::{Error, Result};
+use crate::r#async::{Priority, RateLimit, Server};
+
+/// The options of a [`Server`] which operators set without recompiling, loaded
+/// from a TOML or JSON file, or from environment variables.
+///
+/// Every option is optional and leaves the server default when unset, see the
+/// setters of [`Server`] for what they do. Services, hooks and the other options
+/// which are code are set on the server returned by [`ServerConfig::build`].
+///
+/// TLS and keepalive are options of the clients of this crate, servers neither
+/// terminate TLS nor ping clients, so there are no server options for them.
+///
+/// ```toml
+/// address = "unix:///run/some.sock"
+/// max_concurrent_requests = 256
+/// write_timeout_ms = 5000
+///
+/// [max_concurrency]
+/// low = 4
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    /// The address to listen on, see [`Server::bind`].
+    pub address: Option<String>,
+    /// See [`Server::set_schema_version`].
+    pub schema_version: Option<String>,
+    /// See [`Server::set_restart_epoch`].
+    pub restart_epoch: Option<u64>,
+    /// See [`Server::set_max_concurrent_requests`].
+    pub max_concurrent_requests: Option<usize>,
+    /// See [`Server::set_max_concurrent_requests_per_connection`].
+    pub max_concurrent_requests_per_connection: Option<usize>,
+    /// See [`Server::set_max_streams`].
+    pub max_streams: Option<usize>,
+    /// See [`Server::set_max_decoded_size`].
+    pub max_decoded_size: Option<usize>,
+    /// The limit of every lane, by the name of its priority, e.g. `low`, see
+    /// [`Server::set_max_concurrency`].
+    pub max_concurrency: BTreeMap<String, usize>,
+    /// See [`Server::set_priority_hints`].
+    pub priority_hints: Option<bool>,
+    /// See [`Server::set_catch_panics`].
+    pub catch_panics: Option<bool>,
+    /// See [`Server::set_unary_over_stream`].
+    pub unary_over_stream: Option<bool>,
+    /// See [`Server::set_write_timeout`].
+    pub write_timeout_ms: Option<u64>,
+    /// See [`Server::set_read_rate_limit`] and [`RateLimit::bytes_per_second`].
+    pub read_bytes_per_second: Option<u64>,
+    /// See [`Server::set_read_rate_limit`] and [`RateLimit::frames_per_second`].
+    pub read_frames_per_second: Option<u64>,
+    /// See [`Server::set_write_rate_limit`] and [`RateLimit::bytes_per_second`].
+    pub write_bytes_per_second: Option<u64>,
+    /// See [`Server::set_write_rate_limit`] and [`RateLimit::frames_per_second`].
+    pub write_frames_per_second: Option<u64>,
+}
+
+impl ServerConfig {
+    pub fn from_toml_str(s: &str) -> Result<ServerConfig> {
+        toml::from_str(s).map_err(err_to_others_err!(e, "Invalid server config: "))
+    }
+
+    pub fn from_json_str(s: &str) -> Result<ServerConfig> {
+        serde_json::from_str(s).map_err(err_to_others_err!(e, "Invalid server config: "))
+    }
+
+    /// Load the file at `path`, in TOML or JSON depending on its extension.
+    pub fn from_file(path: impl AsRef<Path>) -> Result<ServerConfig> {
+        let path = path.as_ref();
+        let content = std::fs::read_to_string(path).map_err(|e| {
+            Error::Others(format!("Read server config {} failed: {e}", path.display()))
+        })?;
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("toml") => ServerConfig::from_toml_str(&content),
+            Some("json") => ServerConfig::from_json_str(&content),
+            _ => Err(Error::Others(format!(
+                "Unknown format of server config {}, expected .toml or .json",
+                path.display()
+            ))),
+        }
+    }
+
+    /// Load the environment variables named after the options with `prefix`, e.g.
+    /// `TTRPC_MAX_STREAMS` for `max_streams` with the prefix `TTRPC_`, see
+    /// [`ServerConfig::merge_env`].
+    pub fn from_env(prefix: &str) -> Result<ServerConfig> {
+        ServerConfig::default().merge_env(prefix)
+    }
+
+    /// Override the options of `self`, e.g. loaded from a file, with the
+    /// environment variables named after them with `prefix`.
+    ///
+    /// The limit of a lane is set by the variable named after `max_concurrency` and
+    /// the priority, e.g. `TTRPC_MAX_CONCURRENCY_LOW`. Variables with `prefix` which
+    /// don't name an option are rejected, like unknown keys in files.
+    pub fn merge_env(self, prefix: &str) -> Result<ServerConfig> {
+        self.merge_vars(prefix, std::env::vars())
+    }
+
+    fn merge_vars(
+        mut self,
+        prefix: &str,
+        vars: impl IntoIterator<Item = (String, String)>,
+    ) -> Result<ServerConfig> {
+        for (name, value) in vars {
+            if let Some(key) = name.strip_prefix(prefix) {
+                self.set(&key.to_ascii_lowercase(), &value)
+                    .map_err(|e| Error::Others(format!("Invalid {name}: {e}")))?;
+            }
+        }
+        Ok(self)
+    }
+
+    fn set(&mut self, key: &str, value: &str) -> std::result::Result<(), String> {
+        match key {
+            "address" => self.address = Some(value.to_string()),
+            "schema_version" => self.schema_version = Some(value.to_string()),
+            "restart_epoch" => self.restart_epoch = Some(parse(value)?),
+            "max_concurrent_requests" => self.max_concurrent_requests = Some(parse(value)?),
+            "max_concurrent_requests_per_connection" => {
+                self.max_concurrent_requests_per_connection = Some(parse(value)?)
+            }
+            "max_streams" => self.max_streams = Some(parse(value)?),
+            "max_decoded_size" => self.max_decoded_size = Some(parse(value)?),
+            "priority_hints" => self.priority_hints = Some(parse(value)?),
+            "catch_panics" => self.catch_panics = Some(parse(value)?),
+            "unary_over_stream" => self.unary_over_stream = Some(parse(value)?),
+            "write_timeout_ms" => self.write_timeout_ms = Some(parse(value)?),
+            "read_bytes_per_second" => self.read_bytes_per_second = Some(parse(value)?),
+            "read_frames_per_second" => self.read_frames_per_second = Some(parse(value)?),
+            "write_bytes_per_second" => self.write_bytes_per_second = Some(parse(value)?),
+            "write_frames_per_second" => self.write_frames_per_second = Some(parse(value)?),
+            _ => match key.strip_prefix("max_concurrency_") {
+                Some(priority) => {
+                    self.max_concurrency
+                        .insert(priority.to_string(), parse(value)?);
+                }
+                None => return Err("unknown server option".to_string()),
+            },
+        }
+        Ok(())
+    }
+
+    /// Create a server with the options, bound to [`ServerConfig::address`] if set.
+    ///
+    /// Invalid options, e.g. a limit of 0, are returned as errors instead of
+    /// panicking like the setters of [`Server`] do.
+    pub fn build(&self) -> Result<Server> {
+        let mut server = Server::new();
+
+        if let Some(version) = &self.schema_version {
+            server = server.set_schema_version(version);
+        }
+        if let Some(epoch) = self.restart_epoch {
+            server = server.set_restart_epoch(epoch);
+        }
+        if let Some(max) = self.max_concurrent_requests {
+            server = server.set_max_concurrent_requests(positive("max_concurrent_requests", max)?);
+        }
+        if let Some(max) = self.max_concurrent_requests_per_connection {
+            server = server.set_max_concurrent_requests_per_connection(positive(
+                "max_concurrent_requests_per_connection",
+                max,
+            )?);
+        }
+        if let Some(max) = self.max_streams {
+            server = server.set_max_streams(max);
+        }
+        if let Some(bytes) = self.max_decoded_size {
+            server = server.set_max_decoded_size(bytes);
+        }
+        for (name, &limit) in &self.max_concurrency {
+            let priority = Priority::parse(name).ok_or_else(|| {
+                Error::Others(format!(
+                    "Invalid server config: unknown priority {name} in max_concurrency"
+                ))
+            })?;
+            server = server.set_max_concurrency(priority, positive("max_concurrency", limit)?);
+        }
+        if let Some(enabled) = self.priority_hints {
+            server = server.set_priority_hints(enabled);
+        }
+        if let Some(enabled) = self.catch_panics {
+            server = server.set_catch_panics(enabled);
+        }
+        if let Some(enabled) = self.unary_over_stream {
+            server = server.set_unary_over_stream(enabled);
+        }
+        if let Some(ms) = self.write_timeout_ms {
+            server = server.set_write_timeout(Duration::from_millis(ms));
+        }
+        if let Some(limit) = rate_limit(self.read_bytes_per_second, self.read_frames_per_second)? {
+            server = server.set_read_rate_limit(limit);
+        }
+        if let Some(limit) = rate_limit(self.write_bytes_per_second, self.write_frames_per_second)?
+        {
+            server = server.set_write_rate_limit(limit);
+        }
+
+        match &self.address {
+            Some(address) => server.bind(address),
+            None => Ok(server),
+        }
+    }
+}
+
+fn parse<T: FromStr>(value: &str) -> std::result::Result<T, String>
+where
+    T::Err: std::fmt::Display,
+{
+    value.trim().parse().map_err(|e: T::Err| e.to_string())
+}
+
+fn positive<T: Default + PartialEq>(option: &str, value: T) -> Result<T> {
+    if value == T::default() {
+        return Err(Error::Others(format!(
+            "Invalid server config: {option} must be greater than 0"
+        )));
+    }
+    Ok(value)
+}
+
+fn rate_limit(bytes: Option<u64>, frames: Option<u64>) -> Result<Option<RateLimit>> {
+    if bytes.is_none() && frames.is_none() {
+        return Ok(None);
+    }
+    let mut limit = RateLimit::new();
+    if let Some(bytes) = bytes {
+        limit = limit.bytes_per_second(positive("bytes_per_second", bytes)?);
+    }
+    if let Some(frames) = frames {
+        limit = limit.frames_per_second(positive("frames_per_second", frames)?);
+    }
+    Ok(Some(limit))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::HashMap;
+
+    use async_trait::async_trait;
+
+    use crate::r#async::{testing, Client, MethodHandler, Service, TtrpcContext};
+    use crate::{Code, Request, Response};
+
+    struct Echo;
+
+    #[async_trait]
+    impl MethodHandler for Echo {
+        async fn handler(&self, _ctx: TtrpcContext, req: Request) -> Result<Response> {
+            Ok(Response {
+                payload: req.payload,
+                ..Default::default()
+            })
+        }
+    }
+
+    fn vars(vars: &[(&str, &str)]) -> Vec<(String, String)> {
+        vars.iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_parse() {
+        let toml = r#"
+            address = "unix:///run/test.sock"
+            max_concurrent_requests = 16
+            catch_panics = true
+            write_timeout_ms = 500
+
+            [max_concurrency]
+            low = 2
+        "#;
+        let json = r#"{
+            "address": "unix:///run/test.sock",
+            "max_concurrent_requests": 16,
+            "catch_panics": true,
+            "write_timeout_ms": 500,
+            "max_concurrency": {"low": 2}
+        }"#;
+        let config = ServerConfig::from_toml_str(toml).unwrap();
+        assert_eq!(config.address.as_deref(), Some("unix:///run/test.sock"));
+        assert_eq!(config.max_concurrent_requests, Some(16));
+        assert_eq!(config.catch_panics, Some(true));
+        assert_eq!(config.write_timeout_ms, Some(500));
+        assert_eq!(config.max_concurrency.get("low"), Some(&2));
+        assert_eq!(config.max_streams, None);
+        assert_eq!(ServerConfig::from_json_str(json).unwrap(), config);
+
+        assert!(ServerConfig::from_toml_str("max_stream = 1").is_err());
+        assert!(ServerConfig::from_json_str(r#"{"max_streams": -1}"#).is_err());
+    }
+
+    #[test]
+    fn test_merge_env() {
+        let config = ServerConfig::from_toml_str("max_streams = 4\nmax_decoded_size = 1024")
+            .unwrap()
+            .merge_vars(
+                "TTRPC_",
+                vars(&[
+                    ("TTRPC_MAX_STREAMS", "8"),
+                    ("TTRPC_CATCH_PANICS", "true"),
+                    ("TTRPC_PRIORITY_HINTS", "false"),
+                    ("TTRPC_MAX_CONCURRENCY_HIGH", "3"),
+                    ("OTHER_MAX_STREAMS", "1"),
+                ]),
+            )
+            .unwrap();
+        assert_eq!(config.max_streams, Some(8));
+        assert_eq!(config.max_decoded_size, Some(1024));
+        assert_eq!(config.catch_panics, Some(true));
+        assert_eq!(config.priority_hints, Some(false));
+        assert_eq!(config.max_concurrency.get("high"), Some(&3));
+
+        let unknown = ServerConfig::default().merge_vars("TTRPC_", vars(&[("TTRPC_MAX", "1")]));
+        assert!(unknown.is_err());
+        let invalid =
+            ServerConfig::default().merge_vars("TTRPC_", vars(&[("TTRPC_MAX_STREAMS", "many")]));
+        assert!(invalid.is_err());
+    }
+
+    fn request(payload: &[u8]) -> Request {
+        Request {
+            service: "test.Test".to_string(),
+            method: "Echo".to_string(),
+            payload: payload.to_vec(),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_build() {
+        // The address is left to the test server, see `testing::start`.
+        let config = ServerConfig::from_toml_str(
+            r#"
+            max_concurrent_requests = 4
+            max_decoded_size = 16
+            read_frames_per_second = 100
+
+            [max_concurrency]
+            low = 2
+            "#,
+        )
+        .unwrap();
+
+        let mut methods: HashMap<String, Box<dyn MethodHandler + Send + Sync>> = HashMap::new();
+        methods.insert("Echo".to_string(), Box::new(Echo));
+        let mut services = HashMap::new();
+        services.insert(
+            "test.Test".to_string(),
+            Service {
+                methods,
+                streams: HashMap::new(),
+            },
+        );
+        let server = testing::start(config.build().unwrap().register_service(services))
+            .await
+            .unwrap();
+        let client = Client::connect(&server.address()).unwrap();
+
+        let res = client.request(request(b"ping")).await.unwrap();
+        assert_eq!(res.payload, b"ping");
+        match client.request(request(&[b'x'; 64])).await {
+            Err(Error::RpcStatus(status)) => assert_eq!(status.code(), Code::RESOURCE_EXHAUSTED),
+            res => panic!("expected RESOURCE_EXHAUSTED, got {:?}", res),
+        }
+
+        let zero = ServerConfig {
+            max_concurrent_requests: Some(0),
+            ..Default::default()
+        };
+        assert!(zero.build().is_err());
+        let unknown = ServerConfig {
+            max_concurrency: BTreeMap::from([("urgent".to_string(), 1)]),
+            ..Default::default()
+        };
+        assert!(unknown.build().is_err());
+    }
+}
@@ -4,30 +4,96 @@
 // SPDX-License-Identifier: Apache-2.0
 //
 
+use std::io::IoSlice;
 use std::os::unix::io::AsRawFd;
+#[cfg(feature = "capture")]
+use std::os::unix::io::RawFd;
+use std::time::Duration;
 
 use async_trait::async_trait;
 use log::{error, trace};
 use tokio::{
-    io::{split, AsyncRead, AsyncWrite, ReadHalf},
-    select, task,
+    io::{split, AsyncRead, AsyncWrite, AsyncWriteExt, ReadHalf, WriteHalf},
+    select,
+    sync::oneshot,
+    task,
+    time::timeout,
 };
 
-use crate::error::Error;
-use crate::proto::{GenMessage, GenMessageError, MessageHeader};
+use crate::error::{write_error, Error, Result};
+use crate::proto::{GenMessage, GenMessageError, MessageHeader, MESSAGE_HEADER_LENGTH};
+#[cfg(feature = "capture")]
+use crate::r#async::capture::{Capture, Direction};
+use crate::r#async::rate_limit::{RateLimit, RateLimiter};
+
+/// A connected socket, before any ttrpc message was exchanged on it.
+pub trait RawStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> RawStream for S {}
+
+/// Runs a custom exchange, e.g. a proxy `CONNECT` or an authentication, on every
+/// new connection before ttrpc messages are exchanged on it, see
+/// [`Server::set_connect_hook`](crate::r#async::Server::set_connect_hook) and
+/// [`ClientBuilder::connect_hook`](crate::r#async::ClientBuilder::connect_hook).
+#[async_trait]
+pub trait ConnectHook {
+    /// Returning an error closes the connection.
+    ///
+    /// The hook must not read past the end of its exchange, as those bytes would be
+    /// missing from the first ttrpc message.
+    async fn on_connect(&self, stream: &mut dyn RawStream) -> Result<()>;
+}
 
 pub trait Builder {
     type Reader;
     type Writer;
 
     fn build(&mut self) -> (Self::Reader, Self::Writer);
+
+    /// The connection is considered dead if writing a single message, or a batch
+    /// of them, takes longer.
+    fn write_timeout(&self) -> Option<Duration> {
+        None
+    }
+
+    /// Reading from the connection is delayed to stay within the limit.
+    fn read_rate_limit(&self) -> Option<RateLimit> {
+        None
+    }
+
+    /// The messages exchanged on the connection are recorded into the capture.
+    #[cfg(feature = "capture")]
+    fn capture(&self) -> Option<Capture> {
+        None
+    }
+
+    /// The reader yields to the other tasks of the runtime after handling that
+    /// many messages in a row.
+    fn yield_budget(&self) -> Option<usize> {
+        None
+    }
+
+    /// The writer writes up to that many queued messages at once, with a single
+    /// vectored write.
+    fn write_batch(&self) -> Option<usize> {
+        None
+    }
 }
 
 #[async_trait]
 pub trait WriterDelegate {
     async fn recv(&mut self) -> Option<GenMessage>;
+
+    /// Returns a message already queued, without waiting. Only used to fill the
+    /// batches of [`Builder::write_batch`].
+    fn try_recv(&mut self) -> Option<GenMessage> {
+        None
+    }
     async fn disconnect(&self, msg: &GenMessage, e: Error);
     async fn exit(&self);
+
+    /// Called once `header`'s message has been written to the connection.
+    fn written(&self, _header: &MessageHeader) {}
 }
 
 #[async_trait]
@@ -37,11 +103,22 @@ pub trait ReaderDelegate {
     async fn exit(&self);
     async fn handle_msg(&self, msg: GenMessage);
     async fn handle_err(&self, header: MessageHeader, e: Error);
+
+    /// Resolves with an error once the peer is found dead, e.g. because it
+    /// doesn't answer pings. The future is dropped whenever a message is read.
+    async fn check_alive(&self) -> Error {
+        futures::future::pending().await
+    }
 }
 
 pub struct Connection<S, B: Builder> {
     reader: ReadHalf<S>,
     writer_task: task::JoinHandle<()>,
+    write_stalled: oneshot::Receiver<Error>,
+    rate_limiter: Option<RateLimiter>,
+    #[cfg(feature = "capture")]
+    capture: Option<(Capture, RawFd)>,
+    yield_budget: Option<usize>,
     reader_delegate: B::Reader,
 }
 
@@ -53,17 +130,65 @@ where
     B::Writer: WriterDelegate + Send + Sync + 'static,
 {
     pub fn new(conn: S, mut builder: B) -> Self {
+        #[cfg(feature = "capture")]
+        let capture = builder.capture().map(|capture| (capture, conn.as_raw_fd()));
         let (reader, mut writer) = split(conn);
 
         let (reader_delegate, mut writer_delegate) = builder.build();
+        let write_timeout = builder.write_timeout();
+        let rate_limiter = builder.read_rate_limit().map(RateLimiter::new);
+        let yield_budget = builder.yield_budget();
+        let write_batch = builder.write_batch().unwrap_or(1);
+        let (stalled_tx, write_stalled) = oneshot::channel();
+        #[cfg(feature = "capture")]
+        let writer_capture = capture.clone();
 
         let writer_task = tokio::spawn(async move {
+            let mut batch = Vec::with_capacity(write_batch);
             while let Some(msg) = writer_delegate.recv().await {
-                trace!("write message: {:?}", msg);
-                if let Err(e) = msg.write_to(&mut writer).await {
-                    error!("write_message got error: {:?}", e);
-                    writer_delegate.disconnect(&msg, e).await;
+                batch.push(msg);
+                while batch.len() < write_batch {
+                    match writer_delegate.try_recv() {
+                        Some(msg) => batch.push(msg),
+                        None => break,
+                    }
                 }
+                trace!("write messages: {:?}", batch);
+                let res = match write_timeout {
+                    Some(t) => match timeout(t, write_messages(&mut writer, &batch)).await {
+                        Ok(res) => res,
+                        Err(_) => {
+                            // The peer stopped reading, give up on the connection
+                            // rather than blocking the queued messages forever.
+                            let e = Error::Socket(format!("write timed out after {t:?}"));
+                            error!("write_message got error: {:?}", e);
+                            for msg in batch.iter() {
+                                writer_delegate.disconnect(msg, e.clone()).await;
+                            }
+                            stalled_tx.send(e).ok();
+                            break;
+                        }
+                    },
+                    None => write_messages(&mut writer, &batch).await,
+                };
+                match res {
+                    Ok(_) => {
+                        for msg in batch.iter() {
+                            #[cfg(feature = "capture")]
+                            if let Some((capture, fd)) = &writer_capture {
+                                capture.record(*fd, Direction::Sent, msg);
+                            }
+                            writer_delegate.written(&msg.header)
+                        }
+                    }
+                    Err(e) => {
+                        error!("write_message got error: {:?}", e);
+                        for msg in batch.iter() {
+                            writer_delegate.disconnect(msg, e.clone()).await;
+                        }
+                    }
+                }
+                batch.clear();
             }
             writer_delegate.exit().await;
             trace!("Writer task exit.");
@@ -72,6 +197,11 @@ where
         Self {
             reader,
             writer_task,
+            write_stalled,
+            rate_limiter,
+            #[cfg(feature = "capture")]
+            capture,
+            yield_budget,
             reader_delegate,
         }
     }
@@ -80,14 +210,54 @@ where
         let Connection {
             mut reader,
             mut writer_task,
+            mut write_stalled,
+            mut rate_limiter,
+            #[cfg(feature = "capture")]
+            capture,
+            yield_budget,
             reader_delegate,
         } = self;
+        let mut writing = true;
+        // Messages handled since the reader last yielded.
+        let mut handled = 0;
         loop {
             select! {
-                res = GenMessage::read_from(&mut reader) => {
+                // Checked first, the writer also triggers the shutdown when it gives up.
+                biased;
+
+                res = &mut write_stalled, if writing => {
+                    match res {
+                        Ok(e) => {
+                            reader_delegate.disconnect(e, &mut writer_task).await;
+                            break;
+                        }
+                        // The writer exited normally.
+                        Err(_) => writing = false,
+                    }
+                }
+                res = async {
+                    if let Some(limiter) = rate_limiter.as_mut() {
+                        limiter.ready().await;
+                    }
+                    GenMessage::read_from(&mut reader).await
+                } => {
+                    if let Some(limiter) = rate_limiter.as_mut() {
+                        let header = match &res {
+                            Ok(msg) => Some(&msg.header),
+                            Err(GenMessageError::ReturnError(header, _)) => Some(header),
+                            Err(GenMessageError::InternalError(_)) => None,
+                        };
+                        if let Some(header) = header {
+                            limiter.consume(MESSAGE_HEADER_LENGTH + header.length as usize);
+                        }
+                    }
                     match res {
                         Ok(msg) => {
                             trace!("Got Message {:?}", msg);
+                            #[cfg(feature = "capture")]
+                            if let Some((capture, fd)) = &capture {
+                                capture.record(*fd, Direction::Received, &msg);
+                            }
                             reader_delegate.handle_msg(msg).await;
                         }
                         Err(GenMessageError::ReturnError(header, e)) => {
@@ -101,6 +271,20 @@ where
                             break;
                         }
                     }
+                    if let Some(budget) = yield_budget {
+                        handled += 1;
+                        if handled >= budget {
+                            // A burst of messages must not starve the other tasks
+                            // of the worker.
+                            handled = 0;
+                            task::yield_now().await;
+                        }
+                    }
+                }
+                e = reader_delegate.check_alive() => {
+                    error!("Peer is dead: {:?}", e);
+                    reader_delegate.disconnect(e, &mut writer_task).await;
+                    break;
                 }
                 _v = reader_delegate.wait_shutdown() => {
                     trace!("Receive shutdown.");
@@ -114,3 +298,47 @@ where
         Ok(())
     }
 }
+
+/// Writes `msgs`, a single message the usual way and several with vectored writes
+/// of all their headers and payloads.
+async fn write_messages<S: AsyncWrite>(
+    writer: &mut WriteHalf<S>,
+    msgs: &[GenMessage],
+) -> Result<()> {
+    if let [msg] = msgs {
+        return msg.write_to(writer).await;
+    }
+    let headers: Vec<Vec<u8>> = msgs.iter().map(|msg| msg.header.into()).collect();
+    let mut bufs: Vec<&[u8]> = Vec::with_capacity(msgs.len() * 2);
+    for (header, msg) in headers.iter().zip(msgs) {
+        bufs.push(header);
+        if !msg.payload.is_empty() {
+            bufs.push(&msg.payload);
+        }
+    }
+    write_all_vectored(writer, &mut bufs)
+        .await
+        .map_err(write_error)
+}
+
+/// Writes all of `bufs`, going on after the short writes.
+async fn write_all_vectored<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    mut bufs: &mut [&[u8]],
+) -> std::io::Result<()> {
+    while !bufs.is_empty() {
+        let slices: Vec<IoSlice> = bufs.iter().map(|buf| IoSlice::new(buf)).collect();
+        let mut n = writer.write_vectored(&slices).await?;
+        if n == 0 {
+            return Err(std::io::ErrorKind::WriteZero.into());
+        }
+        while n > 0 && n >= bufs[0].len() {
+            n -= bufs[0].len();
+            bufs = &mut bufs[1..];
+        }
+        if n > 0 {
+            bufs[0] = &bufs[0][n..];
+        }
+    }
+    writer.flush().await
+}
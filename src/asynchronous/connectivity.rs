@@ -0,0 +1,86 @@
+// Copyright (c) 2023 Ant Group
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Connectivity state of clients.
+
+use std::sync::Arc;
+
+use tokio::sync::watch;
+
+/// State of the connection of a client, see
+/// [`Client::state`](crate::r#async::Client::state).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ConnectivityState {
+    /// Not connected until a call is made, see
+    /// [`ClientBuilder::lazy`](crate::r#async::ClientBuilder::lazy).
+    Idle,
+    Connecting,
+    Ready,
+    /// Connecting failed or the connection was lost, the client connects again
+    /// after a while.
+    TransientFailure,
+    /// The client was closed or won't connect again, calls fail.
+    Shutdown,
+}
+
+/// Receives the state transitions of a client, see
+/// [`Client::watch_state`](crate::r#async::Client::watch_state).
+#[derive(Debug)]
+pub struct StateWatcher {
+    rx: watch::Receiver<ConnectivityState>,
+    done: bool,
+}
+
+impl StateWatcher {
+    /// Returns the state the client transitioned to, or `None` after
+    /// [`ConnectivityState::Shutdown`] was returned.
+    ///
+    /// Transitions made faster than they are received are coalesced into the
+    /// latest state.
+    pub async fn recv(&mut self) -> Option<ConnectivityState> {
+        if self.done {
+            return None;
+        }
+        let state = match self.rx.changed().await {
+            Ok(()) => *self.rx.borrow_and_update(),
+            Err(_) => ConnectivityState::Shutdown,
+        };
+        self.done = state == ConnectivityState::Shutdown;
+        Some(state)
+    }
+}
+
+/// Publishes the state of a client to its watchers.
+#[derive(Clone, Debug)]
+pub(crate) struct StateSender(Arc<watch::Sender<ConnectivityState>>);
+
+impl StateSender {
+    pub(crate) fn new(state: ConnectivityState) -> StateSender {
+        StateSender(Arc::new(watch::channel(state).0))
+    }
+
+    pub(crate) fn get(&self) -> ConnectivityState {
+        *self.0.borrow()
+    }
+
+    /// Shutdown is final, later states are ignored.
+    pub(crate) fn set(&self, state: ConnectivityState) {
+        self.0.send_if_modified(|current| {
+            if *current == state || *current == ConnectivityState::Shutdown {
+                return false;
+            }
+            trace!("Client state {:?} -> {:?}", current, state);
+            *current = state;
+            true
+        });
+    }
+
+    pub(crate) fn watch(&self) -> StateWatcher {
+        StateWatcher {
+            rx: self.0.subscribe(),
+            done: self.get() == ConnectivityState::Shutdown,
+        }
+    }
+}
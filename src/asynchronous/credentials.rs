@@ -0,0 +1,294 @@
+// Copyright (c) 2023 Ant Group
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Capture of the credentials of the process which wrote a message on a Unix socket,
+//! see [`Server::set_pass_credentials`](crate::r#async::Server::set_pass_credentials).
+
+use std::os::unix::io::RawFd;
+use std::sync::{Arc, Mutex};
+
+use tokio::net::{TcpStream, UnixStream};
+#[cfg(any(target_os = "linux", target_os = "android"))]
+use tokio_vsock::VsockStream;
+
+/// Credentials of the process which wrote a message, as reported by the kernel.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Credentials {
+    pub pid: i32,
+    pub uid: u32,
+    pub gid: u32,
+}
+
+/// Returns the credentials of the process which connected the Unix socket `fd`, as
+/// reported by `SO_PEERCRED` or `LOCAL_PEERCRED`.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub(crate) fn peer_credentials(fd: RawFd) -> Option<Credentials> {
+    use nix::sys::socket::{getsockopt, sockopt};
+
+    let cred = getsockopt(fd, sockopt::PeerCredentials).ok()?;
+    Some(Credentials {
+        pid: cred.pid(),
+        uid: cred.uid(),
+        gid: cred.gid(),
+    })
+}
+
+/// See the Linux variant. The pid is reported by `LOCAL_PEERPID`.
+#[cfg(target_os = "macos")]
+pub(crate) fn peer_credentials(fd: RawFd) -> Option<Credentials> {
+    use nix::sys::socket::{getsockopt, sockopt};
+
+    let cred = getsockopt(fd, sockopt::LocalPeerCred).ok()?;
+    let mut pid: libc::pid_t = 0;
+    let mut len = std::mem::size_of::<libc::pid_t>() as libc::socklen_t;
+    // Not wrapped by nix.
+    let res = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_LOCAL,
+            libc::LOCAL_PEERPID,
+            &mut pid as *mut libc::pid_t as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    Some(Credentials {
+        pid: if res == 0 { pid } else { 0 },
+        uid: cred.uid(),
+        gid: cred.groups().first().copied()?,
+    })
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "macos")))]
+pub(crate) fn peer_credentials(_fd: RawFd) -> Option<Credentials> {
+    None
+}
+
+/// Holds the credentials of the message being read until the reader takes them.
+pub(crate) type CredentialsSlot = Arc<Mutex<Option<Credentials>>>;
+
+/// A connection which may capture the credentials of its peer.
+pub(crate) trait CaptureCredentials {
+    /// Returns the slot filled with the credentials of the message being read, or
+    /// `None` if the connection doesn't capture credentials.
+    fn credentials(&self) -> Option<CredentialsSlot> {
+        None
+    }
+}
+
+impl CaptureCredentials for UnixStream {}
+
+impl CaptureCredentials for TcpStream {}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+impl CaptureCredentials for VsockStream {}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub(crate) use self::linux::CredentialsIncoming;
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+mod linux {
+    use std::io::{self, IoSliceMut};
+    use std::os::unix::io::{AsRawFd, RawFd};
+    use std::pin::Pin;
+    use std::sync::{Arc, Mutex};
+    use std::task::{Context, Poll};
+
+    use futures::{ready, Stream};
+    use nix::sys::socket::{recvmsg, ControlMessageOwned, MsgFlags, UnixAddr, UnixCredentials};
+    use tokio::io::{AsyncRead, AsyncWrite, Interest, ReadBuf};
+    use tokio::net::UnixStream;
+
+    use super::{CaptureCredentials, Credentials, CredentialsSlot};
+    use crate::asynchronous::unix_incoming::UnixIncoming;
+
+    /// Stream of accepted connections capturing the credentials of their peer.
+    ///
+    /// The listening socket must have `SO_PASSCRED` set, which is inherited by the
+    /// accepted sockets.
+    pub(crate) struct CredentialsIncoming {
+        inner: UnixIncoming,
+    }
+
+    impl CredentialsIncoming {
+        pub(crate) fn new(inner: UnixIncoming) -> Self {
+            Self { inner }
+        }
+    }
+
+    impl Stream for CredentialsIncoming {
+        type Item = io::Result<CredentialsStream>;
+
+        fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+            let socket = ready!(Pin::new(&mut self.inner).poll_next(cx));
+            Poll::Ready(socket.map(|s| s.map(CredentialsStream::new)))
+        }
+    }
+
+    impl AsRawFd for CredentialsIncoming {
+        fn as_raw_fd(&self) -> RawFd {
+            self.inner.as_raw_fd()
+        }
+    }
+
+    /// A Unix stream which reads with `recvmsg(2)` to pick up `SCM_CREDENTIALS`.
+    pub(crate) struct CredentialsStream {
+        inner: UnixStream,
+        slot: CredentialsSlot,
+    }
+
+    impl CredentialsStream {
+        fn new(inner: UnixStream) -> Self {
+            Self {
+                inner,
+                slot: Arc::new(Mutex::new(None)),
+            }
+        }
+    }
+
+    impl CaptureCredentials for CredentialsStream {
+        fn credentials(&self) -> Option<CredentialsSlot> {
+            Some(self.slot.clone())
+        }
+    }
+
+    impl AsRawFd for CredentialsStream {
+        fn as_raw_fd(&self) -> RawFd {
+            self.inner.as_raw_fd()
+        }
+    }
+
+    impl AsyncRead for CredentialsStream {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            let this = self.get_mut();
+            let fd = this.inner.as_raw_fd();
+            loop {
+                ready!(this.inner.poll_read_ready(cx))?;
+                let unfilled = buf.initialize_unfilled();
+                match this
+                    .inner
+                    .try_io(Interest::READABLE, || recv_with_credentials(fd, unfilled))
+                {
+                    Ok((n, credentials)) => {
+                        // A message may take several reads, keep the credentials of the first one.
+                        if let Some(credentials) = credentials {
+                            this.slot.lock().unwrap().get_or_insert(credentials);
+                        }
+                        buf.advance(n);
+                        return Poll::Ready(Ok(()));
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                    Err(e) => return Poll::Ready(Err(e)),
+                }
+            }
+        }
+    }
+
+    impl AsyncWrite for CredentialsStream {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+        }
+    }
+
+    fn recv_with_credentials(
+        fd: RawFd,
+        buf: &mut [u8],
+    ) -> io::Result<(usize, Option<Credentials>)> {
+        let mut iov = [IoSliceMut::new(buf)];
+        let mut cmsg_buf = nix::cmsg_space!(UnixCredentials);
+        let msg = recvmsg::<UnixAddr>(
+            fd,
+            &mut iov,
+            Some(&mut cmsg_buf),
+            MsgFlags::MSG_CMSG_CLOEXEC,
+        )?;
+        let credentials = msg.cmsgs().find_map(|cmsg| match cmsg {
+            ControlMessageOwned::ScmCredentials(c) => Some(Credentials {
+                pid: c.pid(),
+                uid: c.uid(),
+                gid: c.gid(),
+            }),
+            _ => None,
+        });
+        Ok((msg.bytes, credentials))
+    }
+}
+
+#[cfg(all(test, any(target_os = "linux", target_os = "android")))]
+mod tests {
+    use std::collections::HashMap;
+
+    use async_trait::async_trait;
+
+    use super::*;
+    use crate::error::Result;
+    use crate::r#async::{testing, Client, MethodHandler, Server, Service, TtrpcContext};
+    use crate::{Request, Response};
+
+    /// Answers with the credentials of the process which wrote the request.
+    struct WhoAmI;
+
+    #[async_trait]
+    impl MethodHandler for WhoAmI {
+        async fn handler(&self, ctx: TtrpcContext, _req: Request) -> Result<Response> {
+            Ok(Response {
+                payload: format!("{:?}", ctx.credentials).into_bytes(),
+                ..Default::default()
+            })
+        }
+    }
+
+    async fn who_am_i(pass_credentials: bool) -> String {
+        let mut methods: HashMap<String, Box<dyn MethodHandler + Send + Sync>> = HashMap::new();
+        methods.insert("WhoAmI".to_string(), Box::new(WhoAmI));
+        let mut services = HashMap::new();
+        services.insert(
+            "test.Test".to_string(),
+            Service {
+                methods,
+                streams: HashMap::new(),
+            },
+        );
+        let server = Server::new()
+            .set_pass_credentials(pass_credentials)
+            .register_service(services);
+        let server = testing::start(server).await.unwrap();
+        let client = Client::connect(&server.address()).unwrap();
+        let req = Request {
+            service: "test.Test".to_string(),
+            method: "WhoAmI".to_string(),
+            timeout_nano: 5_000_000_000,
+            ..Default::default()
+        };
+        let res = client.request(req).await.unwrap();
+        String::from_utf8(res.payload).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_pass_credentials() {
+        let expected = Credentials {
+            pid: std::process::id() as i32,
+            uid: nix::unistd::getuid().as_raw(),
+            gid: nix::unistd::getgid().as_raw(),
+        };
+        assert_eq!(who_am_i(true).await, format!("{:?}", Some(expected)));
+        assert_eq!(who_am_i(false).await, "None");
+    }
+}
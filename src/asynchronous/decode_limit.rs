@@ -0,0 +1,123 @@
+// Copyright (c) 2023 Ant Group
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Estimate of the memory a protobuf message takes once decoded, see
+//! [`Server::set_max_decoded_size`](crate::r#async::Server::set_max_decoded_size).
+//!
+//! The estimate is made from the wire format, without the schema of the message:
+//! scalar fields are charged 8 bytes at most, and length-delimited fields their
+//! bytes plus the size of a `Vec` or `String` header. Length-delimited fields
+//! which parse as a message are charged the estimate of that message if it's
+//! larger, so repeated tiny or empty sub-messages add up.
+
+use std::convert::TryFrom;
+
+/// Heap cost of a decoded field, e.g. a `String` in a repeated field.
+const FIELD_COST: usize = 24;
+
+/// Length-delimited fields nested deeper are charged their length only, which
+/// bounds the times a byte is scanned.
+const MAX_DEPTH: usize = 32;
+
+/// Returns the estimated bytes taken by the message encoded in `buf` once decoded.
+pub(crate) fn decoded_size(buf: &[u8]) -> usize {
+    estimate(buf, 0).unwrap_or(buf.len())
+}
+
+/// Returns `None` if `buf` isn't a valid message.
+fn estimate(mut buf: &[u8], depth: usize) -> Option<usize> {
+    let mut size = 0usize;
+    while !buf.is_empty() {
+        let tag = read_varint(&mut buf)?;
+        if tag >> 3 == 0 {
+            return None;
+        }
+        let cost = match tag & 7 {
+            0 => {
+                read_varint(&mut buf)?;
+                8
+            }
+            1 => skip(&mut buf, 8)?.len(),
+            5 => skip(&mut buf, 4)?.len(),
+            2 => {
+                let len = usize::try_from(read_varint(&mut buf)?).ok()?;
+                let field = skip(&mut buf, len)?;
+                let nested = if depth < MAX_DEPTH {
+                    estimate(field, depth + 1).unwrap_or(0)
+                } else {
+                    0
+                };
+                FIELD_COST + len.max(nested)
+            }
+            // Groups are deprecated, and not supported by ttrpc.
+            _ => return None,
+        };
+        size = size.saturating_add(cost);
+    }
+    Some(size)
+}
+
+fn read_varint(buf: &mut &[u8]) -> Option<u64> {
+    let mut value = 0u64;
+    for (i, b) in buf.iter().take(10).enumerate() {
+        value |= u64::from(b & 0x7f) << (7 * i);
+        if b & 0x80 == 0 {
+            *buf = &buf[i + 1..];
+            return Some(value);
+        }
+    }
+    None
+}
+
+fn skip<'a>(buf: &mut &'a [u8], len: usize) -> Option<&'a [u8]> {
+    if buf.len() < len {
+        return None;
+    }
+    let (field, rest) = buf.split_at(len);
+    *buf = rest;
+    Some(field)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decoded_size() {
+        assert_eq!(decoded_size(&[]), 0);
+        // Field 1 varint 150.
+        assert_eq!(decoded_size(&[0x08, 0x96, 0x01]), 8);
+        // Field 2 string "abc".
+        assert_eq!(
+            decoded_size(&[0x12, 0x03, b'a', b'b', b'c']),
+            FIELD_COST + 3
+        );
+        // Not a message, e.g. a truncated field.
+        assert_eq!(decoded_size(&[0x12, 0x05, b'a']), 3);
+
+        // 1000 empty sub-messages in field 1 of field 1.
+        let inner = [0x0a, 0x00].repeat(1000);
+        let mut buf = vec![0x0a, 0xd0, 0x0f];
+        buf.extend_from_slice(&inner);
+        assert_eq!(decoded_size(&buf), FIELD_COST + 1000 * FIELD_COST);
+    }
+
+    #[test]
+    fn test_decoded_size_garbage() {
+        let mut seed = 0x2545_f491_4f6c_dd1du64;
+        for len in 0..2000 {
+            let buf: Vec<u8> = (0..len)
+                .map(|_| {
+                    seed ^= seed << 13;
+                    seed ^= seed >> 7;
+                    seed ^= seed << 17;
+                    seed as u8
+                })
+                .collect();
+            // Never panics, whatever the input.
+            decoded_size(&buf);
+        }
+    }
+}
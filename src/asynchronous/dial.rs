@@ -0,0 +1,135 @@
+// Copyright (c) 2023 Ant Group
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Dialing of TCP targets named by host name.
+
+use std::net::SocketAddr;
+use std::os::unix::io::{IntoRawFd, RawFd};
+use std::time::Duration;
+
+use futures::stream::{FuturesUnordered, StreamExt};
+use tokio::net::TcpStream;
+
+use crate::error::{Error, Result};
+
+/// How long an attempt is given before the next address is tried alongside it,
+/// as recommended by RFC 8305.
+const ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// Returns the `host:port` of `sockaddr` if it is a TCP target whose host is a
+/// name rather than an IP, which has to be resolved before dialing.
+pub(crate) fn tcp_host(sockaddr: &str) -> Option<&str> {
+    let host_port = sockaddr.strip_prefix("tcp://")?;
+    if host_port.parse::<SocketAddr>().is_ok() {
+        return None;
+    }
+    Some(host_port)
+}
+
+/// Resolves `host_port` without blocking and connects to the first of its
+/// addresses which accepts, see [`connect_any`].
+///
+/// `timeout` bounds the resolution and all the attempts together.
+pub(crate) async fn connect_tcp(host_port: &str, timeout: Option<Duration>) -> Result<RawFd> {
+    let connect = async {
+        let addrs = tokio::net::lookup_host(host_port)
+            .await
+            .map_err(|e| Error::Others(format!("Resolve {host_port} failed: {e}")))?;
+        let addrs = interleave(addrs.collect());
+        if addrs.is_empty() {
+            return Err(Error::Others(format!("{host_port} resolved to no address")));
+        }
+        connect_any(addrs).await
+    };
+    let stream = match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, connect)
+            .await
+            .map_err(|_| Error::Others(format!("Connect to {host_port} timed out")))??,
+        None => connect.await?,
+    };
+    let stream = stream
+        .into_std()
+        .map_err(err_to_others_err!(e, "Take tcp stream failed: "))?;
+    Ok(stream.into_raw_fd())
+}
+
+/// Connects to `addrs` the happy-eyeballs way: an attempt starts every
+/// [`ATTEMPT_DELAY`], or as soon as the previous ones failed, and the first
+/// connection made wins, the other attempts are dropped.
+async fn connect_any(addrs: Vec<SocketAddr>) -> Result<TcpStream> {
+    let mut pending = addrs.into_iter();
+    let mut attempts = FuturesUnordered::new();
+    let mut last_err = None;
+    loop {
+        if attempts.is_empty() {
+            match pending.next() {
+                Some(addr) => attempts.push(attempt(addr)),
+                None => return Err(last_err.unwrap()),
+            }
+        }
+        tokio::select! {
+            Some(res) = attempts.next() => match res {
+                Ok(stream) => return Ok(stream),
+                Err(e) => last_err = Some(e),
+            },
+            _ = tokio::time::sleep(ATTEMPT_DELAY), if pending.len() > 0 => {
+                attempts.push(attempt(pending.next().unwrap()));
+            }
+        }
+    }
+}
+
+async fn attempt(addr: SocketAddr) -> Result<TcpStream> {
+    TcpStream::connect(addr).await.map_err(|e| {
+        trace!("Connect to {} failed: {:?}", addr, e);
+        Error::Socket(format!("connect to {addr} failed: {e}"))
+    })
+}
+
+/// Alternates the address families, starting with the one the resolver
+/// preferred, so a broken family only delays the connection by an attempt.
+fn interleave(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let first_v6 = match addrs.first() {
+        Some(addr) => addr.is_ipv6(),
+        None => return addrs,
+    };
+    let (mut preferred, mut other): (Vec<_>, Vec<_>) = addrs
+        .into_iter()
+        .partition(|addr| addr.is_ipv6() == first_v6);
+    let mut interleaved = Vec::with_capacity(preferred.len() + other.len());
+    preferred.reverse();
+    other.reverse();
+    while !preferred.is_empty() || !other.is_empty() {
+        interleaved.extend(preferred.pop());
+        interleaved.extend(other.pop());
+    }
+    interleaved
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tcp_host() {
+        assert_eq!(tcp_host("tcp://localhost:1024"), Some("localhost:1024"));
+        assert_eq!(tcp_host("tcp://127.0.0.1:1024"), None);
+        assert_eq!(tcp_host("tcp://[::1]:1024"), None);
+        assert_eq!(tcp_host("unix:///tmp/ttrpc.sock"), None);
+    }
+
+    #[test]
+    fn test_interleave() {
+        let addrs: Vec<SocketAddr> = ["[::1]:1", "[::2]:1", "[::3]:1", "10.0.0.1:1"]
+            .iter()
+            .map(|addr| addr.parse().unwrap())
+            .collect();
+        let expected: Vec<SocketAddr> = ["[::1]:1", "10.0.0.1:1", "[::2]:1", "[::3]:1"]
+            .iter()
+            .map(|addr| addr.parse().unwrap())
+            .collect();
+        assert_eq!(interleave(addrs), expected);
+    }
+}
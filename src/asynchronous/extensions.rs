@@ -0,0 +1,100 @@
+// Copyright (c) 2023 Ant Group
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Storage scoped to a server connection.
+
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+/// Values shared by all calls of one connection, keyed by their type.
+///
+/// Services may keep e.g. session state or negotiated options of a peer here, see
+/// [`TtrpcContext::extensions`](crate::r#async::TtrpcContext::extensions). The
+/// values are dropped when the connection closes.
+#[derive(Clone, Default)]
+pub struct Extensions {
+    map: Arc<Mutex<HashMap<TypeId, Box<dyn Any + Send + Sync>>>>,
+}
+
+impl Extensions {
+    /// Store `val`, returning the previous value of the same type.
+    pub fn insert<T: Send + Sync + 'static>(&self, val: T) -> Option<T> {
+        self.map
+            .lock()
+            .unwrap()
+            .insert(TypeId::of::<T>(), Box::new(val))
+            .and_then(|prev| prev.downcast().ok())
+            .map(|prev| *prev)
+    }
+
+    /// Returns a copy of the value of type `T`.
+    pub fn get<T: Clone + Send + Sync + 'static>(&self) -> Option<T> {
+        self.map
+            .lock()
+            .unwrap()
+            .get(&TypeId::of::<T>())
+            .and_then(|val| val.downcast_ref::<T>())
+            .cloned()
+    }
+
+    /// Runs `f` on the value of type `T`, inserting `T::default()` first if there is
+    /// none yet.
+    pub fn with<T, R, F>(&self, f: F) -> R
+    where
+        T: Default + Send + Sync + 'static,
+        F: FnOnce(&mut T) -> R,
+    {
+        let mut map = self.map.lock().unwrap();
+        let val = map
+            .entry(TypeId::of::<T>())
+            .or_insert_with(|| Box::<T>::default());
+        f(val.downcast_mut().unwrap())
+    }
+
+    /// Removes and returns the value of type `T`.
+    pub fn remove<T: Send + Sync + 'static>(&self) -> Option<T> {
+        self.map
+            .lock()
+            .unwrap()
+            .remove(&TypeId::of::<T>())
+            .and_then(|val| val.downcast().ok())
+            .map(|val| *val)
+    }
+}
+
+impl fmt::Debug for Extensions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Extensions")
+            .field("len", &self.map.lock().unwrap().len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Clone, Debug, Default, PartialEq)]
+    struct Session(u32);
+
+    #[test]
+    fn test_extensions() {
+        let ext = Extensions::default();
+        assert_eq!(ext.get::<Session>(), None);
+        assert_eq!(ext.insert(Session(1)), None);
+        assert_eq!(ext.insert(Session(2)), Some(Session(1)));
+
+        // Shared by the clones.
+        let other = ext.clone();
+        other.with(|s: &mut Session| s.0 += 1);
+        assert_eq!(ext.get::<Session>(), Some(Session(3)));
+        assert_eq!(ext.with(|n: &mut u64| *n), 0);
+
+        assert_eq!(ext.remove::<Session>(), Some(Session(3)));
+        assert_eq!(other.get::<Session>(), None);
+    }
+}
@@ -0,0 +1,216 @@
+// Copyright (c) 2023 Ant Group
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Weighted fair sharing of a server resource between its connections, see
+//! [`Server::set_weigher`](crate::r#async::Server::set_weigher).
+//!
+//! Waiting claims are granted by start-time fair queuing: every connection's claims
+//! are tagged with a virtual time advancing by their cost divided by the weight of
+//! the connection, and the claim with the lowest tag goes first. A connection with
+//! twice the weight of another is thus granted twice as much while both wait, and
+//! a connection which was idle does not get to catch up on its share.
+
+use std::collections::HashMap;
+use std::os::unix::io::RawFd;
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::{self, oneshot};
+
+use crate::r#async::rate_limit::{RateLimit, RateLimiter};
+
+/// Hands out a fixed number of slots to the connections, in weighted fair order.
+pub(crate) struct FairQueue {
+    state: Arc<Mutex<State>>,
+}
+
+struct State {
+    available: usize,
+    /// The start tag of the claim granted last.
+    now: f64,
+    /// The finish tag of the last claim of every connection, connections whose tag
+    /// is behind `now` are forgotten.
+    finish: HashMap<RawFd, f64>,
+    waiters: Vec<Waiter>,
+    seq: u64,
+}
+
+struct Waiter {
+    start: f64,
+    // Orders the claims with the same tag.
+    seq: u64,
+    tx: oneshot::Sender<FairPermit>,
+}
+
+/// A slot of a [`FairQueue`], given back to the next claim once dropped.
+pub(crate) struct FairPermit {
+    // Taken by a permit which is not to be given back.
+    state: Option<Arc<Mutex<State>>>,
+}
+
+enum Claim {
+    Granted(FairPermit),
+    Waiting(oneshot::Receiver<FairPermit>),
+}
+
+impl FairQueue {
+    pub(crate) fn new(slots: usize) -> FairQueue {
+        FairQueue {
+            state: Arc::new(Mutex::new(State {
+                available: slots,
+                now: 0.0,
+                finish: HashMap::new(),
+                waiters: Vec::new(),
+                seq: 0,
+            })),
+        }
+    }
+
+    /// Waits for a slot for a claim of connection `conn`, which costs `cost`, e.g. one
+    /// call or the bytes of a message.
+    pub(crate) async fn acquire(&self, conn: RawFd, weight: u32, cost: f64) -> FairPermit {
+        match self.claim(conn, weight, cost) {
+            Claim::Granted(permit) => permit,
+            // The queue outlives the claims, so the sender is never dropped unused.
+            Claim::Waiting(rx) => rx.await.expect("fair queue dropped"),
+        }
+    }
+
+    fn claim(&self, conn: RawFd, weight: u32, cost: f64) -> Claim {
+        let mut state = self.state.lock().unwrap();
+        let start = state
+            .finish
+            .get(&conn)
+            .copied()
+            .unwrap_or(0.0)
+            .max(state.now);
+        state
+            .finish
+            .insert(conn, start + cost / f64::from(weight.max(1)));
+        if state.available > 0 && state.waiters.is_empty() {
+            state.available -= 1;
+            state.now = start;
+            return Claim::Granted(FairPermit {
+                state: Some(self.state.clone()),
+            });
+        }
+        let (tx, rx) = oneshot::channel();
+        state.seq += 1;
+        let seq = state.seq;
+        state.waiters.push(Waiter { start, seq, tx });
+        Claim::Waiting(rx)
+    }
+}
+
+/// A [`RateLimit`] on the messages written to all connections, shared between
+/// them by weight.
+pub(crate) struct WriteShare {
+    // A single writer at a time waits for the limiter.
+    queue: FairQueue,
+    limiter: sync::Mutex<RateLimiter>,
+}
+
+impl WriteShare {
+    pub(crate) fn new(limit: RateLimit) -> WriteShare {
+        WriteShare {
+            queue: FairQueue::new(1),
+            limiter: sync::Mutex::new(RateLimiter::new(limit)),
+        }
+    }
+
+    /// Waits until connection `conn` may write a message of `bytes` bytes.
+    pub(crate) async fn ready(&self, conn: RawFd, weight: u32, bytes: usize) {
+        let _permit = self.queue.acquire(conn, weight, bytes as f64).await;
+        let mut limiter = self.limiter.lock().await;
+        limiter.ready().await;
+        limiter.consume(bytes);
+    }
+}
+
+impl Drop for FairPermit {
+    fn drop(&mut self) {
+        let arc = match self.state.take() {
+            Some(arc) => arc,
+            None => return,
+        };
+        let mut state = arc.lock().unwrap();
+        while let Some(index) = state
+            .waiters
+            .iter()
+            .enumerate()
+            .min_by(|(_, a), (_, b)| a.start.total_cmp(&b.start).then(a.seq.cmp(&b.seq)))
+            .map(|(index, _)| index)
+        {
+            let waiter = state.waiters.swap_remove(index);
+            state.now = state.now.max(waiter.start);
+            let now = state.now;
+            state.finish.retain(|_, finish| *finish > now);
+            let permit = FairPermit {
+                state: Some(arc.clone()),
+            };
+            match waiter.tx.send(permit) {
+                Ok(()) => return,
+                // The claim was given up, its slot goes to the next one. Giving
+                // the permit back here would take the lock again.
+                Err(mut permit) => permit.state = None,
+            }
+        }
+        state.available += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn waiting(claim: Claim) -> oneshot::Receiver<FairPermit> {
+        match claim {
+            Claim::Waiting(rx) => rx,
+            Claim::Granted(_) => panic!("claim granted while the queue is full"),
+        }
+    }
+
+    #[test]
+    fn test_weighted_order() {
+        let queue = FairQueue::new(1);
+        let mut permit = match queue.claim(1, 1, 1.0) {
+            Claim::Granted(permit) => permit,
+            Claim::Waiting(_) => panic!("claim waiting while the queue is empty"),
+        };
+        // Connection 1 weighs 1, connection 2 weighs 4.
+        let mut rxs: Vec<(RawFd, oneshot::Receiver<FairPermit>)> = Vec::new();
+        for _ in 0..4 {
+            rxs.push((1, waiting(queue.claim(1, 1, 1.0))));
+        }
+        for _ in 0..8 {
+            rxs.push((2, waiting(queue.claim(2, 4, 1.0))));
+        }
+
+        let mut order = Vec::new();
+        for _ in 0..8 {
+            drop(permit);
+            let (index, next) = rxs
+                .iter_mut()
+                .enumerate()
+                .find_map(|(index, (_, rx))| rx.try_recv().ok().map(|next| (index, next)))
+                .expect("no claim granted");
+            order.push(rxs.remove(index).0);
+            permit = next;
+        }
+        assert_eq!(order, vec![2, 2, 2, 2, 1, 2, 2, 2]);
+    }
+
+    #[test]
+    fn test_given_up_claim() {
+        let queue = FairQueue::new(1);
+        let permit = match queue.claim(1, 1, 1.0) {
+            Claim::Granted(permit) => permit,
+            Claim::Waiting(_) => panic!("claim waiting while the queue is empty"),
+        };
+        drop(waiting(queue.claim(2, 1, 1.0)));
+        let mut rx = waiting(queue.claim(3, 1, 1.0));
+        drop(permit);
+        assert!(rx.try_recv().is_ok());
+    }
+}
@@ -0,0 +1,161 @@
+// Copyright (c) 2023 Ant Group
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Fault injection for resilience tests, see [`FaultInjector`].
+
+use std::time::Duration;
+
+use async_trait::async_trait;
+
+use crate::error::{get_rpc_status, Error, Result};
+use crate::proto::{Code, Request, Response, Status};
+use crate::r#async::client::{random_fraction, Interceptor, Next};
+use crate::r#async::clock;
+
+/// A failure a [`FaultInjector`] injects into a call.
+#[derive(Clone, Debug)]
+pub enum Fault {
+    /// Holds the call for the duration before it's handled, or sent.
+    Delay(Duration),
+    /// Fails the call with the status, without handling, or sending, it.
+    Status(Status),
+    /// Handles, or sends, the call but never delivers its response, as if it was
+    /// lost.
+    DropResponse,
+}
+
+#[derive(Clone, Debug)]
+struct Rule {
+    service: String,
+    method: String,
+    fault: Fault,
+    probability: f64,
+}
+
+/// Injects faults into the calls of chosen methods at random, so a project can
+/// test how it copes with a slow or failing peer without a fork of its services.
+///
+/// On a server, see [`Server::set_fault_injector`], it applies to all the calls.
+/// On a client, it's an [`Interceptor`], see [`Client::add_interceptor`], which
+/// applies to the unary calls. There an injected delay doesn't count against the
+/// timeout of the call, and a call whose response is dropped fails with
+/// `DEADLINE_EXCEEDED` once its timeout elapsed, or never returns without one.
+///
+/// ```
+/// # use std::time::Duration;
+/// use ttrpc::r#async::{Fault, FaultInjector};
+///
+/// let faults = FaultInjector::new()
+///     .inject("grpc.Health", "Check", Fault::Delay(Duration::from_secs(1)), 0.1)
+///     .inject("*", "*", Fault::DropResponse, 0.01);
+/// ```
+///
+/// [`Server::set_fault_injector`]: crate::r#async::Server::set_fault_injector
+/// [`Client::add_interceptor`]: crate::r#async::Client::add_interceptor
+#[derive(Clone, Debug, Default)]
+pub struct FaultInjector {
+    rules: Vec<Rule>,
+}
+
+impl FaultInjector {
+    pub fn new() -> FaultInjector {
+        FaultInjector::default()
+    }
+
+    /// Inject `fault` into a call of `service.method` with `probability`, where
+    /// `*` stands for any service or method. The rules are drawn in the order they
+    /// were added, and the first one drawn applies.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `probability` is not between 0 and 1.
+    pub fn inject(
+        mut self,
+        service: &str,
+        method: &str,
+        fault: Fault,
+        probability: f64,
+    ) -> FaultInjector {
+        assert!(
+            (0.0..=1.0).contains(&probability),
+            "fault probability must be between 0 and 1"
+        );
+        self.rules.push(Rule {
+            service: service.to_string(),
+            method: method.to_string(),
+            fault,
+            probability,
+        });
+        self
+    }
+
+    /// The fault to inject into a call of `service.method`, if any.
+    pub(crate) fn pick(&self, service: &str, method: &str) -> Option<&Fault> {
+        let rule = self.rules.iter().find(|rule| {
+            (rule.service == "*" || rule.service == service)
+                && (rule.method == "*" || rule.method == method)
+                && random_fraction() < rule.probability
+        })?;
+        debug!(
+            "Inject {:?} into a call of {}.{}",
+            rule.fault, service, method
+        );
+        Some(&rule.fault)
+    }
+}
+
+#[async_trait]
+impl Interceptor for FaultInjector {
+    async fn call(&self, req: Request, next: Next<'_>) -> Result<Response> {
+        match self.pick(&req.service, &req.method).cloned() {
+            None => next.run(req).await,
+            Some(Fault::Delay(delay)) => {
+                clock::sleep(&*next.clock(), delay).await;
+                next.run(req).await
+            }
+            Some(Fault::Status(status)) => Err(Error::RpcStatus(status)),
+            Some(Fault::DropResponse) => {
+                let clock = next.clock();
+                let started = clock.now();
+                let timeout_nano = req.timeout_nano;
+                next.run(req).await.ok();
+                if timeout_nano <= 0 {
+                    std::future::pending::<()>().await;
+                }
+                let timeout = Duration::from_nanos(timeout_nano as u64);
+                clock.sleep_until(started + timeout).await;
+                Err(get_rpc_status(
+                    Code::DEADLINE_EXCEEDED,
+                    "response dropped by fault injection",
+                ))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::get_status;
+
+    #[test]
+    fn test_pick() {
+        let faults = FaultInjector::new()
+            .inject("a.Svc", "Never", Fault::DropResponse, 0.0)
+            .inject(
+                "a.Svc",
+                "*",
+                Fault::Status(get_status(Code::UNAVAILABLE, "")),
+                1.0,
+            )
+            .inject("*", "Get", Fault::Delay(Duration::from_secs(1)), 1.0);
+        assert!(matches!(
+            faults.pick("a.Svc", "Never"),
+            Some(Fault::Status(_))
+        ));
+        assert!(matches!(faults.pick("b.Svc", "Get"), Some(Fault::Delay(_))));
+        assert!(faults.pick("b.Svc", "Put").is_none());
+    }
+}
@@ -0,0 +1,244 @@
+// Copyright (c) 2023 Ant Group
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Exchange of user-provided schema versions when a connection is established.
+//!
+//! The client sends its version as the payload of a call to the reserved
+//! `ttrpc.Handshake/Exchange` method before any other message, and the server
+//! answers with its own version. Both sides keep the version of their peer.
+//!
+//! The server also lists the optional protocol features it supports in the
+//! `ttrpc-capabilities` metadata of its answer, and its restart epoch, if any, in
+//! the `ttrpc-restart-epoch` metadata.
+//!
+//! A client may also send the schema digests of the methods it was generated with
+//! in the `ttrpc-schema-digests` metadata, as `/service/method=digest` values. The
+//! server answers with the methods whose digest differs from its own in the
+//! `ttrpc-schema-drift` metadata.
+
+use std::collections::{HashMap, HashSet};
+use std::convert::TryInto;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::{mpsc, watch};
+
+use crate::context;
+use crate::error::{Error, Result};
+use crate::proto::{Code, Codec, GenMessage, KeyValue, Message, Request, Response};
+use crate::r#async::stream::ResultSender;
+use crate::r#async::utils::get_path;
+use crate::MethodInfo;
+
+pub(crate) const HANDSHAKE_SERVICE: &str = "ttrpc.Handshake";
+pub(crate) const HANDSHAKE_METHOD: &str = "Exchange";
+
+pub(crate) const CAPABILITIES_KEY: &str = "ttrpc-capabilities";
+/// Calls of unary methods may be made as streams with one message each way.
+pub(crate) const CAPABILITY_UNARY_OVER_STREAM: &str = "unary-over-stream";
+pub(crate) const RESTART_EPOCH_KEY: &str = "ttrpc-restart-epoch";
+pub(crate) const SCHEMA_DIGESTS_KEY: &str = "ttrpc-schema-digests";
+pub(crate) const SCHEMA_DRIFT_KEY: &str = "ttrpc-schema-drift";
+
+/// Calls may be abandoned with a `MESSAGE_TYPE_CANCEL` message.
+pub(crate) const CAPABILITY_CANCEL: &str = "cancel";
+
+/// The schema version of the client, stored in the extensions of its connection.
+#[derive(Clone, Debug)]
+pub(crate) struct PeerVersion(pub(crate) String);
+
+/// The methods of a connection whose calls are rejected for having drifted, see
+/// [`SchemaDrift::Reject`], stored in the extensions of the connection.
+#[derive(Clone, Debug)]
+pub(crate) struct DriftedMethods(pub(crate) Arc<HashSet<String>>);
+
+/// What a server does about the methods a client was generated from another
+/// revision of, see
+/// [`Server::check_schema_digests`](crate::r#async::Server::check_schema_digests).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SchemaDrift {
+    /// Log the methods, and handle their calls as usual.
+    Log,
+    /// Log the methods, and fail their calls with `FAILED_PRECONDITION`.
+    Reject,
+}
+
+/// The answer of the server to the handshake.
+#[derive(Clone, Debug)]
+pub(crate) struct ServerHello {
+    pub(crate) version: String,
+    pub(crate) capabilities: Vec<String>,
+    pub(crate) restart_epoch: Option<u64>,
+    /// The methods the server found generated from another revision of the schema.
+    pub(crate) drifted: Vec<String>,
+}
+
+/// Returns the schema digests of `methods` by path.
+pub(crate) fn schema_digests(methods: &[MethodInfo]) -> HashMap<String, u64> {
+    methods
+        .iter()
+        .map(|m| (get_path(m.service, m.method), m.schema_digest))
+        .collect()
+}
+
+/// Returns the methods of `metadata`, the metadata of a handshake request, whose
+/// digest differs from the one in `digests`. The methods unknown to either side
+/// are left out.
+pub(crate) fn drifted_methods(
+    digests: &HashMap<String, u64>,
+    metadata: &[KeyValue],
+) -> Vec<String> {
+    let mut drifted: Vec<String> = metadata
+        .iter()
+        .filter(|kv| kv.key == SCHEMA_DIGESTS_KEY)
+        .filter_map(|kv| {
+            let (path, digest) = kv.value.rsplit_once('=')?;
+            let digest = u64::from_str_radix(digest, 16).ok()?;
+            let ours = digests.get(path)?;
+            (*ours != digest).then(|| path.to_string())
+        })
+        .collect();
+    drifted.sort();
+    drifted.dedup();
+    drifted
+}
+
+/// The answer of the server, `None` until the handshake finished and if the
+/// server did not take part in it.
+pub(crate) type ServerHelloReceiver = watch::Receiver<Option<Option<ServerHello>>>;
+
+#[derive(Clone, Debug)]
+pub(crate) struct ClientHandshake {
+    version: String,
+    /// The `/service/method=digest` values of the client.
+    digests: Vec<String>,
+    hello: Arc<watch::Sender<Option<Option<ServerHello>>>>,
+}
+
+impl ClientHandshake {
+    /// Returns the handshake of a client with `version` and the schema digests of
+    /// `methods`, and the receiver of the answer of the server. A client without
+    /// either doesn't shake hands.
+    pub(crate) fn new(
+        version: Option<&str>,
+        methods: &[MethodInfo],
+    ) -> (Option<ClientHandshake>, ServerHelloReceiver) {
+        if version.is_none() && methods.is_empty() {
+            return (None, watch::channel(Some(None)).1);
+        }
+        let mut digests: Vec<String> = schema_digests(methods)
+            .into_iter()
+            .map(|(path, digest)| format!("{path}={digest:016x}"))
+            .collect();
+        digests.sort();
+        let (tx, rx) = watch::channel(None);
+        let handshake = ClientHandshake {
+            version: version.unwrap_or_default().to_string(),
+            digests,
+            hello: Arc::new(tx),
+        };
+        (Some(handshake), rx)
+    }
+
+    /// Builds the handshake request of a new connection, and waits for the response
+    /// in the background.
+    pub(crate) fn start(
+        &self,
+        next_stream_id: &AtomicU32,
+        streams: &Mutex<HashMap<u32, ResultSender>>,
+    ) -> Result<GenMessage> {
+        let stream_id = next_stream_id.fetch_add(2, Ordering::Relaxed);
+        let req = Request {
+            service: HANDSHAKE_SERVICE.to_string(),
+            method: HANDSHAKE_METHOD.to_string(),
+            payload: self.version.clone().into_bytes(),
+            metadata: self
+                .digests
+                .iter()
+                .map(|value| KeyValue {
+                    key: SCHEMA_DIGESTS_KEY.to_string(),
+                    value: value.clone(),
+                    ..Default::default()
+                })
+                .collect(),
+            ..Default::default()
+        };
+        let msg: GenMessage = Message::new_request(stream_id, req)?
+            .try_into()
+            .map_err(|e: protobuf::Error| Error::Others(e.to_string()))?;
+
+        let (tx, mut rx) = mpsc::channel(1);
+        streams.lock().unwrap().insert(stream_id, tx);
+        // The answer of the previous connection may come from another server.
+        self.hello.send_replace(None);
+        let hello = self.hello.clone();
+        tokio::spawn(async move {
+            let answer = match rx.recv().await {
+                Some(Ok(msg)) => Response::decode(msg.payload)
+                    .ok()
+                    .filter(|res| res.status().code() == Code::OK)
+                    .map(|res| {
+                        let mut metadata = context::from_pb(&res.metadata);
+                        ServerHello {
+                            version: String::from_utf8_lossy(&res.payload).into_owned(),
+                            capabilities: metadata.remove(CAPABILITIES_KEY).unwrap_or_default(),
+                            restart_epoch: metadata
+                                .remove(RESTART_EPOCH_KEY)
+                                .and_then(|values| values.first()?.parse().ok()),
+                            drifted: metadata.remove(SCHEMA_DRIFT_KEY).unwrap_or_default(),
+                        }
+                    }),
+                _ => None,
+            };
+            match &answer {
+                Some(hello) => {
+                    for path in hello.drifted.iter() {
+                        warn!("{} of the server was generated from another schema", path);
+                    }
+                }
+                None => debug!("Server did not take part in the handshake"),
+            }
+            hello.send_replace(Some(answer));
+        });
+        Ok(msg)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MethodType;
+
+    fn method(method: &'static str, schema_digest: u64) -> MethodInfo {
+        MethodInfo {
+            service: "grpc.Health",
+            method,
+            method_type: MethodType::Unary,
+            schema_digest,
+        }
+    }
+
+    #[test]
+    fn test_drifted_methods() {
+        let digests = schema_digests(&[method("Check", 1), method("Version", 2)]);
+        let metadata: Vec<KeyValue> = [
+            "/grpc.Health/Version=3",
+            "/grpc.Health/Check=1",
+            "/grpc.Health/Watch=4",
+        ]
+        .iter()
+        .map(|value| KeyValue {
+            key: SCHEMA_DIGESTS_KEY.to_string(),
+            value: value.to_string(),
+            ..Default::default()
+        })
+        .collect();
+        assert_eq!(
+            drifted_methods(&digests, &metadata),
+            vec!["/grpc.Health/Version"]
+        );
+        assert!(drifted_methods(&digests, &[]).is_empty());
+    }
+}
@@ -0,0 +1,135 @@
+// Copyright (c) 2023 Ant Group
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Gauges of the internal queues of client and server connections, and timings of
+//! client calls.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use tokio::sync::mpsc;
+
+use crate::r#async::stream::{MessageSender, ResultSender};
+
+/// A snapshot of the internal queues of one connection.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DebugState {
+    /// Messages queued for the writer task but not yet written to the socket.
+    pub writer_queue_depth: usize,
+    /// Calls and streams that have been started but not yet finished.
+    pub pending_calls: usize,
+    /// Messages buffered for each stream and not yet consumed, keyed by stream id.
+    pub stream_buffers: HashMap<u32, usize>,
+}
+
+impl DebugState {
+    pub(crate) fn collect(tx: &MessageSender, streams: &Mutex<HashMap<u32, ResultSender>>) -> Self {
+        let streams = streams.lock().unwrap();
+        DebugState {
+            writer_queue_depth: queue_depth(tx),
+            pending_calls: streams.len(),
+            stream_buffers: streams
+                .iter()
+                .map(|(id, tx)| (*id, queue_depth(tx)))
+                .collect(),
+        }
+    }
+}
+
+/// Receives [`DebugState`] samples of a connection.
+///
+/// Client samples are taken whenever a call is issued, server samples whenever
+/// a message is read from the connection.
+pub trait MetricsHook {
+    fn on_state(&self, state: &DebugState);
+}
+
+fn queue_depth<T>(tx: &mpsc::Sender<T>) -> usize {
+    tx.max_capacity() - tx.capacity()
+}
+
+/// When the steps of a client call happened, see
+/// [`CallOptions::timing`](crate::r#async::CallOptions::timing).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CallTiming {
+    /// The request was queued for the writer task.
+    pub queued: Instant,
+    /// The request was written to the connection.
+    pub written: Option<Instant>,
+    /// The response was read from the connection.
+    pub received: Option<Instant>,
+    /// The response was decoded.
+    pub decoded: Option<Instant>,
+}
+
+impl CallTiming {
+    pub(crate) fn new() -> Self {
+        CallTiming {
+            queued: Instant::now(),
+            written: None,
+            received: None,
+            decoded: None,
+        }
+    }
+
+    /// Time spent in the local send queue.
+    pub fn queue_time(&self) -> Option<Duration> {
+        Some(self.written?.saturating_duration_since(self.queued))
+    }
+
+    /// Time between writing the request and reading the response, i.e. spent in the
+    /// network and the server.
+    pub fn round_trip_time(&self) -> Option<Duration> {
+        Some(self.received?.saturating_duration_since(self.written?))
+    }
+
+    /// Time between reading and decoding the response, this includes the wait for
+    /// the calling task to be scheduled.
+    pub fn decode_time(&self) -> Option<Duration> {
+        Some(self.decoded?.saturating_duration_since(self.received?))
+    }
+}
+
+/// Timings of the calls in progress which asked for them, keyed by stream id.
+pub(crate) type CallTimings = Arc<Mutex<HashMap<u32, CallTiming>>>;
+
+/// Sets the step of the call on `stream_id` selected by `step` to now, if the call
+/// is timed.
+pub(crate) fn record_timing(
+    timings: &CallTimings,
+    stream_id: u32,
+    step: fn(&mut CallTiming) -> &mut Option<Instant>,
+) {
+    if let Some(timing) = timings.lock().unwrap().get_mut(&stream_id) {
+        *step(timing) = Some(Instant::now());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::proto::GenMessage;
+
+    #[tokio::test]
+    async fn test_collect() {
+        let (tx, _rx) = mpsc::channel(10);
+        let streams = Mutex::new(HashMap::new());
+        let (stream_tx, _stream_rx) = mpsc::channel(10);
+        streams.lock().unwrap().insert(1, stream_tx.clone());
+        streams.lock().unwrap().insert(3, mpsc::channel(10).0);
+
+        tx.send(GenMessage::default()).await.unwrap();
+        tx.send(GenMessage::default()).await.unwrap();
+        stream_tx.send(Ok(GenMessage::default())).await.unwrap();
+
+        let state = DebugState::collect(&tx, &streams);
+        assert_eq!(state.writer_queue_depth, 2);
+        assert_eq!(state.pending_calls, 2);
+        assert_eq!(state.stream_buffers.get(&1), Some(&1));
+        assert_eq!(state.stream_buffers.get(&3), Some(&0));
+    }
+}
@@ -5,15 +5,45 @@
 
 //! Server and client in async mode (alias r#async).
 
+mod adaptive;
+mod admin;
+mod blocking;
+mod breaker;
+mod budget;
+#[cfg(feature = "capture")]
+mod capture;
+mod child;
 mod client;
+mod clock;
+mod compression;
+#[cfg(feature = "config")]
+mod config;
 mod server;
 mod stream;
 #[macro_use]
 #[doc(hidden)]
 mod utils;
 mod connection;
+mod connectivity;
+mod credentials;
+mod decode_limit;
+mod dial;
+mod extensions;
+mod fair;
+mod faults;
+mod handshake;
+mod metrics;
+mod notifications;
+mod ping;
+mod rate_limit;
+mod resolver;
+mod seqpacket;
 pub mod shutdown;
+pub mod testing;
+#[cfg(feature = "tls")]
+mod tls;
 mod unix_incoming;
+mod watch;
 
 pub use self::stream::{
     CSReceiver, CSSender, ClientStream, ClientStreamReceiver, ClientStreamSender, Kind, SSReceiver,
@@ -21,8 +51,65 @@ pub use self::stream::{
     StreamSender,
 };
 #[doc(inline)]
-pub use crate::r#async::client::Client;
+pub use crate::r#async::adaptive::AdaptiveLimit;
 #[doc(inline)]
-pub use crate::r#async::server::{Server, Service};
+pub use crate::r#async::blocking::BlockingClient;
 #[doc(inline)]
-pub use utils::{MethodHandler, StreamHandler, TtrpcContext};
+pub use crate::r#async::breaker::{BreakerHook, BreakerState, CircuitBreaker};
+#[doc(inline)]
+pub use crate::r#async::budget::MemoryBudget;
+#[cfg(feature = "capture")]
+#[doc(inline)]
+pub use crate::r#async::capture::Capture;
+#[doc(inline)]
+pub use crate::r#async::child::ScopedChild;
+#[doc(inline)]
+pub use crate::r#async::client::{
+    Balancing, CallOptions, Channel, Client, ClientBuilder, ClientPool, Interceptor, Next,
+    ReconnectPolicy, RetryPolicy,
+};
+#[doc(inline)]
+pub use crate::r#async::clock::{Clock, MockClock, TokioClock};
+#[doc(inline)]
+pub use crate::r#async::compression::{Compression, Compressor};
+#[cfg(feature = "config")]
+#[doc(inline)]
+pub use crate::r#async::config::ServerConfig;
+#[doc(inline)]
+pub use crate::r#async::connection::{ConnectHook, RawStream};
+#[doc(inline)]
+pub use crate::r#async::connectivity::{ConnectivityState, StateWatcher};
+#[doc(inline)]
+pub use crate::r#async::credentials::Credentials;
+#[doc(inline)]
+pub use crate::r#async::extensions::Extensions;
+#[doc(inline)]
+pub use crate::r#async::faults::{Fault, FaultInjector};
+#[doc(inline)]
+pub use crate::r#async::handshake::SchemaDrift;
+#[doc(inline)]
+pub use crate::r#async::metrics::{CallTiming, DebugState, MetricsHook};
+#[doc(inline)]
+pub use crate::r#async::notifications::{Notifications, Notifier};
+#[doc(inline)]
+pub use crate::r#async::ping::{PingHandler, PING_PAYLOAD_MAX};
+#[doc(inline)]
+pub use crate::r#async::rate_limit::RateLimit;
+#[doc(inline)]
+pub use crate::r#async::resolver::{FileResolver, Resolver, StaticResolver};
+#[doc(inline)]
+pub use crate::r#async::server::{
+    Authorizer, ConnectionInfo, ErrorRedactor, Middleware, Priority, Server, ServerInterceptor,
+    Service, Weigher,
+};
+#[doc(inline)]
+pub use crate::r#async::watch::{Broadcaster, WatchEvent, Watcher};
+/// The TLS library, whose `ClientConfig` [`ClientBuilder::tls_config`] takes.
+#[cfg(feature = "tls")]
+pub use tokio_rustls::rustls;
+#[doc(hidden)]
+pub use utils::ResponsePayload;
+#[doc(inline)]
+pub use utils::{
+    DynService, EncodedResponse, MethodHandler, ResponseMetadata, StreamHandler, TtrpcContext,
+};
@@ -0,0 +1,237 @@
+// Copyright (c) 2023 Ant Group
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Notifications pushed by the server without a call per event type.
+//!
+//! A client which subscribed opens a stream on the reserved
+//! `ttrpc.Notifications/Subscribe` method when connecting. The server never
+//! responds to it, but sends a data message with an encoded [`Notification`] on
+//! the stream for every notification, until the connection is closed.
+
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Mutex;
+
+use tokio::sync::{broadcast, mpsc};
+
+use crate::error::{Error, Result};
+use crate::proto::{
+    Codec, GenMessage, Message, MessageHeader, Notification, Request, FLAG_REMOTE_CLOSED,
+    MESSAGE_TYPE_DATA,
+};
+use crate::r#async::stream::{MessageSender, ResultSender};
+
+pub(crate) const NOTIFICATIONS_SERVICE: &str = "ttrpc.Notifications";
+pub(crate) const NOTIFICATIONS_METHOD: &str = "Subscribe";
+
+pub(crate) fn is_subscription(req: &Request) -> bool {
+    req.service == NOTIFICATIONS_SERVICE && req.method == NOTIFICATIONS_METHOD
+}
+
+/// Notifications buffered per receiver, older ones are dropped if it falls behind.
+const NOTIFICATIONS_BUFFER: usize = 64;
+
+/// Sends notifications to a client which subscribed, see
+/// [`TtrpcContext::notifier`](crate::r#async::TtrpcContext::notifier) and
+/// [`Server::notifier`](crate::r#async::Server::notifier).
+#[derive(Clone, Debug)]
+pub struct Notifier {
+    tx: MessageSender,
+    stream_id: u32,
+}
+
+impl Notifier {
+    pub(crate) fn new(tx: MessageSender, stream_id: u32) -> Notifier {
+        Notifier { tx, stream_id }
+    }
+
+    /// Push `payload` to the client, `topic` tells it how to decode the payload.
+    pub async fn notify(&self, topic: &str, payload: Vec<u8>) -> Result<()> {
+        let notification = Notification {
+            topic: topic.to_string(),
+            payload,
+            ..Default::default()
+        };
+        let payload = notification
+            .encode()
+            .map_err(err_to_others_err!(e, "Encode notification failed."))?;
+        let msg = GenMessage {
+            header: MessageHeader::new_data(self.stream_id, payload.len() as u32),
+            payload,
+        };
+        self.tx.send(msg).await.map_err(|_| Error::LocalClosed)
+    }
+}
+
+/// Receives the notifications pushed by the server, see
+/// [`ClientBuilder::notifications`](crate::r#async::ClientBuilder::notifications).
+#[derive(Debug)]
+pub struct Notifications {
+    rx: broadcast::Receiver<Notification>,
+}
+
+impl Notifications {
+    /// Returns the next notification, or `None` once the client is gone.
+    ///
+    /// Notifications are missed if the receiver falls far behind.
+    pub async fn recv(&mut self) -> Option<Notification> {
+        loop {
+            match self.rx.recv().await {
+                Ok(notification) => return Some(notification),
+                Err(broadcast::error::RecvError::Lagged(n)) => {
+                    warn!("Missed {} notifications", n);
+                }
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    }
+}
+
+/// The subscription of a client, renewed on every connection.
+#[derive(Clone, Debug)]
+pub(crate) struct ClientSubscription {
+    tx: broadcast::Sender<Notification>,
+}
+
+impl ClientSubscription {
+    pub(crate) fn new() -> ClientSubscription {
+        ClientSubscription {
+            tx: broadcast::channel(NOTIFICATIONS_BUFFER).0,
+        }
+    }
+
+    pub(crate) fn receiver(&self) -> Notifications {
+        Notifications {
+            rx: self.tx.subscribe(),
+        }
+    }
+
+    /// Builds the subscribe request of a new connection, and forwards the
+    /// notifications received on it in the background.
+    pub(crate) fn start(
+        &self,
+        next_stream_id: &AtomicU32,
+        streams: &Mutex<HashMap<u32, ResultSender>>,
+    ) -> Result<GenMessage> {
+        let stream_id = next_stream_id.fetch_add(2, Ordering::Relaxed);
+        let req = Request {
+            service: NOTIFICATIONS_SERVICE.to_string(),
+            method: NOTIFICATIONS_METHOD.to_string(),
+            ..Default::default()
+        };
+        let mut msg: GenMessage = Message::new_request(stream_id, req)?
+            .try_into()
+            .map_err(|e: protobuf::Error| Error::Others(e.to_string()))?;
+        msg.header.add_flags(FLAG_REMOTE_CLOSED);
+
+        let (tx, mut rx) = mpsc::channel(NOTIFICATIONS_BUFFER);
+        streams.lock().unwrap().insert(stream_id, tx);
+        let notifications = self.tx.clone();
+        tokio::spawn(async move {
+            // Ends with the connection, or with the response of a server which
+            // doesn't support notifications.
+            while let Some(Ok(msg)) = rx.recv().await {
+                if msg.header.type_ != MESSAGE_TYPE_DATA {
+                    debug!("Server does not support notifications");
+                    break;
+                }
+                match Notification::decode(&msg.payload) {
+                    Ok(notification) => {
+                        notifications.send(notification).ok();
+                    }
+                    Err(e) => error!("Decode notification failed: {:?}", e),
+                }
+            }
+        });
+        Ok(msg)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    use async_trait::async_trait;
+
+    use crate::proto::Response;
+    use crate::r#async::{
+        testing, Client, ClientBuilder, MethodHandler, Server, Service, TtrpcContext,
+    };
+
+    /// Notifies the client which called it.
+    struct NotifyMe;
+
+    #[async_trait]
+    impl MethodHandler for NotifyMe {
+        async fn handler(&self, ctx: TtrpcContext, req: Request) -> Result<Response> {
+            if let Some(notifier) = ctx.notifier() {
+                notifier.notify("me", req.payload).await?;
+            }
+            Ok(Response::default())
+        }
+    }
+
+    fn request() -> Request {
+        Request {
+            service: "test.Test".to_string(),
+            method: "NotifyMe".to_string(),
+            payload: b"called".to_vec(),
+            timeout_nano: 5_000_000_000,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_notifications() {
+        let mut methods: HashMap<String, Box<dyn MethodHandler + Send + Sync>> = HashMap::new();
+        methods.insert("NotifyMe".to_string(), Box::new(NotifyMe));
+        let mut services = HashMap::new();
+        services.insert(
+            "test.Test".to_string(),
+            Service {
+                methods,
+                streams: HashMap::new(),
+            },
+        );
+        let server = testing::start(Server::new().register_service(services))
+            .await
+            .unwrap();
+
+        let other = Client::connect(&server.address()).unwrap();
+        other.request(request()).await.unwrap();
+        assert!(other.notifications().is_none());
+        let other_id = server.server().connections()[0].id;
+
+        let client = ClientBuilder::new(&server.address())
+            .notifications()
+            .build()
+            .unwrap();
+        let mut notifications = client.notifications().unwrap();
+        // The subscription is sent before the call, on the same connection.
+        client.request(request()).await.unwrap();
+        let notification = notifications.recv().await.unwrap();
+        assert_eq!(notification.topic, "me");
+        assert_eq!(notification.payload, b"called");
+
+        // Only the subscribed client gets a broadcast.
+        assert!(server.server().notifier(other_id).is_none());
+        server.server().broadcast("all", b"news").await;
+        let notification = notifications.recv().await.unwrap();
+        assert_eq!(notification.topic, "all");
+        assert_eq!(notification.payload, b"news");
+    }
+
+    #[test]
+    fn test_notifications_idle_timeout() {
+        let res = ClientBuilder::new("unix:///run/ttrpc-test.sock")
+            .notifications()
+            .idle_timeout(Duration::from_secs(1))
+            .build();
+        assert!(matches!(res, Err(Error::Others(e)) if e.contains("idle timeout")));
+    }
+}
@@ -0,0 +1,173 @@
+// Copyright (c) 2023 Ant Group
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Ping frames, which clients and servers send each other outside of any call.
+//!
+//! A ping carries a small opaque payload, and is answered with a pong carrying the
+//! payload returned by the [`PingHandler`] of the peer, e.g. its clock to measure
+//! the skew between both. The stream id of ping and pong frames is the id of the
+//! ping, not of a stream.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::oneshot;
+
+use crate::error::{get_rpc_status, Error, Result};
+use crate::proto::{Code, GenMessage, MessageHeader, MESSAGE_TYPE_PING, MESSAGE_TYPE_PONG};
+use crate::r#async::stream::MessageSender;
+
+/// Largest payload of ping and pong frames.
+pub const PING_PAYLOAD_MAX: usize = 256;
+
+/// Answers the pings of the peer, see
+/// [`Server::on_ping`](crate::r#async::Server::on_ping) and
+/// [`Client::on_ping`](crate::r#async::Client::on_ping).
+///
+/// Runs on the task reading the connection, so it must return quickly.
+pub trait PingHandler {
+    /// Returns the payload of the pong, truncated to [`PING_PAYLOAD_MAX`] bytes.
+    fn on_ping(&self, payload: &[u8]) -> Vec<u8>;
+}
+
+/// The ping handler of a client, shared by all its connections.
+pub(crate) type PingHandlerSlot = Arc<Mutex<Option<Arc<dyn PingHandler + Send + Sync>>>>;
+
+/// Pings sent on a connection, waiting for their pong.
+#[derive(Clone, Default)]
+pub(crate) struct Pings {
+    next_id: Arc<AtomicU32>,
+    waiters: Arc<Mutex<HashMap<u32, oneshot::Sender<Vec<u8>>>>>,
+}
+
+impl Pings {
+    /// Sends a ping with `payload` and returns the payload of the pong.
+    pub(crate) async fn ping(&self, tx: &MessageSender, payload: &[u8]) -> Result<Vec<u8>> {
+        if payload.len() > PING_PAYLOAD_MAX {
+            return Err(get_rpc_status(
+                Code::INVALID_ARGUMENT,
+                format!("ping payload is larger than {PING_PAYLOAD_MAX} bytes"),
+            ));
+        }
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (pong_tx, pong_rx) = oneshot::channel();
+        self.waiters.lock().unwrap().insert(id, pong_tx);
+        let _forget = ForgetOnDrop { pings: self, id };
+
+        tx.send(frame(MESSAGE_TYPE_PING, id, payload.to_vec()))
+            .await
+            .map_err(|e| Error::Others(format!("Send packet to sender error {e:?}")))?;
+        // The waiters are dropped when the connection is lost.
+        pong_rx.await.map_err(|_| Error::RemoteClosed)
+    }
+
+    /// Hands the payload of a pong to its ping.
+    pub(crate) fn pong(&self, msg: GenMessage) {
+        match self.waiters.lock().unwrap().remove(&msg.header.stream_id) {
+            Some(waiter) => waiter.send(msg.payload).unwrap_or_default(),
+            None => debug!("Got unexpected pong {}", msg.header.stream_id),
+        }
+    }
+
+    /// Fails the pings waiting for their pong.
+    pub(crate) fn clear(&self) {
+        self.waiters.lock().unwrap().clear();
+    }
+}
+
+struct ForgetOnDrop<'a> {
+    pings: &'a Pings,
+    id: u32,
+}
+
+impl Drop for ForgetOnDrop<'_> {
+    fn drop(&mut self) {
+        self.pings.waiters.lock().unwrap().remove(&self.id);
+    }
+}
+
+/// Returns the pong answering `ping`, with the payload returned by `handler` if any.
+pub(crate) fn pong(
+    handler: Option<&(dyn PingHandler + Send + Sync)>,
+    ping: &GenMessage,
+) -> GenMessage {
+    let mut payload = handler.map_or_else(Vec::new, |h| h.on_ping(&ping.payload));
+    payload.truncate(PING_PAYLOAD_MAX);
+    frame(MESSAGE_TYPE_PONG, ping.header.stream_id, payload)
+}
+
+pub(crate) fn frame(type_: u8, id: u32, payload: Vec<u8>) -> GenMessage {
+    GenMessage {
+        header: MessageHeader {
+            length: payload.len() as u32,
+            stream_id: id,
+            type_,
+            flags: 0,
+        },
+        payload,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::{Duration, Instant};
+
+    use crate::r#async::{testing, Client, Clock, MockClock, Server};
+
+    /// Answers with the time of its clock, in milliseconds, to measure the skew.
+    struct Now {
+        clock: MockClock,
+        start: Instant,
+    }
+
+    impl PingHandler for Now {
+        fn on_ping(&self, payload: &[u8]) -> Vec<u8> {
+            assert_eq!(payload, b"now?");
+            let now = self.clock.now() - self.start;
+            now.as_millis().to_string().into_bytes()
+        }
+    }
+
+    /// Answers with more than a pong can carry.
+    struct Large;
+
+    impl PingHandler for Large {
+        fn on_ping(&self, _payload: &[u8]) -> Vec<u8> {
+            vec![1; PING_PAYLOAD_MAX * 2]
+        }
+    }
+
+    #[tokio::test]
+    async fn test_ping() {
+        let clock = MockClock::new();
+        let now = Now {
+            clock: clock.clone(),
+            start: clock.now(),
+        };
+        let server = testing::start(Server::new().on_ping(Arc::new(now)))
+            .await
+            .unwrap();
+        let client = Client::connect(&server.address())
+            .unwrap()
+            .on_ping(Arc::new(Large));
+
+        assert_eq!(client.ping(b"now?").await.unwrap(), b"0");
+        clock.advance(Duration::from_secs(5));
+        assert_eq!(client.ping(b"now?").await.unwrap(), b"5000");
+
+        // And the other way round, the pong is truncated.
+        let id = server.server().connections()[0].id;
+        let pong = server.server().ping(id, b"").await.unwrap();
+        assert_eq!(pong, vec![1; PING_PAYLOAD_MAX]);
+
+        match client.ping(&[0; PING_PAYLOAD_MAX + 1]).await {
+            Err(Error::RpcStatus(status)) => assert_eq!(status.code(), Code::INVALID_ARGUMENT),
+            res => panic!("unexpected {:?}", res),
+        }
+    }
+}
@@ -0,0 +1,153 @@
+// Copyright (c) 2023 Ant Group
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Limits of the rate at which messages are read from a connection, or written
+//! to all connections of a server.
+
+use std::time::Duration;
+
+use tokio::time::{sleep, Instant};
+
+/// Caps on the bytes and messages read per second from a single connection, or
+/// written per second to all connections of a server.
+///
+/// Once a connection exceeds a cap, reading from it is delayed until it is back
+/// within the limit, so the peer is slowed down by the socket buffer filling up.
+/// A connection may send up to one second's worth of messages in a burst. Writes
+/// are delayed the same way.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RateLimit {
+    bytes_per_second: Option<u64>,
+    frames_per_second: Option<u64>,
+}
+
+impl RateLimit {
+    pub fn new() -> RateLimit {
+        RateLimit::default()
+    }
+
+    /// Transfer at most `bytes` bytes per second, including the message headers.
+    pub fn bytes_per_second(mut self, bytes: u64) -> RateLimit {
+        assert!(bytes > 0, "bytes per second must be positive");
+        self.bytes_per_second = Some(bytes);
+        self
+    }
+
+    /// Transfer at most `frames` messages per second.
+    pub fn frames_per_second(mut self, frames: u64) -> RateLimit {
+        assert!(frames > 0, "frames per second must be positive");
+        self.frames_per_second = Some(frames);
+        self
+    }
+}
+
+/// A token bucket for each cap of a [`RateLimit`].
+///
+/// A message is accounted for after it has been read, as its size is only known
+/// then, so a large message may take the buckets below zero.
+pub(crate) struct RateLimiter {
+    buckets: Vec<Bucket>,
+    last: Instant,
+}
+
+struct Bucket {
+    rate: f64,
+    tokens: f64,
+    per_message: bool,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(limit: RateLimit) -> RateLimiter {
+        let bucket = |rate: u64, per_message| Bucket {
+            rate: rate as f64,
+            tokens: rate as f64,
+            per_message,
+        };
+        let buckets = limit
+            .bytes_per_second
+            .map(|rate| bucket(rate, false))
+            .into_iter()
+            .chain(limit.frames_per_second.map(|rate| bucket(rate, true)))
+            .collect();
+        RateLimiter {
+            buckets,
+            last: Instant::now(),
+        }
+    }
+
+    /// Accounts for a message of `bytes` bytes which has been read.
+    pub(crate) fn consume(&mut self, bytes: usize) {
+        self.refill();
+        for bucket in self.buckets.iter_mut() {
+            bucket.tokens -= if bucket.per_message {
+                1.0
+            } else {
+                bytes as f64
+            };
+        }
+    }
+
+    /// Waits until the next message may be read.
+    pub(crate) async fn ready(&mut self) {
+        let delay = self.delay();
+        if !delay.is_zero() {
+            sleep(delay).await;
+        }
+    }
+
+    /// How long it takes until all buckets are refilled to at least one token.
+    fn delay(&mut self) -> Duration {
+        self.refill();
+        self.buckets
+            .iter()
+            .filter(|bucket| bucket.tokens < 1.0)
+            .map(|bucket| Duration::from_secs_f64((1.0 - bucket.tokens) / bucket.rate))
+            .max()
+            .unwrap_or_default()
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.last = now;
+        for bucket in self.buckets.iter_mut() {
+            bucket.tokens = (bucket.tokens + elapsed * bucket.rate).min(bucket.rate);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_delay(limiter: &mut RateLimiter, expected: Duration) {
+        let delay = limiter.delay();
+        assert!(
+            delay <= expected && delay + Duration::from_millis(50) > expected,
+            "{:?}",
+            delay
+        );
+    }
+
+    #[test]
+    fn test_rate_limiter() {
+        let mut limiter = RateLimiter::new(RateLimit::new().bytes_per_second(1000));
+        limiter.consume(600);
+        assert_eq!(limiter.delay(), Duration::ZERO);
+        limiter.consume(600);
+        assert_delay(&mut limiter, Duration::from_millis(201));
+
+        let mut limiter = RateLimiter::new(
+            RateLimit::new()
+                .frames_per_second(2)
+                .bytes_per_second(1 << 20),
+        );
+        limiter.consume(1);
+        limiter.consume(1);
+        assert_delay(&mut limiter, Duration::from_millis(500));
+        limiter.consume(1);
+        assert_delay(&mut limiter, Duration::from_millis(1000));
+    }
+}
@@ -0,0 +1,89 @@
+// Copyright (c) 2023 Ant Group
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Resolution of client targets to socket addresses.
+
+use async_trait::async_trait;
+
+use crate::address::Address;
+use crate::error::{Error, Result};
+
+/// Resolves the target of a client to the addresses of its server, see
+/// [`ClientBuilder::resolver`](crate::r#async::ClientBuilder::resolver).
+///
+/// The target is resolved again before every dial, so the server may move, e.g.
+/// a sandbox re-created with a new socket path, without recreating the client.
+#[async_trait]
+pub trait Resolver {
+    /// Returns the addresses of `target`, in the order they should be tried.
+    async fn resolve(&self, target: &str) -> Result<Vec<Address>>;
+}
+
+/// Resolves any target to a fixed list of addresses.
+#[derive(Clone, Debug)]
+pub struct StaticResolver {
+    addresses: Vec<Address>,
+}
+
+impl StaticResolver {
+    pub fn new(addresses: Vec<Address>) -> StaticResolver {
+        StaticResolver { addresses }
+    }
+}
+
+#[async_trait]
+impl Resolver for StaticResolver {
+    async fn resolve(&self, _target: &str) -> Result<Vec<Address>> {
+        Ok(self.addresses.clone())
+    }
+}
+
+/// Resolves a target to the addresses listed in the file at that path, read
+/// again on every resolution so whoever manages the server can update it.
+///
+/// The file holds one address per line, e.g. `unix:///run/sandbox/ttrpc.sock`.
+/// Blank lines and lines starting with `#` are ignored.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FileResolver;
+
+#[async_trait]
+impl Resolver for FileResolver {
+    async fn resolve(&self, target: &str) -> Result<Vec<Address>> {
+        let path = target.to_string();
+        let content = tokio::task::spawn_blocking(move || std::fs::read_to_string(path))
+            .await
+            .map_err(err_to_others_err!(e, "Read address file failed: "))?
+            .map_err(|e| Error::Others(format!("Read address file {target} failed: {e}")))?;
+        parse_addresses(&content)
+            .map_err(|e| Error::Others(format!("Invalid address in {target}: {e:?}")))
+    }
+}
+
+fn parse_addresses(content: &str) -> Result<Vec<Address>> {
+    content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::parse)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_addresses() {
+        let content = "# sandbox 1\nunix:///run/a.sock\n\n  vsock://3:1024  \n";
+        assert_eq!(
+            parse_addresses(content).unwrap(),
+            vec![
+                Address::Unix("/run/a.sock".to_string()),
+                crate::address::VsockAddr::new(3, 1024).into(),
+            ]
+        );
+        assert!(parse_addresses("/run/a.sock").is_err());
+    }
+}
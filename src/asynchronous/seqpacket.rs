@@ -0,0 +1,403 @@
+// Copyright (c) 2023 Ant Group
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Connections of clients over Unix sockets of type `SOCK_SEQPACKET`, e.g. to
+//! `unixpacket:///run/agent.sock`.
+//!
+//! Every ttrpc message is sent as a single packet, header and payload together, as
+//! peers reading such sockets expect. A packet read is handed out over as many reads
+//! as needed, so the peer may split its messages over several packets as well.
+
+use std::io;
+use std::os::unix::io::{AsRawFd, IntoRawFd, RawFd};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::UnixStream;
+
+use crate::r#async::utils;
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub(crate) use self::linux::SeqPacketStream;
+
+/// The connected socket of a client.
+pub(crate) enum ClientSocket {
+    Stream(UnixStream),
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    SeqPacket(SeqPacketStream),
+    /// A socket secured with TLS, see
+    /// [`ClientBuilder::tls_config`](crate::r#async::ClientBuilder::tls_config).
+    #[cfg(feature = "tls")]
+    Tls(Box<tokio_rustls::client::TlsStream<ClientSocket>>),
+}
+
+impl ClientSocket {
+    /// Takes ownership of the connected socket `fd`, of whatever type.
+    pub(crate) fn from_raw_fd(fd: RawFd) -> ClientSocket {
+        let stream = utils::new_unix_stream_from_raw_fd(fd);
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        if let Ok(nix::sys::socket::SockType::SeqPacket) =
+            nix::sys::socket::getsockopt(fd, nix::sys::socket::sockopt::SockType)
+        {
+            return ClientSocket::SeqPacket(SeqPacketStream::new(stream));
+        }
+        ClientSocket::Stream(stream)
+    }
+
+    /// Closes the socket, returning the error of `close` which dropping it ignores.
+    pub(crate) fn close(self) -> io::Result<()> {
+        let stream = match self {
+            ClientSocket::Stream(s) => s,
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            ClientSocket::SeqPacket(s) => s.into_inner(),
+            #[cfg(feature = "tls")]
+            ClientSocket::Tls(s) => return s.into_inner().0.close(),
+        };
+        let fd = stream.into_std()?.into_raw_fd();
+        nix::unistd::close(fd).map_err(io::Error::from)
+    }
+}
+
+impl AsRawFd for ClientSocket {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            ClientSocket::Stream(s) => s.as_raw_fd(),
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            ClientSocket::SeqPacket(s) => s.as_raw_fd(),
+            #[cfg(feature = "tls")]
+            ClientSocket::Tls(s) => s.get_ref().0.as_raw_fd(),
+        }
+    }
+}
+
+impl AsyncRead for ClientSocket {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ClientSocket::Stream(s) => Pin::new(s).poll_read(cx, buf),
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            ClientSocket::SeqPacket(s) => Pin::new(s).poll_read(cx, buf),
+            #[cfg(feature = "tls")]
+            ClientSocket::Tls(s) => Pin::new(s).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for ClientSocket {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            ClientSocket::Stream(s) => Pin::new(s).poll_write(cx, buf),
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            ClientSocket::SeqPacket(s) => Pin::new(s).poll_write(cx, buf),
+            #[cfg(feature = "tls")]
+            ClientSocket::Tls(s) => Pin::new(s).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ClientSocket::Stream(s) => Pin::new(s).poll_flush(cx),
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            ClientSocket::SeqPacket(s) => Pin::new(s).poll_flush(cx),
+            #[cfg(feature = "tls")]
+            ClientSocket::Tls(s) => Pin::new(s).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ClientSocket::Stream(s) => Pin::new(s).poll_shutdown(cx),
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            ClientSocket::SeqPacket(s) => Pin::new(s).poll_shutdown(cx),
+            #[cfg(feature = "tls")]
+            ClientSocket::Tls(s) => Pin::new(s).poll_shutdown(cx),
+        }
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+mod linux {
+    use std::io;
+    use std::os::unix::io::{AsRawFd, RawFd};
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+
+    use futures::ready;
+    use nix::sys::socket::{recv, send, MsgFlags};
+    use tokio::io::{AsyncRead, AsyncWrite, Interest, ReadBuf};
+    use tokio::net::UnixStream;
+
+    use crate::proto::{MessageHeader, MESSAGE_HEADER_LENGTH};
+
+    /// A `SOCK_SEQPACKET` socket read and written as a stream of ttrpc messages.
+    pub(crate) struct SeqPacketStream {
+        // Only used to wait for the readiness of the socket.
+        inner: UnixStream,
+        // The packet being read, and the bytes of it already read.
+        read_buf: Vec<u8>,
+        read_pos: usize,
+        // The message being written, sent once complete.
+        write_buf: Vec<u8>,
+    }
+
+    impl SeqPacketStream {
+        pub(crate) fn new(inner: UnixStream) -> Self {
+            SeqPacketStream {
+                inner,
+                read_buf: Vec::new(),
+                read_pos: 0,
+                write_buf: Vec::new(),
+            }
+        }
+
+        /// Returns the socket, dropping the packets partially read or written.
+        pub(crate) fn into_inner(self) -> UnixStream {
+            self.inner
+        }
+
+        fn poll_recv(&self, cx: &mut Context<'_>) -> Poll<io::Result<Vec<u8>>> {
+            let fd = self.inner.as_raw_fd();
+            loop {
+                ready!(self.inner.poll_read_ready(cx))?;
+                match self.inner.try_io(Interest::READABLE, || recv_packet(fd)) {
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                    res => return Poll::Ready(res),
+                }
+            }
+        }
+
+        fn poll_send(&self, cx: &mut Context<'_>, packet: &[u8]) -> Poll<io::Result<()>> {
+            let fd = self.inner.as_raw_fd();
+            loop {
+                ready!(self.inner.poll_write_ready(cx))?;
+                // Packets are sent whole or not at all.
+                match self.inner.try_io(Interest::WRITABLE, || {
+                    Ok(send(fd, packet, MsgFlags::MSG_NOSIGNAL)?)
+                }) {
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                    res => return Poll::Ready(res.map(drop)),
+                }
+            }
+        }
+    }
+
+    fn recv_packet(fd: RawFd) -> io::Result<Vec<u8>> {
+        // The length of the next packet, which is left in the socket.
+        let len = recv(fd, &mut [0u8; 0], MsgFlags::MSG_PEEK | MsgFlags::MSG_TRUNC)?;
+        let mut packet = vec![0; len];
+        let n = recv(fd, &mut packet, MsgFlags::empty())?;
+        packet.truncate(n);
+        Ok(packet)
+    }
+
+    /// Returns the length of the message starting `buf`, once its header is complete.
+    fn message_len(buf: &[u8]) -> Option<usize> {
+        if buf.len() < MESSAGE_HEADER_LENGTH {
+            return None;
+        }
+        Some(MESSAGE_HEADER_LENGTH + MessageHeader::from(buf).length as usize)
+    }
+
+    impl AsRawFd for SeqPacketStream {
+        fn as_raw_fd(&self) -> RawFd {
+            self.inner.as_raw_fd()
+        }
+    }
+
+    impl AsyncRead for SeqPacketStream {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            let this = self.get_mut();
+            if this.read_pos == this.read_buf.len() {
+                // An empty packet is the end of the connection.
+                this.read_buf = ready!(this.poll_recv(cx))?;
+                this.read_pos = 0;
+            }
+            let n = buf.remaining().min(this.read_buf.len() - this.read_pos);
+            buf.put_slice(&this.read_buf[this.read_pos..this.read_pos + n]);
+            this.read_pos += n;
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    impl AsyncWrite for SeqPacketStream {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            let this = self.get_mut();
+            let len = this.write_buf.len();
+            // Take the bytes up to the end of the header, then of the message.
+            let end = message_len(&this.write_buf).unwrap_or(MESSAGE_HEADER_LENGTH);
+            let n = buf.len().min(end - len);
+            this.write_buf.extend_from_slice(&buf[..n]);
+            if message_len(&this.write_buf) == Some(this.write_buf.len()) {
+                match this.poll_send(cx, &this.write_buf) {
+                    Poll::Ready(Ok(())) => this.write_buf.clear(),
+                    res => {
+                        // The bytes are taken again by the next write.
+                        this.write_buf.truncate(len);
+                        return res.map_ok(|_| n);
+                    }
+                }
+            }
+            Poll::Ready(Ok(n))
+        }
+
+        /// Messages are sent as soon as they are complete, the bytes of an incomplete
+        /// message can't be sent.
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+        }
+    }
+}
+
+#[cfg(all(test, any(target_os = "linux", target_os = "android")))]
+mod tests {
+    use std::os::unix::io::RawFd;
+    use std::time::Duration;
+
+    use nix::sys::socket::{recv, send, socketpair, AddressFamily, MsgFlags, SockFlag, SockType};
+    use tokio::io::AsyncReadExt;
+
+    use super::*;
+    use crate::proto::{Codec, MessageHeader, MESSAGE_HEADER_LENGTH};
+    use crate::r#async::Client;
+    use crate::{Request, Response};
+
+    // Larger than any single read of the client.
+    const LARGE: usize = 128 << 10;
+
+    fn pair() -> (RawFd, RawFd) {
+        socketpair(
+            AddressFamily::Unix,
+            SockType::SeqPacket,
+            None,
+            SockFlag::SOCK_CLOEXEC,
+        )
+        .unwrap()
+    }
+
+    fn recv_one(fd: RawFd) -> Vec<u8> {
+        let mut packet = vec![0; LARGE + 4096];
+        let n = recv(fd, &mut packet, MsgFlags::empty()).unwrap();
+        packet.truncate(n);
+        packet
+    }
+
+    fn request(payload: Vec<u8>) -> Request {
+        Request {
+            service: "test.Test".to_string(),
+            method: "Echo".to_string(),
+            payload,
+            timeout_nano: 5_000_000_000,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_round_trip() {
+        let (client_fd, peer_fd) = pair();
+        assert!(matches!(
+            ClientSocket::from_raw_fd(nix::unistd::dup(client_fd).unwrap()),
+            ClientSocket::SeqPacket(_)
+        ));
+        let client = Client::new(client_fd);
+
+        // Answers like a peer reading whole packets, splitting its response in two.
+        let peer = std::thread::spawn(move || {
+            let packet = recv_one(peer_fd);
+            let header = MessageHeader::from(&packet);
+            assert_eq!(
+                packet.len(),
+                MESSAGE_HEADER_LENGTH + header.length as usize,
+                "a message must be sent as a single packet"
+            );
+            let req = Request::decode(&packet[MESSAGE_HEADER_LENGTH..]).unwrap();
+            let res = Response {
+                payload: req.payload,
+                ..Default::default()
+            }
+            .encode()
+            .unwrap();
+            let header: Vec<u8> =
+                MessageHeader::new_response(header.stream_id, res.len() as u32).into();
+            send(peer_fd, &header, MsgFlags::empty()).unwrap();
+            send(peer_fd, &res, MsgFlags::empty()).unwrap();
+            peer_fd
+        });
+
+        let payload = vec![7; LARGE];
+        let res = client.request(request(payload.clone())).await.unwrap();
+        assert_eq!(res.payload, payload);
+        nix::unistd::close(peer.join().unwrap()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_peer_closes_mid_message() {
+        let (fd, peer_fd) = pair();
+        let mut stream = SeqPacketStream::new(utils::new_unix_stream_from_raw_fd(fd));
+
+        let header: Vec<u8> = MessageHeader::new_response(1, LARGE as u32).into();
+        send(peer_fd, &header, MsgFlags::empty()).unwrap();
+        send(peer_fd, &vec![1; LARGE / 2], MsgFlags::empty()).unwrap();
+        nix::unistd::close(peer_fd).unwrap();
+
+        // The packets are handed out over several reads, then the end shows.
+        let mut buf = vec![0; MESSAGE_HEADER_LENGTH];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(MessageHeader::from(&buf).length, LARGE as u32);
+        let mut chunk = [0; 1000];
+        let mut read = 0;
+        loop {
+            match stream.read(&mut chunk).await.unwrap() {
+                0 => break,
+                n => read += n,
+            }
+        }
+        assert_eq!(read, LARGE / 2);
+    }
+
+    #[tokio::test]
+    async fn test_peer_closes_mid_call() {
+        let (client_fd, peer_fd) = pair();
+        let client = Client::new(client_fd);
+
+        let peer = std::thread::spawn(move || {
+            let packet = recv_one(peer_fd);
+            let header = MessageHeader::from(&packet);
+            let header: Vec<u8> = MessageHeader::new_response(header.stream_id, 100).into();
+            send(peer_fd, &header, MsgFlags::empty()).unwrap();
+            send(peer_fd, &[0; 10], MsgFlags::empty()).unwrap();
+            nix::unistd::close(peer_fd).unwrap();
+        });
+
+        // The call fails as soon as the connection ends, not once it times out.
+        let res = tokio::time::timeout(
+            Duration::from_secs(2),
+            client.request(request(b"ping".to_vec())),
+        )
+        .await
+        .expect("the call must end with the connection");
+        assert!(res.is_err(), "unexpected {:?}", res);
+        peer.join().unwrap();
+    }
+}
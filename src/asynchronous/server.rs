@@ -10,45 +10,84 @@ use std::marker::Unpin;
 use std::os::unix::io::RawFd;
 use std::os::unix::io::{AsRawFd, FromRawFd};
 use std::os::unix::net::UnixListener as SysUnixListener;
+use std::panic::AssertUnwindSafe;
 use std::result::Result as StdResult;
-use std::sync::{Arc, Mutex};
-use std::time::Duration;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
 
 use async_trait::async_trait;
 use futures::stream::Stream;
-use futures::StreamExt as _;
+use futures::{FutureExt as _, StreamExt as _};
+use nix::sys::socket;
 use nix::unistd;
 use protobuf::Message as _;
 use tokio::{
     self,
-    io::{AsyncRead, AsyncWrite},
-    net::UnixListener,
+    io::{unix::AsyncFd, AsyncRead, AsyncWrite},
+    net::{TcpListener, UnixListener},
     select, spawn,
     sync::mpsc::{channel, Sender},
+    sync::{oneshot, watch, OwnedSemaphorePermit, Semaphore},
     task,
-    time::timeout,
 };
 #[cfg(any(target_os = "linux", target_os = "android"))]
 use tokio_vsock::VsockListener;
 
-use crate::asynchronous::unix_incoming::UnixIncoming;
+use crate::address::Address;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+use crate::asynchronous::credentials::CredentialsIncoming;
+use crate::asynchronous::credentials::{self, CaptureCredentials, CredentialsSlot};
+use crate::asynchronous::unix_incoming::{TcpIncoming, UnixIncoming};
 use crate::common::{self, Domain};
 use crate::context;
-use crate::error::{get_status, Error, Result};
+use crate::error::{
+    get_limit_status, get_rpc_status, get_status, Error, Result, LIMIT_DEADLINE,
+    LIMIT_DECODED_SIZE, LIMIT_MEMORY, LIMIT_REQUESTS, LIMIT_STREAMS,
+};
 use crate::proto::{
-    check_oversize, Code, Codec, GenMessage, Message, MessageHeader, Request, Response, Status,
-    FLAG_NO_DATA, FLAG_REMOTE_CLOSED, MESSAGE_TYPE_DATA, MESSAGE_TYPE_REQUEST,
+    check_oversize, Code, Codec, GenMessage, KeyValue, Message, MessageHeader, Request, Response,
+    Status, FLAG_ACK, FLAG_NO_DATA, FLAG_REMOTE_CLOSED, FLAG_REMOTE_OPEN, MESSAGE_HEADER_LENGTH,
+    MESSAGE_TYPE_CANCEL, MESSAGE_TYPE_DATA, MESSAGE_TYPE_GOAWAY, MESSAGE_TYPE_PING,
+    MESSAGE_TYPE_PONG, MESSAGE_TYPE_REQUEST,
 };
+use crate::r#async::admin;
+use crate::r#async::budget::{MemoryBudget, Reservation};
+#[cfg(feature = "capture")]
+use crate::r#async::capture::Capture;
+use crate::r#async::clock::{self, default_clock, Clock};
 use crate::r#async::connection::*;
-use crate::r#async::shutdown;
+use crate::r#async::decode_limit;
+use crate::r#async::fair::{FairQueue, WriteShare};
+use crate::r#async::faults::{Fault, FaultInjector};
+use crate::r#async::handshake::{
+    self, DriftedMethods, PeerVersion, SchemaDrift, CAPABILITIES_KEY, CAPABILITY_CANCEL,
+    CAPABILITY_UNARY_OVER_STREAM, HANDSHAKE_METHOD, HANDSHAKE_SERVICE, RESTART_EPOCH_KEY,
+    SCHEMA_DRIFT_KEY,
+};
+use crate::r#async::metrics::{DebugState, MetricsHook};
+use crate::r#async::notifications::{is_subscription, Notifier};
+use crate::r#async::ping::{self, PingHandler, Pings};
+use crate::r#async::rate_limit::RateLimit;
+use crate::r#async::shutdown::{self, ShutdownReport};
 use crate::r#async::stream::{
-    Kind, MessageReceiver, MessageSender, ResultReceiver, ResultSender, StreamInner,
+    notify_ack, AckWaiters, Kind, MessageReceiver, MessageSender, ResultReceiver, ResultSender,
+    StreamInner, DEFAULT_STREAM_BUFFER,
 };
 use crate::r#async::utils;
-use crate::r#async::{MethodHandler, StreamHandler, TtrpcContext};
+use crate::r#async::{
+    Compression, Credentials, DynService, Extensions, MethodHandler, ResponseMetadata,
+    StreamHandler, TtrpcContext,
+};
+use crate::MethodInfo;
 
 const DEFAULT_CONN_SHUTDOWN_TIMEOUT: Duration = Duration::from_millis(5000);
 const DEFAULT_SERVER_SHUTDOWN_TIMEOUT: Duration = Duration::from_millis(10000);
+/// How often [`Server::graceful_shutdown`] checks whether the calls are done.
+const DRAIN_CHECK_INTERVAL: Duration = Duration::from_millis(10);
+/// How long the response of a call without a deadline stays dropped, see
+/// [`Fault::DropResponse`].
+const MAX_DROPPED_RESPONSE_HOLD: Duration = Duration::from_secs(60);
 
 pub struct Service {
     pub methods: HashMap<String, Box<dyn MethodHandler + Send + Sync>>,
@@ -65,14 +104,379 @@ impl Service {
     }
 }
 
+impl Service {
+    /// Build a service which dispatches all methods listed by `service` to it.
+    pub fn from_dyn(service: Arc<dyn DynService + Send + Sync>) -> Service {
+        let mut methods: HashMap<String, Box<dyn MethodHandler + Send + Sync>> = HashMap::new();
+        for method in service.methods() {
+            let handler = DynMethod {
+                service: service.clone(),
+                method: method.clone(),
+            };
+            methods.insert(method, Box::new(handler));
+        }
+
+        let mut streams: HashMap<String, Arc<dyn StreamHandler + Send + Sync>> = HashMap::new();
+        for method in service.streams() {
+            let handler = DynMethod {
+                service: service.clone(),
+                method: method.clone(),
+            };
+            streams.insert(method, Arc::new(handler));
+        }
+
+        Service { methods, streams }
+    }
+}
+
+/// Forwards the calls of one method to a [`DynService`].
+struct DynMethod {
+    service: Arc<dyn DynService + Send + Sync>,
+    method: String,
+}
+
+#[async_trait]
+impl MethodHandler for DynMethod {
+    async fn handler(&self, ctx: TtrpcContext, req: Request) -> Result<Response> {
+        self.service.call(ctx, &self.method, req).await
+    }
+}
+
+#[async_trait]
+impl StreamHandler for DynMethod {
+    async fn handler(&self, ctx: TtrpcContext, stream: StreamInner) -> Result<Option<Response>> {
+        self.service.call_stream(ctx, &self.method, stream).await
+    }
+}
+
+/// Handles the calls of a service registered by [`Server::register_relay`].
+type Relay = Arc<dyn MethodHandler + Send + Sync>;
+
+pub(crate) type ConnectionMap = Arc<Mutex<HashMap<RawFd, ConnectionEntry>>>;
+
+/// Registry entry of a connection, used to inspect and close it.
+pub(crate) struct ConnectionEntry {
+    tx: MessageSender,
+    streams: Arc<Mutex<HashMap<u32, ResultSender>>>,
+    /// The calls being handled, unary or streaming.
+    calls: Arc<Mutex<HashMap<u32, oneshot::Sender<()>>>>,
+    peer: String,
+    accepted: Instant,
+    traffic: Arc<Traffic>,
+    close: shutdown::Notifier,
+    extensions: Extensions,
+    pings: Pings,
+}
+
+/// Bytes read from and written to a connection, including message headers.
+#[derive(Default)]
+struct Traffic {
+    read: AtomicU64,
+    written: AtomicU64,
+}
+
+impl Traffic {
+    fn add(counter: &AtomicU64, header: &MessageHeader) {
+        counter.fetch_add(
+            (MESSAGE_HEADER_LENGTH + header.length as usize) as u64,
+            Ordering::Relaxed,
+        );
+    }
+}
+
+/// Information about a connection of a [`Server`], see [`Server::connections`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ConnectionInfo {
+    /// Identifies the connection, this is the fd of the connection.
+    pub id: RawFd,
+    /// Address of the peer as reported by `getpeername(2)`.
+    pub peer: String,
+    /// Time since the connection was accepted.
+    pub age: Duration,
+    /// Calls and streams in progress.
+    pub open_streams: usize,
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+}
+
+pub(crate) fn list_connections(connections: &ConnectionMap) -> Vec<ConnectionInfo> {
+    let mut infos: Vec<ConnectionInfo> = connections
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(fd, c)| ConnectionInfo {
+            id: *fd,
+            peer: c.peer.clone(),
+            age: c.accepted.elapsed(),
+            open_streams: c.streams.lock().unwrap().len(),
+            bytes_read: c.traffic.read.load(Ordering::Relaxed),
+            bytes_written: c.traffic.written.load(Ordering::Relaxed),
+        })
+        .collect();
+    infos.sort_by_key(|info| info.id);
+    infos
+}
+
+pub(crate) fn close_connection(connections: &ConnectionMap, id: RawFd, reason: &str) -> Result<()> {
+    match connections.lock().unwrap().get(&id) {
+        Some(c) => {
+            info!("Close connection {} ({}): {}", id, c.peer, reason);
+            c.close.shutdown();
+            Ok(())
+        }
+        None => Err(get_rpc_status(
+            Code::NOT_FOUND,
+            format!("connection {id} does not exist"),
+        )),
+    }
+}
+
+/// Settings shared by all listeners, see [`Server::set_stream_buffer`],
+/// [`Server::register_relay`], [`Server::set_write_timeout`],
+/// [`Server::set_compression`], [`Server::set_priority`],
+/// [`Server::set_schema_version`], [`Server::set_restart_epoch`],
+/// [`Server::replace_middleware`], [`Server::set_connect_hook`],
+/// [`Server::on_ping`],
+/// [`Server::set_memory_budget`],
+/// [`Server::set_capture`], [`Server::set_max_streams`],
+/// [`Server::set_max_concurrent_requests`],
+/// [`Server::set_max_concurrent_requests_per_connection`],
+/// [`Server::set_max_decoded_size`], [`Server::set_catch_panics`],
+/// [`Server::set_unary_over_stream`], [`Server::check_schema_digests`] and
+/// [`Server::set_clock`].
+#[derive(Default)]
+struct ServerConfig {
+    stream_buffers: HashMap<String, usize>,
+    relays: HashMap<String, Arc<dyn MethodHandler + Send + Sync>>,
+    write_timeout: Option<Duration>,
+    middleware: RwLock<Arc<Middleware>>,
+    compression: Option<Compression>,
+    priorities: HashMap<String, Priority>,
+    /// See [`Server::set_priority_hints`].
+    priority_hints: bool,
+    lanes: HashMap<Priority, FairQueue>,
+    schema_version: String,
+    restart_epoch: Option<u64>,
+    connect_hook: Option<Arc<dyn ConnectHook + Send + Sync>>,
+    ping_handler: Option<Arc<dyn PingHandler + Send + Sync>>,
+    memory_budget: Option<MemoryBudget>,
+    #[cfg(feature = "capture")]
+    capture: Option<Capture>,
+    max_streams: Option<usize>,
+    max_requests: Option<RequestLimit>,
+    max_requests_per_connection: Option<usize>,
+    max_decoded_size: Option<usize>,
+    catch_panics: bool,
+    unary_over_stream: bool,
+    schema_digests: Option<(HashMap<String, u64>, SchemaDrift)>,
+    /// Set by [`Server::graceful_shutdown`], new calls are refused.
+    draining: AtomicBool,
+    /// `None` for the `TokioClock`.
+    clock: Option<Arc<dyn Clock>>,
+}
+
+/// Bounds the requests handled at once, see
+/// [`Server::set_max_concurrent_requests`].
+#[derive(Clone)]
+struct RequestLimit {
+    max: usize,
+    permits: Arc<Semaphore>,
+}
+
+impl RequestLimit {
+    fn new(max: usize) -> RequestLimit {
+        RequestLimit {
+            max,
+            permits: Arc::new(Semaphore::new(max)),
+        }
+    }
+
+    /// The permit held while a request is handled, or the status rejecting it.
+    fn try_acquire(&self, msg: &str) -> StdResult<OwnedSemaphorePermit, Status> {
+        self.permits.clone().try_acquire_owned().map_err(|_| {
+            get_limit_status(
+                Code::RESOURCE_EXHAUSTED,
+                msg,
+                LIMIT_REQUESTS,
+                self.max as u64,
+                self.max as u64 + 1,
+            )
+        })
+    }
+}
+
+impl ServerConfig {
+    /// The middleware for a call, or a message, to use throughout.
+    fn middleware(&self) -> Arc<Middleware> {
+        self.middleware.read().unwrap().clone()
+    }
+
+    /// The middleware to set up before the server is started.
+    fn middleware_mut(&mut self) -> &mut Middleware {
+        Arc::make_mut(self.middleware.get_mut().unwrap())
+    }
+
+    fn clock(&self) -> Arc<dyn Clock> {
+        self.clock.clone().unwrap_or_else(default_clock)
+    }
+}
+
+/// The policies a server applies to calls, which can be replaced while it runs,
+/// see [`Server::replace_middleware`].
+///
+/// Every call uses the middleware in place when it was received, and every
+/// message written the one in place when it was sent, so a call never sees half
+/// of a replacement.
+#[derive(Clone, Default)]
+pub struct Middleware {
+    authorizer: Option<Arc<dyn Authorizer + Send + Sync>>,
+    error_redactor: Option<Arc<dyn ErrorRedactor + Send + Sync>>,
+    weigher: Option<Arc<dyn Weigher + Send + Sync>>,
+    interceptors: Vec<Arc<dyn ServerInterceptor + Send + Sync>>,
+    fault_injector: Option<FaultInjector>,
+    read_rate_limit: Option<RateLimit>,
+    write_share: Option<Arc<WriteShare>>,
+}
+
+impl Middleware {
+    pub fn new() -> Middleware {
+        Middleware::default()
+    }
+
+    /// See [`Server::set_authorizer`].
+    pub fn authorizer(mut self, authorizer: Arc<dyn Authorizer + Send + Sync>) -> Middleware {
+        self.authorizer = Some(authorizer);
+        self
+    }
+
+    /// See [`Server::set_error_redactor`].
+    pub fn error_redactor(mut self, redactor: Arc<dyn ErrorRedactor + Send + Sync>) -> Middleware {
+        self.error_redactor = Some(redactor);
+        self
+    }
+
+    /// See [`Server::set_weigher`].
+    pub fn weigher(mut self, weigher: Arc<dyn Weigher + Send + Sync>) -> Middleware {
+        self.weigher = Some(weigher);
+        self
+    }
+
+    /// See [`Server::add_interceptor`].
+    pub fn interceptor(
+        mut self,
+        interceptor: Arc<dyn ServerInterceptor + Send + Sync>,
+    ) -> Middleware {
+        self.interceptors.push(interceptor);
+        self
+    }
+
+    /// See [`Server::set_fault_injector`].
+    pub fn fault_injector(mut self, faults: FaultInjector) -> Middleware {
+        self.fault_injector = Some(faults);
+        self
+    }
+
+    /// See [`Server::set_read_rate_limit`]. The limit of a connection is the one in
+    /// place when it was accepted.
+    pub fn read_rate_limit(mut self, limit: RateLimit) -> Middleware {
+        self.read_rate_limit = Some(limit);
+        self
+    }
+
+    /// See [`Server::set_write_rate_limit`]. Replacing the middleware restarts the
+    /// accounting of the rate.
+    pub fn write_rate_limit(mut self, limit: RateLimit) -> Middleware {
+        self.write_share = Some(Arc::new(WriteShare::new(limit)));
+        self
+    }
+}
+
+/// Rewrites the error statuses sent to clients, see [`Server::set_error_redactor`].
+pub trait ErrorRedactor {
+    fn redact(&self, status: &mut Status);
+}
+
+/// Decides which calls a peer may make, see [`Server::set_authorizer`].
+///
+/// The identity of the peer is found in the context of the call: its
+/// credentials, its address, the extensions set by the connect hook and the
+/// metadata of the request.
+pub trait Authorizer {
+    fn authorize(&self, ctx: &TtrpcContext, service: &str, method: &str) -> bool;
+}
+
+/// Inspects and rewrites the requests before they are handled, see
+/// [`Server::add_interceptor`], e.g. to authenticate a token in the metadata,
+/// audit the calls or fill in defaults.
+pub trait ServerInterceptor {
+    /// Runs before the handler of `req`, whose service, method, metadata and
+    /// encoded payload may be changed. Returning a status fails the call with it
+    /// instead of handling it.
+    fn intercept(&self, ctx: &TtrpcContext, req: &mut Request) -> StdResult<(), Status>;
+}
+
+/// Weighs the connections competing for the server, see [`Server::set_weigher`].
+pub trait Weigher {
+    /// Returns the weight of the connection of the call, from the identity of its
+    /// peer as for an [`Authorizer`]. A weight of 0 counts as 1.
+    fn weight(&self, ctx: &TtrpcContext) -> u32;
+}
+
+/// Scheduling priority of a method, see [`Server::set_priority`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum Priority {
+    High,
+    #[default]
+    Normal,
+    Low,
+}
+
+/// Metadata carrying the priority a client asked for, see
+/// [`CallOptions::with_priority`](crate::r#async::CallOptions::with_priority).
+pub(crate) const PRIORITY_METADATA_KEY: &str = "ttrpc-priority";
+
+impl Priority {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            Priority::High => "high",
+            Priority::Normal => "normal",
+            Priority::Low => "low",
+        }
+    }
+
+    pub(crate) fn parse(s: &str) -> Option<Priority> {
+        [Priority::High, Priority::Normal, Priority::Low]
+            .iter()
+            .copied()
+            .find(|p| p.as_str() == s)
+    }
+}
+
+/// A listener serving its own set of services, see [`Server::bind_with_services`].
+struct Route {
+    fd: RawFd,
+    domain: Domain,
+    services: Arc<HashMap<String, Service>>,
+    stop_listen_tx: Option<Sender<Sender<RawFd>>>,
+}
+
 /// A ttrpc Server (async).
 pub struct Server {
     listeners: Vec<RawFd>,
     services: Arc<HashMap<String, Service>>,
+    config: Arc<ServerConfig>,
     domain: Option<Domain>,
+    routes: Vec<Route>,
+    connections: ConnectionMap,
+    metrics_hook: Option<Arc<dyn MetricsHook + Send + Sync>>,
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pass_credentials: bool,
 
     shutdown: shutdown::Notifier,
     stop_listen_tx: Option<Sender<Sender<RawFd>>>,
+    shutdown_fd: Option<(RawFd, Duration)>,
+    /// Set once the fd of [`Server::shutdown_on_fd`] shut the server down.
+    shutdown_triggered: Arc<watch::Sender<bool>>,
 }
 
 impl Default for Server {
@@ -80,9 +484,17 @@ impl Default for Server {
         Server {
             listeners: Vec::with_capacity(1),
             services: Arc::new(HashMap::new()),
+            config: Arc::new(ServerConfig::default()),
             domain: None,
+            routes: Vec::new(),
+            connections: Arc::new(Mutex::new(HashMap::new())),
+            metrics_hook: None,
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            pass_credentials: false,
             shutdown: shutdown::with_timeout(DEFAULT_SERVER_SHUTDOWN_TIMEOUT).0,
             stop_listen_tx: None,
+            shutdown_fd: None,
+            shutdown_triggered: Arc::new(watch::channel(false).0),
         }
     }
 }
@@ -107,6 +519,28 @@ impl Server {
         Ok(self)
     }
 
+    /// Bind an additional socket which serves `services` instead of the services
+    /// registered by [`Server::register_service`].
+    ///
+    /// This allows e.g. a privileged admin socket to expose extra methods next to the
+    /// public socket, while sharing the runtime and connection machinery.
+    pub fn bind_with_services(
+        mut self,
+        sockaddr: &str,
+        services: HashMap<String, Service>,
+    ) -> Result<Self> {
+        let (fd, domain) = common::do_bind(sockaddr)?;
+        common::do_listen(fd)?;
+
+        self.routes.push(Route {
+            fd,
+            domain,
+            services: Arc::new(services),
+            stop_listen_tx: None,
+        });
+        Ok(self)
+    }
+
     pub fn set_domain_unix(mut self) -> Self {
         self.domain = Some(Domain::Unix);
         self
@@ -118,6 +552,24 @@ impl Server {
         self
     }
 
+    pub fn set_domain_tcp(mut self) -> Self {
+        self.domain = Some(Domain::Tcp);
+        self
+    }
+
+    /// Enable `SO_PASSCRED` on the Unix sockets of the server and pass the credentials
+    /// of the process which wrote each request to its handler in
+    /// [`TtrpcContext::credentials`].
+    ///
+    /// Unlike [`TtrpcContext::peer_credentials`], which reports the process that
+    /// connected, this detects a connection handed over to another process through
+    /// fd passing.
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub fn set_pass_credentials(mut self, enabled: bool) -> Self {
+        self.pass_credentials = enabled;
+        self
+    }
+
     pub fn add_listener(mut self, fd: RawFd) -> Result<Server> {
         self.listeners.push(fd);
 
@@ -130,163 +582,864 @@ impl Server {
         self
     }
 
-    fn get_listenfd(&self) -> Result<RawFd> {
-        if self.listeners.is_empty() {
-            return Err(Error::Others("ttrpc-rust not bind".to_string()));
-        }
+    /// Register a service whose methods are only known at runtime, see [`DynService`].
+    pub fn register_dyn_service(self, service: Arc<dyn DynService + Send + Sync>) -> Server {
+        let mut new = HashMap::new();
+        new.insert(service.name(), Service::from_dyn(service));
+        self.register_service(new)
+    }
 
-        let listenfd = self.listeners[self.listeners.len() - 1];
-        Ok(listenfd)
+    /// Set how many messages from the client are buffered on each stream of the
+    /// `service.method` streaming method, 100 by default. Further messages wait for
+    /// the handler to catch up.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `capacity` is 0.
+    pub fn set_stream_buffer(mut self, service: &str, method: &str, capacity: usize) -> Server {
+        assert!(
+            capacity > 0,
+            "stream buffer capacity must be greater than 0"
+        );
+        let config = Arc::get_mut(&mut self.config).unwrap();
+        config
+            .stream_buffers
+            .insert(utils::get_path(service, method), capacity);
+        self
     }
 
-    pub async fn start(&mut self) -> Result<()> {
-        let listenfd = self.get_listenfd()?;
+    /// Pass the calls of `service` to `handler` without decoding them.
+    ///
+    /// This is meant for proxies which merely forward messages: the handler gets the
+    /// method name and the raw payload in the [`Request`], and may decode it on demand
+    /// with [`Codec::decode`]. Methods registered by [`Server::register_service`] take
+    /// precedence over the relay. Only unary calls can be relayed.
+    pub fn register_relay(
+        mut self,
+        service: &str,
+        handler: Arc<dyn MethodHandler + Send + Sync>,
+    ) -> Server {
+        let config = Arc::get_mut(&mut self.config).unwrap();
+        config.relays.insert(service.to_string(), handler);
+        self
+    }
 
-        match self.domain.as_ref() {
-            Some(Domain::Unix) => {
-                let sys_unix_listener;
-                unsafe {
-                    sys_unix_listener = SysUnixListener::from_raw_fd(listenfd);
-                }
-                sys_unix_listener
-                    .set_nonblocking(true)
-                    .map_err(err_to_others_err!(e, "set_nonblocking error "))?;
-                let unix_listener = UnixListener::from_std(sys_unix_listener)
-                    .map_err(err_to_others_err!(e, "from_std error "))?;
+    /// Disconnect a client if writing a single message to it takes longer than
+    /// `timeout`, e.g. because it stopped reading.
+    ///
+    /// Without a write timeout such a connection blocks its writer, and the messages
+    /// queued for it, until the client goes away.
+    pub fn set_write_timeout(mut self, timeout: Duration) -> Server {
+        let config = Arc::get_mut(&mut self.config).unwrap();
+        config.write_timeout = Some(timeout);
+        self
+    }
 
-                let incoming = UnixIncoming::new(unix_listener);
+    /// Limit the rate at which messages are read from each connection, so a single
+    /// chatty client cannot monopolize the server, see [`RateLimit`].
+    pub fn set_read_rate_limit(mut self, limit: RateLimit) -> Server {
+        let config = Arc::get_mut(&mut self.config).unwrap();
+        config.middleware_mut().read_rate_limit = Some(limit);
+        self
+    }
 
-                self.do_start(incoming).await
-            }
-            // It seems that we can use UnixStream to represent both UnixStream and VsockStream.
-            // Whatever, we keep it for now for the compatibility and vsock-specific features maybe
-            // used in the future.
-            #[cfg(any(target_os = "linux", target_os = "android"))]
-            Some(Domain::Vsock) => {
-                let incoming = unsafe { VsockListener::from_raw_fd(listenfd).incoming() };
-                self.do_start(incoming).await
-            }
-            _ => Err(Error::Others(
-                "Domain is not set or not supported".to_string(),
-            )),
-        }
+    /// Limit the rate at which messages are written to all connections together,
+    /// see [`RateLimit`]. The connections waiting to write share it by weight, see
+    /// [`Server::set_weigher`].
+    pub fn set_write_rate_limit(mut self, limit: RateLimit) -> Server {
+        let config = Arc::get_mut(&mut self.config).unwrap();
+        config.middleware_mut().write_share = Some(Arc::new(WriteShare::new(limit)));
+        self
     }
 
-    async fn do_start<I, S>(&mut self, mut incoming: I) -> Result<()>
-    where
-        I: Stream<Item = std::io::Result<S>> + Unpin + Send + 'static + AsRawFd,
-        S: AsyncRead + AsyncWrite + AsRawFd + Send + 'static,
-    {
-        let services = self.services.clone();
+    /// Compress the large messages sent on streams, see [`Compression`].
+    ///
+    /// Compressed messages from clients are accepted only if this is set, the
+    /// clients must use the same compressor.
+    pub fn set_compression(mut self, compression: Compression) -> Server {
+        let config = Arc::get_mut(&mut self.config).unwrap();
+        config.compression = Some(compression);
+        self
+    }
 
-        let shutdown_waiter = self.shutdown.subscribe();
+    /// Run the calls of `service.method` in the lane of `priority`. Other methods
+    /// run in the [`Priority::Normal`] lane, or the one their client asked for, see
+    /// [`Server::set_priority_hints`].
+    ///
+    /// Every lane runs at most the number of calls set by
+    /// [`Server::set_max_concurrency`], so e.g. `Kill` and `Delete` stay responsive
+    /// when bulk methods saturate their own lane.
+    pub fn set_priority(mut self, service: &str, method: &str, priority: Priority) -> Server {
+        let config = Arc::get_mut(&mut self.config).unwrap();
+        config
+            .priorities
+            .insert(utils::get_path(service, method), priority);
+        self
+    }
 
-        let (stop_listen_tx, mut stop_listen_rx) = channel(1);
-        self.stop_listen_tx = Some(stop_listen_tx);
+    /// Run the calls of methods without a priority, see [`Server::set_priority`],
+    /// in the lane their client asked for, see
+    /// [`CallOptions::with_priority`](crate::r#async::CallOptions::with_priority).
+    ///
+    /// Off by default: any client could otherwise put all its calls in the
+    /// [`Priority::High`] lane and starve the others, so only enable it if the
+    /// clients are trusted. The priorities set by the server always win.
+    pub fn set_priority_hints(mut self, enabled: bool) -> Server {
+        let config = Arc::get_mut(&mut self.config).unwrap();
+        config.priority_hints = enabled;
+        self
+    }
 
-        spawn(async move {
-            loop {
-                select! {
-                    conn = incoming.next() => {
-                        if let Some(conn) = conn {
-                            // Accept a new connection
-                            match conn {
-                                Ok(conn) => {
-                                    let fd = conn.as_raw_fd();
-                                    // spawn a connection handler, would not block
-                                    spawn_connection_handler(
-                                        fd,
-                                        conn,
-                                        services.clone(),
-                                        shutdown_waiter.clone(),
-                                    ).await;
-                                }
-                                Err(e) => {
-                                    error!("{:?}", e)
-                                }
-                            }
+    /// Run at most `limit` calls of the `priority` lane at once, across all
+    /// connections. Further calls wait for a running one to finish, and the
+    /// connections share the lane by weight, see [`Server::set_weigher`]. Lanes are
+    /// unbounded by default.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `limit` is 0.
+    pub fn set_max_concurrency(mut self, priority: Priority, limit: usize) -> Server {
+        assert!(limit > 0, "concurrency limit must be greater than 0");
+        let config = Arc::get_mut(&mut self.config).unwrap();
+        config.lanes.insert(priority, FairQueue::new(limit));
+        self
+    }
 
-                        } else {
-                            break;
-                        }
-                    }
-                    fd_tx = stop_listen_rx.recv() => {
-                        if let Some(fd_tx) = fd_tx {
-                            // dup fd to keep the listener open
-                            // or the listener will be closed when the incoming was dropped.
-                            let dup_fd = unistd::dup(incoming.as_raw_fd()).unwrap();
-                            common::set_fd_close_exec(dup_fd).unwrap();
-                            drop(incoming);
+    /// Answer the handshake of clients with `version`, see
+    /// [`ClientBuilder::schema_version`](crate::r#async::ClientBuilder::schema_version).
+    ///
+    /// The version of a client is exposed to the handlers in
+    /// [`TtrpcContext::peer_version`] whether or not this is set.
+    pub fn set_schema_version(mut self, version: &str) -> Server {
+        let config = Arc::get_mut(&mut self.config).unwrap();
+        config.schema_version = version.to_string();
+        self
+    }
 
-                            fd_tx.send(dup_fd).await.unwrap();
-                            break;
-                        }
-                    }
-                }
-            }
-        });
-        Ok(())
+    /// Compare the schema digests of `methods`, e.g. the `METHODS` of the generated
+    /// modules of the server, with those clients send in their handshake, see
+    /// [`ClientBuilder::schema_digests`](crate::r#async::ClientBuilder::schema_digests).
+    ///
+    /// The methods whose digests differ, which the client and the server were
+    /// generated from different revisions of, are logged and reported to the client,
+    /// and their calls on the connection are handled according to `drift`.
+    pub fn check_schema_digests(mut self, methods: &[MethodInfo], drift: SchemaDrift) -> Server {
+        let config = Arc::get_mut(&mut self.config).unwrap();
+        config.schema_digests = Some((handshake::schema_digests(methods), drift));
+        self
     }
 
-    pub async fn shutdown(&mut self) -> Result<()> {
-        self.stop_listen().await;
-        self.disconnect().await;
+    /// Shut down gracefully once `fd`, e.g. an eventfd or the read end of a pipe,
+    /// becomes readable, so a supervisor can stop the server without a call or a
+    /// signal. The fd stays owned by the caller.
+    ///
+    /// The connections are drained like [`Server::graceful_shutdown`] does, for up
+    /// to `timeout`, then closed, and the connections accepted from then on are
+    /// closed right away. [`Server::wait_shutdown_trigger`] returns once done, the
+    /// listeners are still closed by [`Server::shutdown`] or [`Server::close`].
+    pub fn shutdown_on_fd(mut self, fd: RawFd, timeout: Duration) -> Server {
+        self.shutdown_fd = Some((fd, timeout));
+        self
+    }
 
-        while let Some(fd) = self.listeners.pop() {
-            unistd::close(fd).unwrap_or_else(|e| {
-                warn!("failed to close listener fd: {}", e);
-            });
+    /// Waits until the fd of [`Server::shutdown_on_fd`] shut the server down, or
+    /// forever without such an fd.
+    pub async fn wait_shutdown_trigger(&self) {
+        let mut triggered = self.shutdown_triggered.subscribe();
+        while !*triggered.borrow_and_update() {
+            // The sender is owned by the server.
+            triggered.changed().await.ok();
         }
-        Ok(())
     }
 
-    pub async fn disconnect(&mut self) {
-        self.shutdown.shutdown();
+    /// Announce `epoch` in the handshake of clients, see
+    /// [`Client::peer_restart_epoch`](crate::r#async::Client::peer_restart_epoch).
+    ///
+    /// The epoch is kept by the caller across restarts of the server, e.g. bumped
+    /// every time it's restarted with the listeners of the previous instance, so
+    /// clients reconnecting to a new instance know their cached state is stale.
+    pub fn set_restart_epoch(mut self, epoch: u64) -> Server {
+        let config = Arc::get_mut(&mut self.config).unwrap();
+        config.restart_epoch = Some(epoch);
+        self
+    }
 
-        self.shutdown
-            .wait_all_exit()
-            .await
-            .map_err(|e| {
-                trace!("wait connection exit error: {}", e);
-            })
-            .ok();
-        trace!("wait connection exit.");
+    /// Pass every error status through `redactor` before sending it to the client,
+    /// e.g. to strip host paths and internal addresses from the messages sent to
+    /// less-trusted guests.
+    ///
+    /// Errors are logged by the server before they are redacted.
+    pub fn set_error_redactor(mut self, redactor: Arc<dyn ErrorRedactor + Send + Sync>) -> Server {
+        let config = Arc::get_mut(&mut self.config).unwrap();
+        config.middleware_mut().error_redactor = Some(redactor);
+        self
     }
 
-    pub async fn stop_listen(&mut self) {
-        if let Some(tx) = self.stop_listen_tx.take() {
-            let (fd_tx, mut fd_rx) = channel(1);
-            tx.send(fd_tx).await.unwrap();
+    /// Ask `authorizer` whether every call may proceed, and fail those it denies
+    /// with `PERMISSION_DENIED` before they are handled.
+    ///
+    /// The methods of generated services are listed in the `METHODS` constant of
+    /// their module, see [`MethodInfo`](crate::MethodInfo).
+    pub fn set_authorizer(mut self, authorizer: Arc<dyn Authorizer + Send + Sync>) -> Server {
+        let config = Arc::get_mut(&mut self.config).unwrap();
+        config.middleware_mut().authorizer = Some(authorizer);
+        self
+    }
 
-            let fd = fd_rx.recv().await.unwrap();
-            self.listeners.clear();
-            self.listeners.push(fd);
-        }
+    /// Ask `weigher` for the weight of the connection of every call, e.g. from the
+    /// cid or uid of its peer, so a high priority sandbox is not starved by the bulk
+    /// traffic of others.
+    ///
+    /// The connections waiting for a lane of [`Server::set_max_concurrency`] or to
+    /// write under [`Server::set_write_rate_limit`] get a share in proportion to
+    /// their weight, the weight of their latest call. All connections weigh 1 by
+    /// default.
+    pub fn set_weigher(mut self, weigher: Arc<dyn Weigher + Send + Sync>) -> Server {
+        let config = Arc::get_mut(&mut self.config).unwrap();
+        config.middleware_mut().weigher = Some(weigher);
+        self
     }
-}
 
-async fn spawn_connection_handler<C>(
+    /// Run `interceptor` on every call after the [`Authorizer`], and after the
+    /// interceptors added before it, see [`ServerInterceptor`]. The first one to
+    /// return a status fails the call.
+    ///
+    /// The request the last one leaves is the one handled, by the service and
+    /// method it names. A call routed to another method is checked again, by the
+    /// [`Authorizer`] and for schema drift, see [`Server::check_schema_digests`].
+    pub fn add_interceptor(
+        mut self,
+        interceptor: Arc<dyn ServerInterceptor + Send + Sync>,
+    ) -> Server {
+        let config = Arc::get_mut(&mut self.config).unwrap();
+        config.middleware_mut().interceptors.push(interceptor);
+        self
+    }
+
+    /// Inject the faults of `faults` into the calls, e.g. in a staging environment
+    /// to check that the clients cope with a slow or failing server. They are drawn
+    /// once a call is let through by the authorizer, the interceptors and the
+    /// limits, and a delay holds the slot of the call in its lane. A dropped
+    /// response is replaced with `DEADLINE_EXCEEDED` once the client gave up on
+    /// the call, when it timed out by the clock of the server, or after a minute
+    /// if it has no timeout. Meanwhile the call counts towards no limit, and a
+    /// graceful shutdown doesn't wait for it.
+    ///
+    /// Replace the middleware with one without faults to stop, see
+    /// [`Server::replace_middleware`].
+    pub fn set_fault_injector(mut self, faults: FaultInjector) -> Server {
+        let config = Arc::get_mut(&mut self.config).unwrap();
+        config.middleware_mut().fault_injector = Some(faults);
+        self
+    }
+
+    /// Returns the authorizer, error redactor, weigher, interceptors, fault injector
+    /// and rate limits of the server, e.g. to change one of them with [`Server::replace_middleware`].
+    pub fn middleware(&self) -> Middleware {
+        (*self.config.middleware()).clone()
+    }
+
+    /// Replace the authorizer, error redactor, weigher, interceptors, fault injector
+    /// and rate limits of the server at once with those of `middleware`, while it runs, e.g. to
+    /// apply a policy update without restarting a long-lived shim.
+    ///
+    /// The calls in progress finish with the middleware they started with.
+    pub fn replace_middleware(&self, middleware: Middleware) {
+        *self.config.middleware.write().unwrap() = Arc::new(middleware);
+    }
+
+    /// Answer the pings of clients with the payload returned by `handler`, see
+    /// [`Client::ping`](crate::r#async::Client::ping). Pings are answered with an
+    /// empty payload by default.
+    pub fn on_ping(mut self, handler: Arc<dyn PingHandler + Send + Sync>) -> Server {
+        let config = Arc::get_mut(&mut self.config).unwrap();
+        config.ping_handler = Some(handler);
+        self
+    }
+
+    /// Run `hook` on every accepted connection before reading requests from it.
+    ///
+    /// Hooks run concurrently, so a slow client does not hold up the others.
+    pub fn set_connect_hook(mut self, hook: Arc<dyn ConnectHook + Send + Sync>) -> Server {
+        let config = Arc::get_mut(&mut self.config).unwrap();
+        config.connect_hook = Some(hook);
+        self
+    }
+
+    /// Reject requests with `RESOURCE_EXHAUSTED` once the requests and responses
+    /// in progress on all connections hold more than the `budget`.
+    pub fn set_memory_budget(mut self, budget: MemoryBudget) -> Server {
+        let config = Arc::get_mut(&mut self.config).unwrap();
+        config.memory_budget = Some(budget);
+        self
+    }
+
+    /// Record the messages exchanged on every connection into `capture`, to
+    /// analyze the traffic with Wireshark.
+    #[cfg(feature = "capture")]
+    pub fn set_capture(mut self, capture: Capture) -> Server {
+        let config = Arc::get_mut(&mut self.config).unwrap();
+        config.capture = Some(capture);
+        self
+    }
+
+    /// Reject the calls of streaming methods with `RESOURCE_EXHAUSTED` while
+    /// `max` streams are open on their connection.
+    pub fn set_max_streams(mut self, max: usize) -> Server {
+        let config = Arc::get_mut(&mut self.config).unwrap();
+        config.max_streams = Some(max);
+        self
+    }
+
+    /// Reject requests with `RESOURCE_EXHAUSTED` while `max` requests are handled
+    /// on all connections. A request counts until it's answered, which for
+    /// streaming methods is when the stream ends.
+    ///
+    /// Unlike [`Server::set_max_concurrency`], which queues the calls beyond the
+    /// limit, the client learns right away that the server is saturated.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `max` is 0.
+    pub fn set_max_concurrent_requests(mut self, max: usize) -> Server {
+        assert!(max > 0, "request limit must be greater than 0");
+        let config = Arc::get_mut(&mut self.config).unwrap();
+        config.max_requests = Some(RequestLimit::new(max));
+        self
+    }
+
+    /// Reject requests with `RESOURCE_EXHAUSTED` while `max` requests are handled
+    /// on their connection, see [`Server::set_max_concurrent_requests`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if `max` is 0.
+    pub fn set_max_concurrent_requests_per_connection(mut self, max: usize) -> Server {
+        assert!(max > 0, "request limit must be greater than 0");
+        let config = Arc::get_mut(&mut self.config).unwrap();
+        config.max_requests_per_connection = Some(max);
+        self
+    }
+
+    /// Reject requests with `RESOURCE_EXHAUSTED` if they would take more than
+    /// `bytes` once decoded, e.g. a small message repeating an empty field which
+    /// expands to a large vector.
+    ///
+    /// The size is estimated from the encoded request, before it's decoded by the
+    /// handler. The messages of streams and relayed requests are not checked.
+    pub fn set_max_decoded_size(mut self, bytes: usize) -> Server {
+        let config = Arc::get_mut(&mut self.config).unwrap();
+        config.max_decoded_size = Some(bytes);
+        self
+    }
+
+    /// Answer the calls of unary methods whose handler panicked with an error,
+    /// instead of never answering them.
+    ///
+    /// Decoding the request happens in the handler, so hosts which treat the
+    /// requests of their clients as hostile, e.g. of a guest, get an error for a
+    /// request which trips up the decoder rather than a hung call. The handlers of
+    /// streaming methods run in their own task, their panics always fail the call.
+    pub fn set_catch_panics(mut self, enabled: bool) -> Server {
+        let config = Arc::get_mut(&mut self.config).unwrap();
+        config.catch_panics = enabled;
+        self
+    }
+
+    /// Measure and wait for the deadlines of calls, the injected delays and the
+    /// timeout of a graceful shutdown with `clock` instead of the
+    /// [`TokioClock`](crate::r#async::TokioClock), e.g. a
+    /// [`MockClock`](crate::r#async::MockClock) in tests.
+    pub fn set_clock(mut self, clock: Arc<dyn Clock>) -> Server {
+        let config = Arc::get_mut(&mut self.config).unwrap();
+        config.clock = Some(clock);
+        self
+    }
+
+    /// Accept calls of unary methods made as streams with one message each way,
+    /// e.g. by proxies handling all the calls alike. Clients learn about it in the
+    /// handshake, see
+    /// [`Client::peer_supports_unary_over_stream`](crate::r#async::Client::peer_supports_unary_over_stream).
+    pub fn set_unary_over_stream(mut self, enabled: bool) -> Server {
+        let config = Arc::get_mut(&mut self.config).unwrap();
+        config.unary_over_stream = enabled;
+        self
+    }
+
+    /// Set a hook which receives a [`DebugState`] sample of a connection every time
+    /// a message is read from it.
+    pub fn set_metrics_hook(mut self, hook: Arc<dyn MetricsHook + Send + Sync>) -> Server {
+        self.metrics_hook = Some(hook);
+        self
+    }
+
+    /// Returns the current depth of the internal queues of every connection, keyed by
+    /// the connection fd.
+    pub fn debug_state(&self) -> HashMap<RawFd, DebugState> {
+        self.connections
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(fd, c)| (*fd, DebugState::collect(&c.tx, &c.streams)))
+            .collect()
+    }
+
+    /// Returns the connections currently served, ordered by id.
+    pub fn connections(&self) -> Vec<ConnectionInfo> {
+        list_connections(&self.connections)
+    }
+
+    /// Close the connection `id`, cancelling the calls in progress on it.
+    ///
+    /// `reason` is logged to tell why the peer was evicted.
+    pub fn close_connection(&self, id: RawFd, reason: &str) -> Result<()> {
+        close_connection(&self.connections, id, reason)
+    }
+
+    /// Pings the client of the connection `id` with `payload`, and returns the
+    /// payload of its answer, see
+    /// [`Client::on_ping`](crate::r#async::Client::on_ping).
+    ///
+    /// Clients which don't support pings never answer, so callers should bound
+    /// the wait with a timeout.
+    pub async fn ping(&self, id: RawFd, payload: &[u8]) -> Result<Vec<u8>> {
+        let (tx, pings) = match self.connections.lock().unwrap().get(&id) {
+            Some(c) => (c.tx.clone(), c.pings.clone()),
+            None => {
+                return Err(get_rpc_status(
+                    Code::NOT_FOUND,
+                    format!("connection {id} does not exist"),
+                ))
+            }
+        };
+        pings.ping(&tx, payload).await
+    }
+
+    /// Returns the notifier of the connection `id`, or `None` if its client did not
+    /// subscribe to notifications, see
+    /// [`ClientBuilder::notifications`](crate::r#async::ClientBuilder::notifications).
+    pub fn notifier(&self, id: RawFd) -> Option<Notifier> {
+        self.connections
+            .lock()
+            .unwrap()
+            .get(&id)
+            .and_then(|c| c.extensions.get::<Notifier>())
+    }
+
+    /// Push a notification to every client which subscribed.
+    pub async fn broadcast(&self, topic: &str, payload: &[u8]) {
+        let notifiers: Vec<Notifier> = self
+            .connections
+            .lock()
+            .unwrap()
+            .values()
+            .filter_map(|c| c.extensions.get::<Notifier>())
+            .collect();
+        for notifier in notifiers {
+            // The connection is closing, its client won't miss the notification.
+            notifier.notify(topic, payload.to_vec()).await.ok();
+        }
+    }
+
+    /// Returns the built-in `ttrpc.admin.Admin` service, which exposes
+    /// [`Server::connections`] and [`Server::close_connection`] over ttrpc.
+    ///
+    /// The service is not registered by default. It is meant to be served on a
+    /// privileged socket only, see [`Server::bind_with_services`].
+    pub fn admin_service(&self) -> HashMap<String, Service> {
+        admin::service(self.connections.clone())
+    }
+
+    fn get_listenfd(&self) -> Result<RawFd> {
+        if self.listeners.is_empty() {
+            return Err(Error::Others("ttrpc-rust not bind".to_string()));
+        }
+
+        let listenfd = self.listeners[self.listeners.len() - 1];
+        Ok(listenfd)
+    }
+
+    pub async fn start(&mut self) -> Result<()> {
+        let listenfd = self.get_listenfd()?;
+
+        let stop_listen_tx = self.start_listener(listenfd, self.domain, self.services.clone())?;
+        self.stop_listen_tx = Some(stop_listen_tx);
+
+        for i in 0..self.routes.len() {
+            let route = &self.routes[i];
+            let stop_listen_tx =
+                self.start_listener(route.fd, Some(route.domain), route.services.clone())?;
+            self.routes[i].stop_listen_tx = Some(stop_listen_tx);
+        }
+
+        if let Some((fd, timeout)) = self.shutdown_fd {
+            self.watch_shutdown_fd(fd, timeout)?;
+        }
+        Ok(())
+    }
+
+    fn watch_shutdown_fd(&self, fd: RawFd, timeout: Duration) -> Result<()> {
+        let trigger = AsyncFd::new(TriggerFd(fd))
+            .map_err(err_to_others_err!(e, "watch shutdown fd error "))?;
+        let connections = self.connections.clone();
+        let config = self.config.clone();
+        let shutdown_waiter = self.shutdown.subscribe();
+        let triggered = self.shutdown_triggered.clone();
+        spawn(async move {
+            select! {
+                res = trigger.readable() => {
+                    if let Err(e) = res {
+                        error!("wait for shutdown fd {} error: {}", fd, e);
+                        return;
+                    }
+                }
+                _ = shutdown_waiter.wait_shutdown() => return,
+            }
+            info!("shutdown triggered by fd {}", fd);
+            drain(&connections, &config, timeout).await;
+            for c in connections.lock().unwrap().values() {
+                c.close.shutdown();
+            }
+            triggered.send_replace(true);
+        });
+        Ok(())
+    }
+
+    fn start_listener(
+        &self,
+        listenfd: RawFd,
+        domain: Option<Domain>,
+        services: Arc<HashMap<String, Service>>,
+    ) -> Result<Sender<Sender<RawFd>>> {
+        match domain {
+            Some(Domain::Unix) => {
+                let sys_unix_listener;
+                unsafe {
+                    sys_unix_listener = SysUnixListener::from_raw_fd(listenfd);
+                }
+                sys_unix_listener
+                    .set_nonblocking(true)
+                    .map_err(err_to_others_err!(e, "set_nonblocking error "))?;
+                let unix_listener = UnixListener::from_std(sys_unix_listener)
+                    .map_err(err_to_others_err!(e, "from_std error "))?;
+
+                let incoming = UnixIncoming::new(unix_listener);
+
+                #[cfg(any(target_os = "linux", target_os = "android"))]
+                if self.pass_credentials {
+                    // Accepted sockets inherit the option from the listener.
+                    nix::sys::socket::setsockopt(
+                        listenfd,
+                        nix::sys::socket::sockopt::PassCred,
+                        &true,
+                    )
+                    .map_err(err_to_others_err!(e, "set SO_PASSCRED error "))?;
+                    let incoming = CredentialsIncoming::new(incoming);
+                    return Ok(self.do_start(incoming, services));
+                }
+
+                Ok(self.do_start(incoming, services))
+            }
+            // It seems that we can use UnixStream to represent both UnixStream and VsockStream.
+            // Whatever, we keep it for now for the compatibility and vsock-specific features maybe
+            // used in the future.
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            Some(Domain::Vsock) => {
+                let incoming = unsafe { VsockListener::from_raw_fd(listenfd).incoming() };
+                Ok(self.do_start(incoming, services))
+            }
+            Some(Domain::Tcp) => {
+                let sys_tcp_listener = unsafe { std::net::TcpListener::from_raw_fd(listenfd) };
+                sys_tcp_listener
+                    .set_nonblocking(true)
+                    .map_err(err_to_others_err!(e, "set_nonblocking error "))?;
+                let tcp_listener = TcpListener::from_std(sys_tcp_listener)
+                    .map_err(err_to_others_err!(e, "from_std error "))?;
+                Ok(self.do_start(TcpIncoming::new(tcp_listener), services))
+            }
+            _ => Err(Error::Others(
+                "Domain is not set or not supported".to_string(),
+            )),
+        }
+    }
+
+    fn do_start<I, S>(
+        &self,
+        mut incoming: I,
+        services: Arc<HashMap<String, Service>>,
+    ) -> Sender<Sender<RawFd>>
+    where
+        I: Stream<Item = std::io::Result<S>> + Unpin + Send + 'static + AsRawFd,
+        S: AsyncRead + AsyncWrite + AsRawFd + CaptureCredentials + Unpin + Send + 'static,
+    {
+        let config = self.config.clone();
+        let connections = self.connections.clone();
+        let metrics_hook = self.metrics_hook.clone();
+
+        let shutdown_waiter = self.shutdown.subscribe();
+
+        let (stop_listen_tx, mut stop_listen_rx) = channel::<Sender<RawFd>>(1);
+
+        spawn(async move {
+            loop {
+                select! {
+                    conn = incoming.next() => {
+                        if let Some(conn) = conn {
+                            // Accept a new connection
+                            match conn {
+                                Ok(_) if config.draining.load(Ordering::SeqCst) => {
+                                    trace!("refuse connection, server is shutting down");
+                                }
+                                Ok(conn) => {
+                                    let fd = conn.as_raw_fd();
+                                    // spawn a connection handler, would not block
+                                    spawn_connection_handler(
+                                        fd,
+                                        conn,
+                                        services.clone(),
+                                        config.clone(),
+                                        connections.clone(),
+                                        metrics_hook.clone(),
+                                        shutdown_waiter.clone(),
+                                    ).await;
+                                }
+                                Err(e) => {
+                                    error!("{:?}", e)
+                                }
+                            }
+
+                        } else {
+                            break;
+                        }
+                    }
+                    fd_tx = stop_listen_rx.recv() => {
+                        if let Some(fd_tx) = fd_tx {
+                            // dup fd to keep the listener open
+                            // or the listener will be closed when the incoming was dropped.
+                            let dup_fd = unistd::dup(incoming.as_raw_fd()).unwrap();
+                            common::set_fd_close_exec(dup_fd).unwrap();
+                            drop(incoming);
+
+                            fd_tx.send(dup_fd).await.unwrap();
+                            break;
+                        }
+                    }
+                }
+            }
+        });
+        stop_listen_tx
+    }
+
+    pub async fn shutdown(&mut self) -> Result<()> {
+        self.shutdown_with_report(false).await;
+        Ok(())
+    }
+
+    /// Stops listening and taking new calls, waits up to `timeout` for the calls
+    /// and streams in progress to finish, then closes the connections and the
+    /// listeners like [`Server::close`].
+    ///
+    /// The clients are sent a `MESSAGE_TYPE_GOAWAY` message, and the calls they
+    /// make meanwhile fail with `UNAVAILABLE`, without being sent by the clients of
+    /// this crate. The report counts the streams which didn't finish in time.
+    pub async fn graceful_shutdown(&mut self, timeout: Duration) -> ShutdownReport {
+        self.stop_listen().await;
+        let drained = drain(&self.connections, &self.config, timeout).await;
+        self.shutdown_with_report(!drained).await
+    }
+
+    /// Stops listening, closes the connections and the listeners, and reports what
+    /// was left.
+    ///
+    /// Dropping a server only signals its connections to close, without waiting for
+    /// their handlers.
+    pub async fn close(mut self) -> ShutdownReport {
+        self.shutdown_with_report(false).await
+    }
+
+    /// Closes like [`Server::close`], without waiting for the handlers of the
+    /// calls in progress if `cancel` is set.
+    async fn shutdown_with_report(&mut self, cancel: bool) -> ShutdownReport {
+        let mut report = {
+            let connections = self.connections.lock().unwrap();
+            if cancel {
+                for c in connections.values() {
+                    c.close.shutdown();
+                }
+            }
+            ShutdownReport {
+                connections: connections.len(),
+                calls_in_progress: connections
+                    .values()
+                    .map(|c| c.calls.lock().unwrap().len())
+                    .sum(),
+                ..Default::default()
+            }
+        };
+
+        self.stop_listen().await;
+        self.shutdown.shutdown();
+        if self.shutdown.wait_all_exit().await.is_err() {
+            report.detached = self.shutdown.waiters();
+        }
+        trace!("wait connection exit.");
+
+        let routes = self.routes.drain(..).map(|r| r.fd);
+        for fd in self.listeners.drain(..).chain(routes) {
+            match unistd::close(fd) {
+                Ok(()) => report.fds_closed += 1,
+                Err(e) => warn!("failed to close listener fd: {}", e),
+            }
+        }
+        report
+    }
+
+    pub async fn disconnect(&mut self) {
+        self.shutdown.shutdown();
+
+        self.shutdown
+            .wait_all_exit()
+            .await
+            .map_err(|e| {
+                trace!("wait connection exit error: {}", e);
+            })
+            .ok();
+        trace!("wait connection exit.");
+    }
+
+    pub async fn stop_listen(&mut self) {
+        if let Some(tx) = self.stop_listen_tx.take() {
+            let (fd_tx, mut fd_rx) = channel(1);
+            tx.send(fd_tx).await.unwrap();
+
+            let fd = fd_rx.recv().await.unwrap();
+            self.listeners.clear();
+            self.listeners.push(fd);
+        }
+
+        for route in self.routes.iter_mut() {
+            if let Some(tx) = route.stop_listen_tx.take() {
+                let (fd_tx, mut fd_rx) = channel(1);
+                tx.send(fd_tx).await.unwrap();
+
+                route.fd = fd_rx.recv().await.unwrap();
+            }
+        }
+    }
+}
+
+/// The fd of [`Server::shutdown_on_fd`], which isn't closed when dropped.
+struct TriggerFd(RawFd);
+
+impl AsRawFd for TriggerFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0
+    }
+}
+
+/// Refuses new calls, sends a GOAWAY to the clients and waits up to `timeout` for
+/// the calls in progress to finish. Returns `false` if some didn't.
+async fn drain(connections: &ConnectionMap, config: &ServerConfig, timeout: Duration) -> bool {
+    config.draining.store(true, Ordering::SeqCst);
+    let txs: Vec<MessageSender> = connections
+        .lock()
+        .unwrap()
+        .values()
+        .map(|c| c.tx.clone())
+        .collect();
+    for tx in txs {
+        let goaway = GenMessage {
+            header: MessageHeader {
+                length: 0,
+                stream_id: 0,
+                type_: MESSAGE_TYPE_GOAWAY,
+                flags: 0,
+            },
+            payload: Vec::new(),
+        };
+        tx.send(goaway).await.ok();
+    }
+
+    let calls_in_progress = || -> usize {
+        connections
+            .lock()
+            .unwrap()
+            .values()
+            .map(|c| c.calls.lock().unwrap().len())
+            .sum()
+    };
+    let clock = config.clock();
+    let drained = clock::timeout(&*clock, timeout, async {
+        // Polled in real time, the calls finish whatever the clock says.
+        while calls_in_progress() > 0 {
+            tokio::time::sleep(DRAIN_CHECK_INTERVAL).await;
+        }
+    })
+    .await;
+    if drained.is_err() {
+        debug!(
+            "{} calls still in progress, cancel them",
+            calls_in_progress()
+        );
+    }
+    drained.is_ok()
+}
+
+async fn spawn_connection_handler<C>(
     fd: RawFd,
-    conn: C,
+    mut conn: C,
     services: Arc<HashMap<String, Service>>,
+    config: Arc<ServerConfig>,
+    connections: ConnectionMap,
+    metrics_hook: Option<Arc<dyn MetricsHook + Send + Sync>>,
     shutdown_waiter: shutdown::Waiter,
 ) where
-    C: AsyncRead + AsyncWrite + AsRawFd + Send + 'static,
+    C: AsyncRead + AsyncWrite + AsRawFd + CaptureCredentials + Unpin + Send + 'static,
 {
+    let peer = socket::getpeername::<socket::SockaddrStorage>(fd)
+        .map(|addr| addr.to_string())
+        .unwrap_or_else(|e| format!("unknown ({e})"));
+    let peer_addr = common::peer_address(fd);
+    // Other sockets report made up credentials, if any.
+    let peer_credentials = match peer_addr {
+        Some(Address::Unix(_)) => credentials::peer_credentials(fd),
+        _ => None,
+    };
+    let connect_hook = config.connect_hook.clone();
+    let hook_shutdown = shutdown_waiter.clone();
     let delegate = ServerBuilder {
         fd,
+        peer,
+        peer_addr,
+        peer_credentials,
         services,
+        config,
+        credentials: conn.credentials(),
         streams: Arc::new(Mutex::new(HashMap::new())),
+        acks: Arc::new(Mutex::new(HashMap::new())),
+        connections: connections.clone(),
+        metrics_hook,
         shutdown_waiter,
     };
-    let conn = Connection::new(conn, delegate);
     spawn(async move {
-        conn.run()
+        if let Some(hook) = connect_hook {
+            let res = select! {
+                res = hook.on_connect(&mut conn) => res,
+                _ = hook_shutdown.wait_shutdown() => return,
+            };
+            if let Err(e) = res {
+                trace!("connect hook error. {}", e);
+                return;
+            }
+        }
+        Connection::new(conn, delegate)
+            .run()
             .await
             .map_err(|e| {
                 trace!("connection run error. {}", e);
             })
             .ok();
+        connections.lock().unwrap().remove(&fd);
     });
 }
 
@@ -304,8 +1457,16 @@ impl AsRawFd for Server {
 
 struct ServerBuilder {
     fd: RawFd,
+    peer: String,
+    peer_addr: Option<Address>,
+    peer_credentials: Option<Credentials>,
     services: Arc<HashMap<String, Service>>,
+    config: Arc<ServerConfig>,
+    credentials: Option<CredentialsSlot>,
     streams: Arc<Mutex<HashMap<u32, ResultSender>>>,
+    acks: AckWaiters,
+    connections: ConnectionMap,
+    metrics_hook: Option<Arc<dyn MetricsHook + Send + Sync>>,
     shutdown_waiter: shutdown::Waiter,
 }
 
@@ -313,56 +1474,160 @@ impl Builder for ServerBuilder {
     type Reader = ServerReader;
     type Writer = ServerWriter;
 
+    fn write_timeout(&self) -> Option<Duration> {
+        self.config.write_timeout
+    }
+
+    fn read_rate_limit(&self) -> Option<RateLimit> {
+        self.config.middleware().read_rate_limit
+    }
+
+    #[cfg(feature = "capture")]
+    fn capture(&self) -> Option<Capture> {
+        self.config.capture.clone()
+    }
+
     fn build(&mut self) -> (Self::Reader, Self::Writer) {
         let (tx, rx): (MessageSender, MessageReceiver) = channel(100);
         let (disconnect_notifier, _disconnect_waiter) =
             shutdown::with_timeout(DEFAULT_CONN_SHUTDOWN_TIMEOUT);
+        let disconnect_notifier = Arc::new(disconnect_notifier);
+        let (close_notifier, close_waiter) = shutdown::new();
+        let traffic = Arc::new(Traffic::default());
+        let extensions = Extensions::default();
+        let pings = Pings::default();
+        let weight = Arc::new(AtomicU32::new(1));
+        let calls = Arc::new(Mutex::new(HashMap::new()));
+        self.connections.lock().unwrap().insert(
+            self.fd,
+            ConnectionEntry {
+                tx: tx.clone(),
+                streams: self.streams.clone(),
+                calls: calls.clone(),
+                peer: self.peer.clone(),
+                accepted: Instant::now(),
+                traffic: traffic.clone(),
+                close: close_notifier,
+                extensions: extensions.clone(),
+                pings: pings.clone(),
+            },
+        );
 
         (
             ServerReader {
                 fd: self.fd,
+                peer_addr: self.peer_addr.clone(),
+                peer_credentials: self.peer_credentials,
                 tx,
                 services: self.services.clone(),
+                config: self.config.clone(),
+                credentials: self.credentials.clone(),
                 streams: self.streams.clone(),
+                acks: self.acks.clone(),
+                calls,
+                pings,
+                extensions,
+                metrics_hook: self.metrics_hook.clone(),
+                traffic: traffic.clone(),
+                weight: weight.clone(),
                 server_shutdown: self.shutdown_waiter.clone(),
+                close_waiter,
+                handler_shutdown: disconnect_notifier.clone(),
+                max_requests: self
+                    .config
+                    .max_requests_per_connection
+                    .map(RequestLimit::new),
+            },
+            ServerWriter {
+                fd: self.fd,
+                rx,
+                config: self.config.clone(),
+                traffic,
+                weight,
                 handler_shutdown: disconnect_notifier,
+                _server_shutdown: self.shutdown_waiter.clone(),
             },
-            ServerWriter { rx, _server_shutdown: self.shutdown_waiter.clone() },
         )
     }
 }
 
 struct ServerWriter {
+    fd: RawFd,
     rx: MessageReceiver,
-    _server_shutdown: shutdown::Waiter
+    config: Arc<ServerConfig>,
+    traffic: Arc<Traffic>,
+    weight: Arc<AtomicU32>,
+    handler_shutdown: Arc<shutdown::Notifier>,
+    _server_shutdown: shutdown::Waiter,
 }
 
 #[async_trait]
 impl WriterDelegate for ServerWriter {
     async fn recv(&mut self) -> Option<GenMessage> {
-        self.rx.recv().await
+        let msg = self.rx.recv().await?;
+        if let Some(share) = &self.config.middleware().write_share {
+            let bytes = MESSAGE_HEADER_LENGTH + msg.header.length as usize;
+            share
+                .ready(self.fd, self.weight.load(Ordering::Relaxed), bytes)
+                .await;
+        }
+        Traffic::add(&self.traffic.written, &msg.header);
+        Some(msg)
+    }
+    async fn disconnect(&self, msg: &GenMessage, e: Error) {
+        // The client is gone (e.g. EPIPE), no response can be delivered anymore,
+        // so cancel the running handlers instead of letting them finish for nothing.
+        trace!("Write {:?} failed, cancel handlers: {:?}", msg.header, e);
+        self.handler_shutdown.shutdown();
     }
-    async fn disconnect(&self, _msg: &GenMessage, _: Error) {}
     async fn exit(&self) {}
 }
 
 struct ServerReader {
     fd: RawFd,
+    peer_addr: Option<Address>,
+    peer_credentials: Option<Credentials>,
     tx: MessageSender,
     services: Arc<HashMap<String, Service>>,
+    config: Arc<ServerConfig>,
+    credentials: Option<CredentialsSlot>,
     streams: Arc<Mutex<HashMap<u32, ResultSender>>>,
+    acks: AckWaiters,
+    /// Cancels the calls in progress, see [`MESSAGE_TYPE_CANCEL`].
+    calls: Arc<Mutex<HashMap<u32, oneshot::Sender<()>>>>,
+    pings: Pings,
+    extensions: Extensions,
+    metrics_hook: Option<Arc<dyn MetricsHook + Send + Sync>>,
+    traffic: Arc<Traffic>,
+    weight: Arc<AtomicU32>,
     server_shutdown: shutdown::Waiter,
-    handler_shutdown: shutdown::Notifier,
+    close_waiter: shutdown::Waiter,
+    handler_shutdown: Arc<shutdown::Notifier>,
+    /// See [`Server::set_max_concurrent_requests_per_connection`].
+    max_requests: Option<RequestLimit>,
 }
 
 #[async_trait]
 impl ReaderDelegate for ServerReader {
     async fn wait_shutdown(&self) {
-        self.server_shutdown.wait_shutdown().await
+        select! {
+            // A graceful shutdown which timed out closes the connections right
+            // before shutting the server down, and must not wait for the calls.
+            biased;
+
+            _ = self.close_waiter.wait_shutdown() => {
+                // Evicted by Server::close_connection, don't wait for the calls in progress.
+                self.handler_shutdown.shutdown();
+            }
+            _ = self.server_shutdown.wait_shutdown() => {}
+        }
     }
 
     async fn disconnect(&self, _: Error, _: &mut task::JoinHandle<()>) {
         self.handler_shutdown.shutdown();
+        // Fail the sends waiting for an acknowledgement, and the pings.
+        self.acks.lock().unwrap().clear();
+        self.pings.clear();
         // TODO: Don't wait for all requests to complete? when the connection is disconnected.
     }
 
@@ -379,38 +1644,158 @@ impl ReaderDelegate for ServerReader {
     }
 
     async fn handle_msg(&self, msg: GenMessage) {
+        Traffic::add(&self.traffic.read, &msg.header);
+        if let Some(hook) = self.metrics_hook.as_ref() {
+            hook.on_state(&DebugState::collect(&self.tx, &self.streams));
+        }
+        let stream_id = msg.header.stream_id;
+        match msg.header.type_ {
+            MESSAGE_TYPE_CANCEL => {
+                // Wakes up the call, which drops its handler. The call may have
+                // finished already.
+                if self.calls.lock().unwrap().remove(&stream_id).is_some() {
+                    trace!("Cancel call {}", stream_id);
+                }
+                return;
+            }
+            MESSAGE_TYPE_PING => {
+                let pong = ping::pong(self.config.ping_handler.as_deref(), &msg);
+                self.tx.send(pong).await.ok();
+                return;
+            }
+            MESSAGE_TYPE_PONG => {
+                self.pings.pong(msg);
+                return;
+            }
+            _ => {}
+        }
+        // Held until the request is answered.
+        let permits = match self.acquire_request_permits(&msg.header) {
+            Ok(permits) => permits,
+            Err(status) => {
+                HandlerContext::respond_with_status(self.tx.clone(), stream_id, status).await;
+                return;
+            }
+        };
+        let cancel_rx = (msg.header.type_ == MESSAGE_TYPE_REQUEST).then(|| {
+            let (cancel_tx, cancel_rx) = oneshot::channel();
+            self.calls.lock().unwrap().insert(stream_id, cancel_tx);
+            cancel_rx
+        });
         let handler_shutdown_waiter = self.handler_shutdown.subscribe();
+        let server_shutdown = self.server_shutdown.clone();
         let context = self.context();
+        let calls = self.calls.clone();
         spawn(async move {
-            select! {
-                _ = context.handle_msg(msg) => {}
-                _ = handler_shutdown_waiter.wait_shutdown() => {}
+            let is_request = cancel_rx.is_some();
+            let dropped = select! {
+                dropped = context.handle_msg(msg) => dropped,
+                _ = handler_shutdown_waiter.wait_shutdown() => None,
+                _ = cancelled(cancel_rx) => None,
+            };
+            if is_request {
+                calls.lock().unwrap().remove(&stream_id);
+            }
+            drop(permits);
+            // The call is done, only its answer waits and holds no limits.
+            if let Some(until) = dropped {
+                let clock = context.clock();
+                select! {
+                    _ = clock.sleep_until(until) => {
+                        let status = get_status(
+                            Code::DEADLINE_EXCEEDED,
+                            "response dropped by fault injection",
+                        );
+                        HandlerContext::respond_with_status(context.tx.clone(), stream_id, status)
+                            .await;
+                    }
+                    _ = handler_shutdown_waiter.wait_shutdown() => {}
+                    // Unlike the calls, not waited for when the server shuts down.
+                    _ = server_shutdown.wait_shutdown() => {}
+                }
             }
         });
     }
 
     async fn handle_err(&self, header: MessageHeader, e: Error) {
+        Traffic::add(&self.traffic.read, &header);
         self.context().handle_err(header, e).await
     }
 }
 
 impl ServerReader {
+    /// The permits of the server and connection request limits for a request, none
+    /// for other messages.
+    fn acquire_request_permits(
+        &self,
+        header: &MessageHeader,
+    ) -> StdResult<Vec<OwnedSemaphorePermit>, Status> {
+        if header.type_ != MESSAGE_TYPE_REQUEST {
+            return Ok(Vec::new());
+        }
+        let mut permits = Vec::new();
+        if let Some(limit) = self.config.max_requests.as_ref() {
+            permits.push(limit.try_acquire("too many requests in progress on the server")?);
+        }
+        if let Some(limit) = self.max_requests.as_ref() {
+            permits.push(limit.try_acquire("too many requests in progress on the connection")?);
+        }
+        Ok(permits)
+    }
+
     fn context(&self) -> HandlerContext {
         HandlerContext {
             fd: self.fd,
+            peer_addr: self.peer_addr.clone(),
+            peer_credentials: self.peer_credentials,
             tx: self.tx.clone(),
             services: self.services.clone(),
+            config: self.config.clone(),
+            // Every call consumes a message, take the credentials of its writer.
+            credentials: self
+                .credentials
+                .as_ref()
+                .and_then(|slot| slot.lock().unwrap().take()),
             streams: self.streams.clone(),
+            acks: self.acks.clone(),
+            extensions: self.extensions.clone(),
+            weight: self.weight.clone(),
             _handler_shutdown_waiter: self.handler_shutdown.subscribe(),
         }
     }
 }
 
+/// Resolves once the call is cancelled by the client, never for messages which
+/// don't start a call.
+async fn cancelled(cancel_rx: Option<oneshot::Receiver<()>>) {
+    match cancel_rx {
+        Some(cancel_rx) => cancel_rx.await.ok(),
+        None => std::future::pending().await,
+    };
+}
+
+/// How to answer a request.
+enum Reply {
+    /// Send the response, or close the stream of a streaming call.
+    Send(Option<Response>),
+    /// Send DEADLINE_EXCEEDED at `until` instead of the response, see
+    /// [`Fault::DropResponse`].
+    Drop { until: Instant },
+}
+
 struct HandlerContext {
     fd: RawFd,
+    peer_addr: Option<Address>,
+    peer_credentials: Option<Credentials>,
     tx: MessageSender,
     services: Arc<HashMap<String, Service>>,
+    config: Arc<ServerConfig>,
+    credentials: Option<Credentials>,
     streams: Arc<Mutex<HashMap<u32, ResultSender>>>,
+    acks: AckWaiters,
+    extensions: Extensions,
+    /// The weight of the connection, see [`Server::set_weigher`].
+    weight: Arc<AtomicU32>,
     // Used for waiting handler exit.
     _handler_shutdown_waiter: shutdown::Waiter,
 }
@@ -424,7 +1809,9 @@ impl HandlerContext {
             })
             .ok();
     }
-    async fn handle_msg(&self, msg: GenMessage) {
+    /// Handles a message, returns when to send the answer of a request whose
+    /// response a [`Fault::DropResponse`] dropped.
+    async fn handle_msg(&self, msg: GenMessage) -> Option<Instant> {
         let stream_id = msg.header.stream_id;
 
         if (stream_id % 2) != 1 {
@@ -434,42 +1821,75 @@ impl HandlerContext {
                 get_status(Code::INVALID_ARGUMENT, "stream id must be odd"),
             )
             .await;
-            return;
+            return None;
         }
 
         match msg.header.type_ {
-            MESSAGE_TYPE_REQUEST => match self.handle_request(msg).await {
-                Ok(opt_msg) => match opt_msg {
-                    Some(mut resp) => {
-                        // Server: check size before sending to client
-                        if let Err(e) = check_oversize(resp.compute_size() as usize, true) {
-                            resp = e.into();
-                        }
-
-                        Self::respond(self.tx.clone(), stream_id, resp)
-                            .await
-                            .map_err(|e| {
-                                error!("respond got error {:?}", e);
-                            })
-                            .ok();
+            MESSAGE_TYPE_REQUEST => {
+                let request_size = msg.payload.len();
+                let res = match Message::<Request>::try_from(msg) {
+                    Ok(req_msg) if is_subscription(&req_msg.payload) => {
+                        // Never answered, the stream stays open for notifications.
+                        let notifier = Notifier::new(self.tx.clone(), stream_id);
+                        self.extensions.insert(notifier);
+                        return None;
                     }
-                    None => {
-                        let mut header = MessageHeader::new_data(stream_id, 0);
-                        header.set_flags(FLAG_REMOTE_CLOSED | FLAG_NO_DATA);
-                        let msg = GenMessage {
-                            header,
-                            payload: Vec::new(),
-                        };
-
-                        self.tx
-                            .send(msg)
-                            .await
-                            .map_err(err_to_others_err!(e, "Send packet to sender error "))
-                            .ok();
+                    Ok(req_msg) => match self.reserve(request_size) {
+                        // Held while the request is handled.
+                        Ok(_request) => self.handle_request(req_msg).await,
+                        Err(status) => Err(status),
+                    },
+                    Err(e) => Err(get_status(Code::INVALID_ARGUMENT, e.to_string())),
+                };
+                match res {
+                    Ok(Reply::Drop { until }) => return Some(until),
+                    Ok(Reply::Send(opt_msg)) => match opt_msg {
+                        Some(mut resp) => {
+                            let size = resp.compute_size() as usize;
+                            // Server: check size before sending to client
+                            if let Err(e) = check_oversize(size, true) {
+                                resp = e.into();
+                            }
+                            // Held until the response is queued.
+                            let _response = self.reserve(size).unwrap_or_else(|status| {
+                                resp = Error::RpcStatus(status).into();
+                                None
+                            });
+                            if let Some(status) = resp.status.as_mut() {
+                                self.redact(status);
+                            }
+
+                            Self::respond(self.tx.clone(), stream_id, resp)
+                                .await
+                                .map_err(|e| {
+                                    error!("respond got error {:?}", e);
+                                })
+                                .ok();
+                        }
+                        None => {
+                            let mut header = MessageHeader::new_data(stream_id, 0);
+                            header.set_flags(FLAG_REMOTE_CLOSED | FLAG_NO_DATA);
+                            let msg = GenMessage {
+                                header,
+                                payload: Vec::new(),
+                            };
+
+                            self.tx
+                                .send(msg)
+                                .await
+                                .map_err(err_to_others_err!(e, "Send packet to sender error "))
+                                .ok();
+                        }
+                    },
+                    Err(mut status) => {
+                        self.redact(&mut status);
+                        Self::respond_with_status(self.tx.clone(), stream_id, status).await
                     }
-                },
-                Err(status) => Self::respond_with_status(self.tx.clone(), stream_id, status).await,
-            },
+                }
+            }
+            MESSAGE_TYPE_DATA if (msg.header.flags & FLAG_ACK) == FLAG_ACK => {
+                notify_ack(&self.acks, stream_id);
+            }
             MESSAGE_TYPE_DATA => {
                 // TODO(wllenyj): Compatible with golang behavior.
                 if (msg.header.flags & FLAG_REMOTE_CLOSED) == FLAG_REMOTE_CLOSED
@@ -486,7 +1906,7 @@ impl HandlerContext {
                         ),
                     )
                     .await;
-                    return;
+                    return None;
                 }
                 let stream_tx = self.streams.lock().unwrap().get(&stream_id).cloned();
                 if let Some(stream_tx) = stream_tx {
@@ -516,40 +1936,276 @@ impl HandlerContext {
                 error!("Unknown message type. {:?}", msg.header);
             }
         }
+        None
+    }
+
+    fn clock(&self) -> Arc<dyn Clock> {
+        self.config.clock()
+    }
+
+    fn context(
+        &self,
+        header: MessageHeader,
+        req: &Request,
+        response_metadata: ResponseMetadata,
+    ) -> TtrpcContext {
+        let clock = self.clock();
+        TtrpcContext {
+            fd: self.fd,
+            mh: header,
+            metadata: context::from_pb(&req.metadata),
+            timeout_nano: req.timeout_nano,
+            deadline: context::deadline_after(clock.now(), req.timeout_nano),
+            clock,
+            credentials: self.credentials,
+            extensions: self.extensions.clone(),
+            peer: self.peer_addr.clone(),
+            peer_credentials: self.peer_credentials,
+            response_metadata,
+        }
+    }
+
+    fn redact(&self, status: &mut Status) {
+        if let Some(redactor) = self.config.middleware().error_redactor.as_ref() {
+            if status.code() != Code::OK {
+                redactor.redact(status);
+            }
+        }
+    }
+
+    /// Reserves `bytes` against the memory budget of the server, if any.
+    fn reserve(&self, bytes: usize) -> StdResult<Option<Reservation>, Status> {
+        match &self.config.memory_budget {
+            Some(budget) => budget.try_reserve(bytes).map(Some).ok_or_else(|| {
+                get_limit_status(
+                    Code::RESOURCE_EXHAUSTED,
+                    "server memory budget exceeded",
+                    LIMIT_MEMORY,
+                    budget.limit() as u64,
+                    (budget.used() + bytes) as u64,
+                )
+            }),
+            None => Ok(None),
+        }
     }
 
-    async fn handle_request(&self, msg: GenMessage) -> StdResult<Option<Response>, Status> {
+    async fn handle_request(&self, mut req_msg: Message<Request>) -> StdResult<Reply, Status> {
         //TODO:
         //if header.stream_id <= self.last_stream_id {
         //    return Err;
         //}
         // self.last_stream_id = header.stream_id;
 
-        let req_msg = Message::<Request>::try_from(msg)
-            .map_err(|e| get_status(Code::INVALID_ARGUMENT, e.to_string()))?;
-
         let req = &req_msg.payload;
         trace!("Got Message request {} {}", req.service, req.method);
 
-        let srv = self.services.get(&req.service).ok_or_else(|| {
-            get_status(
-                Code::INVALID_ARGUMENT,
-                format!("{} service does not exist", &req.service),
-            )
-        })?;
+        if self.config.draining.load(Ordering::SeqCst) {
+            return Err(get_status(Code::UNAVAILABLE, "server is shutting down"));
+        }
+
+        if req.service == HANDSHAKE_SERVICE && req.method == HANDSHAKE_METHOD {
+            let version = String::from_utf8_lossy(&req.payload).into_owned();
+            self.extensions.insert(PeerVersion(version));
+            let mut res = Response::new();
+            res.set_status(get_status(Code::OK, ""));
+            res.payload = self.config.schema_version.clone().into_bytes();
+            res.metadata.push(KeyValue {
+                key: CAPABILITIES_KEY.to_string(),
+                value: CAPABILITY_CANCEL.to_string(),
+                ..Default::default()
+            });
+            if self.config.unary_over_stream {
+                res.metadata.push(KeyValue {
+                    key: CAPABILITIES_KEY.to_string(),
+                    value: CAPABILITY_UNARY_OVER_STREAM.to_string(),
+                    ..Default::default()
+                });
+            }
+            if let Some(epoch) = self.config.restart_epoch {
+                res.metadata.push(KeyValue {
+                    key: RESTART_EPOCH_KEY.to_string(),
+                    value: epoch.to_string(),
+                    ..Default::default()
+                });
+            }
+            if let Some((digests, drift)) = &self.config.schema_digests {
+                let drifted = handshake::drifted_methods(digests, &req.metadata);
+                for path in drifted.iter() {
+                    warn!(
+                        "{} of client {} was generated from another schema",
+                        path, self.fd
+                    );
+                    res.metadata.push(KeyValue {
+                        key: SCHEMA_DRIFT_KEY.to_string(),
+                        value: path.clone(),
+                        ..Default::default()
+                    });
+                }
+                if *drift == SchemaDrift::Reject && !drifted.is_empty() {
+                    let drifted = DriftedMethods(Arc::new(drifted.into_iter().collect()));
+                    self.extensions.insert(drifted);
+                }
+            }
+            return Ok(Reply::Send(Some(res)));
+        }
+
+        self.find_service(&req.service)?;
+
+        let middleware = self.config.middleware();
+        self.check_call(&middleware, req_msg.header, req)?;
+        let route = utils::get_path(&req.service, &req.method);
+        for interceptor in middleware.interceptors.iter() {
+            let ctx = self.context(
+                req_msg.header,
+                &req_msg.payload,
+                ResponseMetadata::default(),
+            );
+            interceptor.intercept(&ctx, &mut req_msg.payload)?;
+        }
+        let req = &req_msg.payload;
+        // The interceptors may have routed the call to another service.
+        let (srv, relay) = self.find_service(&req.service)?;
+        if utils::get_path(&req.service, &req.method) != route {
+            self.check_call(&middleware, req_msg.header, req)?;
+        }
+
+        if let (Some(limit), Some(_)) = (self.config.max_decoded_size, srv) {
+            let size = decode_limit::decoded_size(&req.payload);
+            if size > limit {
+                return Err(get_limit_status(
+                    Code::RESOURCE_EXHAUSTED,
+                    format!(
+                        "{}.{} request decodes to about {} bytes, more than {}",
+                        &req.service, &req.method, size, limit
+                    ),
+                    LIMIT_DECODED_SIZE,
+                    limit as u64,
+                    size as u64,
+                ));
+            }
+        }
+
+        let path = utils::get_path(&req.service, &req.method);
+        let priority = self
+            .config
+            .priorities
+            .get(&path)
+            .copied()
+            .or_else(|| {
+                if !self.config.priority_hints {
+                    return None;
+                }
+                req.metadata
+                    .iter()
+                    .find(|kv| kv.key == PRIORITY_METADATA_KEY)
+                    .and_then(|kv| Priority::parse(&kv.value))
+            })
+            .unwrap_or_default();
+        if let Some(weigher) = &middleware.weigher {
+            let ctx = self.context(req_msg.header, req, ResponseMetadata::default());
+            self.weight.store(weigher.weight(&ctx), Ordering::Relaxed);
+        }
+        // Held until the call finished.
+        let _permit = match self.config.lanes.get(&priority) {
+            Some(lane) => {
+                let weight = self.weight.load(Ordering::Relaxed);
+                Some(lane.acquire(self.fd, weight, 1.0).await)
+            }
+            None => None,
+        };
+
+        let fault = middleware
+            .fault_injector
+            .as_ref()
+            .and_then(|faults| faults.pick(&req.service, &req.method))
+            .cloned();
+        match &fault {
+            Some(Fault::Delay(delay)) => clock::sleep(&*self.clock(), *delay).await,
+            Some(Fault::Status(status)) => return Err(status.clone()),
+            _ => {}
+        }
+        let now = self.clock().now();
+        let deadline = context::deadline_after(now, req.timeout_nano);
+        let res = self.dispatch(srv, relay, req_msg).await;
+        if let Some(Fault::DropResponse) = fault {
+            // The client gives up on the call by its deadline, answer it then, or
+            // once it surely gave up on a call without one.
+            let hold = now + MAX_DROPPED_RESPONSE_HOLD;
+            let until = deadline.map_or(hold, |deadline| deadline.min(hold));
+            return Ok(Reply::Drop { until });
+        }
+        res.map(Reply::Send)
+    }
+
+    /// Fails a call of a method which drifted from the schema of the client, see
+    /// [`SchemaDrift::Reject`], or which the authorizer denies.
+    fn check_call(
+        &self,
+        middleware: &Middleware,
+        header: MessageHeader,
+        req: &Request,
+    ) -> StdResult<(), Status> {
+        if let Some(DriftedMethods(drifted)) = self.extensions.get::<DriftedMethods>() {
+            let path = utils::get_path(&req.service, &req.method);
+            if drifted.contains(&path) {
+                return Err(get_status(
+                    Code::FAILED_PRECONDITION,
+                    format!("{path} of the client was generated from another schema"),
+                ));
+            }
+        }
+        if let Some(authorizer) = &middleware.authorizer {
+            let ctx = self.context(header, req, ResponseMetadata::default());
+            if !authorizer.authorize(&ctx, &req.service, &req.method) {
+                return Err(get_status(
+                    Code::PERMISSION_DENIED,
+                    format!("{}.{} is not allowed", &req.service, &req.method),
+                ));
+            }
+        }
+        Ok(())
+    }
 
-        if let Some(method) = srv.get_method(&req.method) {
+    /// Runs the handler of a call.
+    async fn dispatch(
+        &self,
+        srv: Option<&Service>,
+        relay: Option<&Relay>,
+        req_msg: Message<Request>,
+    ) -> StdResult<Option<Response>, Status> {
+        let req = &req_msg.payload;
+        if let Some(method) = srv.and_then(|srv| srv.get_method(&req.method)) {
+            let streaming = (req_msg.header.flags & FLAG_REMOTE_OPEN) == FLAG_REMOTE_OPEN;
+            if streaming && self.config.unary_over_stream {
+                return self.handle_method_over_stream(method, req_msg).await;
+            }
             return self.handle_method(method, req_msg).await;
         }
-        if let Some(stream) = srv.get_stream(&req.method) {
+        if let Some(stream) = srv.and_then(|srv| srv.get_stream(&req.method)) {
             return self.handle_stream(stream, req_msg).await;
         }
+        if let Some(relay) = relay {
+            return self.handle_method(relay.as_ref(), req_msg).await;
+        }
         Err(get_status(
             Code::UNIMPLEMENTED,
             format!("{} method", &req.method),
         ))
     }
 
+    /// The service, or the relay, handling the calls of `service`.
+    fn find_service(&self, service: &str) -> StdResult<(Option<&Service>, Option<&Relay>), Status> {
+        let srv = self.services.get(service);
+        let relay = self.config.relays.get(service);
+        if srv.is_none() && relay.is_none() {
+            return Err(get_status(
+                Code::INVALID_ARGUMENT,
+                format!("{} service does not exist", service),
+            ));
+        }
+        Ok((srv, relay))
+    }
+
     async fn handle_method(
         &self,
         method: &(dyn MethodHandler + Send + Sync),
@@ -558,40 +2214,128 @@ impl HandlerContext {
         let req = req_msg.payload;
         let path = utils::get_path(&req.service, &req.method);
 
-        let ctx = TtrpcContext {
-            fd: self.fd,
-            mh: req_msg.header,
-            metadata: context::from_pb(&req.metadata),
-            timeout_nano: req.timeout_nano,
-        };
+        let response_metadata = ResponseMetadata::default();
+        let ctx = self.context(req_msg.header, &req, response_metadata.clone());
 
         let get_unknown_status_and_log_err = |e| {
             error!("method handle {} got error {:?}", path, &e);
             get_status(Code::UNKNOWN, e)
         };
-        if req.timeout_nano == 0 {
-            method
-                .handler(ctx, req)
+        let with_metadata = |mut resp: Response| {
+            response_metadata.apply(&mut resp);
+            Some(resp)
+        };
+        let timeout_nano = req.timeout_nano;
+        let clock = ctx.clock.clone();
+        let started = clock.now();
+        let handler = self.call_handler(method, ctx, req);
+        if timeout_nano == 0 {
+            handler
                 .await
                 .map_err(get_unknown_status_and_log_err)
-                .map(Some)
+                .map(with_metadata)
         } else {
-            timeout(
-                Duration::from_nanos(req.timeout_nano as u64),
-                method.handler(ctx, req),
-            )
+            clock::timeout(&*clock, Duration::from_nanos(timeout_nano as u64), handler)
+                .await
+                .map_err(|_| {
+                    // Timed out
+                    error!("method handle {} got error timed out", path);
+                    get_limit_status(
+                        Code::DEADLINE_EXCEEDED,
+                        "timeout",
+                        LIMIT_DEADLINE,
+                        timeout_nano as u64,
+                        (clock.now() - started).as_nanos() as u64,
+                    )
+                })
+                .and_then(|r| {
+                    // Handler finished
+                    r.map_err(get_unknown_status_and_log_err)
+                })
+                .map(with_metadata)
+        }
+    }
+
+    /// Runs the handler of a unary method, see [`Server::set_catch_panics`].
+    async fn call_handler(
+        &self,
+        method: &(dyn MethodHandler + Send + Sync),
+        ctx: TtrpcContext,
+        req: Request,
+    ) -> Result<Response> {
+        let handler = method.handler(ctx, req);
+        if !self.config.catch_panics {
+            return handler.await;
+        }
+        // The handler is dropped once it panicked, nothing observes its state.
+        AssertUnwindSafe(handler)
+            .catch_unwind()
             .await
-            .map_err(|_| {
-                // Timed out
-                error!("method handle {} got error timed out", path);
-                get_status(Code::DEADLINE_EXCEEDED, "timeout")
+            .unwrap_or_else(|panic| {
+                let msg = panic
+                    .downcast_ref::<&str>()
+                    .copied()
+                    .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+                    .unwrap_or("unknown panic");
+                Err(Error::Others(format!("handler panicked: {msg}")))
             })
-            .and_then(|r| {
-                // Handler finished
-                r.map_err(get_unknown_status_and_log_err)
-            })
-            .map(Some)
+    }
+
+    /// Handles a unary method called as a stream, which carries the request
+    /// message and then the response message.
+    async fn handle_method_over_stream(
+        &self,
+        method: &(dyn MethodHandler + Send + Sync),
+        mut req_msg: Message<Request>,
+    ) -> StdResult<Option<Response>, Status> {
+        let stream_id = req_msg.header.stream_id;
+        let (tx, rx): (ResultSender, ResultReceiver) = channel(2);
+        self.open_stream(stream_id, tx)?;
+        let mut si = StreamInner::new(
+            stream_id,
+            self.tx.clone(),
+            rx,
+            true,
+            true,
+            Kind::Server,
+            self.streams.clone(),
+            self.acks.clone(),
+        )
+        .with_compression(self.config.compression.clone());
+
+        if (req_msg.header.flags & FLAG_NO_DATA) == FLAG_NO_DATA {
+            req_msg.payload.payload = si.recv().await.map_err(|e| match e {
+                Error::Eof => get_status(Code::INVALID_ARGUMENT, "stream has no request"),
+                e => get_status(Code::UNKNOWN, e),
+            })?;
+        }
+        let resp = self.handle_method(method, req_msg).await;
+        self.acks.lock().unwrap().remove(&stream_id);
+        match resp {
+            Ok(Some(mut resp)) if resp.status().code() == Code::OK => {
+                si.send(std::mem::take(&mut resp.payload))
+                    .await
+                    .map_err(|e| get_status(Code::UNKNOWN, e))?;
+                Ok(Some(resp))
+            }
+            resp => resp,
+        }
+    }
+
+    /// Registers the sender of the messages of a new stream.
+    fn open_stream(&self, stream_id: u32, tx: ResultSender) -> StdResult<(), Status> {
+        let mut streams = self.streams.lock().unwrap();
+        if let Some(max) = self.config.max_streams.filter(|max| streams.len() >= *max) {
+            return Err(get_limit_status(
+                Code::RESOURCE_EXHAUSTED,
+                "too many open streams on the connection",
+                LIMIT_STREAMS,
+                max as u64,
+                streams.len() as u64 + 1,
+            ));
         }
+        streams.insert(stream_id, tx);
+        Ok(())
     }
 
     async fn handle_stream(
@@ -603,9 +2347,15 @@ impl HandlerContext {
         let req = req_msg.payload;
         let path = utils::get_path(&req.service, &req.method);
 
-        let (tx, rx): (ResultSender, ResultReceiver) = channel(100);
+        let buffer = self
+            .config
+            .stream_buffers
+            .get(&path)
+            .copied()
+            .unwrap_or(DEFAULT_STREAM_BUFFER);
+        let (tx, rx): (ResultSender, ResultReceiver) = channel(buffer);
         let stream_tx = tx.clone();
-        self.streams.lock().unwrap().insert(stream_id, tx);
+        self.open_stream(stream_id, tx)?;
 
         let no_data = (req_msg.header.flags & FLAG_NO_DATA) == FLAG_NO_DATA;
 
@@ -617,16 +2367,17 @@ impl HandlerContext {
             true,
             Kind::Server,
             self.streams.clone(),
-        );
+            self.acks.clone(),
+        )
+        .with_compression(self.config.compression.clone());
 
-        let ctx = TtrpcContext {
-            fd: self.fd,
-            mh: req_msg.header,
-            metadata: context::from_pb(&req.metadata),
-            timeout_nano: req.timeout_nano,
-        };
+        let response_metadata = ResponseMetadata::default();
+        let ctx = self.context(req_msg.header, &req, response_metadata.clone());
 
         let task = spawn(async move { stream.handler(ctx, si).await });
+        // The handler runs in its own task, abort it if the call is cancelled because
+        // the connection is gone.
+        let _abort = AbortOnDrop(task.abort_handle());
 
         if !no_data {
             // Fake the first data message.
@@ -639,9 +2390,26 @@ impl HandlerContext {
                 get_status(Code::UNKNOWN, e)
             })?;
         }
-        task.await
+        let result = task
+            .await
             .unwrap_or_else(|e| Err(Error::Others(format!("stream {path} task got error {e:?}"))))
             .map_err(|e| get_status(Code::UNKNOWN, e))
+            .map(|resp| {
+                if resp.is_none() && response_metadata.is_empty() {
+                    return None;
+                }
+                // Streams without a response of their own end with an empty one which
+                // carries the metadata.
+                let mut resp = resp.unwrap_or_else(|| {
+                    let mut resp = Response::new();
+                    resp.set_status(get_status(Code::OK, ""));
+                    resp
+                });
+                response_metadata.apply(&mut resp);
+                Some(resp)
+            });
+        self.acks.lock().unwrap().remove(&stream_id);
+        result
     }
 
     async fn respond(tx: MessageSender, stream_id: u32, resp: Response) -> Result<()> {
@@ -668,3 +2436,951 @@ impl HandlerContext {
             .ok();
     }
 }
+
+struct AbortOnDrop(task::AbortHandle);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tokio::sync::Notify;
+
+    use crate::r#async::{testing, CallOptions, Client, ClientBuilder, FaultInjector, MockClock};
+
+    /// Answers with the payload of the request, and notifies `handled` if set.
+    struct Echo {
+        handled: Option<Arc<Notify>>,
+    }
+
+    #[async_trait]
+    impl MethodHandler for Echo {
+        async fn handler(&self, _ctx: TtrpcContext, req: Request) -> Result<Response> {
+            if let Some(handled) = &self.handled {
+                handled.notify_one();
+            }
+            Ok(Response {
+                payload: req.payload,
+                ..Default::default()
+            })
+        }
+    }
+
+    /// Notifies `started`, and never answers.
+    struct Hang {
+        started: Arc<Notify>,
+    }
+
+    #[async_trait]
+    impl MethodHandler for Hang {
+        async fn handler(&self, _ctx: TtrpcContext, _req: Request) -> Result<Response> {
+            self.started.notify_one();
+            std::future::pending().await
+        }
+    }
+
+    /// `Echo`, `Hang`, and `Drop` which notifies `handled` like `Hang` does.
+    fn services(handled: &Arc<Notify>) -> HashMap<String, Service> {
+        let mut methods: HashMap<String, Box<dyn MethodHandler + Send + Sync>> = HashMap::new();
+        methods.insert("Echo".to_string(), Box::new(Echo { handled: None }));
+        let hang = Hang {
+            started: handled.clone(),
+        };
+        methods.insert("Hang".to_string(), Box::new(hang));
+        let drop = Echo {
+            handled: Some(handled.clone()),
+        };
+        methods.insert("Drop".to_string(), Box::new(drop));
+        let mut services = HashMap::new();
+        services.insert(
+            "test.Test".to_string(),
+            Service {
+                methods,
+                streams: HashMap::new(),
+            },
+        );
+        services
+    }
+
+    fn request(method: &str) -> Request {
+        Request {
+            service: "test.Test".to_string(),
+            method: method.to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// Retries `Echo` while the server rejects it with `RESOURCE_EXHAUSTED`.
+    async fn echo_when_admitted(client: &Client) -> Result<Response> {
+        for _ in 0..1000 {
+            match client.request(request("Echo")).await {
+                Err(Error::RpcStatus(s)) if s.code() == Code::RESOURCE_EXHAUSTED => {
+                    tokio::time::sleep(Duration::from_millis(5)).await
+                }
+                res => return res,
+            }
+        }
+        client.request(request("Echo")).await
+    }
+
+    #[tokio::test]
+    async fn test_drop_response() {
+        let handled = Arc::new(Notify::new());
+        let clock = MockClock::new();
+        let server = Server::new()
+            .register_service(services(&handled))
+            .set_max_concurrent_requests_per_connection(1)
+            .set_clock(Arc::new(clock.clone()))
+            .set_fault_injector(FaultInjector::new().inject(
+                "test.Test",
+                "Drop",
+                Fault::DropResponse,
+                1.0,
+            ));
+        let mut server = testing::start(server).await.unwrap();
+        let client = Client::connect(&server.address()).unwrap();
+
+        // The dropped call no longer holds the limit of the connection, and is
+        // answered once the hold is over by the clock of the server.
+        let dropped = tokio::spawn({
+            let client = client.clone();
+            async move { client.request(request("Drop")).await }
+        });
+        handled.notified().await;
+        echo_when_admitted(&client).await.unwrap();
+        assert!(!dropped.is_finished());
+        clock.advance(MAX_DROPPED_RESPONSE_HOLD);
+        let res = tokio::time::timeout(Duration::from_secs(5), dropped)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(matches!(res, Err(Error::RpcStatus(s)) if s.code() == Code::DEADLINE_EXCEEDED));
+
+        // Nor does a graceful shutdown wait for it.
+        let mut req = request("Drop");
+        req.timeout_nano = Duration::from_secs(3600).as_nanos() as i64;
+        let dropped = tokio::spawn({
+            let client = client.clone();
+            async move { client.request(req).await }
+        });
+        handled.notified().await;
+        echo_when_admitted(&client).await.unwrap();
+        let report = server
+            .server_mut()
+            .graceful_shutdown(Duration::from_secs(5))
+            .await;
+        assert_eq!(report.calls_in_progress, 0);
+        assert_eq!(report.detached, 0);
+        tokio::time::timeout(Duration::from_secs(5), dropped)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap_err();
+    }
+
+    #[tokio::test]
+    async fn test_graceful_shutdown_timeout() {
+        let started = Arc::new(Notify::new());
+        let server = Server::new().register_service(services(&started));
+        let mut server = testing::start(server).await.unwrap();
+        let client = Client::connect(&server.address()).unwrap();
+        let call = tokio::spawn({
+            let client = client.clone();
+            async move { client.request(request("Hang")).await }
+        });
+        started.notified().await;
+
+        // The call is cancelled once the timeout elapsed, rather than waited for.
+        let report = tokio::time::timeout(
+            Duration::from_secs(2),
+            server
+                .server_mut()
+                .graceful_shutdown(Duration::from_millis(100)),
+        )
+        .await
+        .unwrap();
+        assert_eq!(report.calls_in_progress, 1);
+        assert_eq!(report.detached, 0);
+        call.await.unwrap().unwrap_err();
+    }
+
+    #[tokio::test]
+    async fn test_bind_with_services() {
+        let handled = Arc::new(Notify::new());
+        let mut admin = services(&handled);
+        let service = admin.remove("test.Test").unwrap();
+        admin.insert("test.Admin".to_string(), service);
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let admin_address = format!("tcp://127.0.0.1:{port}");
+        let server = Server::new()
+            .register_service(services(&handled))
+            .bind_with_services(&admin_address, admin)
+            .unwrap();
+        let server = testing::start(server).await.unwrap();
+        let admin_request = Request {
+            service: "test.Admin".to_string(),
+            ..request("Echo")
+        };
+
+        let unknown = |res: Result<Response>| matches!(res, Err(Error::RpcStatus(s)) if s.code() == Code::INVALID_ARGUMENT);
+
+        // Each socket only serves its own services.
+        let public = Client::connect(&server.address()).unwrap();
+        public.request(request("Echo")).await.unwrap();
+        assert!(unknown(public.request(admin_request.clone()).await));
+        let admin = Client::connect(&admin_address).unwrap();
+        admin.request(admin_request).await.unwrap();
+        assert!(unknown(admin.request(request("Echo")).await));
+    }
+
+    /// Answers with the credentials and the cid of the peer.
+    struct Peer;
+
+    #[async_trait]
+    impl MethodHandler for Peer {
+        async fn handler(&self, ctx: TtrpcContext, _req: Request) -> Result<Response> {
+            let peer = format!("{:?} {:?}", ctx.peer_credentials, ctx.peer_cid());
+            Ok(Response {
+                payload: peer.into_bytes(),
+                ..Default::default()
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_peer_credentials() {
+        let handled = Arc::new(Notify::new());
+        let services = || {
+            let mut services = services(&handled);
+            let methods = &mut services.get_mut("test.Test").unwrap().methods;
+            methods.insert("Peer".to_string(), Box::new(Peer));
+            services
+        };
+        let server = testing::start(Server::new().register_service(services()))
+            .await
+            .unwrap();
+
+        // The kernel reports the credentials of the peers of Unix sockets.
+        let client = Client::connect(&server.address()).unwrap();
+        let res = client.request(request("Peer")).await.unwrap();
+        let credentials = Credentials {
+            pid: std::process::id() as i32,
+            uid: nix::unistd::getuid().as_raw(),
+            gid: nix::unistd::getgid().as_raw(),
+        };
+        assert_eq!(
+            String::from_utf8(res.payload).unwrap(),
+            format!("{:?} None", Some(credentials))
+        );
+
+        // Other peers have none, and only those of vsock sockets have a cid.
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let tcp_address = format!("tcp://127.0.0.1:{port}");
+        let mut tcp_server = Server::new()
+            .register_service(services())
+            .bind(&tcp_address)
+            .unwrap();
+        tcp_server.start().await.unwrap();
+        let client = Client::connect(&tcp_address).unwrap();
+        let res = client.request(request("Peer")).await.unwrap();
+        assert_eq!(String::from_utf8(res.payload).unwrap(), "None None");
+        tcp_server.shutdown().await.unwrap();
+    }
+
+    /// Answers with the method and the undecoded payload of the request.
+    struct Relayed;
+
+    #[async_trait]
+    impl MethodHandler for Relayed {
+        async fn handler(&self, _ctx: TtrpcContext, req: Request) -> Result<Response> {
+            Ok(Response {
+                payload: [req.method.as_bytes(), b":", &req.payload].concat(),
+                ..Default::default()
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_relay() {
+        let handled = Arc::new(Notify::new());
+        let server = Server::new()
+            .register_service(services(&handled))
+            .register_relay("test.Test", Arc::new(Relayed))
+            .register_relay("test.Proxy", Arc::new(Relayed));
+        let server = testing::start(server).await.unwrap();
+        let client = Client::connect(&server.address()).unwrap();
+        let call = |service: &str, method: &str, payload: &[u8]| {
+            client.request(Request {
+                service: service.to_string(),
+                method: method.to_string(),
+                payload: payload.to_vec(),
+                ..Default::default()
+            })
+        };
+
+        // The payload reaches the relay as is, even if it isn't a valid message.
+        let res = call("test.Proxy", "Any", b"\xff\xff").await.unwrap();
+        assert_eq!(res.payload, b"Any:\xff\xff");
+        // The methods of a registered service take precedence.
+        let res = call("test.Test", "Echo", b"echo").await.unwrap();
+        assert_eq!(res.payload, b"echo");
+        let res = call("test.Test", "Other", b"other").await.unwrap();
+        assert_eq!(res.payload, b"Other:other");
+    }
+
+    /// A service only known at runtime, with a unary `Hello` answering with the
+    /// method and payload of the request, and a streaming `Count` sending 3
+    /// messages.
+    struct Plugin;
+
+    #[async_trait]
+    impl DynService for Plugin {
+        fn name(&self) -> String {
+            "test.Plugin".to_string()
+        }
+
+        fn methods(&self) -> Vec<String> {
+            vec!["Hello".to_string()]
+        }
+
+        fn streams(&self) -> Vec<String> {
+            vec!["Count".to_string()]
+        }
+
+        async fn call(&self, _ctx: TtrpcContext, method: &str, req: Request) -> Result<Response> {
+            Ok(Response {
+                payload: [method.as_bytes(), b":", &req.payload].concat(),
+                ..Default::default()
+            })
+        }
+
+        async fn call_stream(
+            &self,
+            _ctx: TtrpcContext,
+            _method: &str,
+            stream: StreamInner,
+        ) -> Result<Option<Response>> {
+            for i in 0..3 {
+                stream.send(vec![i]).await?;
+            }
+            Ok(None)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_dyn_service() {
+        let server = Server::new().register_dyn_service(Arc::new(Plugin));
+        let server = testing::start(server).await.unwrap();
+        let client = Client::connect(&server.address()).unwrap();
+        let request = |method: &str| Request {
+            service: "test.Plugin".to_string(),
+            method: method.to_string(),
+            payload: b"plugin".to_vec(),
+            ..Default::default()
+        };
+
+        let res = client.request(request("Hello")).await.unwrap();
+        assert_eq!(res.payload, b"Hello:plugin");
+        let mut count = client
+            .new_stream(request("Count"), false, true)
+            .await
+            .unwrap();
+        for i in 0..3 {
+            assert_eq!(count.recv().await.unwrap(), [i]);
+        }
+        // Only the listed methods are served.
+        client.request(request("Unlisted")).await.unwrap_err();
+    }
+
+    #[tokio::test]
+    async fn test_clock() {
+        let started = Arc::new(Notify::new());
+        let clock = MockClock::new();
+        let hour = Duration::from_secs(3600);
+        let server = Server::new()
+            .register_service(services(&started))
+            .set_clock(Arc::new(clock.clone()))
+            .set_fault_injector(FaultInjector::new().inject(
+                "test.Test",
+                "Echo",
+                Fault::Delay(hour),
+                1.0,
+            ));
+        let mut server = testing::start(server).await.unwrap();
+        let client = Client::connect(&server.address()).unwrap();
+        // The injected delay and the timeout of a graceful shutdown elapse by the
+        // clock of the server.
+        let delayed = tokio::spawn({
+            let client = client.clone();
+            async move { client.request(request("Echo")).await }
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert!(!delayed.is_finished());
+        while !delayed.is_finished() {
+            clock.advance(Duration::from_secs(60));
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        delayed.await.unwrap().unwrap();
+
+        tokio::spawn(async move { client.request(request("Hang")).await });
+        started.notified().await;
+        let shutdown =
+            tokio::spawn(async move { server.server_mut().graceful_shutdown(hour).await });
+        while !shutdown.is_finished() {
+            clock.advance(Duration::from_secs(60));
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        assert_eq!(shutdown.await.unwrap().calls_in_progress, 1);
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_on_fd() {
+        let started = Arc::new(Notify::new());
+        let (rfd, wfd) = nix::unistd::pipe().unwrap();
+        let server = Server::new()
+            .register_service(services(&started))
+            .shutdown_on_fd(rfd, Duration::from_millis(100));
+        let server = testing::start(server).await.unwrap();
+        let client = Client::connect(&server.address()).unwrap();
+        client.request(request("Echo")).await.unwrap();
+        let call = tokio::spawn({
+            let client = client.clone();
+            async move { client.request(request("Hang")).await }
+        });
+        started.notified().await;
+
+        nix::unistd::write(wfd, &[1]).unwrap();
+        tokio::time::timeout(
+            Duration::from_secs(5),
+            server.server().wait_shutdown_trigger(),
+        )
+        .await
+        .unwrap();
+        // Drained, then closed with the call which didn't finish in time.
+        call.await.unwrap().unwrap_err();
+        while !server.server().connections().is_empty() {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        let late = Client::connect(&server.address()).unwrap();
+        late.request(request("Echo")).await.unwrap_err();
+
+        nix::unistd::close(rfd).unwrap();
+        nix::unistd::close(wfd).unwrap();
+    }
+
+    /// Calls `Hang` on `client`, which the server times out after 200ms.
+    async fn hang(client: &Client, started: &Notify) -> tokio::task::JoinHandle<Result<Response>> {
+        let mut req = request("Hang");
+        req.timeout_nano = Duration::from_millis(200).as_nanos() as i64;
+        let call = tokio::spawn({
+            let client = client.clone();
+            async move { client.request(req).await }
+        });
+        started.notified().await;
+        call
+    }
+
+    fn assert_exhausted(res: Result<Response>, msg: &str) {
+        match res {
+            Err(Error::RpcStatus(s)) => {
+                assert_eq!(s.code(), Code::RESOURCE_EXHAUSTED);
+                assert_eq!(s.message(), msg);
+            }
+            res => panic!("unexpected {:?}", res),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_max_concurrent_requests() {
+        let started = Arc::new(Notify::new());
+        let server = Server::new()
+            .register_service(services(&started))
+            .set_max_concurrent_requests(1);
+        let server = testing::start(server).await.unwrap();
+        let busy = Client::connect(&server.address()).unwrap();
+        let other = Client::connect(&server.address()).unwrap();
+
+        let call = hang(&busy, &started).await;
+        assert_exhausted(
+            other.request(request("Echo")).await,
+            "too many requests in progress on the server",
+        );
+        call.await.unwrap().unwrap_err();
+        echo_when_admitted(&other).await.unwrap();
+        echo_when_admitted(&busy).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_max_concurrent_requests_per_connection() {
+        let started = Arc::new(Notify::new());
+        let server = Server::new()
+            .register_service(services(&started))
+            .set_max_concurrent_requests_per_connection(1);
+        let server = testing::start(server).await.unwrap();
+        let busy = Client::connect(&server.address()).unwrap();
+        let other = Client::connect(&server.address()).unwrap();
+
+        let call = hang(&busy, &started).await;
+        assert_exhausted(
+            busy.request(request("Echo")).await,
+            "too many requests in progress on the connection",
+        );
+        other.request(request("Echo")).await.unwrap();
+        call.await.unwrap().unwrap_err();
+        echo_when_admitted(&busy).await.unwrap();
+    }
+
+    /// Notifies `started`, and holds the stream open until the client closes it.
+    struct Held {
+        started: Arc<Notify>,
+    }
+
+    #[async_trait]
+    impl StreamHandler for Held {
+        async fn handler(
+            &self,
+            _ctx: TtrpcContext,
+            mut stream: StreamInner,
+        ) -> Result<Option<Response>> {
+            self.started.notify_one();
+            while stream.recv().await.is_ok() {}
+            Ok(None)
+        }
+    }
+
+    async fn held(client: &Client) -> StreamInner {
+        client
+            .new_stream(request("Held"), true, true)
+            .await
+            .unwrap()
+    }
+
+    /// Notifies `started`, and runs until cancelled, without reading its stream.
+    /// Notifies `cancelled` once dropped.
+    struct Orphaned {
+        started: Arc<Notify>,
+        cancelled: Arc<Notify>,
+    }
+
+    struct NotifyOnDrop(Arc<Notify>);
+
+    impl Drop for NotifyOnDrop {
+        fn drop(&mut self) {
+            self.0.notify_one();
+        }
+    }
+
+    #[async_trait]
+    impl StreamHandler for Orphaned {
+        async fn handler(
+            &self,
+            _ctx: TtrpcContext,
+            _stream: StreamInner,
+        ) -> Result<Option<Response>> {
+            let _cancelled = NotifyOnDrop(self.cancelled.clone());
+            self.started.notify_one();
+            std::future::pending().await
+        }
+    }
+
+    #[tokio::test]
+    async fn test_cancel_orphaned() {
+        let started = Arc::new(Notify::new());
+        let cancelled = Arc::new(Notify::new());
+        let mut services = services(&started);
+        services.get_mut("test.Test").unwrap().streams.insert(
+            "Orphaned".to_string(),
+            Arc::new(Orphaned {
+                started: started.clone(),
+                cancelled: cancelled.clone(),
+            }),
+        );
+        let server = testing::start(Server::new().register_service(services))
+            .await
+            .unwrap();
+        let client = Client::connect(&server.address()).unwrap();
+        let _stream = client
+            .new_stream(request("Orphaned"), true, true)
+            .await
+            .unwrap();
+        started.notified().await;
+
+        // Nothing could reach the client once it went away, the handler is cancelled.
+        client.close().await;
+        tokio::time::timeout(Duration::from_secs(5), cancelled.notified())
+            .await
+            .unwrap();
+    }
+
+    /// Sends 5 messages, then holds the stream until `release` is notified.
+    struct Flood {
+        release: Arc<Notify>,
+    }
+
+    #[async_trait]
+    impl StreamHandler for Flood {
+        async fn handler(
+            &self,
+            _ctx: TtrpcContext,
+            stream: StreamInner,
+        ) -> Result<Option<Response>> {
+            for i in 0..5 {
+                stream.send(vec![i]).await?;
+            }
+            self.release.notified().await;
+            Ok(None)
+        }
+    }
+
+    /// Reads its stream once `release` is notified, until the client closes it.
+    struct Sink {
+        release: Arc<Notify>,
+    }
+
+    #[async_trait]
+    impl StreamHandler for Sink {
+        async fn handler(
+            &self,
+            _ctx: TtrpcContext,
+            mut stream: StreamInner,
+        ) -> Result<Option<Response>> {
+            self.release.notified().await;
+            while stream.recv().await.is_ok() {}
+            Ok(None)
+        }
+    }
+
+    /// Waits until the buffers of the streams of `state` hold `depths` messages.
+    async fn wait_buffered(state: impl Fn() -> DebugState, depths: &[(u32, usize)]) {
+        while depths
+            .iter()
+            .any(|(id, depth)| state().stream_buffers.get(id) != Some(depth))
+        {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_stream_buffer() {
+        let release = Arc::new(Notify::new());
+        let mut services = services(&release);
+        let streams = &mut services.get_mut("test.Test").unwrap().streams;
+        streams.insert(
+            "Flood".to_string(),
+            Arc::new(Flood {
+                release: release.clone(),
+            }),
+        );
+        let sink = Arc::new(Sink {
+            release: release.clone(),
+        });
+        streams.insert("Sink".to_string(), sink.clone());
+        streams.insert("Buffered".to_string(), sink);
+        let server =
+            Server::new()
+                .register_service(services)
+                .set_stream_buffer("test.Test", "Sink", 2);
+        let server = testing::start(server).await.unwrap();
+
+        // The server buffers the messages of the streams of a method up to its size.
+        let client = Client::connect(&server.address()).unwrap();
+        let mut sinks = Vec::new();
+        for method in ["Sink", "Buffered"] {
+            let sink = client.new_stream(request(method), true, false).await;
+            sinks.push(sink.unwrap());
+        }
+        for i in 0..5 {
+            for sink in sinks.iter() {
+                sink.send(vec![i]).await.unwrap();
+            }
+        }
+        let state = || {
+            let states = server.server().debug_state();
+            states.into_values().next().unwrap_or_default()
+        };
+        wait_buffered(state, &[(1, 2), (3, 5)]).await;
+
+        // And the client those of a call up to the size of its options.
+        let opts = CallOptions::new().stream_buffer(2);
+        let mut flood = client
+            .new_stream_with_options(request("Flood"), false, true, &opts)
+            .await
+            .unwrap();
+        let _buffered = client
+            .new_stream(request("Flood"), false, true)
+            .await
+            .unwrap();
+        wait_buffered(|| client.debug_state(), &[(5, 2), (7, 5)]).await;
+        for i in 0..5 {
+            assert_eq!(flood.recv().await.unwrap(), [i]);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_unary_over_stream() {
+        let handled = Arc::new(Notify::new());
+        let server = Server::new()
+            .register_service(services(&handled))
+            .set_unary_over_stream(true);
+        let server = testing::start(server).await.unwrap();
+        let client = ClientBuilder::new(&server.address())
+            .schema_version("1.0")
+            .build()
+            .unwrap();
+        assert!(client.peer_supports_unary_over_stream().await);
+
+        let mut stream = client
+            .new_stream(request("Echo"), true, true)
+            .await
+            .unwrap();
+        stream.send(b"hello".to_vec()).await.unwrap();
+        stream.close_send().await.unwrap();
+        assert_eq!(stream.recv().await.unwrap(), b"hello");
+        assert!(matches!(stream.recv().await, Err(Error::Eof)));
+        // The unary calls are still made as usual.
+        client.request(request("Echo")).await.unwrap();
+
+        let server = testing::start(Server::new().register_service(services(&handled)))
+            .await
+            .unwrap();
+        let client = ClientBuilder::new(&server.address())
+            .schema_version("1.0")
+            .build()
+            .unwrap();
+        assert!(!client.peer_supports_unary_over_stream().await);
+    }
+
+    #[tokio::test]
+    async fn test_max_streams() {
+        let started = Arc::new(Notify::new());
+        let mut services = services(&started);
+        services.get_mut("test.Test").unwrap().streams.insert(
+            "Held".to_string(),
+            Arc::new(Held {
+                started: started.clone(),
+            }),
+        );
+        let server = Server::new().register_service(services).set_max_streams(1);
+        let server = testing::start(server).await.unwrap();
+        let client = Client::connect(&server.address()).unwrap();
+        let other = Client::connect(&server.address()).unwrap();
+
+        let mut open = held(&client).await;
+        started.notified().await;
+        let mut refused = held(&client).await;
+        match refused.recv().await {
+            Err(Error::RpcStatus(s)) => {
+                assert_eq!(s.code(), Code::RESOURCE_EXHAUSTED);
+                assert_eq!(s.message(), "too many open streams on the connection");
+            }
+            res => panic!("unexpected {:?}", res),
+        }
+        // Unary calls and the streams of other connections are not limited.
+        client.request(request("Echo")).await.unwrap();
+        let other_stream = held(&other).await;
+        started.notified().await;
+        other_stream.close_send().await.unwrap();
+
+        // Once the stream is closed, another one can be opened.
+        open.close_send().await.unwrap();
+        assert!(matches!(open.recv().await, Err(Error::Eof)));
+        let mut reopened = held(&client).await;
+        started.notified().await;
+        reopened.close_send().await.unwrap();
+        assert!(matches!(reopened.recv().await, Err(Error::Eof)));
+    }
+
+    /// Denies the calls of `Drop`.
+    struct DenyDrop;
+
+    impl Authorizer for DenyDrop {
+        fn authorize(&self, _ctx: &TtrpcContext, _service: &str, method: &str) -> bool {
+            method != "Drop"
+        }
+    }
+
+    /// Fails the calls without a token, and routes `Alias` to `Drop` and `Other` to
+    /// `Echo`.
+    struct Route;
+
+    impl ServerInterceptor for Route {
+        fn intercept(&self, ctx: &TtrpcContext, req: &mut Request) -> StdResult<(), Status> {
+            if !ctx.metadata.contains_key("token") {
+                return Err(get_status(Code::UNAUTHENTICATED, "no token"));
+            }
+            match req.method.as_str() {
+                "Alias" => req.method = "Drop".to_string(),
+                "Other" => req.method = "Echo".to_string(),
+                _ => {}
+            }
+            Ok(())
+        }
+    }
+
+    /// The status a call failed with.
+    fn status(res: Result<Response>) -> (Code, String) {
+        match res {
+            Err(Error::RpcStatus(s)) => (s.code(), s.message().to_string()),
+            res => panic!("unexpected {:?}", res),
+        }
+    }
+
+    /// Calls `method` on `client`, with a token if `token` is set.
+    async fn call(client: &Client, method: &str, token: bool) -> Result<Response> {
+        let mut opts = CallOptions::new();
+        if token {
+            opts = opts.with_metadata("token", "t");
+        }
+        let res = client.request_with_options(request(method), &opts).await;
+        res.map(|(res, _)| res)
+    }
+
+    #[tokio::test]
+    async fn test_interceptor() {
+        let handled = Arc::new(Notify::new());
+        let server = Server::new()
+            .register_service(services(&handled))
+            .set_authorizer(Arc::new(DenyDrop))
+            .add_interceptor(Arc::new(Route));
+        let server = testing::start(server).await.unwrap();
+        let client = Client::connect(&server.address()).unwrap();
+
+        assert_eq!(
+            status(call(&client, "Echo", false).await),
+            (Code::UNAUTHENTICATED, "no token".to_string())
+        );
+        call(&client, "Echo", true).await.unwrap();
+        call(&client, "Other", true).await.unwrap();
+        // Allowed as it was sent, denied as it's routed.
+        assert_eq!(
+            status(call(&client, "Alias", true).await),
+            (
+                Code::PERMISSION_DENIED,
+                "test.Test.Drop is not allowed".to_string()
+            )
+        );
+    }
+
+    /// Answers once `release` is notified, and notifies `started` before.
+    struct Gate {
+        started: Arc<Notify>,
+        release: Arc<Notify>,
+    }
+
+    #[async_trait]
+    impl MethodHandler for Gate {
+        async fn handler(&self, _ctx: TtrpcContext, _req: Request) -> Result<Response> {
+            self.started.notify_one();
+            self.release.notified().await;
+            Ok(Response::default())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_replace_middleware() {
+        let handled = Arc::new(Notify::new());
+        let started = Arc::new(Notify::new());
+        let release = Arc::new(Notify::new());
+        let mut services = services(&handled);
+        services.get_mut("test.Test").unwrap().methods.insert(
+            "Gate".to_string(),
+            Box::new(Gate {
+                started: started.clone(),
+                release: release.clone(),
+            }),
+        );
+        let server = testing::start(Server::new().register_service(services))
+            .await
+            .unwrap();
+        let client = Client::connect(&server.address()).unwrap();
+        let gated = tokio::spawn({
+            let client = client.clone();
+            async move { call(&client, "Gate", false).await }
+        });
+        started.notified().await;
+
+        server.server().replace_middleware(
+            Middleware::new()
+                .authorizer(Arc::new(DenyDrop))
+                .interceptor(Arc::new(Route)),
+        );
+        // New calls go through the new policies.
+        assert_eq!(
+            status(call(&client, "Gate", false).await),
+            (Code::UNAUTHENTICATED, "no token".to_string())
+        );
+        assert_eq!(
+            status(call(&client, "Drop", true).await).0,
+            Code::PERMISSION_DENIED
+        );
+        call(&client, "Echo", true).await.unwrap();
+        // The call in progress isn't checked again.
+        release.notify_one();
+        gated.await.unwrap().unwrap();
+
+        // Replaced at once, nothing is left of the previous policies.
+        server.server().replace_middleware(Middleware::new());
+        call(&client, "Echo", false).await.unwrap();
+        call(&client, "Drop", false).await.unwrap();
+    }
+
+    /// Calls `method` on `client` with `opts`, for at most 100ms.
+    async fn call_with(client: &Client, method: &str, opts: CallOptions) -> Result<Response> {
+        let opts = opts.with_timeout(Duration::from_millis(100));
+        let res = client.request_with_options(request(method), &opts).await;
+        res.map(|(res, _)| res)
+    }
+
+    #[tokio::test]
+    async fn test_priority_hints() {
+        let high = || CallOptions::new().with_priority(Priority::High);
+
+        // Ignored by default, the hint doesn't get the call out of the full lane.
+        let started = Arc::new(Notify::new());
+        let server = Server::new()
+            .register_service(services(&started))
+            .set_max_concurrency(Priority::Normal, 1);
+        let server = testing::start(server).await.unwrap();
+        let client = Client::connect(&server.address()).unwrap();
+        let _hang = tokio::spawn({
+            let client = client.clone();
+            async move { client.request(request("Hang")).await }
+        });
+        started.notified().await;
+        call_with(&client, "Echo", high()).await.unwrap_err();
+
+        // Taken once trusted, but never over the priority set by the server.
+        let started = Arc::new(Notify::new());
+        let server = Server::new()
+            .register_service(services(&started))
+            .set_priority_hints(true)
+            .set_priority("test.Test", "Hang", Priority::Low)
+            .set_priority("test.Test", "Echo", Priority::Low)
+            .set_max_concurrency(Priority::Low, 1);
+        let server = testing::start(server).await.unwrap();
+        let client = Client::connect(&server.address()).unwrap();
+        let _hang = tokio::spawn({
+            let client = client.clone();
+            async move { client.request(request("Hang")).await }
+        });
+        started.notified().await;
+        call_with(&client, "Echo", high()).await.unwrap_err();
+        call_with(&client, "Drop", high()).await.unwrap();
+        call_with(&client, "Drop", CallOptions::new())
+            .await
+            .unwrap();
+    }
+}
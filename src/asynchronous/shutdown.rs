@@ -9,6 +9,23 @@ use std::sync::Arc;
 use tokio::sync::Notify;
 use tokio::time::{error::Elapsed, timeout, Duration};
 
+/// What was left when closing a server or a client, see
+/// [`Server::close`](crate::r#async::Server::close) and
+/// [`Client::close`](crate::r#async::Client::close).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ShutdownReport {
+    /// Connections open when closing.
+    pub connections: usize,
+    /// Calls and streams in progress when closing, which were cancelled.
+    pub calls_in_progress: usize,
+    /// Connections whose handlers were still running after the grace period, and
+    /// were left running in the background.
+    pub detached: usize,
+    /// File descriptors closed, i.e. listeners of a server and the socket of a
+    /// client.
+    pub fds_closed: usize,
+}
+
 #[derive(Debug)]
 struct Shared {
     shutdown: AtomicBool,
@@ -4,18 +4,21 @@
 // SPDX-License-Identifier: Apache-2.0
 //
 
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::marker::PhantomData;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
 
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, oneshot};
 
+use crate::context;
 use crate::error::{Error, Result};
 use crate::proto::{
-    Code, Codec, GenMessage, MessageHeader, Response, FLAG_NO_DATA, FLAG_REMOTE_CLOSED,
-    MESSAGE_TYPE_DATA, MESSAGE_TYPE_RESPONSE,
+    check_oversize, Code, Codec, GenMessage, MessageHeader, Response, FLAG_ACK, FLAG_ACK_REQUIRED,
+    FLAG_COMPRESSED, FLAG_NO_DATA, FLAG_REMOTE_CLOSED, MESSAGE_TYPE_DATA, MESSAGE_TYPE_RESPONSE,
 };
+use crate::r#async::client::CallGuard;
+use crate::r#async::Compression;
 
 pub type MessageSender = mpsc::Sender<GenMessage>;
 pub type MessageReceiver = mpsc::Receiver<GenMessage>;
@@ -23,6 +26,29 @@ pub type MessageReceiver = mpsc::Receiver<GenMessage>;
 pub type ResultSender = mpsc::Sender<Result<GenMessage>>;
 pub type ResultReceiver = mpsc::Receiver<Result<GenMessage>>;
 
+/// Default capacity of the queue of messages received on a stream.
+pub(crate) const DEFAULT_STREAM_BUFFER: usize = 100;
+
+/// Items of a blocking iterator produced ahead of the stream, see
+/// [`ServerStreamSender::send_iter`].
+const ITER_BUFFER: usize = 16;
+
+/// Senders waiting for an acknowledgement from the peer, keyed by stream id.
+pub type AckWaiters = Arc<Mutex<HashMap<u32, VecDeque<oneshot::Sender<()>>>>>;
+
+/// Wakes up the oldest sender waiting for an acknowledgement on the stream.
+pub(crate) fn notify_ack(acks: &AckWaiters, stream_id: u32) {
+    let waiter = acks
+        .lock()
+        .unwrap()
+        .get_mut(&stream_id)
+        .and_then(|q| q.pop_front());
+    match waiter {
+        Some(waiter) => waiter.send(()).unwrap_or_default(),
+        None => debug!("Receiver got unexpected ack of stream {}", stream_id),
+    }
+}
+
 #[derive(Debug)]
 pub struct ClientStream<Q, P> {
     tx: CSSender<Q>,
@@ -58,6 +84,12 @@ where
         self.tx.send(req).await
     }
 
+    /// Like [`ClientStream::send`], but only returns once the server has received
+    /// the message, see [`StreamSender::send_acked`].
+    pub async fn send_acked(&self, req: &Q) -> Result<()> {
+        self.tx.send_acked(req).await
+    }
+
     pub async fn close_send(&self) -> Result<()> {
         self.tx.close_send().await
     }
@@ -65,6 +97,11 @@ where
     pub async fn recv(&mut self) -> Result<P> {
         self.rx.recv().await
     }
+
+    /// Metadata the server sent with its response, only set once the stream finished.
+    pub fn response_metadata(&self) -> Option<&HashMap<String, Vec<String>>> {
+        self.rx.response_metadata()
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -85,6 +122,15 @@ where
         self.tx.send(msg_buf).await
     }
 
+    /// Like [`CSSender::send`], but only returns once the server has received the
+    /// message, see [`StreamSender::send_acked`].
+    pub async fn send_acked(&self, req: &Q) -> Result<()> {
+        let msg_buf = req
+            .encode()
+            .map_err(err_to_others_err!(e, "Encode message failed."))?;
+        self.tx.send_acked(msg_buf).await
+    }
+
     pub async fn close_send(&self) -> Result<()> {
         self.tx.close_send().await
     }
@@ -105,6 +151,11 @@ where
         let msg_buf = self.rx.recv().await?;
         P::decode(msg_buf).map_err(err_to_others_err!(e, "Decode message failed."))
     }
+
+    /// Metadata the server sent with its response, only set once the stream finished.
+    pub fn response_metadata(&self) -> Option<&HashMap<String, Vec<String>>> {
+        self.rx.response_metadata()
+    }
 }
 
 #[derive(Debug)]
@@ -142,6 +193,12 @@ where
         self.tx.send(resp).await
     }
 
+    /// Like [`ServerStream::send`], but only returns once the client has received
+    /// the message, see [`StreamSender::send_acked`].
+    pub async fn send_acked(&self, resp: &P) -> Result<()> {
+        self.tx.send_acked(resp).await
+    }
+
     pub async fn recv(&mut self) -> Result<Option<Q>> {
         self.rx.recv().await
     }
@@ -164,6 +221,15 @@ where
             .map_err(err_to_others_err!(e, "Encode message failed."))?;
         self.tx.send(msg_buf).await
     }
+
+    /// Like [`SSSender::send`], but only returns once the client has received the
+    /// message, see [`StreamSender::send_acked`].
+    pub async fn send_acked(&self, resp: &P) -> Result<()> {
+        let msg_buf = resp
+            .encode()
+            .map_err(err_to_others_err!(e, "Encode message failed."))?;
+        self.tx.send_acked(msg_buf).await
+    }
 }
 
 #[derive(Debug)]
@@ -218,11 +284,25 @@ where
         self.inner.send(msg_buf).await
     }
 
+    /// Like [`ClientStreamSender::send`], but only returns once the server has
+    /// received the message, see [`StreamSender::send_acked`].
+    pub async fn send_acked(&self, req: &Q) -> Result<()> {
+        let msg_buf = req
+            .encode()
+            .map_err(err_to_others_err!(e, "Encode message failed."))?;
+        self.inner.sender.send_acked(msg_buf).await
+    }
+
     pub async fn close_and_recv(&mut self) -> Result<P> {
         self.inner.close_send().await?;
         let msg_buf = self.inner.recv().await?;
         P::decode(msg_buf).map_err(err_to_others_err!(e, "Decode message failed."))
     }
+
+    /// Metadata the server sent with its response, only set once the stream finished.
+    pub fn response_metadata(&self) -> Option<&HashMap<String, Vec<String>>> {
+        self.inner.response_metadata()
+    }
 }
 
 pub struct ServerStreamSender<P> {
@@ -248,6 +328,44 @@ where
             .map_err(err_to_others_err!(e, "Encode message failed."))?;
         self.inner.send(msg_buf).await
     }
+
+    /// Like [`ServerStreamSender::send`], but only returns once the client has
+    /// received the message, see [`StreamSender::send_acked`].
+    pub async fn send_acked(&self, resp: &P) -> Result<()> {
+        let msg_buf = resp
+            .encode()
+            .map_err(err_to_others_err!(e, "Encode message failed."))?;
+        self.inner.send_acked(msg_buf).await
+    }
+
+    /// Sends the items of a blocking iterator, e.g. the lines of a file or the
+    /// output of a process, until it is exhausted.
+    ///
+    /// The iterator runs on the blocking thread pool, and only gets a few items
+    /// ahead of the client. It is dropped once the stream fails, when it returns
+    /// its next item.
+    pub async fn send_iter<I>(&self, iter: I) -> Result<()>
+    where
+        I: IntoIterator<Item = P> + Send + 'static,
+        I::IntoIter: Send,
+        P: Send + 'static,
+    {
+        let (tx, mut rx) = mpsc::channel(ITER_BUFFER);
+        let producer = tokio::task::spawn_blocking(move || {
+            for item in iter {
+                // The stream failed.
+                if tx.blocking_send(item).is_err() {
+                    break;
+                }
+            }
+        });
+        while let Some(item) = rx.recv().await {
+            self.send(&item).await?;
+        }
+        producer
+            .await
+            .map_err(err_to_others_err!(e, "Stream iterator panicked: "))
+    }
 }
 
 pub struct ClientStreamReceiver<P> {
@@ -277,6 +395,11 @@ where
             .map_err(err_to_others_err!(e, "Decode message failed."))
             .map(Some)
     }
+
+    /// Metadata the server sent with its response, only set once the stream finished.
+    pub fn response_metadata(&self) -> Option<&HashMap<String, Vec<String>>> {
+        self.inner.response_metadata()
+    }
 }
 
 pub struct ServerStreamReceiver<Q> {
@@ -335,6 +458,7 @@ pub struct StreamInner {
 }
 
 impl StreamInner {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         stream_id: u32,
         tx: MessageSender,
@@ -344,26 +468,47 @@ impl StreamInner {
         recveivable: bool,
         kind: Kind,
         streams: Arc<Mutex<HashMap<u32, ResultSender>>>,
+        acks: AckWaiters,
     ) -> Self {
         Self {
             sender: StreamSender {
-                tx,
+                tx: tx.clone(),
                 stream_id,
                 sendable,
                 local_closed: Arc::new(AtomicBool::new(false)),
                 kind,
+                acks,
+                compression: None,
             },
             receiver: StreamReceiver {
+                tx,
                 rx,
                 stream_id,
                 recveivable,
                 remote_closed: false,
                 kind,
                 streams,
+                compression: None,
+                response_metadata: None,
+                _guard: None,
             },
         }
     }
 
+    /// Compress the data messages sent on the stream, and decompress the
+    /// compressed ones received.
+    pub(crate) fn with_compression(mut self, compression: Option<Compression>) -> Self {
+        self.sender.compression = compression.clone();
+        self.receiver.compression = compression;
+        self
+    }
+
+    /// Hold `guard` until the stream is dropped.
+    pub(crate) fn with_guard(mut self, guard: CallGuard) -> Self {
+        self.receiver._guard = Some(guard);
+        self
+    }
+
     fn split(self) -> (StreamSender, StreamReceiver) {
         (self.sender, self.receiver)
     }
@@ -379,6 +524,11 @@ impl StreamInner {
     pub async fn recv(&mut self) -> Result<Vec<u8>> {
         self.receiver.recv().await
     }
+
+    /// Metadata the server sent with its response, only set once the stream finished.
+    pub fn response_metadata(&self) -> Option<&HashMap<String, Vec<String>>> {
+        self.receiver.response_metadata()
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -388,16 +538,23 @@ pub struct StreamSender {
     sendable: bool,
     local_closed: Arc<AtomicBool>,
     kind: Kind,
+    acks: AckWaiters,
+    compression: Option<Compression>,
 }
 
 #[derive(Debug)]
 pub struct StreamReceiver {
+    tx: MessageSender,
     rx: ResultReceiver,
     stream_id: u32,
     recveivable: bool,
     remote_closed: bool,
     kind: Kind,
     streams: Arc<Mutex<HashMap<u32, ResultSender>>>,
+    compression: Option<Compression>,
+    response_metadata: Option<HashMap<String, Vec<String>>>,
+    /// Counts the stream as in progress on its client.
+    _guard: Option<CallGuard>,
 }
 
 impl Drop for StreamReceiver {
@@ -408,12 +565,50 @@ impl Drop for StreamReceiver {
 
 impl StreamSender {
     pub async fn send(&self, buf: Vec<u8>) -> Result<()> {
+        let msg = self.data_message(buf)?;
+        _send(&self.tx, msg).await?;
+
+        Ok(())
+    }
+
+    /// Sends `buf` and waits until the peer has received it.
+    ///
+    /// The peer acknowledges the message once it is handed out by its `recv()`, so
+    /// a slow consumer holds the sender back across the whole link. Both peers must
+    /// support acknowledgements, a peer which doesn't will never acknowledge.
+    pub async fn send_acked(&self, buf: Vec<u8>) -> Result<()> {
+        let mut msg = self.data_message(buf)?;
+        msg.header.add_flags(FLAG_ACK_REQUIRED);
+
+        let (ack_tx, ack_rx) = oneshot::channel();
+        self.acks
+            .lock()
+            .unwrap()
+            .entry(self.stream_id)
+            .or_default()
+            .push_back(ack_tx);
+        _send(&self.tx, msg).await?;
+
+        // The waiter is dropped if the stream finishes before the peer received the message.
+        ack_rx.await.map_err(|_| Error::RemoteClosed)
+    }
+
+    fn data_message(&self, buf: Vec<u8>) -> Result<GenMessage> {
         debug_assert!(self.sendable);
         if self.local_closed.load(Ordering::Relaxed) {
             debug_assert_eq!(self.kind, Kind::Client);
             return Err(Error::LocalClosed);
         }
-        let header = MessageHeader::new_data(self.stream_id, buf.len() as u32);
+        let compressed = match self.compression.as_ref() {
+            Some(compression) => compression.compress(&buf)?,
+            None => None,
+        };
+        let (buf, flags) = match compressed {
+            Some(compressed) => (compressed, FLAG_COMPRESSED),
+            None => (buf, 0),
+        };
+        let mut header = MessageHeader::new_data(self.stream_id, buf.len() as u32);
+        header.add_flags(flags);
         let msg = GenMessage {
             header,
             payload: buf,
@@ -421,9 +616,7 @@ impl StreamSender {
 
         msg.check()?;
 
-        _send(&self.tx, msg).await?;
-
-        Ok(())
+        Ok(msg)
     }
 
     pub async fn close_send(&self) -> Result<()> {
@@ -445,6 +638,11 @@ impl StreamSender {
 }
 
 impl StreamReceiver {
+    /// Metadata the server sent with its response, only set once the stream finished.
+    pub fn response_metadata(&self) -> Option<&HashMap<String, Vec<String>>> {
+        self.response_metadata.as_ref()
+    }
+
     pub async fn recv(&mut self) -> Result<Vec<u8>> {
         if self.remote_closed {
             return Err(Error::RemoteClosed);
@@ -457,11 +655,16 @@ impl StreamReceiver {
                 self.remote_closed = true;
                 let resp = Response::decode(&msg.payload)
                     .map_err(err_to_others_err!(e, "Decode message failed."))?;
+                self.response_metadata = Some(context::from_pb(&resp.metadata));
                 if let Some(status) = resp.status.as_ref() {
                     if status.code() != Code::OK {
                         return Err(Error::RpcStatus((*status).clone()));
                     }
                 }
+                // Streaming servers only respond to close the stream.
+                if self.recveivable && resp.payload.is_empty() {
+                    return Err(Error::Eof);
+                }
                 resp.payload
             }
             MESSAGE_TYPE_DATA => {
@@ -471,13 +674,20 @@ impl StreamReceiver {
                         "received data from non-streaming server.".to_string(),
                     ));
                 }
+                if (msg.header.flags & FLAG_ACK_REQUIRED) == FLAG_ACK_REQUIRED {
+                    self.send_ack().await;
+                }
                 if (msg.header.flags & FLAG_REMOTE_CLOSED) == FLAG_REMOTE_CLOSED {
                     self.remote_closed = true;
                     if (msg.header.flags & FLAG_NO_DATA) == FLAG_NO_DATA {
                         return Err(Error::Eof);
                     }
                 }
-                msg.payload
+                if (msg.header.flags & FLAG_COMPRESSED) == FLAG_COMPRESSED {
+                    self.decompress(&msg.payload)?
+                } else {
+                    msg.payload
+                }
             }
             _ => {
                 return Err(Error::Others("not support".to_string()));
@@ -485,4 +695,202 @@ impl StreamReceiver {
         };
         Ok(payload)
     }
+
+    fn decompress(&self, buf: &[u8]) -> Result<Vec<u8>> {
+        let compression = self.compression.as_ref().ok_or_else(|| {
+            Error::Others("received compressed data but compression is not enabled".to_string())
+        })?;
+        let payload = compression.compressor.decompress(buf)?;
+        check_oversize(payload.len(), false)?;
+        Ok(payload)
+    }
+
+    async fn send_ack(&self) {
+        let mut header = MessageHeader::new_data(self.stream_id, 0);
+        header.set_flags(FLAG_ACK | FLAG_NO_DATA);
+        let msg = GenMessage {
+            header,
+            payload: Vec::new(),
+        };
+        if let Err(e) = _send(&self.tx, msg).await {
+            warn!(
+                "Failed to acknowledge data of stream {}: {:?}",
+                self.stream_id, e
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    use async_trait::async_trait;
+    use protobuf::well_known_types::wrappers::UInt32Value;
+    use tokio::sync::{Notify, Semaphore};
+
+    use crate::r#async::{testing, Client, Service, StreamHandler, TtrpcContext};
+    use crate::Request;
+
+    /// Receives a message every time the gate lets it.
+    struct Gated(Arc<Semaphore>);
+
+    #[async_trait]
+    impl StreamHandler for Gated {
+        async fn handler(
+            &self,
+            _ctx: TtrpcContext,
+            mut stream: StreamInner,
+        ) -> Result<Option<Response>> {
+            loop {
+                self.0.acquire().await.unwrap().forget();
+                match stream.recv().await {
+                    Ok(_) => {}
+                    Err(Error::Eof) => return Ok(Some(Response::default())),
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+    }
+
+    /// Counts from 0, up to `end` if set, and notifies `dropped` once dropped.
+    struct Counter {
+        next: u32,
+        end: Option<u32>,
+        dropped: Arc<Notify>,
+    }
+
+    impl Iterator for Counter {
+        type Item = UInt32Value;
+
+        fn next(&mut self) -> Option<UInt32Value> {
+            if Some(self.next) == self.end {
+                return None;
+            }
+            let mut item = UInt32Value::new();
+            item.value = self.next;
+            self.next += 1;
+            Some(item)
+        }
+    }
+
+    impl Drop for Counter {
+        fn drop(&mut self) {
+            self.dropped.notify_one();
+        }
+    }
+
+    /// Sends the items of a [`Counter`] up to the value of its request.
+    struct Count {
+        dropped: Arc<Notify>,
+    }
+
+    #[async_trait]
+    impl StreamHandler for Count {
+        async fn handler(
+            &self,
+            _ctx: TtrpcContext,
+            mut stream: StreamInner,
+        ) -> Result<Option<Response>> {
+            let end = UInt32Value::decode(stream.recv().await?).unwrap().value;
+            let counter = Counter {
+                next: 0,
+                end: (end > 0).then_some(end),
+                dropped: self.dropped.clone(),
+            };
+            ServerStreamSender::new(stream).send_iter(counter).await?;
+            Ok(None)
+        }
+    }
+
+    async fn serve(
+        name: &str,
+        handler: Arc<dyn StreamHandler + Send + Sync>,
+    ) -> (Client, testing::ServerGuard) {
+        let mut streams: HashMap<String, Arc<dyn StreamHandler + Send + Sync>> = HashMap::new();
+        streams.insert(name.to_string(), handler);
+        let mut services = HashMap::new();
+        services.insert(
+            "test.Test".to_string(),
+            Service {
+                methods: HashMap::new(),
+                streams,
+            },
+        );
+        testing::serve(services).await.unwrap()
+    }
+
+    fn request(method: &str) -> Request {
+        Request {
+            service: "test.Test".to_string(),
+            method: method.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_send_iter() {
+        let dropped = Arc::new(Notify::new());
+        let handler = Arc::new(Count {
+            dropped: dropped.clone(),
+        });
+        let (client, server) = serve("Count", handler).await;
+
+        async fn count(client: &Client, end: u32) -> ClientStreamReceiver<UInt32Value> {
+            let stream = client
+                .new_stream(request("Count"), true, true)
+                .await
+                .unwrap();
+            let mut req = UInt32Value::new();
+            req.value = end;
+            stream.send(req.encode().unwrap()).await.unwrap();
+            ClientStreamReceiver::new(stream)
+        }
+
+        // Every item reaches the client, in order.
+        let mut stream = count(&client, 100).await;
+        for i in 0..100 {
+            assert_eq!(stream.recv().await.unwrap().unwrap().value, i);
+        }
+        assert!(stream.recv().await.unwrap().is_none());
+        dropped.notified().await;
+
+        // An endless iterator is dropped once the client went away.
+        let client = Client::connect(&server.address()).unwrap();
+        let mut stream = count(&client, 0).await;
+        for i in 0..3 {
+            assert_eq!(stream.recv().await.unwrap().unwrap().value, i);
+        }
+        drop(stream);
+        drop(client);
+        tokio::time::timeout(Duration::from_secs(5), dropped.notified())
+            .await
+            .expect("the iterator outlived its client");
+    }
+
+    #[tokio::test]
+    async fn test_send_acked() {
+        let gate = Arc::new(Semaphore::new(0));
+        let (client, _server) = serve("Gated", Arc::new(Gated(gate.clone()))).await;
+
+        let mut stream = client
+            .new_stream(request("Gated"), true, false)
+            .await
+            .unwrap();
+        let sender = stream.sender.clone();
+        let sent = sender.send_acked(b"data".to_vec());
+        tokio::pin!(sent);
+        // Written, but not received by the handler yet.
+        let waited = tokio::time::timeout(Duration::from_millis(50), &mut sent).await;
+        assert!(waited.is_err());
+
+        gate.add_permits(1);
+        sent.await.unwrap();
+
+        stream.close_send().await.unwrap();
+        gate.add_permits(1);
+        stream.recv().await.unwrap();
+    }
 }
@@ -0,0 +1,155 @@
+// Copyright (c) 2023 Ant Group
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Helpers for tests which need a connected client and server.
+
+use std::collections::HashMap;
+use std::os::unix::io::IntoRawFd;
+use std::os::unix::net::UnixListener;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::error::{Error, Result};
+use crate::r#async::{Client, Server, Service};
+
+static NEXT_SOCKET: AtomicUsize = AtomicUsize::new(0);
+
+/// Start a server with `services` on the current runtime and connect a client to it.
+///
+/// The server listens on a fresh Unix socket in the temporary directory. It is shut
+/// down and the socket is removed when the returned [`ServerGuard`] is dropped.
+///
+/// ```no_run
+/// # async fn run(services: std::collections::HashMap<String, ttrpc::r#async::Service>) {
+/// let (client, _server) = ttrpc::testing::serve(services).await.unwrap();
+/// # }
+/// ```
+pub async fn serve(services: HashMap<String, Service>) -> Result<(Client, ServerGuard)> {
+    let guard = start(Server::new().register_service(services)).await?;
+    let client = Client::connect(&guard.address())?;
+    Ok((client, guard))
+}
+
+/// Start `server`, e.g. one configured with limits, on the current runtime like
+/// [`serve`] does, for clients built for [`ServerGuard::address`].
+pub async fn start(server: Server) -> Result<ServerGuard> {
+    let path = std::env::temp_dir().join(format!(
+        "ttrpc-test-{}-{}.sock",
+        std::process::id(),
+        NEXT_SOCKET.fetch_add(1, Ordering::Relaxed)
+    ));
+    let _ = std::fs::remove_file(&path);
+    let listener = UnixListener::bind(&path).map_err(err_to_others_err!(e, ""))?;
+
+    // The guard removes the socket if any of the steps below fails.
+    let mut guard = ServerGuard { server: None, path };
+    let mut server = server
+        .add_listener(listener.into_raw_fd())?
+        .set_domain_unix();
+    server.start().await?;
+    guard.server = Some(server);
+    Ok(guard)
+}
+
+/// Owns the server started by [`serve`] or [`start`].
+pub struct ServerGuard {
+    server: Option<Server>,
+    path: PathBuf,
+}
+
+impl ServerGuard {
+    /// The address the server listens on.
+    pub fn address(&self) -> String {
+        format!("unix://{}", self.path.display())
+    }
+
+    /// The running server, e.g. to inspect its connections.
+    pub fn server(&self) -> &Server {
+        self.server.as_ref().unwrap()
+    }
+
+    /// The running server, e.g. to shut it down gracefully.
+    pub fn server_mut(&mut self) -> &mut Server {
+        self.server.as_mut().unwrap()
+    }
+
+    /// Shut the server down and wait for its connections to finish.
+    pub async fn shutdown(mut self) -> Result<()> {
+        match self.server.take() {
+            Some(mut server) => server.shutdown().await,
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for ServerGuard {
+    fn drop(&mut self) {
+        if let Some(mut server) = self.server.take() {
+            // The runtime may already be gone, in which case its tasks went with it.
+            if let Ok(handle) = tokio::runtime::Handle::try_current() {
+                handle.spawn(async move {
+                    server.shutdown().await.unwrap_or_else(|e| {
+                        warn!("failed to shutdown test server: {:?}", e);
+                    });
+                });
+            }
+        }
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use async_trait::async_trait;
+
+    use crate::r#async::{MethodHandler, TtrpcContext};
+    use crate::{Request, Response};
+
+    struct Echo;
+
+    #[async_trait]
+    impl MethodHandler for Echo {
+        async fn handler(&self, _ctx: TtrpcContext, req: Request) -> Result<Response> {
+            Ok(Response {
+                payload: req.payload,
+                ..Default::default()
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_serve() {
+        let mut methods: HashMap<String, Box<dyn MethodHandler + Send + Sync>> = HashMap::new();
+        methods.insert("Echo".to_string(), Box::new(Echo));
+        let mut services = HashMap::new();
+        services.insert(
+            "test.Echo".to_string(),
+            Service {
+                methods,
+                streams: HashMap::new(),
+            },
+        );
+
+        let (client, guard) = serve(services).await.unwrap();
+        let path = guard.path.clone();
+        assert!(path.exists());
+
+        let req = Request {
+            service: "test.Echo".to_string(),
+            method: "Echo".to_string(),
+            payload: b"ping".to_vec(),
+            timeout_nano: 5_000_000_000,
+            ..Default::default()
+        };
+        let res = client.request(req).await.unwrap();
+        assert_eq!(res.payload, b"ping");
+        assert_eq!(guard.server().connections().len(), 1);
+
+        drop(guard);
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,169 @@
+// Copyright (c) 2023 Ant Group
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! TLS of client connections, see
+//! [`ClientBuilder::tls_config`](crate::r#async::ClientBuilder::tls_config).
+
+use std::convert::TryFrom;
+use std::sync::Arc;
+
+#[cfg(feature = "tls-keylog")]
+use tokio_rustls::rustls::KeyLog;
+use tokio_rustls::rustls::{ClientConfig, ServerName};
+use tokio_rustls::TlsConnector;
+
+use crate::error::{Error, Result};
+use crate::r#async::seqpacket::ClientSocket;
+
+#[derive(Clone)]
+pub(crate) struct Tls {
+    config: Arc<ClientConfig>,
+}
+
+impl Tls {
+    pub(crate) fn new(config: ClientConfig) -> Tls {
+        Tls {
+            config: Arc::new(config),
+        }
+    }
+
+    /// Returns a copy logging the secrets of its connections to `key_log`, see
+    /// [`ClientBuilder::tls_key_log`](crate::r#async::ClientBuilder::tls_key_log).
+    #[cfg(feature = "tls-keylog")]
+    pub(crate) fn with_key_log(&self, key_log: Arc<dyn KeyLog>) -> Tls {
+        let mut config = (*self.config).clone();
+        config.key_log = key_log;
+        Tls::new(config)
+    }
+
+    /// Runs the TLS handshake over `stream`, verifying the certificate of the
+    /// server for `name`.
+    pub(crate) async fn connect(
+        &self,
+        name: ServerName,
+        stream: ClientSocket,
+    ) -> Result<ClientSocket> {
+        let stream = TlsConnector::from(self.config.clone())
+            .connect(name.clone(), stream)
+            .await
+            .map_err(|e| Error::Socket(format!("TLS handshake with {name:?} failed: {e}")))?;
+        Ok(ClientSocket::Tls(Box::new(stream)))
+    }
+}
+
+/// Returns the name the certificate of the server of `sockaddr` is verified for:
+/// `name` if set, or else the host of a `tcp://` target.
+pub(crate) fn server_name(sockaddr: &str, name: Option<&str>) -> Result<ServerName> {
+    let name = match name {
+        Some(name) => name,
+        None => tcp_host_name(sockaddr).ok_or_else(|| {
+            Error::Others(format!(
+                "TLS over {sockaddr} requires a server name, see ClientBuilder::tls_server_name"
+            ))
+        })?,
+    };
+    ServerName::try_from(name)
+        .map_err(|e| Error::Others(format!("Invalid TLS server name {name:?}: {e}")))
+}
+
+/// Returns the host of a `tcp://` target, without the brackets of an IPv6.
+fn tcp_host_name(sockaddr: &str) -> Option<&str> {
+    let (host, _port) = sockaddr.strip_prefix("tcp://")?.rsplit_once(':')?;
+    Some(host.trim_start_matches('[').trim_end_matches(']'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tcp_host_name() {
+        assert_eq!(tcp_host_name("tcp://agent.local:1024"), Some("agent.local"));
+        assert_eq!(tcp_host_name("tcp://10.0.0.1:1024"), Some("10.0.0.1"));
+        assert_eq!(tcp_host_name("tcp://[::1]:1024"), Some("::1"));
+        assert_eq!(tcp_host_name("vsock://3:1024"), None);
+    }
+
+    #[test]
+    fn test_server_name() {
+        assert!(server_name("tcp://agent.local:1024", None).is_ok());
+        assert!(server_name("vsock://3:1024", None).is_err());
+        assert!(server_name("vsock://3:1024", Some("agent.local")).is_ok());
+        assert!(server_name("vsock://3:1024", Some("not a name")).is_err());
+    }
+
+    #[cfg(feature = "tls-keylog")]
+    #[tokio::test]
+    async fn test_key_log() {
+        use std::sync::Mutex;
+
+        use tokio::net::UnixListener;
+        use tokio_rustls::rustls::{Certificate, PrivateKey, RootCertStore, ServerConfig};
+        use tokio_rustls::TlsAcceptor;
+
+        use crate::r#async::ClientBuilder;
+        use crate::Request;
+
+        /// Keeps the labels of the secrets it's given.
+        #[derive(Default)]
+        struct Labels(Mutex<Vec<String>>);
+
+        impl KeyLog for Labels {
+            fn log(&self, label: &str, _client_random: &[u8], _secret: &[u8]) {
+                self.0.lock().unwrap().push(label.to_string());
+            }
+        }
+
+        let mut roots = RootCertStore::empty();
+        roots
+            .add(&Certificate(include_bytes!("testdata/ca.der").to_vec()))
+            .unwrap();
+        let client_config = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let server_config = ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(
+                vec![Certificate(include_bytes!("testdata/server.der").to_vec())],
+                PrivateKey(include_bytes!("testdata/server.key.der").to_vec()),
+            )
+            .unwrap();
+
+        let path = std::env::temp_dir().join(format!("ttrpc-key-log-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        let labels = Arc::new(Labels::default());
+        let client = ClientBuilder::new(&format!("unix://{}", path.display()))
+            .tls_config(client_config)
+            .tls_server_name("agent.local")
+            .tls_key_log(labels.clone())
+            .build()
+            .unwrap();
+        // Only the handshake is of interest, the request is never answered.
+        let call = tokio::spawn(async move {
+            let req = Request {
+                service: "test.Test".to_string(),
+                method: "Echo".to_string(),
+                ..Default::default()
+            };
+            client.request(req).await
+        });
+
+        let (stream, _) = listener.accept().await.unwrap();
+        let acceptor = TlsAcceptor::from(Arc::new(server_config));
+        let _stream = acceptor.accept(stream).await.unwrap();
+        assert!(labels
+            .0
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|label| label == "CLIENT_TRAFFIC_SECRET_0"));
+
+        call.abort();
+        let _ = std::fs::remove_file(&path);
+    }
+}
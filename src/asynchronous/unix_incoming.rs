@@ -5,6 +5,7 @@
 
 //! Because Tokio has removed UnixIncoming since version 0.3,
 //! we define the UnixIncoming and implement the Stream for UnixIncoming.
+//! TcpIncoming does the same for TCP listeners.
 
 use std::io;
 use std::os::unix::io::{AsRawFd, RawFd};
@@ -12,7 +13,7 @@ use std::pin::Pin;
 use std::task::{Context, Poll};
 
 use futures::{ready, Stream};
-use tokio::net::{UnixListener, UnixStream};
+use tokio::net::{TcpListener, TcpStream, UnixListener, UnixStream};
 
 /// Stream of listeners
 #[derive(Debug)]
@@ -41,3 +42,31 @@ impl AsRawFd for UnixIncoming {
         self.inner.as_raw_fd()
     }
 }
+
+/// Stream of TCP listeners
+#[derive(Debug)]
+#[must_use = "streams do nothing unless polled"]
+pub struct TcpIncoming {
+    inner: TcpListener,
+}
+
+impl TcpIncoming {
+    pub fn new(listener: TcpListener) -> Self {
+        Self { inner: listener }
+    }
+}
+
+impl Stream for TcpIncoming {
+    type Item = io::Result<TcpStream>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let (socket, _) = ready!(self.inner.poll_accept(cx))?;
+        Poll::Ready(Some(Ok(socket)))
+    }
+}
+
+impl AsRawFd for TcpIncoming {
+    fn as_raw_fd(&self) -> RawFd {
+        self.inner.as_raw_fd()
+    }
+}
@@ -6,41 +6,41 @@
 
 use std::collections::HashMap;
 use std::os::unix::io::{FromRawFd, RawFd};
+use std::sync::{Arc, Mutex};
 
 use async_trait::async_trait;
 use tokio::net::UnixStream;
 
-use crate::error::Result;
-use crate::proto::{MessageHeader, Request, Response};
+use crate::error::{get_rpc_status, Error, Result};
+use crate::proto::{Code, MessageHeader, Request, Response};
 
 /// Handle request in async mode.
 #[macro_export]
 macro_rules! async_request_handler {
     ($class: ident, $ctx: ident, $req: ident, $server: ident, $req_type: ident, $req_fn: ident) => {
-        let mut req = super::$server::$req_type::new();
+        $crate::async_request_handler!($class, $ctx, $req, super::$server::$req_type, $req_fn);
+    };
+    ($class: ident, $ctx: ident, $req: ident, $req_type: ty, $req_fn: ident) => {
+        let mut req = <$req_type>::new();
         {
             let mut s = CodedInputStream::from_bytes(&$req.payload);
             req.merge_from(&mut s)
-                .map_err(::ttrpc::err_to_others!(e, ""))?;
+                .map_err($crate::err_to_others!(e, ""))?;
         }
 
-        let mut res = ::ttrpc::Response::new();
+        let mut res = $crate::Response::new();
         match $class.service.$req_fn(&$ctx, req).await {
             Ok(rep) => {
-                res.set_status(::ttrpc::get_status(::ttrpc::Code::OK, "".to_string()));
-                res.payload.reserve(rep.compute_size() as usize);
-                let mut s = protobuf::CodedOutputStream::vec(&mut res.payload);
-                rep.write_to(&mut s)
-                    .map_err(::ttrpc::err_to_others!(e, ""))?;
-                s.flush().map_err(::ttrpc::err_to_others!(e, ""))?;
+                res.set_status($crate::get_status($crate::Code::OK, "".to_string()));
+                res.payload = $crate::r#async::ResponsePayload::into_payload(rep)?;
             }
             Err(x) => match x {
-                ::ttrpc::Error::RpcStatus(s) => {
+                $crate::Error::RpcStatus(s) => {
                     res.set_status(s);
                 }
                 _ => {
-                    res.set_status(::ttrpc::get_status(
-                        ::ttrpc::Code::UNKNOWN,
+                    res.set_status($crate::get_status(
+                        $crate::Code::UNKNOWN,
                         format!("{:?}", x),
                     ));
                 }
@@ -55,24 +55,20 @@ macro_rules! async_request_handler {
 #[macro_export]
 macro_rules! async_client_streamimg_handler {
     ($class: ident, $ctx: ident, $inner: ident, $req_fn: ident) => {
-        let stream = ::ttrpc::r#async::ServerStreamReceiver::new($inner);
-        let mut res = ::ttrpc::Response::new();
+        let stream = $crate::r#async::ServerStreamReceiver::new($inner);
+        let mut res = $crate::Response::new();
         match $class.service.$req_fn(&$ctx, stream).await {
             Ok(rep) => {
-                res.set_status(::ttrpc::get_status(::ttrpc::Code::OK, "".to_string()));
-                res.payload.reserve(rep.compute_size() as usize);
-                let mut s = protobuf::CodedOutputStream::vec(&mut res.payload);
-                rep.write_to(&mut s)
-                    .map_err(::ttrpc::err_to_others!(e, ""))?;
-                s.flush().map_err(::ttrpc::err_to_others!(e, ""))?;
+                res.set_status($crate::get_status($crate::Code::OK, "".to_string()));
+                res.payload = $crate::r#async::ResponsePayload::into_payload(rep)?;
             }
             Err(x) => match x {
-                ::ttrpc::Error::RpcStatus(s) => {
+                $crate::Error::RpcStatus(s) => {
                     res.set_status(s);
                 }
                 _ => {
-                    res.set_status(::ttrpc::get_status(
-                        ::ttrpc::Code::UNKNOWN,
+                    res.set_status($crate::get_status(
+                        $crate::Code::UNKNOWN,
                         format!("{:?}", x),
                     ));
                 }
@@ -86,23 +82,32 @@ macro_rules! async_client_streamimg_handler {
 #[macro_export]
 macro_rules! async_server_streamimg_handler {
     ($class: ident, $ctx: ident, $inner: ident, $server: ident, $req_type: ident, $req_fn: ident) => {
+        $crate::async_server_streamimg_handler!(
+            $class,
+            $ctx,
+            $inner,
+            super::$server::$req_type,
+            $req_fn
+        );
+    };
+    ($class: ident, $ctx: ident, $inner: ident, $req_type: ty, $req_fn: ident) => {
         let req_buf = $inner.recv().await?;
-        let req = <super::$server::$req_type as ::ttrpc::proto::Codec>::decode(&req_buf)
-            .map_err(|e| ::ttrpc::Error::Others(e.to_string()))?;
-        let stream = ::ttrpc::r#async::ServerStreamSender::new($inner);
+        let req = <$req_type as $crate::proto::Codec>::decode(&req_buf)
+            .map_err(|e| $crate::Error::Others(e.to_string()))?;
+        let stream = $crate::r#async::ServerStreamSender::new($inner);
         match $class.service.$req_fn(&$ctx, req, stream).await {
             Ok(_) => {
                 return Ok(None);
             }
             Err(x) => {
-                let mut res = ::ttrpc::Response::new();
+                let mut res = $crate::Response::new();
                 match x {
-                    ::ttrpc::Error::RpcStatus(s) => {
+                    $crate::Error::RpcStatus(s) => {
                         res.set_status(s);
                     }
                     _ => {
-                        res.set_status(::ttrpc::get_status(
-                            ::ttrpc::Code::UNKNOWN,
+                        res.set_status($crate::get_status(
+                            $crate::Code::UNKNOWN,
                             format!("{:?}", x),
                         ));
                     }
@@ -117,20 +122,20 @@ macro_rules! async_server_streamimg_handler {
 #[macro_export]
 macro_rules! async_duplex_streamimg_handler {
     ($class: ident, $ctx: ident, $inner: ident, $req_fn: ident) => {
-        let stream = ::ttrpc::r#async::ServerStream::new($inner);
+        let stream = $crate::r#async::ServerStream::new($inner);
         match $class.service.$req_fn(&$ctx, stream).await {
             Ok(_) => {
                 return Ok(None);
             }
             Err(x) => {
-                let mut res = ::ttrpc::Response::new();
+                let mut res = $crate::Response::new();
                 match x {
-                    ::ttrpc::Error::RpcStatus(s) => {
+                    $crate::Error::RpcStatus(s) => {
                         res.set_status(s);
                     }
                     _ => {
-                        res.set_status(::ttrpc::get_status(
-                            ::ttrpc::Code::UNKNOWN,
+                        res.set_status($crate::get_status(
+                            $crate::Code::UNKNOWN,
                             format!("{:?}", x),
                         ));
                     }
@@ -145,11 +150,53 @@ macro_rules! async_duplex_streamimg_handler {
 #[macro_export]
 macro_rules! async_client_request {
     ($self: ident, $ctx: ident, $req: ident, $server: expr, $method: expr, $cres: ident) => {
-        let mut creq = ttrpc::Request {
+        $crate::async_client_request!(
+            $self,
+            $ctx,
+            $req,
+            $server,
+            $method,
+            $cres,
+            &$crate::r#async::CallOptions::new()
+        );
+    };
+    ($self: ident, $ctx: ident, $req: ident, $server: expr, $method: expr, $cres: ident, $opts: expr) => {
+        $crate::async_client_call!($self, $ctx, $req, $server, $method, $cres, $opts);
+        return Ok($cres);
+    };
+}
+
+/// Send request through async client, returning the response metadata too.
+#[macro_export]
+macro_rules! async_client_request_with_metadata {
+    ($self: ident, $ctx: ident, $req: ident, $server: expr, $method: expr, $cres: ident) => {
+        $crate::async_client_request_with_metadata!(
+            $self,
+            $ctx,
+            $req,
+            $server,
+            $method,
+            $cres,
+            &$crate::r#async::CallOptions::new()
+        );
+    };
+    ($self: ident, $ctx: ident, $req: ident, $server: expr, $method: expr, $cres: ident, $opts: expr) => {
+        let res = $crate::async_client_call!($self, $ctx, $req, $server, $method, $cres, $opts);
+        return Ok(($cres, $crate::context::from_pb(&res.metadata)));
+    };
+}
+
+/// Makes a unary call through async client and decodes its response into `$cres`,
+/// evaluating to the [`Response`](crate::Response).
+#[doc(hidden)]
+#[macro_export]
+macro_rules! async_client_call {
+    ($self: ident, $ctx: ident, $req: ident, $server: expr, $method: expr, $cres: ident, $opts: expr) => {{
+        let mut creq = $crate::Request {
             service: $server.to_string(),
             method: $method.to_string(),
             timeout_nano: $ctx.timeout_nano,
-            metadata: ttrpc::context::to_pb($ctx.metadata),
+            metadata: $crate::context::to_pb($ctx.metadata),
             payload: Vec::with_capacity($req.compute_size() as usize),
             ..Default::default()
         };
@@ -157,33 +204,46 @@ macro_rules! async_client_request {
         {
             let mut s = CodedOutputStream::vec(&mut creq.payload);
             $req.write_to(&mut s)
-                .map_err(::ttrpc::err_to_others!(e, ""))?;
-            s.flush().map_err(::ttrpc::err_to_others!(e, ""))?;
+                .map_err($crate::err_to_others!(e, ""))?;
+            s.flush().map_err($crate::err_to_others!(e, ""))?;
         }
 
-        let res = $self.client.request(creq).await?;
-        let mut s = CodedInputStream::from_bytes(&res.payload);
-        $cres
-            .merge_from(&mut s)
-            .map_err(::ttrpc::err_to_others!(e, "Unpack get error "))?;
-
-        return Ok($cres);
-    };
+        let (res, _) = $self.client.request_with_options(creq, $opts).await?;
+        {
+            let mut s = CodedInputStream::from_bytes(&res.payload);
+            $cres
+                .merge_from(&mut s)
+                .map_err($crate::err_to_others!(e, "Unpack get error "))?;
+        }
+        res
+    }};
 }
 
 /// Duplex streaming through async client.
 #[macro_export]
 macro_rules! async_client_stream {
     ($self: ident, $ctx: ident, $server: expr, $method: expr) => {
-        let mut creq = ::ttrpc::Request::new();
+        $crate::async_client_stream!(
+            $self,
+            $ctx,
+            $server,
+            $method,
+            &$crate::r#async::CallOptions::new()
+        );
+    };
+    ($self: ident, $ctx: ident, $server: expr, $method: expr, $opts: expr) => {
+        let mut creq = $crate::Request::new();
         creq.set_service($server.to_string());
         creq.set_method($method.to_string());
         creq.set_timeout_nano($ctx.timeout_nano);
-        let md = ::ttrpc::context::to_pb($ctx.metadata);
+        let md = $crate::context::to_pb($ctx.metadata);
         creq.set_metadata(md);
 
-        let inner = $self.client.new_stream(creq, true, true).await?;
-        let stream = ::ttrpc::r#async::ClientStream::new(inner);
+        let inner = $self
+            .client
+            .new_stream_with_options(creq, true, true, $opts)
+            .await?;
+        let stream = $crate::r#async::ClientStream::new(inner);
 
         return Ok(stream);
     };
@@ -193,15 +253,27 @@ macro_rules! async_client_stream {
 #[macro_export]
 macro_rules! async_client_stream_send {
     ($self: ident, $ctx: ident, $server: expr, $method: expr) => {
-        let mut creq = ::ttrpc::Request::new();
+        $crate::async_client_stream_send!(
+            $self,
+            $ctx,
+            $server,
+            $method,
+            &$crate::r#async::CallOptions::new()
+        );
+    };
+    ($self: ident, $ctx: ident, $server: expr, $method: expr, $opts: expr) => {
+        let mut creq = $crate::Request::new();
         creq.set_service($server.to_string());
         creq.set_method($method.to_string());
         creq.set_timeout_nano($ctx.timeout_nano);
-        let md = ::ttrpc::context::to_pb($ctx.metadata);
+        let md = $crate::context::to_pb($ctx.metadata);
         creq.set_metadata(md);
 
-        let inner = $self.client.new_stream(creq, true, false).await?;
-        let stream = ::ttrpc::r#async::ClientStreamSender::new(inner);
+        let inner = $self
+            .client
+            .new_stream_with_options(creq, true, false, $opts)
+            .await?;
+        let stream = $crate::r#async::ClientStreamSender::new(inner);
 
         return Ok(stream);
     };
@@ -211,27 +283,111 @@ macro_rules! async_client_stream_send {
 #[macro_export]
 macro_rules! async_client_stream_receive {
     ($self: ident, $ctx: ident, $req: ident, $server: expr, $method: expr) => {
-        let mut creq = ::ttrpc::Request::new();
+        $crate::async_client_stream_receive!(
+            $self,
+            $ctx,
+            $req,
+            $server,
+            $method,
+            &$crate::r#async::CallOptions::new()
+        );
+    };
+    ($self: ident, $ctx: ident, $req: ident, $server: expr, $method: expr, $opts: expr) => {
+        let mut creq = $crate::Request::new();
         creq.set_service($server.to_string());
         creq.set_method($method.to_string());
         creq.set_timeout_nano($ctx.timeout_nano);
-        let md = ::ttrpc::context::to_pb($ctx.metadata);
+        let md = $crate::context::to_pb($ctx.metadata);
         creq.set_metadata(md);
         creq.payload.reserve($req.compute_size() as usize);
         {
             let mut s = CodedOutputStream::vec(&mut creq.payload);
             $req.write_to(&mut s)
-                .map_err(::ttrpc::err_to_others!(e, ""))?;
-            s.flush().map_err(::ttrpc::err_to_others!(e, ""))?;
+                .map_err($crate::err_to_others!(e, ""))?;
+            s.flush().map_err($crate::err_to_others!(e, ""))?;
         }
 
-        let inner = $self.client.new_stream(creq, false, true).await?;
-        let stream = ::ttrpc::r#async::ClientStreamReceiver::new(inner);
+        let inner = $self
+            .client
+            .new_stream_with_options(creq, false, true, $opts)
+            .await?;
+        let stream = $crate::r#async::ClientStreamReceiver::new(inner);
 
         return Ok(stream);
     };
 }
 
+/// The response of a generated service method, which may be encoded already.
+///
+/// Caching layers and proxies which hold the canonical encoded response can return
+/// it without decoding and re-encoding it. Methods return this instead of the bare
+/// message if the code generator's `encoded_responses` option is set.
+#[derive(Clone, Debug)]
+pub struct EncodedResponse<T> {
+    inner: Encoded<T>,
+}
+
+#[derive(Clone, Debug)]
+enum Encoded<T> {
+    Message(T),
+    Payload(Vec<u8>),
+}
+
+impl<T: protobuf::Message> EncodedResponse<T> {
+    /// Wraps `payload`, which must be an encoded `T`. It is sent as it is.
+    pub fn from_encoded(payload: Vec<u8>) -> Self {
+        EncodedResponse {
+            inner: Encoded::Payload(payload),
+        }
+    }
+
+    /// Encodes `msg` once, e.g. to cache the result.
+    pub fn encode(msg: &T) -> Result<Self> {
+        let payload = msg
+            .write_to_bytes()
+            .map_err(err_to_others_err!(e, "Encode response failed."))?;
+        Ok(Self::from_encoded(payload))
+    }
+
+    /// Returns the payload if the response is encoded already.
+    pub fn encoded(&self) -> Option<&[u8]> {
+        match &self.inner {
+            Encoded::Message(_) => None,
+            Encoded::Payload(payload) => Some(payload),
+        }
+    }
+}
+
+impl<T> From<T> for EncodedResponse<T> {
+    fn from(msg: T) -> Self {
+        EncodedResponse {
+            inner: Encoded::Message(msg),
+        }
+    }
+}
+
+/// Encodes the response of a generated service method.
+#[doc(hidden)]
+pub trait ResponsePayload {
+    fn into_payload(self) -> Result<Vec<u8>>;
+}
+
+impl<T: protobuf::Message> ResponsePayload for T {
+    fn into_payload(self) -> Result<Vec<u8>> {
+        self.write_to_bytes()
+            .map_err(err_to_others_err!(e, "Encode response failed."))
+    }
+}
+
+impl<T: protobuf::Message> ResponsePayload for EncodedResponse<T> {
+    fn into_payload(self) -> Result<Vec<u8>> {
+        match self.inner {
+            Encoded::Message(msg) => msg.into_payload(),
+            Encoded::Payload(payload) => Ok(payload),
+        }
+    }
+}
+
 /// Trait that implements handler which is a proxy to the desired method (async).
 #[async_trait]
 pub trait MethodHandler {
@@ -248,13 +404,164 @@ pub trait StreamHandler {
     ) -> Result<Option<Response>>;
 }
 
+/// Trait that implements a whole service whose methods are only known at runtime,
+/// e.g. one loaded from a plugin (async).
+///
+/// Register it with `Server::register_dyn_service`, each of the listed methods is
+/// dispatched to [`DynService::call`] or [`DynService::call_stream`].
+#[async_trait]
+pub trait DynService {
+    /// The fully qualified name of the service, e.g. `grpc.Health`.
+    fn name(&self) -> String;
+
+    /// The names of the unary methods of the service.
+    fn methods(&self) -> Vec<String>;
+
+    /// The names of the streaming methods of the service.
+    fn streams(&self) -> Vec<String> {
+        Vec::new()
+    }
+
+    async fn call(&self, ctx: TtrpcContext, method: &str, req: Request) -> Result<Response>;
+
+    async fn call_stream(
+        &self,
+        _ctx: TtrpcContext,
+        method: &str,
+        _stream: crate::r#async::StreamInner,
+    ) -> Result<Option<Response>> {
+        Err(get_rpc_status(
+            Code::UNIMPLEMENTED,
+            format!("{} is not a streaming method of {}", method, self.name()),
+        ))
+    }
+}
+
 /// The context of ttrpc (async).
+///
+/// Only the server creates contexts, so adding a field isn't a breaking change.
 #[derive(Debug)]
+#[non_exhaustive]
 pub struct TtrpcContext {
     pub fd: std::os::unix::io::RawFd,
     pub mh: MessageHeader,
     pub metadata: HashMap<String, Vec<String>>,
     pub timeout_nano: i64,
+    /// When the call times out, `None` if the client set no timeout. Calls made on
+    /// behalf of this one inherit it with
+    /// [`Context::with_parent_deadline`](crate::context::Context::with_parent_deadline).
+    pub deadline: Option<std::time::Instant>,
+    /// The clock of the server, which the deadline is measured with.
+    pub clock: Arc<dyn crate::r#async::Clock>,
+    /// Credentials of the process which wrote the request, only set if the server
+    /// passes credentials, see `Server::set_pass_credentials`.
+    pub credentials: Option<crate::r#async::Credentials>,
+    /// Storage shared by all calls of the connection, e.g. for session state.
+    pub extensions: crate::r#async::Extensions,
+    /// Address of the peer, see [`TtrpcContext::peer_addr`].
+    pub peer: Option<crate::address::Address>,
+    /// Credentials of the process which connected, for the peers of Unix sockets.
+    /// Unlike [`TtrpcContext::credentials`], they are the same for all the calls of
+    /// the connection, even once it was handed over to another process.
+    pub peer_credentials: Option<crate::r#async::Credentials>,
+    /// Metadata sent back to the client with the response.
+    pub response_metadata: ResponseMetadata,
+}
+
+impl TtrpcContext {
+    /// The time left until the call times out, `None` if it has no timeout.
+    pub fn remaining(&self) -> Option<std::time::Duration> {
+        self.deadline
+            .map(|deadline| deadline.saturating_duration_since(self.clock.now()))
+    }
+
+    /// Address of the peer which sent the request, e.g. to log or authorize
+    /// [`Address::Vsock`](crate::address::Address::Vsock) peers by their cid.
+    pub fn peer_addr(&self) -> Option<&crate::address::Address> {
+        self.peer.as_ref()
+    }
+
+    /// The cid of the peer, for the peers of vsock sockets, e.g. to tell the guests
+    /// of a host apart.
+    pub fn peer_cid(&self) -> Option<u32> {
+        match self.peer.as_ref()? {
+            crate::address::Address::Vsock(addr) => Some(addr.cid),
+            _ => None,
+        }
+    }
+
+    /// Sends notifications to the client, `None` if it did not subscribe to them, see
+    /// [`ClientBuilder::notifications`](crate::r#async::ClientBuilder::notifications).
+    pub fn notifier(&self) -> Option<crate::r#async::Notifier> {
+        self.extensions.get::<crate::r#async::Notifier>()
+    }
+
+    /// Schema version of the client, if it sent one when connecting, see
+    /// [`ClientBuilder::schema_version`](crate::r#async::ClientBuilder::schema_version).
+    pub fn peer_version(&self) -> Option<String> {
+        self.extensions
+            .get::<crate::r#async::handshake::PeerVersion>()
+            .map(|version| version.0)
+    }
+}
+
+impl crate::context::Deadline for TtrpcContext {
+    fn deadline(&self) -> Option<std::time::Instant> {
+        self.deadline
+    }
+
+    fn remaining(&self) -> Option<std::time::Duration> {
+        TtrpcContext::remaining(self)
+    }
+}
+
+/// Metadata sent back to the client with the response of a call, e.g. to report
+/// which backend served it. Clients read it from `Response::metadata`.
+#[derive(Clone, Debug, Default)]
+pub struct ResponseMetadata(Arc<Mutex<HashMap<String, Vec<String>>>>);
+
+impl ResponseMetadata {
+    /// Appends a value to the given key.
+    pub fn add(&self, key: String, value: String) {
+        self.0
+            .lock()
+            .unwrap()
+            .entry(key.to_lowercase())
+            .or_default()
+            .push(value);
+    }
+
+    /// Sets the values of the given key, overwriting any existing values.
+    /// If no values are provided, the key is deleted.
+    pub fn set(&self, key: String, value: Vec<String>) {
+        let mut metadata = self.0.lock().unwrap();
+        if value.is_empty() {
+            metadata.remove(&key.to_lowercase());
+        } else {
+            metadata.insert(key.to_lowercase(), value);
+        }
+    }
+
+    /// Sets the binary values of the given key, see
+    /// [`Context::set_bin`](crate::context::Context::set_bin). They travel base64
+    /// encoded, see [`BINARY_SUFFIX`](crate::context::BINARY_SUFFIX) for why.
+    ///
+    /// Fails if `key` doesn't end with [`BINARY_SUFFIX`](crate::context::BINARY_SUFFIX).
+    pub fn set_bin(&self, key: String, value: Vec<Vec<u8>>) -> Result<()> {
+        let value = crate::context::encode_bin(&key, &value)?;
+        self.set(key, value);
+        Ok(())
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.0.lock().unwrap().is_empty()
+    }
+
+    /// Adds the metadata to `resp`, after anything the handler set itself.
+    pub(crate) fn apply(&self, resp: &mut Response) {
+        let metadata = std::mem::take(&mut *self.0.lock().unwrap());
+        resp.metadata.extend(crate::context::to_pb(metadata));
+    }
 }
 
 pub(crate) fn new_unix_stream_from_raw_fd(fd: RawFd) -> UnixStream {
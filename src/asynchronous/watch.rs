@@ -0,0 +1,312 @@
+// Copyright (c) 2023 Ant Group
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Event subscriptions layered on server streaming methods.
+//!
+//! The server sends every event through a [`Broadcaster`], and serves each call
+//! of the watch method with [`Broadcaster::serve`]. The client receives the
+//! events with a [`Watcher`], which calls the watch method again whenever the
+//! stream breaks, asking for the events following the last one it received.
+
+use std::collections::VecDeque;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::sync::broadcast;
+
+use crate::error::{Error, Result};
+use crate::proto::Codec;
+use crate::r#async::{ClientStreamReceiver, ServerStreamSender};
+
+const DEFAULT_INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+const DEFAULT_MAX_BACKOFF: Duration = Duration::from_secs(10);
+
+/// An event carrying the sequence number assigned by a [`Broadcaster`], usually
+/// a `uint64` field of the event message.
+pub trait WatchEvent: Clone {
+    fn sequence(&self) -> u64;
+    fn set_sequence(&mut self, sequence: u64);
+}
+
+/// Sends events to all the watchers, and keeps the last events so watchers which
+/// reconnect get the events they missed.
+pub struct Broadcaster<T> {
+    inner: Arc<Mutex<History<T>>>,
+    tx: broadcast::Sender<T>,
+}
+
+struct History<T> {
+    next_sequence: u64,
+    events: VecDeque<T>,
+    capacity: usize,
+}
+
+impl<T> Clone for Broadcaster<T> {
+    fn clone(&self) -> Self {
+        Broadcaster {
+            inner: self.inner.clone(),
+            tx: self.tx.clone(),
+        }
+    }
+}
+
+impl<T> Broadcaster<T>
+where
+    T: WatchEvent + Codec + Send + 'static,
+    <T as Codec>::E: std::fmt::Display,
+{
+    /// Keep the last `history` events for watchers which reconnect. Watchers
+    /// falling further behind are disconnected, and resume from the history.
+    pub fn new(history: usize) -> Broadcaster<T> {
+        assert!(history > 0, "history must be greater than 0");
+        Broadcaster {
+            inner: Arc::new(Mutex::new(History {
+                next_sequence: 1,
+                events: VecDeque::with_capacity(history),
+                capacity: history,
+            })),
+            tx: broadcast::channel(history).0,
+        }
+    }
+
+    /// Sends `event` to all the watchers, and returns its sequence number.
+    /// Sequence numbers start at 1.
+    pub fn send(&self, mut event: T) -> u64 {
+        let mut history = self.inner.lock().unwrap();
+        let sequence = history.next_sequence;
+        history.next_sequence += 1;
+        event.set_sequence(sequence);
+        if history.events.len() == history.capacity {
+            history.events.pop_front();
+        }
+        history.events.push_back(event.clone());
+        // Nobody is watching.
+        self.tx.send(event).ok();
+        sequence
+    }
+
+    /// Sends the events to `stream` until the watcher goes away, starting with the
+    /// kept events whose sequence number is at least `from`, if it isn't 0.
+    ///
+    /// Returns an error if the watcher fell too far behind, so it resumes from the
+    /// history.
+    pub async fn serve(&self, from: u64, stream: &ServerStreamSender<T>) -> Result<()> {
+        let (mut rx, missed): (_, Vec<T>) = {
+            let history = self.inner.lock().unwrap();
+            let missed = history
+                .events
+                .iter()
+                .filter(|event| from != 0 && event.sequence() >= from)
+                .cloned()
+                .collect();
+            (self.tx.subscribe(), missed)
+        };
+        for event in missed.iter() {
+            stream.send(event).await?;
+        }
+        loop {
+            match rx.recv().await {
+                Ok(event) => stream.send(&event).await?,
+                Err(broadcast::error::RecvError::Lagged(n)) => {
+                    return Err(Error::Others(format!("Watcher missed {} events", n)));
+                }
+                Err(broadcast::error::RecvError::Closed) => return Ok(()),
+            }
+        }
+    }
+}
+
+/// Receives the events of a watch method, calling it again with backoff whenever
+/// the stream breaks.
+///
+/// `open` calls the watch method, asking for the events from the given sequence
+/// number on, or for new events only if it is 0.
+pub struct Watcher<T, F> {
+    open: F,
+    stream: Option<ClientStreamReceiver<T>>,
+    next_sequence: u64,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+}
+
+impl<T, F, Fut> Watcher<T, F>
+where
+    T: WatchEvent + Codec,
+    <T as Codec>::E: std::fmt::Display,
+    F: FnMut(u64) -> Fut,
+    Fut: Future<Output = Result<ClientStreamReceiver<T>>>,
+{
+    pub fn new(open: F) -> Watcher<T, F> {
+        Watcher {
+            open,
+            stream: None,
+            next_sequence: 0,
+            initial_backoff: DEFAULT_INITIAL_BACKOFF,
+            max_backoff: DEFAULT_MAX_BACKOFF,
+        }
+    }
+
+    /// Wait `initial` before calling the watch method again, doubling the wait up
+    /// to `max` while the calls keep failing.
+    pub fn backoff(mut self, initial: Duration, max: Duration) -> Watcher<T, F> {
+        self.initial_backoff = initial;
+        self.max_backoff = max.max(initial);
+        self
+    }
+
+    /// Returns the next event.
+    ///
+    /// Returns an error if the watch method failed with an error which is not
+    /// [transient](Error::is_transient), e.g. because the client was closed.
+    pub async fn next(&mut self) -> Result<T> {
+        let mut backoff = self.initial_backoff;
+        loop {
+            let stream = match self.stream.as_mut() {
+                Some(stream) => stream,
+                None => match (self.open)(self.next_sequence).await {
+                    Ok(stream) => self.stream.insert(stream),
+                    Err(e) if e.is_transient() => {
+                        debug!("Watch failed, retrying in {:?}: {:?}", backoff, e);
+                        tokio::time::sleep(backoff).await;
+                        backoff = (backoff * 2).min(self.max_backoff);
+                        continue;
+                    }
+                    Err(e) => return Err(e),
+                },
+            };
+            match stream.recv().await {
+                // Replayed events the watcher already got.
+                Ok(Some(event)) if event.sequence() < self.next_sequence => {}
+                Ok(Some(event)) => {
+                    self.next_sequence = event.sequence() + 1;
+                    return Ok(event);
+                }
+                Ok(None) => {
+                    self.stream = None;
+                    tokio::time::sleep(backoff).await;
+                }
+                Err(e) => {
+                    debug!("Watch stream broke: {:?}", e);
+                    self.stream = None;
+                    tokio::time::sleep(backoff).await;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::HashMap;
+
+    use async_trait::async_trait;
+    use protobuf::well_known_types::wrappers::UInt64Value;
+    use tokio::sync::mpsc;
+
+    use crate::r#async::{
+        testing, ClientBuilder, ReconnectPolicy, Server, Service, StreamHandler, StreamInner,
+        TtrpcContext,
+    };
+    use crate::{Request, Response};
+
+    /// An event which is nothing but its sequence number.
+    impl WatchEvent for UInt64Value {
+        fn sequence(&self) -> u64 {
+            self.value
+        }
+
+        fn set_sequence(&mut self, sequence: u64) {
+            self.value = sequence;
+        }
+    }
+
+    /// Watches the events from the sequence number sent in the request.
+    struct Watch(Broadcaster<UInt64Value>);
+
+    #[async_trait]
+    impl StreamHandler for Watch {
+        async fn handler(
+            &self,
+            _ctx: TtrpcContext,
+            mut stream: StreamInner,
+        ) -> Result<Option<Response>> {
+            let from = UInt64Value::decode(stream.recv().await?).unwrap().value;
+            self.0.serve(from, &ServerStreamSender::new(stream)).await?;
+            Ok(None)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_watch() {
+        let broadcaster = Broadcaster::new(8);
+        let mut streams: HashMap<String, Arc<dyn StreamHandler + Send + Sync>> = HashMap::new();
+        streams.insert("Watch".to_string(), Arc::new(Watch(broadcaster.clone())));
+        let mut services = HashMap::new();
+        services.insert(
+            "test.Test".to_string(),
+            Service {
+                methods: HashMap::new(),
+                streams,
+            },
+        );
+        let server = testing::start(Server::new().register_service(services))
+            .await
+            .unwrap();
+        let backoff = Duration::from_millis(10);
+        let client = ClientBuilder::new(&server.address())
+            .reconnect(ReconnectPolicy::new().backoff(backoff, backoff))
+            .build()
+            .unwrap();
+
+        let mut watcher = Watcher::new(move |from: u64| {
+            let client = client.clone();
+            async move {
+                let req = Request {
+                    service: "test.Test".to_string(),
+                    method: "Watch".to_string(),
+                    payload: UInt64Value::from(from).encode().unwrap(),
+                    ..Default::default()
+                };
+                let stream = client.new_stream(req, false, true).await?;
+                Ok(ClientStreamReceiver::<UInt64Value>::new(stream))
+            }
+        })
+        .backoff(Duration::from_millis(1), backoff);
+        let (events_tx, mut events) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Ok(event) = watcher.next().await {
+                events_tx.send(event.value).unwrap();
+            }
+        });
+        let watching = |n: usize| {
+            let broadcaster = broadcaster.clone();
+            async move {
+                while broadcaster.tx.receiver_count() != n {
+                    tokio::time::sleep(Duration::from_millis(1)).await;
+                }
+            }
+        };
+
+        watching(1).await;
+        assert_eq!(broadcaster.send(UInt64Value::new()), 1);
+        assert_eq!(broadcaster.send(UInt64Value::new()), 2);
+        assert_eq!(events.recv().await, Some(1));
+        assert_eq!(events.recv().await, Some(2));
+
+        // Sent while the stream is broken, the watcher gets it once it resumed.
+        let id = server.server().connections()[0].id;
+        server.server().close_connection(id, "test").unwrap();
+        watching(0).await;
+        broadcaster.send(UInt64Value::new());
+        assert_eq!(events.recv().await, Some(3));
+        watching(1).await;
+        broadcaster.send(UInt64Value::new());
+        assert_eq!(events.recv().await, Some(4));
+        assert!(events.try_recv().is_err());
+    }
+}
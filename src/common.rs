@@ -13,8 +13,19 @@
 use nix::fcntl::FdFlag;
 use nix::fcntl::{fcntl, FcntlArg, OFlag};
 use nix::sys::socket::*;
+#[cfg(feature = "async")]
+use nix::{
+    errno::Errno,
+    poll::{poll, PollFd, PollFlags},
+};
+#[cfg(feature = "async")]
+use std::convert::TryFrom;
 use std::os::unix::io::RawFd;
+#[cfg(feature = "async")]
+use std::time::{Duration, Instant};
 
+#[cfg(feature = "async")]
+use crate::address::Address;
 use crate::error::{Error, Result};
 
 #[derive(Debug, Clone, Copy, PartialEq)]
@@ -22,6 +33,10 @@ pub(crate) enum Domain {
     Unix,
     #[cfg(any(target_os = "linux", target_os = "android"))]
     Vsock,
+    /// A Unix domain socket of type `SOCK_SEQPACKET`.
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    UnixPacket,
+    Tcp,
 }
 
 pub(crate) fn do_listen(listener: RawFd) -> Result<()> {
@@ -44,6 +59,14 @@ fn parse_sockaddr(addr: &str) -> Result<(Domain, &str)> {
         return Ok((Domain::Vsock, addr));
     }
 
+    if let Some(addr) = addr.strip_prefix("unixpacket://") {
+        return Ok((Domain::UnixPacket, addr));
+    }
+
+    if let Some(addr) = addr.strip_prefix("tcp://") {
+        return Ok((Domain::Tcp, addr));
+    }
+
     Err(Error::Others(format!("Scheme {addr:?} is not supported")))
 }
 
@@ -58,6 +81,10 @@ fn parse_sockaddr(addr: &str) -> Result<(Domain, &str)> {
         return Ok((Domain::Unix, addr));
     }
 
+    if let Some(addr) = addr.strip_prefix("tcp://") {
+        return Ok((Domain::Tcp, addr));
+    }
+
     Err(Error::Others(format!("Scheme {addr:?} is not supported")))
 }
 
@@ -83,8 +110,12 @@ pub(crate) const SOCK_CLOEXEC: SockFlag = SockFlag::empty();
 #[cfg(any(target_os = "linux", target_os = "android"))]
 fn make_addr(domain: Domain, sockaddr: &str) -> Result<UnixAddr> {
     match domain {
-        Domain::Unix => {
+        Domain::Unix | Domain::UnixPacket => {
             if let Some(sockaddr) = sockaddr.strip_prefix('@') {
+                // Binding to an empty name lets the kernel pick one, see unix(7).
+                if sockaddr.is_empty() {
+                    return Ok(UnixAddr::new_unnamed());
+                }
                 UnixAddr::new_abstract(sockaddr.as_bytes()).map_err(err_to_others_err!(e, ""))
             } else {
                 UnixAddr::new(sockaddr).map_err(err_to_others_err!(e, ""))
@@ -93,6 +124,9 @@ fn make_addr(domain: Domain, sockaddr: &str) -> Result<UnixAddr> {
         Domain::Vsock => Err(Error::Others(
             "function make_addr does not support create vsock socket".to_string(),
         )),
+        Domain::Tcp => Err(Error::Others(
+            "function make_addr does not support create tcp socket".to_string(),
+        )),
     }
 }
 
@@ -106,7 +140,14 @@ fn make_socket(addr: (&str, u32)) -> Result<(RawFd, Domain, Box<dyn SockaddrLike
     let (domain, sockaddrv) = parse_sockaddr(sockaddr)?;
 
     let get_sock_addr = |domain, sockaddr| -> Result<(RawFd, Box<dyn SockaddrLike>)> {
-        let fd = socket(AddressFamily::Unix, SockType::Stream, SOCK_CLOEXEC, None)
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        let ty = match domain {
+            Domain::UnixPacket => SockType::SeqPacket,
+            _ => SockType::Stream,
+        };
+        #[cfg(not(any(target_os = "linux", target_os = "android")))]
+        let ty = SockType::Stream;
+        let fd = socket(AddressFamily::Unix, ty, SOCK_CLOEXEC, None)
             .map_err(|e| Error::Socket(e.to_string()))?;
 
         // MacOS doesn't support atomic creation of a socket descriptor with SOCK_CLOEXEC flag,
@@ -120,16 +161,10 @@ fn make_socket(addr: (&str, u32)) -> Result<(RawFd, Domain, Box<dyn SockaddrLike
     let (fd, sockaddr): (i32, Box<dyn SockaddrLike>) = match domain {
         Domain::Unix => get_sock_addr(domain, sockaddrv)?,
         #[cfg(any(target_os = "linux", target_os = "android"))]
+        Domain::UnixPacket => get_sock_addr(domain, sockaddrv)?,
+        #[cfg(any(target_os = "linux", target_os = "android"))]
         Domain::Vsock => {
-            let sockaddr_port_v: Vec<&str> = sockaddrv.split(':').collect();
-            if sockaddr_port_v.len() != 2 {
-                return Err(Error::Others(format!(
-                    "sockaddr {sockaddr} is not right for vsock"
-                )));
-            }
-            let port: u32 = sockaddr_port_v[1]
-                .parse()
-                .expect("the vsock port is not an number");
+            let port = sockaddrv.parse::<crate::address::VsockAddr>()?.port;
             let fd = socket(
                 AddressFamily::Vsock,
                 SockType::Stream,
@@ -141,6 +176,22 @@ fn make_socket(addr: (&str, u32)) -> Result<(RawFd, Domain, Box<dyn SockaddrLike
             let sockaddr = VsockAddr::new(cid, port);
             (fd, Box::new(sockaddr))
         }
+        // Only IPs are accepted, host names would have to be resolved.
+        Domain::Tcp => {
+            let addr = sockaddrv
+                .parse::<std::net::SocketAddr>()
+                .map_err(|e| Error::Others(format!("invalid tcp address {sockaddrv:?}: {e}")))?;
+            let family = if addr.is_ipv4() {
+                AddressFamily::Inet
+            } else {
+                AddressFamily::Inet6
+            };
+            let fd = socket(family, SockType::Stream, SOCK_CLOEXEC, None)
+                .map_err(|e| Error::Socket(e.to_string()))?;
+            #[cfg(target_os = "macos")]
+            set_fd_close_exec(fd)?;
+            (fd, Box::new(SockaddrStorage::from(addr)))
+        }
     };
 
     Ok((fd, domain, sockaddr))
@@ -156,24 +207,136 @@ use libc::VMADDR_CID_HOST;
 #[cfg(not(any(target_os = "linux", target_os = "android")))]
 const VMADDR_CID_HOST: u32 = 0;
 
+/// Fails for `unixpacket://` addresses, which only async clients support.
+pub(crate) fn check_not_unixpacket(sockaddr: &str) -> Result<()> {
+    if sockaddr.starts_with("unixpacket://") {
+        return Err(Error::Others(
+            "unixpacket:// is only supported by async clients".to_string(),
+        ));
+    }
+    Ok(())
+}
+
 pub(crate) fn do_bind(sockaddr: &str) -> Result<(RawFd, Domain)> {
+    check_not_unixpacket(sockaddr)?;
     let (fd, domain, sockaddr) = make_socket((sockaddr, VMADDR_CID_ANY))?;
 
-    setsockopt(fd, sockopt::ReusePort, &true)?;
+    // Another process could bind the same TCP port and take over some of the
+    // connections, Unix and vsock sockets are protected by their file and cid.
+    if domain != Domain::Tcp {
+        setsockopt(fd, sockopt::ReusePort, &true)?;
+    }
     bind(fd, sockaddr.as_ref()).map_err(err_to_others_err!(e, ""))?;
 
     Ok((fd, domain))
 }
 
+/// Returns the address of the peer of the connection `fd`.
+#[cfg(feature = "async")]
+pub(crate) fn peer_address(fd: RawFd) -> Option<Address> {
+    let addr = getpeername::<SockaddrStorage>(fd).ok()?;
+    if let Some(addr) = addr.as_unix_addr() {
+        if let Some(path) = addr.path() {
+            return Some(Address::Unix(path.display().to_string()));
+        }
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        if let Some(name) = addr.as_abstract() {
+            return Some(Address::Unix(format!("@{}", String::from_utf8_lossy(name))));
+        }
+        return Some(Address::Unix(String::new()));
+    }
+    if let Some(addr) = addr.as_sockaddr_in() {
+        return Some(Address::Tcp(std::net::SocketAddrV4::from(*addr).into()));
+    }
+    if let Some(addr) = addr.as_sockaddr_in6() {
+        return Some(Address::Tcp(std::net::SocketAddrV6::from(*addr).into()));
+    }
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    if let Some(addr) = addr.as_vsock_addr() {
+        let addr = crate::address::VsockAddr::new(addr.cid(), addr.port());
+        return Some(addr.into());
+    }
+    None
+}
+
+/// Creates the socket of a client connecting to `sockaddr`.
+///
+/// The socket of a client of an abstract Unix socket is autobound to an abstract
+/// name the kernel picks, so the server can tell its clients apart without any
+/// of them creating a file to clean up.
+fn make_client_socket(sockaddr: &str) -> Result<(RawFd, Box<dyn SockaddrLike>)> {
+    let (domain, name) = parse_sockaddr(sockaddr)?;
+    let is_abstract = matches!(domain, Domain::Unix) && name.starts_with('@');
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    let is_abstract = is_abstract || matches!(domain, Domain::UnixPacket) && name.starts_with('@');
+    if is_abstract && name.len() == 1 {
+        return Err(Error::Others(format!(
+            "{sockaddr:?} has no abstract socket name to connect to"
+        )));
+    }
+    let (fd, _, sockaddr) = make_socket((sockaddr, VMADDR_CID_HOST))?;
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    if is_abstract {
+        if let Err(e) = bind(fd, &UnixAddr::new_unnamed()) {
+            nix::unistd::close(fd).ok();
+            return Err(Error::Socket(format!("autobind failed: {e}")));
+        }
+    }
+    Ok((fd, sockaddr))
+}
+
 /// Creates a unix socket for client.
 pub(crate) unsafe fn client_connect(sockaddr: &str) -> Result<RawFd> {
-    let (fd, _, sockaddr) = make_socket((sockaddr, VMADDR_CID_HOST))?;
+    let (fd, sockaddr) = make_client_socket(sockaddr)?;
 
     connect(fd, sockaddr.as_ref())?;
 
     Ok(fd)
 }
 
+/// Creates a socket for client, giving up if the peer doesn't accept the connection
+/// within `timeout`.
+#[cfg(feature = "async")]
+pub(crate) unsafe fn client_connect_timeout(sockaddr: &str, timeout: Duration) -> Result<RawFd> {
+    let (fd, sockaddr) = make_client_socket(sockaddr)?;
+    let res = connect_timeout(fd, sockaddr.as_ref(), timeout);
+    if res.is_err() {
+        nix::unistd::close(fd).ok();
+    }
+    res.map(|_| fd)
+}
+
+#[cfg(feature = "async")]
+fn connect_timeout(fd: RawFd, sockaddr: &dyn SockaddrLike, timeout: Duration) -> Result<()> {
+    let deadline = Instant::now() + timeout;
+    let timed_out = || Error::Socket(format!("connect timed out after {timeout:?}"));
+    let flags = OFlag::from_bits_truncate(fcntl(fd, FcntlArg::F_GETFL)?);
+    fcntl(fd, FcntlArg::F_SETFL(flags | OFlag::O_NONBLOCK))?;
+    loop {
+        let left = deadline.saturating_duration_since(Instant::now());
+        match connect(fd, sockaddr) {
+            Ok(()) => break,
+            Err(Errno::EINPROGRESS) => {
+                let mut fds = [PollFd::new(fd, PollFlags::POLLOUT)];
+                let ms = i32::try_from(left.as_millis()).unwrap_or(i32::MAX);
+                if poll(&mut fds, ms)? == 0 {
+                    return Err(timed_out());
+                }
+                match getsockopt(fd, sockopt::SocketError)? {
+                    0 => break,
+                    e => return Err(Errno::from_i32(e).into()),
+                }
+            }
+            // The backlog of the Unix socket is full, the connection has to be retried.
+            Err(Errno::EAGAIN) if left.is_zero() => return Err(timed_out()),
+            Err(Errno::EAGAIN) => std::thread::sleep(left.min(Duration::from_millis(10))),
+            Err(e) => return Err(e.into()),
+        }
+    }
+    fcntl(fd, FcntlArg::F_SETFL(flags))?;
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -196,6 +359,19 @@ mod tests {
                 "@/run/b.sock",
                 true,
             ),
+            ("unix://@", Some(Domain::Unix), "@", true),
+            (
+                "unixpacket:///run/d.sock",
+                Some(Domain::UnixPacket),
+                "/run/d.sock",
+                true,
+            ),
+            (
+                "tcp://127.0.0.1:1024",
+                Some(Domain::Tcp),
+                "127.0.0.1:1024",
+                true,
+            ),
             ("abc:///run/c.sock", None, "", false),
         ] {
             let (input, domain, addr, success) = (i.0, i.1, i.2, i.3);
@@ -220,6 +396,12 @@ mod tests {
                 "/run/a.sock",
                 true,
             ),
+            (
+                "tcp://127.0.0.1:1024",
+                Some(Domain::Tcp),
+                "127.0.0.1:1024",
+                true,
+            ),
             ("vsock:///run/c.sock", None, "", false),
             ("Vsock:///run/c.sock", None, "", false),
             ("unix://@/run/b.sock", None, "", false),
@@ -236,4 +418,77 @@ mod tests {
             }
         }
     }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    #[test]
+    fn test_abstract_autobind() {
+        let (listener, _, addr) = make_socket(("unix://@", VMADDR_CID_ANY)).unwrap();
+        bind(listener, addr.as_ref()).unwrap();
+        listen(listener, 1).unwrap();
+        let name = getsockname::<UnixAddr>(listener).unwrap();
+        let name = String::from_utf8_lossy(name.as_abstract().unwrap()).to_string();
+        assert!(!name.is_empty());
+
+        let client = unsafe { client_connect(&format!("unix://@{name}")) }.unwrap();
+        let conn = accept(listener).unwrap();
+        let peer = getpeername::<UnixAddr>(conn).unwrap();
+        assert!(!peer.as_abstract().unwrap().is_empty());
+        assert!(unsafe { client_connect("unix://@") }.is_err());
+        for fd in [client, conn, listener] {
+            nix::unistd::close(fd).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_tcp_sockaddr() {
+        for (input, family) in [
+            ("tcp://127.0.0.1:1024", AddressFamily::Inet),
+            ("tcp://[::1]:1024", AddressFamily::Inet6),
+        ] {
+            let (fd, domain, addr) = make_socket((input, VMADDR_CID_ANY)).unwrap();
+            assert_eq!(domain, Domain::Tcp);
+            assert_eq!(addr.family(), Some(family));
+            nix::unistd::close(fd).unwrap();
+        }
+        // Host names are not resolved, and the port is required.
+        for input in ["tcp://localhost:1024", "tcp://127.0.0.1"] {
+            assert!(make_socket((input, VMADDR_CID_ANY)).is_err());
+        }
+
+        // Without SO_REUSEPORT, a TCP port is only bound once.
+        let (fd, _) = do_bind("tcp://127.0.0.1:0").unwrap();
+        let port = getsockname::<SockaddrIn>(fd).unwrap().port();
+        assert!(do_bind(&format!("tcp://127.0.0.1:{port}")).is_err());
+        nix::unistd::close(fd).unwrap();
+    }
+
+    #[cfg(all(feature = "async", any(target_os = "linux", target_os = "android")))]
+    #[test]
+    fn test_connect_timeout() {
+        let (listener, _, addr) = make_socket(("unix://@", VMADDR_CID_ANY)).unwrap();
+        bind(listener, addr.as_ref()).unwrap();
+        listen(listener, 0).unwrap();
+        let name = getsockname::<UnixAddr>(listener).unwrap();
+        let name = String::from_utf8_lossy(name.as_abstract().unwrap()).to_string();
+        let address = format!("unix://@{name}");
+
+        // The listener never accepts, its backlog soon fills up.
+        let timeout = Duration::from_millis(50);
+        let mut clients = Vec::new();
+        let e = loop {
+            assert!(clients.len() < 8, "the backlog never filled up");
+            let start = Instant::now();
+            match unsafe { client_connect_timeout(&address, timeout) } {
+                Ok(fd) => clients.push(fd),
+                Err(e) => {
+                    assert!(start.elapsed() >= timeout);
+                    break e;
+                }
+            }
+        };
+        assert!(matches!(e, Error::Socket(e) if e.contains("timed out")));
+        for fd in clients.into_iter().chain([listener]) {
+            nix::unistd::close(fd).unwrap();
+        }
+    }
 }
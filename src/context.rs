@@ -3,8 +3,25 @@
 // SPDX-License-Identifier: Apache-2.0
 //
 
+use crate::error::{Error, Result};
 use crate::proto::KeyValue;
 use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// Suffix of the keys whose values are binary, e.g. `trace-context-bin`.
+///
+/// As in gRPC, binary values travel base64 encoded in the string values of the
+/// metadata, see [`Context::set_bin`] and [`get_bin`], or [`Context::set_message`]
+/// and [`get_message`] for protobuf messages.
+///
+/// The values are not sent raw: `KeyValue.value` is a proto3 `string`, shared
+/// with Go ttrpc, and strings must be valid UTF-8. Go peers reject a message with
+/// an invalid string, so raw bytes would fail the whole call, and a separate
+/// `bytes` field would be dropped by them. Callers still never handle base64.
+pub const BINARY_SUFFIX: &str = "-bin";
+
+const BASE64_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
 
 #[derive(Clone, Default, Debug)]
 pub struct Context {
@@ -26,7 +43,31 @@ pub fn with_metadata(md: HashMap<String, Vec<String>>) -> Context {
     }
 }
 
+/// A call with a deadline, e.g. the `TtrpcContext` of the call a server handles,
+/// see [`Context::with_parent_deadline`].
+pub trait Deadline {
+    /// When the call times out, `None` if it has no timeout.
+    fn deadline(&self) -> Option<Instant>;
+
+    /// The time left until the call times out, `None` if it has no timeout.
+    fn remaining(&self) -> Option<Duration> {
+        self.deadline()
+            .map(|deadline| deadline.saturating_duration_since(Instant::now()))
+    }
+}
+
 impl Context {
+    /// Returns a context for a call made on behalf of `parent`, e.g. by a handler
+    /// calling a downstream service, which times out at the deadline of `parent`
+    /// at the latest. Its timeout is the time left until then, or none if `parent`
+    /// has no deadline.
+    pub fn with_parent_deadline<P: Deadline + ?Sized>(parent: &P) -> Context {
+        Context {
+            timeout_nano: parent.remaining().map_or(0, timeout_nanos),
+            ..Default::default()
+        }
+    }
+
     // appends additional values to the given key.
     pub fn add(&mut self, key: String, value: String) {
         if let Some(ref mut vl) = self.metadata.get_mut(&key) {
@@ -46,6 +87,125 @@ impl Context {
             self.metadata.insert(key.to_lowercase(), value);
         }
     }
+
+    /// Sets the binary values of `key`, like [`Context::set`].
+    ///
+    /// Fails if `key` doesn't end with [`BINARY_SUFFIX`].
+    pub fn set_bin(&mut self, key: String, value: Vec<Vec<u8>>) -> Result<()> {
+        let value = encode_bin(&key, &value)?;
+        self.set(key, value);
+        Ok(())
+    }
+
+    /// Returns the binary values of `key`, see [`get_bin`].
+    pub fn get_bin(&self, key: &str) -> Option<Vec<Vec<u8>>> {
+        get_bin(&self.metadata, key)
+    }
+
+    /// Sets `key` to `message` serialized, e.g. a token or a trace context defined
+    /// in a proto file, see [`Context::set_bin`].
+    ///
+    /// Fails if `key` doesn't end with [`BINARY_SUFFIX`].
+    pub fn set_message<M: protobuf::Message>(&mut self, key: String, message: &M) -> Result<()> {
+        let buf = message
+            .write_to_bytes()
+            .map_err(err_to_others_err!(e, "Serialize metadata error: "))?;
+        self.set_bin(key, vec![buf])
+    }
+
+    /// Returns the message of `key`, see [`get_message`].
+    pub fn get_message<M: protobuf::Message>(&self, key: &str) -> Option<M> {
+        get_message(&self.metadata, key)
+    }
+}
+
+/// Returns when a call received at `now` with a timeout of `timeout_nano` times out.
+pub(crate) fn deadline_after(now: Instant, timeout_nano: i64) -> Option<Instant> {
+    (timeout_nano > 0).then(|| now + Duration::from_nanos(timeout_nano as u64))
+}
+
+/// Returns the timeout of a call which must finish within `remaining`, at least
+/// 1ns as 0 is no timeout at all.
+fn timeout_nanos(remaining: Duration) -> i64 {
+    (remaining.as_nanos().min(i64::MAX as u128) as i64).max(1)
+}
+
+/// Encodes the binary values of `key` as standard base64 without padding, as gRPC
+/// sends them, which peers decode with or without padding.
+///
+/// Fails if `key` doesn't end with [`BINARY_SUFFIX`], in any case.
+pub(crate) fn encode_bin(key: &str, value: &[Vec<u8>]) -> Result<Vec<String>> {
+    if !is_bin(key) {
+        return Err(Error::Others(format!(
+            "binary metadata key {:?} must end with {:?}",
+            key, BINARY_SUFFIX
+        )));
+    }
+    Ok(value.iter().map(|v| encode_base64(v)).collect())
+}
+
+/// Whether the values of `key` are binary. Keys are case insensitive, so is the
+/// suffix.
+fn is_bin(key: &str) -> bool {
+    key.to_lowercase().ends_with(BINARY_SUFFIX)
+}
+
+/// Returns the binary values of `key` in `metadata`, e.g. the metadata of a request
+/// received by a server, or `None` if there is none or one isn't valid base64.
+pub fn get_bin(metadata: &HashMap<String, Vec<String>>, key: &str) -> Option<Vec<Vec<u8>>> {
+    if !is_bin(key) {
+        return None;
+    }
+    metadata
+        .get(&key.to_lowercase())?
+        .iter()
+        .map(|v| decode_base64(v))
+        .collect()
+}
+
+/// Returns the message of `key` in `metadata`, the first one if there are several,
+/// or `None` if there is none or it can't be parsed.
+pub fn get_message<M: protobuf::Message>(
+    metadata: &HashMap<String, Vec<String>>,
+    key: &str,
+) -> Option<M> {
+    let buf = get_bin(metadata, key)?.into_iter().next()?;
+    M::parse_from_bytes(&buf).ok()
+}
+
+/// Encodes `buf` in base64 without padding, as gRPC sends binary metadata.
+fn encode_base64(buf: &[u8]) -> String {
+    let mut s = String::with_capacity((buf.len() * 4 + 2) / 3);
+    for chunk in buf.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, b)| n | u32::from(*b) << (16 - 8 * i));
+        for i in 0..=chunk.len() {
+            s.push(BASE64_ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+        }
+    }
+    s
+}
+
+/// Decodes base64 with or without padding.
+fn decode_base64(s: &str) -> Option<Vec<u8>> {
+    let s = s.trim_end_matches('=');
+    let mut buf = Vec::with_capacity(s.len() * 3 / 4);
+    for chunk in s.as_bytes().chunks(4) {
+        if chunk.len() == 1 {
+            return None;
+        }
+        let mut n = 0u32;
+        for (i, c) in chunk.iter().enumerate() {
+            let v = BASE64_ALPHABET.iter().position(|a| a == c)? as u32;
+            n |= v << (18 - 6 * i);
+        }
+        for i in 0..chunk.len() - 1 {
+            buf.push((n >> (16 - 8 * i)) as u8);
+        }
+    }
+    Some(buf)
 }
 
 pub fn from_pb(kvs: &Vec<KeyValue>) -> HashMap<String, Vec<String>> {
@@ -80,7 +240,9 @@ pub fn to_pb(kvs: HashMap<String, Vec<String>>) -> Vec<KeyValue> {
 #[cfg(test)]
 mod tests {
     use crate::context;
+    use crate::error::Error;
     use crate::proto::KeyValue;
+    use std::time::{Duration, Instant};
 
     #[test]
     fn test_metadata() {
@@ -157,4 +319,78 @@ mod tests {
         assert_eq!(ctx.metadata.len(), 1);
         assert_eq!(ctx.metadata.get("key1"), None);
     }
+
+    #[test]
+    fn test_binary_metadata() {
+        let mut ctx = context::Context::default();
+        let values: Vec<Vec<u8>> = (0..8)
+            .map(|n| (0..n).map(|b: u8| b.wrapping_mul(97)).collect())
+            .collect();
+        ctx.set_bin("token-bin".to_string(), values.clone())
+            .unwrap();
+        assert_eq!(ctx.get_bin("token-bin"), Some(values));
+        assert_eq!(
+            ctx.metadata.get("token-bin").unwrap()[..4],
+            ["", "AA", "AGE", "AGHC"]
+        );
+
+        // Padded values, e.g. from other implementations, are accepted too.
+        let mut md = std::collections::HashMap::new();
+        md.insert("a-bin".to_string(), vec!["AGE=".to_string()]);
+        md.insert("b-bin".to_string(), vec!["A".to_string()]);
+        md.insert("c".to_string(), vec!["AGE".to_string()]);
+        assert_eq!(context::get_bin(&md, "a-bin"), Some(vec![vec![0, 97]]));
+        assert_eq!(context::get_bin(&md, "b-bin"), None);
+        assert_eq!(context::get_bin(&md, "c"), None);
+    }
+
+    #[test]
+    fn test_message_metadata() {
+        let mut ctx = context::Context::default();
+        let kv = KeyValue {
+            key: "trace".to_string(),
+            value: "00f067aa0ba902b7".to_string(),
+            ..Default::default()
+        };
+        ctx.set_message("trace-bin".to_string(), &kv).unwrap();
+        assert_eq!(ctx.get_message::<KeyValue>("trace-bin"), Some(kv));
+        assert_eq!(ctx.get_message::<KeyValue>("span-bin"), None);
+    }
+
+    #[test]
+    fn test_parent_deadline() {
+        struct Parent(Option<Instant>);
+        impl context::Deadline for Parent {
+            fn deadline(&self) -> Option<Instant> {
+                self.0
+            }
+        }
+
+        let deadline = Instant::now() + Duration::from_secs(2);
+        let ctx = context::Context::with_parent_deadline(&Parent(Some(deadline)));
+        assert!(ctx.timeout_nano > 1_000_000_000 && ctx.timeout_nano <= 2_000_000_000);
+        let ctx = context::Context::with_parent_deadline(&Parent(Some(Instant::now())));
+        assert_eq!(ctx.timeout_nano, 1);
+        let ctx = context::Context::with_parent_deadline(&Parent(None));
+        assert_eq!(ctx.timeout_nano, 0);
+    }
+
+    #[test]
+    fn test_binary_metadata_key() {
+        let mut ctx = context::Context::default();
+        assert!(matches!(
+            ctx.set_bin("token".to_string(), vec![vec![1]]),
+            Err(Error::Others(_))
+        ));
+        assert!(matches!(
+            ctx.set_message("token".to_string(), &KeyValue::new()),
+            Err(Error::Others(_))
+        ));
+        assert!(ctx.metadata.is_empty());
+
+        // Keys are lowercased, the suffix may be in any case.
+        ctx.set_bin("Token-BIN".to_string(), vec![vec![1]]).unwrap();
+        assert_eq!(ctx.metadata.get("token-bin").unwrap(), &["AQ"]);
+        assert_eq!(ctx.get_bin("Token-Bin"), Some(vec![vec![1]]));
+    }
 }
@@ -14,7 +14,8 @@
 
 //! Error and Result of ttrpc and relevant functions, macros.
 
-use crate::proto::{Code, Response, Status};
+use crate::proto::{Any, Code, LimitExceeded, Response, Status};
+use protobuf::{Message, MessageFull};
 use std::result;
 use thiserror::Error;
 
@@ -44,10 +45,100 @@ pub enum Error {
     #[error("eof")]
     Eof,
 
+    #[error("ttrpc err: too many requests in flight")]
+    ResourceExhausted,
+
+    #[error("ttrpc err: no answer to keepalive ping")]
+    KeepaliveTimeout,
+
+    /// Writing to the connection failed because the peer closed it, e.g. `EPIPE`
+    /// or `ECONNRESET`.
+    #[error("ttrpc err: connection closed by peer")]
+    PeerClosed,
+
     #[error("ttrpc err: {0}")]
     Others(String),
 }
 
+/// Classification of errors.
+///
+/// | Variant                              | [`Error::code()`]      | connection | transient |
+/// |--------------------------------------|------------------------|------------|-----------|
+/// | `Socket`, `Nix`, `Windows`           | `UNAVAILABLE`          | yes        | yes       |
+/// | `KeepaliveTimeout`, `PeerClosed`     | `UNAVAILABLE`          | yes        | yes       |
+/// | `RpcStatus` with `UNAVAILABLE`, `RESOURCE_EXHAUSTED` or `ABORTED` | status code | no | yes |
+/// | `RpcStatus` with any other code      | status code            | no         | no        |
+/// | `LocalClosed`                        | `FAILED_PRECONDITION`  | no         | no        |
+/// | `RemoteClosed`, `Eof`                | `OUT_OF_RANGE`         | no         | no        |
+/// | `ResourceExhausted`                  | `RESOURCE_EXHAUSTED`   | no         | yes       |
+/// | `Others`                             | `UNKNOWN`              | no         | no        |
+impl Error {
+    /// Returns the status code of the error, see the table above for errors which
+    /// don't carry a [`Status`].
+    pub fn code(&self) -> Code {
+        match self {
+            Error::RpcStatus(s) => s.code(),
+            Error::Socket(_) | Error::KeepaliveTimeout | Error::PeerClosed => Code::UNAVAILABLE,
+            #[cfg(unix)]
+            Error::Nix(_) => Code::UNAVAILABLE,
+            #[cfg(windows)]
+            Error::Windows(_) => Code::UNAVAILABLE,
+            Error::LocalClosed => Code::FAILED_PRECONDITION,
+            Error::RemoteClosed | Error::Eof => Code::OUT_OF_RANGE,
+            Error::ResourceExhausted => Code::RESOURCE_EXHAUSTED,
+            Error::Others(_) => Code::UNKNOWN,
+        }
+    }
+
+    /// Returns `true` if the error was caused by the underlying connection rather
+    /// than by the peer's handling of the call.
+    pub fn is_connection_error(&self) -> bool {
+        match self {
+            Error::Socket(_) | Error::KeepaliveTimeout | Error::PeerClosed => true,
+            #[cfg(unix)]
+            Error::Nix(_) => true,
+            #[cfg(windows)]
+            Error::Windows(_) => true,
+            _ => false,
+        }
+    }
+
+    /// Returns the limit of the server the call exceeded, if the server said so,
+    /// see [`get_limit_status`].
+    pub fn limit_exceeded(&self) -> Option<LimitExceeded> {
+        match self {
+            Error::RpcStatus(status) => limit_exceeded(status),
+            _ => None,
+        }
+    }
+
+    /// Returns the first details of type `T` of the status, see [`details_as`].
+    pub fn details_as<T: MessageFull>(&self) -> Option<T> {
+        match self {
+            Error::RpcStatus(status) => details_as(status),
+            _ => None,
+        }
+    }
+
+    /// Returns `true` if the same call may succeed when it is retried later.
+    pub fn is_transient(&self) -> bool {
+        self.is_connection_error()
+            || matches!(
+                self.code(),
+                Code::UNAVAILABLE | Code::RESOURCE_EXHAUSTED | Code::ABORTED
+            )
+    }
+}
+
+/// Returns the error of a failed write on the connection.
+#[cfg(feature = "async")]
+pub(crate) fn write_error(e: std::io::Error) -> Error {
+    match e.kind() {
+        std::io::ErrorKind::BrokenPipe | std::io::ErrorKind::ConnectionReset => Error::PeerClosed,
+        _ => Error::Socket(e.to_string()),
+    }
+}
+
 impl From<Error> for Response {
     fn from(e: Error) -> Self {
         let status = if let Error::RpcStatus(stat) = e {
@@ -78,6 +169,120 @@ pub fn get_rpc_status(c: Code, msg: impl ToString) -> Error {
     Error::RpcStatus(get_status(c, msg))
 }
 
+/// Builds a [`Status`] carrying details, the structured counterpart of its
+/// message, which clients decode with [`Error::details_as`].
+///
+/// ```
+/// use ttrpc::error::StatusBuilder;
+/// use ttrpc::proto::{Code, KeyValue};
+///
+/// let mut conflict = KeyValue::new();
+/// conflict.key = "owner".to_string();
+/// conflict.value = "sandbox-1".to_string();
+/// let e = StatusBuilder::new(Code::ALREADY_EXISTS, "container exists")
+///     .details(&conflict)
+///     .into_error();
+/// assert_eq!(e.details_as::<KeyValue>().unwrap().value, "sandbox-1");
+/// ```
+#[derive(Clone, Debug)]
+pub struct StatusBuilder {
+    status: Status,
+}
+
+impl StatusBuilder {
+    pub fn new(c: Code, msg: impl ToString) -> StatusBuilder {
+        StatusBuilder {
+            status: get_status(c, msg),
+        }
+    }
+
+    /// Attach `details`, packed the way `google.protobuf.Any` is, so clients in
+    /// other languages can decode them too.
+    pub fn details<T: MessageFull>(mut self, details: &T) -> StatusBuilder {
+        // Writing a message to a vector never fails.
+        if let Ok(value) = details.write_to_bytes() {
+            self.status.details.push(Any {
+                type_url: format!("{}{}", TYPE_URL_PREFIX, T::descriptor().full_name()),
+                value,
+                ..Default::default()
+            });
+        }
+        self
+    }
+
+    pub fn build(self) -> Status {
+        self.status
+    }
+
+    /// Returns the status as the error of a handler.
+    pub fn into_error(self) -> Error {
+        Error::RpcStatus(self.status)
+    }
+}
+
+/// Prefix of the type URLs of the details packed by [`StatusBuilder`].
+pub const TYPE_URL_PREFIX: &str = "type.googleapis.com/";
+
+/// Returns the first details of `status` of type `T`, whatever the prefix of
+/// their type URL, or `None` if there is none or they can't be decoded.
+pub fn details_as<T: MessageFull>(status: &Status) -> Option<T> {
+    let name = T::descriptor().full_name().to_string();
+    status
+        .details
+        .iter()
+        .find(|any| any.type_url.rsplit('/').next() == Some(name.as_str()))
+        .and_then(|any| T::parse_from_bytes(&any.value).ok())
+}
+
+/// Type URL of the [`LimitExceeded`] details of a status.
+pub const LIMIT_EXCEEDED_TYPE_URL: &str = "ttrpc.io/grpc.LimitExceeded";
+
+/// The size of a message, in bytes, see [`crate::proto::MESSAGE_LENGTH_MAX`].
+pub const LIMIT_MESSAGE_SIZE: &str = "message_size";
+/// The size of a request once decoded, in bytes.
+pub const LIMIT_DECODED_SIZE: &str = "decoded_size";
+/// The memory of the requests and responses in progress on the server, in bytes.
+pub const LIMIT_MEMORY: &str = "memory";
+/// The timeout of the call, in nanoseconds.
+pub const LIMIT_DEADLINE: &str = "deadline";
+/// The streams open on the connection.
+pub const LIMIT_STREAMS: &str = "streams";
+/// The requests in progress on the server, or on the connection.
+pub const LIMIT_REQUESTS: &str = "requests";
+
+/// Get ttrpc::Status rejecting a request which exceeded `limit`, with `max` and
+/// `observed` as [`LimitExceeded`] details, so the client can adapt, e.g. split
+/// a payload which is too large.
+pub fn get_limit_status(
+    c: Code,
+    msg: impl ToString,
+    limit: &str,
+    max: u64,
+    observed: u64,
+) -> Status {
+    let mut status = get_status(c, msg);
+    let details = LimitExceeded {
+        limit: limit.to_string(),
+        max,
+        observed,
+        ..Default::default()
+    };
+    // Writing a message to a vector never fails.
+    if let Ok(value) = details.write_to_bytes() {
+        status.details.push(Any {
+            type_url: LIMIT_EXCEEDED_TYPE_URL.to_string(),
+            value,
+            ..Default::default()
+        });
+    }
+    status
+}
+
+/// Returns the limit `status` reports was exceeded, see [`get_limit_status`].
+pub fn limit_exceeded(status: &Status) -> Option<LimitExceeded> {
+    details_as(status)
+}
+
 const SOCK_DICONNECTED: &str = "socket disconnected";
 pub fn sock_error_msg(size: usize, msg: String) -> Error {
     if size == 0 {
@@ -97,6 +302,87 @@ macro_rules! err_to_others_err {
 #[macro_export]
 macro_rules! err_to_others {
     ($e: ident, $s: expr) => {
-        |$e| ::ttrpc::Error::Others($s.to_string() + &$e.to_string())
+        |$e| $crate::Error::Others($s.to_string() + &$e.to_string())
     };
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_classification() {
+        let e = Error::Socket("socket disconnected".to_string());
+        assert_eq!(e.code(), Code::UNAVAILABLE);
+        assert!(e.is_connection_error());
+        assert!(e.is_transient());
+
+        let e = get_rpc_status(Code::RESOURCE_EXHAUSTED, "too many requests");
+        assert_eq!(e.code(), Code::RESOURCE_EXHAUSTED);
+        assert!(!e.is_connection_error());
+        assert!(e.is_transient());
+
+        let e = get_rpc_status(Code::NOT_FOUND, "no such container");
+        assert_eq!(e.code(), Code::NOT_FOUND);
+        assert!(!e.is_transient());
+
+        let e = Error::Others("decode failed".to_string());
+        assert_eq!(e.code(), Code::UNKNOWN);
+        assert!(!e.is_connection_error());
+        assert!(!e.is_transient());
+
+        assert_eq!(Error::Eof.code(), Code::OUT_OF_RANGE);
+        assert!(!Error::LocalClosed.is_transient());
+        assert!(Error::ResourceExhausted.is_transient());
+        assert!(Error::KeepaliveTimeout.is_connection_error());
+        assert!(Error::PeerClosed.is_transient());
+    }
+
+    #[test]
+    fn test_limit_status() {
+        let status = get_limit_status(
+            Code::INVALID_ARGUMENT,
+            "too large",
+            LIMIT_MESSAGE_SIZE,
+            4 << 20,
+            5 << 20,
+        );
+        let e = Error::RpcStatus(status);
+        let limit = e.limit_exceeded().unwrap();
+        assert_eq!(limit.limit, LIMIT_MESSAGE_SIZE);
+        assert_eq!((limit.max, limit.observed), (4 << 20, 5 << 20));
+        assert_eq!(get_rpc_status(Code::NOT_FOUND, "").limit_exceeded(), None);
+        assert_eq!(Error::Eof.limit_exceeded(), None);
+    }
+
+    #[test]
+    fn test_details() {
+        let limit = LimitExceeded {
+            limit: LIMIT_STREAMS.to_string(),
+            max: 16,
+            ..Default::default()
+        };
+        let status = StatusBuilder::new(Code::RESOURCE_EXHAUSTED, "too many streams")
+            .details(&Status::new())
+            .details(&limit)
+            .build();
+        assert_eq!(
+            status.details[1].type_url,
+            "type.googleapis.com/grpc.LimitExceeded"
+        );
+        let e = Error::RpcStatus(status);
+        assert_eq!(e.details_as::<LimitExceeded>(), Some(limit));
+        assert_eq!(e.limit_exceeded().unwrap().max, 16);
+        assert_eq!(e.details_as::<Response>(), None);
+        assert_eq!(Error::Eof.details_as::<Status>(), None);
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn test_write_error() {
+        let e = write_error(std::io::ErrorKind::BrokenPipe.into());
+        assert!(matches!(e, Error::PeerClosed), "{:?}", e);
+        let e = write_error(std::io::ErrorKind::WouldBlock.into());
+        assert!(matches!(e, Error::Socket(_)), "{:?}", e);
+    }
+}
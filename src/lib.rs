@@ -24,18 +24,30 @@
 //!
 //! - `async`: Enables async server and client.
 //! - `sync`: Enables traditional sync server and client (default enabled).
+//! - `tls`: Enables TLS on the connections of async clients.
+//! - `tls-keylog`: Enables logging the TLS secrets of async clients, to decrypt
+//!   captured traffic while debugging.
+//! - `capture`: Enables recording the messages of async connections into pcapng
+//!   files, for debugging with Wireshark.
+//! - `config`: Enables loading the options of async servers from TOML or JSON files
+//!   and the environment, see `r#async::ServerConfig`.
 //!
 //! # Socket address
 //!
-//! For Linux distributions, ttrpc-rust supports three types of socket:
+//! For Linux distributions, ttrpc-rust supports five types of socket:
 //!
 //! - `unix:///run/some.sock`: Normal Unix domain socket.
 //! - `unix://@/run/some.sock`: Abstract Unix domain socket.
-//! - `vsock://vsock://8:1024`: [vsock](https://man7.org/linux/man-pages/man7/vsock.7.html).
+//! - `vsock://8:1024`: [vsock](https://man7.org/linux/man-pages/man7/vsock.7.html).
+//! - `unixpacket:///run/some.sock`: Unix domain socket of type `SOCK_SEQPACKET`, or
+//!   `unixpacket://@/run/some.sock` for an abstract one, for async clients only.
+//! - `tcp://127.0.0.1:1024`: TCP socket, e.g. across network namespaces in tests. The
+//!   address must be an IP, host names are not resolved.
 //!
-//! For mscOS, ttrpc-rust **only** supports normal Unix domain socket:
+//! For mscOS, ttrpc-rust **only** supports normal Unix domain socket and TCP socket:
 //!
 //! - `unix:///run/some.sock`: Normal Unix domain socket.
+//! - `tcp://127.0.0.1:1024`: TCP socket.
 //!
 
 #![cfg_attr(docsrs, feature(doc_cfg))]
@@ -51,11 +63,15 @@ mod common;
 #[macro_use]
 mod macros;
 
+pub mod address;
 pub mod context;
+pub mod registry;
 
 pub mod proto;
 #[doc(inline)]
 pub use self::proto::{Code, MessageHeader, Request, Response, Status};
+#[doc(inline)]
+pub use self::registry::{MethodInfo, MethodType};
 
 #[doc(inline)]
 pub use crate::error::{get_status, Error, Result};
@@ -75,4 +91,5 @@ cfg_async! {
     pub mod asynchronous;
     #[doc(hidden)]
     pub use asynchronous as r#async;
+    pub use asynchronous::testing;
 }
@@ -10,10 +10,17 @@ mod compiled {
 }
 pub use compiled::ttrpc::*;
 
+/// Messages of the built-in admin service, see `Server::admin_service`.
+pub mod admin {
+    pub use super::compiled::admin::*;
+}
+
 use byteorder::{BigEndian, ByteOrder};
 use protobuf::{CodedInputStream, CodedOutputStream};
 
-use crate::error::{get_rpc_status, Error, Result as TtResult};
+#[cfg(feature = "async")]
+use crate::error::write_error;
+use crate::error::{get_limit_status, Error, Result as TtResult, LIMIT_MESSAGE_SIZE};
 
 pub const MESSAGE_HEADER_LENGTH: usize = 10;
 pub const MESSAGE_LENGTH_MAX: usize = 4 << 20;
@@ -22,10 +29,24 @@ pub const DEFAULT_PAGE_SIZE: usize = 4 << 10;
 pub const MESSAGE_TYPE_REQUEST: u8 = 0x1;
 pub const MESSAGE_TYPE_RESPONSE: u8 = 0x2;
 pub const MESSAGE_TYPE_DATA: u8 = 0x3;
+/// Asks the server to abandon the call of the stream, which has no payload.
+pub const MESSAGE_TYPE_CANCEL: u8 = 0x4;
+/// Asks the peer for a [`MESSAGE_TYPE_PONG`], outside of any stream.
+pub const MESSAGE_TYPE_PING: u8 = 0x5;
+pub const MESSAGE_TYPE_PONG: u8 = 0x6;
+/// Tells the client the server is shutting down and takes no new calls, outside
+/// of any stream.
+pub const MESSAGE_TYPE_GOAWAY: u8 = 0x7;
 
 pub const FLAG_REMOTE_CLOSED: u8 = 0x1;
 pub const FLAG_REMOTE_OPEN: u8 = 0x2;
 pub const FLAG_NO_DATA: u8 = 0x4;
+/// Asks the peer to acknowledge the data message once it has been received.
+pub const FLAG_ACK_REQUIRED: u8 = 0x8;
+/// Acknowledges a data message sent with [`FLAG_ACK_REQUIRED`].
+pub const FLAG_ACK: u8 = 0x10;
+/// The payload of the data message is compressed.
+pub const FLAG_COMPRESSED: u8 = 0x20;
 
 pub(crate) fn check_oversize(len: usize, return_rpc_error: bool) -> TtResult<()> {
     if len > MESSAGE_LENGTH_MAX {
@@ -34,7 +55,13 @@ pub(crate) fn check_oversize(len: usize, return_rpc_error: bool) -> TtResult<()>
             len, MESSAGE_LENGTH_MAX
         );
         let e = if return_rpc_error {
-            get_rpc_status(Code::INVALID_ARGUMENT, msg)
+            Error::RpcStatus(get_limit_status(
+                Code::INVALID_ARGUMENT,
+                msg,
+                LIMIT_MESSAGE_SIZE,
+                MESSAGE_LENGTH_MAX as u64,
+                len as u64,
+            ))
         } else {
             Error::Others(msg)
         };
@@ -136,6 +163,18 @@ impl MessageHeader {
         }
     }
 
+    /// Creates a cancel MessageHeader from stream_id.
+    ///
+    /// Use the MESSAGE_TYPE_CANCEL message type, and default flags 0.
+    pub fn new_cancel(stream_id: u32) -> Self {
+        Self {
+            length: 0,
+            stream_id,
+            type_: MESSAGE_TYPE_CANCEL,
+            flags: 0,
+        }
+    }
+
     /// Set the stream_id of message using the given value.
     pub fn set_stream_id(&mut self, stream_id: u32) {
         self.stream_id = stream_id;
@@ -217,11 +256,8 @@ impl GenMessage {
         self.header
             .write_to(&mut writer)
             .await
-            .map_err(|e| Error::Socket(e.to_string()))?;
-        writer
-            .write_all(&self.payload)
-            .await
-            .map_err(|e| Error::Socket(e.to_string()))?;
+            .map_err(write_error)?;
+        writer.write_all(&self.payload).await.map_err(write_error)?;
         Ok(())
     }
 
@@ -563,4 +599,26 @@ mod tests {
         dmsg.write_to(&mut io).await.unwrap();
         assert_eq!(&dbuf, &buf[..MESSAGE_HEADER_LENGTH + TEST_PAYLOAD_LEN]);
     }
+
+    #[cfg(feature = "async")]
+    #[tokio::test]
+    async fn async_corrupted_message() {
+        let mut valid = Vec::from(PROTOBUF_MESSAGE_HEADER);
+        valid.extend_from_slice(&PROTOBUF_REQUEST);
+
+        // Corrupted and truncated messages are rejected with an error, never a panic.
+        let mut seed = 0x2545_f491_4f6c_dd1du64;
+        for _ in 0..10_000 {
+            let mut buf = valid.clone();
+            for _ in 0..(seed % 4 + 1) {
+                seed ^= seed << 13;
+                seed ^= seed >> 7;
+                seed ^= seed << 17;
+                let i = seed as usize % buf.len();
+                buf[i] = (seed >> 32) as u8;
+            }
+            buf.truncate(MESSAGE_HEADER_LENGTH + seed as usize % (TEST_PAYLOAD_LEN + 1));
+            let _ = Message::<Request>::read_from(&*buf).await;
+        }
+    }
 }
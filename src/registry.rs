@@ -0,0 +1,36 @@
+// Copyright (c) 2023 Ant Group
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Description of the methods of generated services.
+//!
+//! Every module generated by `ttrpc-compiler` lists the methods of its services
+//! in a `METHODS` constant, e.g. to write authorization policies against the
+//! generated APIs, or to check that a policy doesn't name unknown methods.
+//!
+//! The schema digests of the methods let peers generated from different revisions
+//! of their protos find out when connecting, see
+//! [`ClientBuilder::schema_digests`](crate::r#async::ClientBuilder::schema_digests)
+//! and [`Server::check_schema_digests`](crate::r#async::Server::check_schema_digests).
+
+/// How the messages of a method are exchanged.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum MethodType {
+    Unary,
+    ClientStreaming,
+    ServerStreaming,
+    Duplex,
+}
+
+/// A method of a generated service.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct MethodInfo {
+    /// The fully qualified name of the service, e.g. `grpc.Health`.
+    pub service: &'static str,
+    pub method: &'static str,
+    pub method_type: MethodType,
+    /// A digest of the descriptors of the request and response messages, and of
+    /// the messages and enums they refer to, computed by `ttrpc-compiler`.
+    pub schema_digest: u64,
+}
@@ -57,6 +57,7 @@ fn write_count(conn: &PipeConnection, buf: &[u8], count: usize) -> Result<usize>
                     break;
                 }
             }
+            Err(Error::PeerClosed) => return Err(Error::PeerClosed),
             Err(e) => {
                 return Err(Error::Socket(e.to_string()));
             }
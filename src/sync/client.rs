@@ -19,37 +19,65 @@ use std::os::unix::io::RawFd;
 
 use protobuf::Message;
 use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc;
 use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time::Duration;
 
-use crate::error::{Error, Result};
+use crate::error::{get_rpc_status, Error, Result};
 use crate::proto::{
-    check_oversize, Code, Codec, MessageHeader, Request, Response, MESSAGE_TYPE_RESPONSE,
+    check_oversize, Code, Codec, MessageHeader, Request, Response, FLAG_NO_DATA,
+    FLAG_REMOTE_CLOSED, FLAG_REMOTE_OPEN, MESSAGE_TYPE_DATA, MESSAGE_TYPE_RESPONSE,
 };
 use crate::sync::channel::{read_message, write_message};
+use crate::sync::stream::StreamInner;
 use crate::sync::sys::ClientConnection;
+use crate::sync::thread::ThreadConfig;
 
 #[cfg(windows)]
 use super::sys::PipeConnection;
 
-type Sender = mpsc::Sender<(Vec<u8>, mpsc::SyncSender<Result<Vec<u8>>>)>;
-type Receiver = mpsc::Receiver<(Vec<u8>, mpsc::SyncSender<Result<Vec<u8>>>)>;
-type ReciverMap = Arc<Mutex<HashMap<u32, mpsc::SyncSender<Result<Vec<u8>>>>>>;
+pub(crate) type ResultSender = mpsc::Sender<Result<(MessageHeader, Vec<u8>)>>;
+pub(crate) type ResultReceiver = mpsc::Receiver<Result<(MessageHeader, Vec<u8>)>>;
+/// A message to write, with where the messages of its stream go if it starts a call.
+pub(crate) type Sender = mpsc::Sender<(MessageHeader, Vec<u8>, Option<ResultSender>)>;
+type Receiver = mpsc::Receiver<(MessageHeader, Vec<u8>, Option<ResultSender>)>;
+type ReciverMap = Arc<Mutex<HashMap<u32, ResultSender>>>;
 
 /// A ttrpc Client (sync).
+///
+/// Clones share the connection, and the connection redialed once it was lost by a
+/// client with a [reconnect policy](ClientBuilder::reconnect).
 #[derive(Clone)]
 pub struct Client {
-    _connection: Arc<ClientConnection>,
+    connection: Arc<Mutex<Connection>>,
+    // Taken while sending the request of a call, so the stream ids increase on
+    // the wire.
+    next_stream_id: Arc<Mutex<u32>>,
+    redial: Option<Arc<Redial>>,
+}
+
+/// The connection of a client and the threads writing and reading it.
+struct Connection {
+    conn: Arc<ClientConnection>,
     sender_tx: Sender,
+    // Set by the threads once the connection failed.
+    lost: Arc<AtomicBool>,
+}
+
+/// How a client dials its server again.
+struct Redial {
+    sockaddr: String,
+    threads: ThreadConfig,
+    policy: ReconnectPolicy,
 }
 
 impl Client {
     pub fn connect(sockaddr: &str) -> Result<Client> {
         let conn = ClientConnection::client_connect(sockaddr)?;
 
-        Self::new_client(conn)
+        Self::new_client(conn, &ThreadConfig::default())
     }
 
     #[cfg(unix)]
@@ -57,42 +85,165 @@ impl Client {
     pub fn new(fd: RawFd) -> Result<Client> {
         let conn = ClientConnection::new(fd);
 
-        Self::new_client(conn)
+        Self::new_client(conn, &ThreadConfig::default())
+    }
+
+    fn new_client(pipe_client: ClientConnection, threads: &ThreadConfig) -> Result<Client> {
+        Ok(Client {
+            connection: Arc::new(Mutex::new(Connection::new(pipe_client, threads)?)),
+            next_stream_id: Arc::new(Mutex::new(1)),
+            redial: None,
+        })
+    }
+
+    /// Sends the request of a new call, returns its stream id and where the
+    /// messages of its stream are received, with the connection it is made on.
+    fn start_call(
+        &self,
+        mut mh: MessageHeader,
+        buf: Vec<u8>,
+    ) -> Result<(u32, ResultReceiver, Sender, Arc<ClientConnection>)> {
+        let (tx, rx) = mpsc::channel();
+        let mut next_stream_id = self.next_stream_id.lock().unwrap();
+        let (sender_tx, conn) = self.current()?;
+        let stream_id = *next_stream_id;
+        mh.set_stream_id(stream_id);
+        sender_tx
+            .send((mh, buf, Some(tx)))
+            .map_err(err_to_others_err!(e, "Send packet to sender error "))?;
+        *next_stream_id += 2;
+        Ok((stream_id, rx, sender_tx, conn))
     }
 
-    fn new_client(pipe_client: ClientConnection) -> Result<Client> {
+    /// Returns the connection to make calls on, redialed first if it was lost.
+    fn current(&self) -> Result<(Sender, Arc<ClientConnection>)> {
+        let mut connection = self.connection.lock().unwrap();
+        if let Some(redial) = self.redial.as_ref() {
+            if connection.lost.load(Ordering::Acquire) {
+                *connection = redial.dial()?;
+            }
+        }
+        Ok((connection.sender_tx.clone(), connection.conn.clone()))
+    }
+
+    /// Waits for the response of a call, at most `timeout_nano` if it is not 0.
+    fn recv_response(rx: &ResultReceiver, timeout_nano: i64) -> Result<Vec<u8>> {
+        let result = if timeout_nano == 0 {
+            rx.recv().map_err(err_to_others_err!(
+                e,
+                "Receive packet from Receiver error: "
+            ))?
+        } else {
+            rx.recv_timeout(Duration::from_nanos(timeout_nano as u64))
+                .map_err(err_to_others_err!(
+                    e,
+                    "Receive packet from Receiver timeout: "
+                ))?
+        };
+        result.map(|(_, buf)| buf)
+    }
+
+    pub fn request(&self, req: Request) -> Result<Response> {
+        check_oversize(req.compute_size() as usize, false)?;
+
+        let buf = req.encode().map_err(err_to_others_err!(e, ""))?;
+        // Notice: pure client problem can't be rpc error
+
+        let mh = MessageHeader::new_request(0, buf.len() as u32);
+        let retry = self.redial.as_ref().map(|_| buf.clone());
+        let (_, rx, _, _) = self.start_call(mh, buf)?;
+
+        let buf = match (Self::recv_response(&rx, req.timeout_nano), retry) {
+            // The request could not be written as the connection was lost, so the
+            // server never handled it: make it again on a new connection.
+            (Err(Error::PeerClosed), Some(buf)) => {
+                let (_, rx, _, _) = self.start_call(mh, buf)?;
+                Self::recv_response(&rx, req.timeout_nano)?
+            }
+            (res, _) => res?,
+        };
+        let res = Response::decode(buf).map_err(err_to_others_err!(e, "Unpack response error "))?;
+
+        let status = res.status();
+        if status.code() != Code::OK {
+            return Err(Error::RpcStatus((*status).clone()));
+        }
+
+        Ok(res)
+    }
+
+    /// Starts a call of a streaming method, see
+    /// [`ClientStreamSender`](crate::sync::ClientStreamSender),
+    /// [`ClientStreamReceiver`](crate::sync::ClientStreamReceiver) and
+    /// [`ClientStream`](crate::sync::ClientStream).
+    pub fn new_stream(
+        &self,
+        req: Request,
+        streaming_client: bool,
+        streaming_server: bool,
+    ) -> Result<StreamInner> {
+        check_oversize(req.compute_size() as usize, false)?;
+        if streaming_client && !req.payload.is_empty() {
+            return Err(get_rpc_status(
+                Code::INVALID_ARGUMENT,
+                "Creating a ClientStream and sending payload at the same time is not allowed",
+            ));
+        }
+
+        let buf = req.encode().map_err(err_to_others_err!(e, ""))?;
+        let mut mh = MessageHeader::new_request(0, buf.len() as u32);
+        if streaming_client {
+            mh.add_flags(FLAG_REMOTE_OPEN | FLAG_NO_DATA);
+        } else {
+            mh.add_flags(FLAG_REMOTE_CLOSED);
+        }
+        let (stream_id, rx, sender_tx, conn) = self.start_call(mh, buf)?;
+
+        Ok(StreamInner::new(
+            stream_id,
+            sender_tx,
+            rx,
+            streaming_client,
+            streaming_server,
+            conn,
+        ))
+    }
+}
+
+impl Connection {
+    fn new(pipe_client: ClientConnection, threads: &ThreadConfig) -> Result<Connection> {
         let client = Arc::new(pipe_client);
         let weak_client = Arc::downgrade(&client);
         let (sender_tx, rx): (Sender, Receiver) = mpsc::channel();
         let recver_map_orig = Arc::new(Mutex::new(HashMap::new()));
+        let lost = Arc::new(AtomicBool::new(false));
 
         let receiver_map = recver_map_orig.clone();
         let connection = Arc::new(client.get_pipe_connection()?);
         let sender_client = connection.clone();
+        let sender_lost = lost.clone();
 
         //Sender
-        thread::spawn(move || {
-            let mut stream_id: u32 = 1;
-            for (buf, recver_tx) in rx.iter() {
-                let current_stream_id = stream_id;
-                stream_id += 2;
-                //Put current_stream_id and recver_tx to recver_map
-                {
+        threads.spawn(move || {
+            for (mh, buf, recver_tx) in rx.iter() {
+                let stream_id = mh.stream_id;
+                //Put stream_id and recver_tx to recver_map
+                if let Some(recver_tx) = recver_tx {
                     let mut map = receiver_map.lock().unwrap();
-                    map.insert(current_stream_id, recver_tx.clone());
+                    map.insert(stream_id, recver_tx);
                 }
-                let mut mh = MessageHeader::new_request(0, buf.len() as u32);
-                mh.set_stream_id(current_stream_id);
 
                 if let Err(e) = write_message(&sender_client, mh, buf) {
-                    //Remove current_stream_id and recver_tx to recver_map
-                    {
-                        let mut map = receiver_map.lock().unwrap();
-                        map.remove(&current_stream_id);
+                    if e.is_connection_error() {
+                        sender_lost.store(true, Ordering::Release);
+                    }
+                    //Remove stream_id and recver_tx from recver_map, the call failed
+                    let recver_tx = receiver_map.lock().unwrap().remove(&stream_id);
+                    if let Some(recver_tx) = recver_tx {
+                        recver_tx
+                            .send(Err(e))
+                            .unwrap_or_else(|_e| error!("The request has returned"));
                     }
-                    recver_tx
-                        .send(Err(e))
-                        .unwrap_or_else(|_e| error!("The request has returned"));
                 }
             }
             trace!("Sender quit");
@@ -104,7 +255,8 @@ impl Client {
         //ClientConnection's drop will be not call until the thread finished. It means if all the external references are finished,
         //this thread should be release.
         let receiver_client = weak_client.clone();
-        thread::spawn(move || {
+        let receiver_lost = lost.clone();
+        threads.spawn(move || {
             loop {
                 //The count of ClientConnection's Arc will be add one , and back to original value when this code ends. 
                 if let Some(receiver_client) = receiver_client.upgrade(){
@@ -129,6 +281,7 @@ impl Client {
                     Err(x) => match x {
                         Error::Socket(y) => {
                             trace!("Socket error {}", y);
+                            receiver_lost.store(true, Ordering::Release);
                             let mut map = recver_map_orig.lock().unwrap();
                             for (_, recver_tx) in map.iter_mut() {
                                 recver_tx
@@ -151,45 +304,152 @@ impl Client {
             trace!("Receiver quit");
         });
 
-        Ok(Client {
-            _connection: client,
+        Ok(Connection {
+            conn: client,
             sender_tx,
+            lost,
         })
     }
-    pub fn request(&self, req: Request) -> Result<Response> {
-        check_oversize(req.compute_size() as usize, false)?;
+}
 
-        let buf = req.encode().map_err(err_to_others_err!(e, ""))?;
-        // Notice: pure client problem can't be rpc error
+impl Redial {
+    /// Dials the server until it accepts the connection or the policy gives up,
+    /// returning the error of the last dial then.
+    fn dial(&self) -> Result<Connection> {
+        let mut failures = 0;
+        loop {
+            let res = ClientConnection::client_connect(&self.sockaddr)
+                .and_then(|conn| Connection::new(conn, &self.threads));
+            match res {
+                Ok(connection) => {
+                    debug!("Reconnected to {}", self.sockaddr);
+                    return Ok(connection);
+                }
+                Err(e) => {
+                    failures += 1;
+                    if self.policy.gave_up(failures) {
+                        return Err(e);
+                    }
+                    warn!("Failed to reconnect to {}: {:?}", self.sockaddr, e);
+                    thread::sleep(self.policy.delay(failures));
+                }
+            }
+        }
+    }
+}
 
-        let (tx, rx) = mpsc::sync_channel(0);
+/// How a client redials once its connection is lost, see
+/// [`ClientBuilder::reconnect`].
+#[derive(Clone, Debug)]
+pub struct ReconnectPolicy {
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    max_attempts: Option<u32>,
+}
 
-        self.sender_tx
-            .send((buf, tx))
-            .map_err(err_to_others_err!(e, "Send packet to sender error "))?;
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        ReconnectPolicy {
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(10),
+            max_attempts: None,
+        }
+    }
+}
 
-        let result = if req.timeout_nano == 0 {
-            rx.recv().map_err(err_to_others_err!(
-                e,
-                "Receive packet from Receiver error: "
-            ))?
-        } else {
-            rx.recv_timeout(Duration::from_nanos(req.timeout_nano as u64))
-                .map_err(err_to_others_err!(
-                    e,
-                    "Receive packet from Receiver timeout: "
-                ))?
-        };
+impl ReconnectPolicy {
+    pub fn new() -> ReconnectPolicy {
+        ReconnectPolicy::default()
+    }
 
-        let buf = result?;
-        let res = Response::decode(buf).map_err(err_to_others_err!(e, "Unpack response error "))?;
+    /// Wait `initial` before redialing again, doubling the wait up to `max` while
+    /// dialing keeps failing. 100ms and 10s by default.
+    pub fn backoff(mut self, initial: Duration, max: Duration) -> ReconnectPolicy {
+        self.initial_backoff = initial;
+        self.max_backoff = max.max(initial);
+        self
+    }
 
-        let status = res.status();
-        if status.code() != Code::OK {
-            return Err(Error::RpcStatus((*status).clone()));
+    /// Give up after `attempts` dials failed in a row, failing the call with the
+    /// error of the last one. The next call dials again. Unlimited by default.
+    pub fn max_attempts(mut self, attempts: u32) -> ReconnectPolicy {
+        self.max_attempts = Some(attempts);
+        self
+    }
+
+    fn gave_up(&self, failures: u32) -> bool {
+        matches!(self.max_attempts, Some(max) if failures >= max)
+    }
+
+    /// The wait before the next dial, after `failures` dials failed in a row.
+    fn delay(&self, failures: u32) -> Duration {
+        let exp = failures.saturating_sub(1).min(31);
+        self.initial_backoff
+            .checked_mul(1 << exp)
+            .map_or(self.max_backoff, |delay| delay.min(self.max_backoff))
+    }
+}
+
+/// Builder of a [`Client`] connected to a socket address (sync).
+pub struct ClientBuilder {
+    sockaddr: String,
+    threads: ThreadConfig,
+    reconnect: Option<ReconnectPolicy>,
+}
+
+impl ClientBuilder {
+    pub fn new(sockaddr: &str) -> ClientBuilder {
+        ClientBuilder {
+            sockaddr: sockaddr.to_string(),
+            threads: ThreadConfig::default(),
+            reconnect: None,
         }
+    }
 
-        Ok(res)
+    /// Name the threads which write requests and read responses
+    /// `{prefix}-{index}`. They are unnamed by default.
+    pub fn thread_name_prefix(mut self, prefix: &str) -> ClientBuilder {
+        self.threads.name_prefix = Some(prefix.to_string());
+        self
+    }
+
+    /// Set the stack size of the threads of the client in bytes. Defaults to the
+    /// stack size of [`std::thread`].
+    pub fn thread_stack_size(mut self, size: usize) -> ClientBuilder {
+        self.threads.stack_size = Some(size);
+        self
+    }
+
+    /// Redial the server with `policy` once the connection was lost, e.g. on
+    /// `ECONNRESET` or `EPIPE`, instead of failing every later call. The client and
+    /// its clones keep working on the new connection.
+    ///
+    /// The connection is redialed by the next call, which blocks meanwhile. The
+    /// calls in progress when the connection was lost fail, but for unary calls
+    /// whose request could not be written, which are made again on the new
+    /// connection. Clients don't reconnect by default.
+    pub fn reconnect(mut self, policy: ReconnectPolicy) -> ClientBuilder {
+        self.reconnect = Some(policy);
+        self
+    }
+
+    pub fn build(self) -> Result<Client> {
+        let conn = ClientConnection::client_connect(&self.sockaddr)?;
+
+        let mut client = Client::new_client(conn, &self.threads)?;
+        let ClientBuilder {
+            sockaddr,
+            threads,
+            reconnect,
+        } = self;
+        client.redial = reconnect.map(|policy| {
+            Arc::new(Redial {
+                sockaddr,
+                threads,
+                policy,
+            })
+        });
+        Ok(client)
     }
 }
 
@@ -212,29 +472,129 @@ impl Drop for PipeConnection {
     }
 }
 
-/// Transfer the response
+/// Transfer the response, or the data of a stream
 fn trans_resp(recver_map_orig: ReciverMap, mh: MessageHeader, buf: Result<Vec<u8>>) {
     let mut map = recver_map_orig.lock().unwrap();
-    let recver_tx = match map.get(&mh.stream_id) {
+    let stream_id = mh.stream_id;
+    let recver_tx = match map.get(&stream_id) {
         Some(tx) => tx,
         None => {
             debug!("Recver got unknown packet {:?} {:?}", mh, buf);
             return;
         }
     };
-    if mh.type_ != MESSAGE_TYPE_RESPONSE {
-        recver_tx
-            .send(Err(Error::Others(format!(
-                "Recver got malformed packet {:?} {:?}",
-                mh, buf
-            ))))
-            .unwrap_or_else(|_e| error!("The request has returned"));
-        return;
-    }
+    // The stream is over once the server responded or closed it.
+    let finished = match mh.type_ {
+        MESSAGE_TYPE_RESPONSE => true,
+        MESSAGE_TYPE_DATA => (mh.flags & FLAG_REMOTE_CLOSED) == FLAG_REMOTE_CLOSED,
+        _ => {
+            recver_tx
+                .send(Err(Error::Others(format!(
+                    "Recver got malformed packet {:?} {:?}",
+                    mh, buf
+                ))))
+                .unwrap_or_else(|_e| error!("The request has returned"));
+            return;
+        }
+    };
 
     recver_tx
-        .send(buf)
+        .send(buf.map(|buf| (mh, buf)))
         .unwrap_or_else(|_e| error!("The request has returned"));
 
-    map.remove(&mh.stream_id);
+    if finished {
+        map.remove(&stream_id);
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    use std::os::unix::io::IntoRawFd;
+    use std::os::unix::net::UnixListener;
+    use std::path::Path;
+
+    use crate::sync::{response_to_channel, MethodHandler, Server, TtrpcContext};
+
+    struct Echo;
+
+    impl MethodHandler for Echo {
+        fn handler(&self, ctx: TtrpcContext, req: Request) -> Result<()> {
+            let res = Response {
+                payload: req.payload,
+                ..Default::default()
+            };
+            response_to_channel(ctx.mh.stream_id, res, ctx.res_tx)
+        }
+    }
+
+    fn start_server(path: &Path) -> Server {
+        let _ = std::fs::remove_file(path);
+        let listener = UnixListener::bind(path).unwrap();
+        let mut methods: HashMap<String, Box<dyn MethodHandler + Send + Sync>> = HashMap::new();
+        methods.insert("/test.Test/Echo".to_string(), Box::new(Echo));
+        let mut server = Server::new()
+            .add_listener(listener.into_raw_fd())
+            .unwrap()
+            .register_service(methods);
+        server.start().unwrap();
+        server
+    }
+
+    fn request() -> Request {
+        Request {
+            service: "test.Test".to_string(),
+            method: "Echo".to_string(),
+            payload: b"ping".to_vec(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_reconnect() {
+        let path =
+            std::env::temp_dir().join(format!("ttrpc-sync-reconnect-{}.sock", std::process::id()));
+        let server = start_server(&path);
+        let backoff = Duration::from_millis(10);
+        let client = ClientBuilder::new(&format!("unix://{}", path.display()))
+            .reconnect(ReconnectPolicy::new().backoff(backoff, backoff))
+            .build()
+            .unwrap();
+        let clone = client.clone();
+        assert_eq!(client.request(request()).unwrap().payload, b"ping");
+
+        // A new server on the same socket, the connection to the old one is lost.
+        server.shutdown();
+        let server = start_server(&path);
+
+        // The calls racing the client noticing the lost connection may fail.
+        let mut answered = false;
+        for _ in 0..100 {
+            if let Ok(res) = clone.request(request()) {
+                assert_eq!(res.payload, b"ping");
+                answered = true;
+                break;
+            }
+            thread::sleep(backoff);
+        }
+        assert!(answered, "the client didn't reconnect");
+        assert_eq!(client.request(request()).unwrap().payload, b"ping");
+
+        server.shutdown();
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_delay() {
+        let policy = ReconnectPolicy::new()
+            .backoff(Duration::from_millis(10), Duration::from_millis(50))
+            .max_attempts(3);
+        assert_eq!(policy.delay(1), Duration::from_millis(10));
+        assert_eq!(policy.delay(2), Duration::from_millis(20));
+        assert_eq!(policy.delay(4), Duration::from_millis(50));
+        assert_eq!(policy.delay(100), Duration::from_millis(50));
+        assert!(!policy.gave_up(2));
+        assert!(policy.gave_up(3));
+    }
 }
@@ -0,0 +1,246 @@
+// Copyright (c) 2023 Ant Group
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Event-loop mode of the sync server, see [`Server::set_event_loop`].
+//!
+//! [`Server::set_event_loop`]: crate::sync::Server::set_event_loop
+
+use std::os::unix::io::RawFd;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::channel;
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+
+use nix::poll::{poll, PollFd, PollFlags};
+use nix::sys::socket::{socketpair, AddressFamily, SockType};
+
+#[cfg(target_os = "macos")]
+use crate::common::set_fd_close_exec;
+use crate::common::SOCK_CLOEXEC;
+use crate::error::{Error, Result};
+use crate::proto::MessageHeader;
+use crate::sync::channel::{read_message, write_message};
+use crate::sync::server::{handle_message, MessageReceiver, MessageSender, Methods};
+use crate::sync::sys::PipeConnection;
+use crate::sync::thread::ThreadConfig;
+
+/// A message read from a connection, for the handler threads.
+type Call = (Arc<Conn>, MessageHeader, Result<Vec<u8>>);
+
+/// Connections multiplexed on a few poller threads, whose calls are handled by a
+/// pool of threads shared by all of them.
+pub(crate) struct EventLoop {
+    pollers: Vec<Arc<Poller>>,
+    next: AtomicUsize,
+    handles: Mutex<Vec<JoinHandle<()>>>,
+}
+
+struct Poller {
+    conns: Mutex<Vec<Arc<Conn>>>,
+    /// Wakes the poller up when the connections change.
+    wake: (RawFd, RawFd),
+    quit: AtomicBool,
+}
+
+struct Conn {
+    pipe: PipeConnection,
+    res_tx: Mutex<MessageSender>,
+    res_rx: Mutex<MessageReceiver>,
+    cancel_tx: Mutex<Option<crossbeam::channel::Sender<()>>>,
+    cancel_rx: crossbeam::channel::Receiver<()>,
+    closed: AtomicBool,
+}
+
+impl EventLoop {
+    pub(crate) fn new(
+        pollers: usize,
+        handlers: usize,
+        methods: Arc<Methods>,
+        threads: &ThreadConfig,
+    ) -> Result<EventLoop> {
+        let (call_tx, call_rx) = crossbeam::channel::unbounded::<Call>();
+        let mut handles = Vec::with_capacity(pollers + handlers);
+        let mut all = Vec::with_capacity(pollers);
+        for _ in 0..pollers {
+            let (rfd, wfd) = socketpair(AddressFamily::Unix, SockType::Stream, None, SOCK_CLOEXEC)
+                .map_err(|e| Error::Socket(e.to_string()))?;
+            #[cfg(target_os = "macos")]
+            {
+                set_fd_close_exec(rfd)?;
+                set_fd_close_exec(wfd)?;
+            }
+            let poller = Arc::new(Poller {
+                conns: Mutex::new(Vec::new()),
+                wake: (rfd, wfd),
+                quit: AtomicBool::new(false),
+            });
+            let call_tx = call_tx.clone();
+            let p = poller.clone();
+            handles.push(threads.spawn(move || p.run(call_tx)));
+            all.push(poller);
+        }
+        // The handler threads quit once the pollers did.
+        drop(call_tx);
+        for _ in 0..handlers {
+            let call_rx = call_rx.clone();
+            let methods = methods.clone();
+            handles.push(threads.spawn(move || {
+                for (conn, mh, buf) in call_rx.iter() {
+                    conn.handle(mh, buf, &methods);
+                }
+            }));
+        }
+        Ok(EventLoop {
+            pollers: all,
+            next: AtomicUsize::new(0),
+            handles: Mutex::new(handles),
+        })
+    }
+
+    /// Hands `pipe` over to the poller with the fewest connections.
+    pub(crate) fn add(&self, pipe: PipeConnection) {
+        let (res_tx, res_rx) = channel();
+        let (cancel_tx, cancel_rx) = crossbeam::channel::unbounded();
+        let conn = Arc::new(Conn {
+            pipe,
+            res_tx: Mutex::new(res_tx),
+            res_rx: Mutex::new(res_rx),
+            cancel_tx: Mutex::new(Some(cancel_tx)),
+            cancel_rx,
+            closed: AtomicBool::new(false),
+        });
+        // Ties are broken round-robin.
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        let poller = (0..self.pollers.len())
+            .map(|i| &self.pollers[(start + i) % self.pollers.len()])
+            .min_by_key(|p| p.conns.lock().unwrap().len())
+            .unwrap();
+        poller.conns.lock().unwrap().push(conn);
+        poller.wake();
+    }
+
+    /// Shuts the reading side of the connections down, the pollers close them
+    /// once they read the end of them.
+    pub(crate) fn shutdown_connections(&self) {
+        for poller in self.pollers.iter() {
+            for conn in poller.conns.lock().unwrap().iter() {
+                conn.pipe.shutdown().unwrap_or(());
+            }
+        }
+    }
+
+    /// Closes the connections and waits for the calls in progress.
+    pub(crate) fn shutdown(&self) {
+        for poller in self.pollers.iter() {
+            poller.quit.store(true, Ordering::SeqCst);
+            for conn in poller.conns.lock().unwrap().drain(..) {
+                conn.close();
+            }
+            poller.wake();
+        }
+        for handle in self.handles.lock().unwrap().drain(..) {
+            handle.join().unwrap_or(());
+        }
+        for poller in self.pollers.iter() {
+            nix::unistd::close(poller.wake.0).ok();
+            nix::unistd::close(poller.wake.1).ok();
+        }
+    }
+}
+
+impl Poller {
+    fn wake(&self) {
+        nix::unistd::write(self.wake.1, &[1]).ok();
+    }
+
+    fn run(&self, call_tx: crossbeam::channel::Sender<Call>) {
+        while !self.quit.load(Ordering::SeqCst) {
+            let conns = self.conns.lock().unwrap().clone();
+            let mut fds = Vec::with_capacity(conns.len() + 1);
+            fds.push(PollFd::new(self.wake.0, PollFlags::POLLIN));
+            fds.extend(
+                conns
+                    .iter()
+                    .map(|conn| PollFd::new(conn.pipe.id(), PollFlags::POLLIN)),
+            );
+            match poll(&mut fds, -1) {
+                Ok(_) => {}
+                Err(nix::Error::EINTR) => continue,
+                Err(e) => {
+                    error!("event loop poll failed: {:?}", e);
+                    break;
+                }
+            }
+            if fds[0].revents().map_or(false, |r| !r.is_empty()) {
+                let mut buf = [0u8; 64];
+                nix::unistd::read(self.wake.0, &mut buf).ok();
+            }
+            for (conn, fd) in conns.iter().zip(&fds[1..]) {
+                if fd.revents().map_or(true, |r| r.is_empty()) {
+                    continue;
+                }
+                // A message is read whole, a connection sending part of one holds
+                // the poller until the rest arrives.
+                match read_message(&conn.pipe) {
+                    Ok((mh, buf)) => {
+                        if call_tx.send((conn.clone(), mh, buf)).is_err() {
+                            return;
+                        }
+                    }
+                    Err(Error::Socket(e)) => {
+                        trace!("Socket error {}", e);
+                        self.remove(conn);
+                    }
+                    Err(e) => trace!("Other error {:?}", e),
+                }
+            }
+        }
+        trace!("event loop poller quit");
+    }
+
+    fn remove(&self, conn: &Arc<Conn>) {
+        self.conns.lock().unwrap().retain(|c| !Arc::ptr_eq(c, conn));
+        conn.close();
+    }
+}
+
+impl Conn {
+    fn handle(&self, mh: MessageHeader, buf: Result<Vec<u8>>, methods: &Methods) {
+        if self.closed.load(Ordering::SeqCst) {
+            return;
+        }
+        let res_tx = self.res_tx.lock().unwrap().clone();
+        let res = handle_message(self.pipe.id(), mh, buf, methods, &res_tx, &self.cancel_rx);
+        // The responses are written by the handler threads, the one which got the
+        // lock writing those of the others too.
+        let res_rx = self.res_rx.lock().unwrap();
+        for (mh, buf) in res_rx.try_iter() {
+            if let Err(e) = write_message(&self.pipe, mh, buf) {
+                error!("write_message got {:?}", e);
+                self.close();
+                return;
+            }
+        }
+        if res.is_err() {
+            self.close();
+        }
+    }
+
+    /// Stops reading the connection, which is closed once the calls in progress
+    /// are done with it.
+    fn close(&self) {
+        if self.closed.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        self.cancel_tx.lock().unwrap().take();
+        self.pipe.shutdown().unwrap_or(());
+    }
+}
+
+impl Drop for Conn {
+    fn drop(&mut self) {
+        self.pipe.close().unwrap_or(());
+    }
+}
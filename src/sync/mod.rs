@@ -7,14 +7,19 @@
 
 mod channel;
 mod client;
+#[cfg(unix)]
+mod event_loop;
 mod server;
+mod stream;
 mod sys;
+mod thread;
 
 #[macro_use]
 mod utils;
 
-pub use client::Client;
+pub use client::{Client, ClientBuilder, ReconnectPolicy};
 pub use server::Server;
+pub use stream::{ClientStream, ClientStreamReceiver, ClientStreamSender, StreamInner};
 
 #[doc(hidden)]
 pub use utils::response_to_channel;
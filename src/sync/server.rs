@@ -22,7 +22,7 @@ use protobuf::{CodedInputStream, Message};
 use std::collections::HashMap;
 use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::mpsc::{channel, sync_channel, Receiver, Sender, SyncSender};
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Condvar, Mutex};
 use std::thread;
 use std::thread::JoinHandle;
 
@@ -31,7 +31,10 @@ use crate::context;
 use crate::error::{get_status, Error, Result};
 use crate::proto::{Code, MessageHeader, Request, Response, MESSAGE_TYPE_REQUEST};
 use crate::sync::channel::{read_message, write_message};
+#[cfg(unix)]
+use crate::sync::event_loop::EventLoop;
 use crate::sync::sys::{PipeConnection, PipeListener};
+use crate::sync::thread::ThreadConfig;
 use crate::{MethodHandler, TtrpcContext};
 
 // poll_queue will create WAIT_THREAD_COUNT_DEFAULT threads in begin.
@@ -41,8 +44,9 @@ const DEFAULT_WAIT_THREAD_COUNT_DEFAULT: usize = 3;
 const DEFAULT_WAIT_THREAD_COUNT_MIN: usize = 1;
 const DEFAULT_WAIT_THREAD_COUNT_MAX: usize = 5;
 
-type MessageSender = Sender<(MessageHeader, Vec<u8>)>;
-type MessageReceiver = Receiver<(MessageHeader, Vec<u8>)>;
+pub(super) type MessageSender = Sender<(MessageHeader, Vec<u8>)>;
+pub(super) type MessageReceiver = Receiver<(MessageHeader, Vec<u8>)>;
+pub(super) type Methods = HashMap<String, Box<dyn MethodHandler + Send + Sync>>;
 type WorkloadSender = crossbeam::channel::Sender<(MessageHeader, Result<Vec<u8>>)>;
 type WorkloadReceiver = crossbeam::channel::Receiver<(MessageHeader, Result<Vec<u8>>)>;
 
@@ -57,6 +61,38 @@ pub struct Server {
     thread_count_default: usize,
     thread_count_min: usize,
     thread_count_max: usize,
+    threads: ThreadConfig,
+    /// Threads polling the connections and handling the calls in event-loop mode.
+    #[cfg(unix)]
+    event_loop_threads: Option<(usize, usize)>,
+    #[cfg(unix)]
+    event_loop: Option<Arc<EventLoop>>,
+    /// The fd set by [`Server::shutdown_on_fd`].
+    #[cfg(unix)]
+    shutdown_fd: Option<RawFd>,
+    #[cfg(unix)]
+    shutdown_watcher: Option<ShutdownWatcher>,
+    /// Set once the server shut down for its shutdown fd.
+    #[cfg(unix)]
+    shutdown_triggered: Arc<(Mutex<bool>, Condvar)>,
+}
+
+/// The thread waiting on the shutdown fd, woken up through `wake` when the
+/// server shuts down first.
+#[cfg(unix)]
+struct ShutdownWatcher {
+    wake: (RawFd, RawFd),
+    handle: JoinHandle<()>,
+}
+
+#[cfg(unix)]
+impl ShutdownWatcher {
+    fn stop(self) {
+        nix::unistd::write(self.wake.1, &[1]).ok();
+        self.handle.join().unwrap_or(());
+        nix::unistd::close(self.wake.0).ok();
+        nix::unistd::close(self.wake.1).ok();
+    }
 }
 
 struct Connection {
@@ -87,6 +123,7 @@ struct ThreadS<'a> {
     res_tx: &'a MessageSender,
     control_tx: &'a SyncSender<()>,
     cancel_rx: &'a crossbeam::channel::Receiver<()>,
+    threads: &'a ThreadConfig,
     default: usize,
     min: usize,
     max: usize,
@@ -102,10 +139,11 @@ fn start_method_handler_thread(
     res_tx: MessageSender,
     control_tx: SyncSender<()>,
     cancel_rx: crossbeam::channel::Receiver<()>,
+    threads: &ThreadConfig,
     min: usize,
     max: usize,
 ) {
-    thread::spawn(move || {
+    threads.spawn(move || {
         while !quit.load(Ordering::SeqCst) {
             let c = wtc.fetch_add(1, Ordering::SeqCst) + 1;
             if c > max {
@@ -131,73 +169,18 @@ fn start_method_handler_thread(
                     .unwrap_or_else(|err| trace!("Failed to send {:?}", err));
             }
 
-            let mh;
-            let buf;
-            match result {
-                Ok((x, Ok(y))) => {
-                    mh = x;
-                    buf = y;
-                }
-                Ok((mh, Err(e))) => {
-                    if let Err(x) = response_error_to_channel(mh.stream_id, e, res_tx.clone()) {
-                        debug!("response_error_to_channel get error {:?}", x);
-                        quit_connection(quit, control_tx);
-                        break;
-                    }
-                    continue;
-                }
-                Err(x) => match x {
-                    crossbeam::channel::RecvError => {
-                        trace!("workload_rx recv error");
-                        quit_connection(quit, control_tx);
-                        trace!("workload_rx recv error, send control_tx");
-                        break;
-                    }
-                },
-            }
-
-            if mh.type_ != MESSAGE_TYPE_REQUEST {
-                continue;
-            }
-            let mut s = CodedInputStream::from_bytes(&buf);
-            let mut req = Request::new();
-            if let Err(x) = req.merge_from(&mut s) {
-                let status = get_status(Code::INVALID_ARGUMENT, x.to_string());
-                let mut res = Response::new();
-                res.set_status(status);
-                if let Err(x) = response_to_channel(mh.stream_id, res, res_tx.clone()) {
-                    debug!("response_to_channel get error {:?}", x);
-                    quit_connection(quit, control_tx);
-                    break;
-                }
-                continue;
-            }
-            trace!("Got Message request {:?}", req);
-
-            let path = format!("/{}/{}", req.service, req.method);
-            let method = if let Some(x) = methods.get(&path) {
-                x
-            } else {
-                let status = get_status(Code::INVALID_ARGUMENT, format!("{path} does not exist"));
-                let mut res = Response::new();
-                res.set_status(status);
-                if let Err(x) = response_to_channel(mh.stream_id, res, res_tx.clone()) {
-                    info!("response_to_channel get error {:?}", x);
+            let (mh, buf) = match result {
+                Ok(workload) => workload,
+                Err(crossbeam::channel::RecvError) => {
+                    trace!("workload_rx recv error");
                     quit_connection(quit, control_tx);
+                    trace!("workload_rx recv error, send control_tx");
                     break;
                 }
-                continue;
             };
-            let ctx = TtrpcContext {
-                fd: connection.id(),
-                cancel_rx: cancel_rx.clone(),
-                mh,
-                res_tx: res_tx.clone(),
-                metadata: context::from_pb(&req.metadata),
-                timeout_nano: req.timeout_nano,
-            };
-            if let Err(x) = method.handler(ctx, req) {
-                debug!("method handle {} get error {:?}", path, x);
+            if let Err(x) = handle_message(connection.id(), mh, buf, &methods, &res_tx, &cancel_rx)
+            {
+                debug!("handle message get error {:?}", x);
                 quit_connection(quit, control_tx);
                 break;
             }
@@ -205,6 +188,59 @@ fn start_method_handler_thread(
     });
 }
 
+/// Handles a message read from the connection `fd`, its response is sent to `res_tx`.
+/// Fails if the response can't be sent or the handler failed, and the connection
+/// should be closed.
+pub(super) fn handle_message(
+    fd: i32,
+    mh: MessageHeader,
+    buf: Result<Vec<u8>>,
+    methods: &Methods,
+    res_tx: &MessageSender,
+    cancel_rx: &crossbeam::channel::Receiver<()>,
+) -> Result<()> {
+    let buf = match buf {
+        Ok(buf) => buf,
+        Err(e) => return response_error_to_channel(mh.stream_id, e, res_tx.clone()),
+    };
+
+    if mh.type_ != MESSAGE_TYPE_REQUEST {
+        return Ok(());
+    }
+    let mut s = CodedInputStream::from_bytes(&buf);
+    let mut req = Request::new();
+    if let Err(x) = req.merge_from(&mut s) {
+        let status = get_status(Code::INVALID_ARGUMENT, x.to_string());
+        let mut res = Response::new();
+        res.set_status(status);
+        return response_to_channel(mh.stream_id, res, res_tx.clone());
+    }
+    trace!("Got Message request {:?}", req);
+
+    let path = format!("/{}/{}", req.service, req.method);
+    let method = if let Some(x) = methods.get(&path) {
+        x
+    } else {
+        let status = get_status(Code::INVALID_ARGUMENT, format!("{path} does not exist"));
+        let mut res = Response::new();
+        res.set_status(status);
+        return response_to_channel(mh.stream_id, res, res_tx.clone());
+    };
+    let ctx = TtrpcContext {
+        fd,
+        cancel_rx: cancel_rx.clone(),
+        mh,
+        res_tx: res_tx.clone(),
+        metadata: context::from_pb(&req.metadata),
+        timeout_nano: req.timeout_nano,
+        deadline: context::deadline_after(std::time::Instant::now(), req.timeout_nano),
+    };
+    method.handler(ctx, req).map_err(|x| {
+        debug!("method handle {} get error {:?}", path, x);
+        x
+    })
+}
+
 fn start_method_handler_threads(num: usize, ts: &ThreadS) {
     for _ in 0..num {
         if ts.quit.load(Ordering::SeqCst) {
@@ -219,6 +255,7 @@ fn start_method_handler_threads(num: usize, ts: &ThreadS) {
             ts.res_tx.clone(),
             ts.control_tx.clone(),
             ts.cancel_rx.clone(),
+            ts.threads,
             ts.min,
             ts.max,
         );
@@ -244,6 +281,17 @@ impl Default for Server {
             thread_count_default: DEFAULT_WAIT_THREAD_COUNT_DEFAULT,
             thread_count_min: DEFAULT_WAIT_THREAD_COUNT_MIN,
             thread_count_max: DEFAULT_WAIT_THREAD_COUNT_MAX,
+            threads: ThreadConfig::default(),
+            #[cfg(unix)]
+            event_loop_threads: None,
+            #[cfg(unix)]
+            event_loop: None,
+            #[cfg(unix)]
+            shutdown_fd: None,
+            #[cfg(unix)]
+            shutdown_watcher: None,
+            #[cfg(unix)]
+            shutdown_triggered: Arc::new((Mutex::new(false), Condvar::new())),
         }
     }
 }
@@ -305,6 +353,63 @@ impl Server {
         self
     }
 
+    /// Name the worker threads, which handle the calls and read and write the
+    /// connections, `{prefix}-{index}`. They are unnamed by default.
+    pub fn set_thread_name_prefix(mut self, prefix: &str) -> Server {
+        self.threads.name_prefix = Some(prefix.to_string());
+        self
+    }
+
+    /// Set the stack size of the worker threads in bytes, see
+    /// [`Server::set_thread_name_prefix`]. Defaults to the stack size of
+    /// [`std::thread`].
+    pub fn set_thread_stack_size(mut self, size: usize) -> Server {
+        self.threads.stack_size = Some(size);
+        self
+    }
+
+    /// Multiplex the connections on `pollers` threads polling them, and handle
+    /// their calls on a pool of `handlers` threads they share, instead of reading
+    /// each connection on its own threads.
+    ///
+    /// This suits servers with many connections which are idle most of the time.
+    /// The thread counts set by [`Server::set_thread_count_default`] and the
+    /// like don't apply.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `pollers` or `handlers` is 0.
+    #[cfg(unix)]
+    pub fn set_event_loop(mut self, pollers: usize, handlers: usize) -> Server {
+        assert!(
+            pollers > 0 && handlers > 0,
+            "event loop thread counts must be greater than 0"
+        );
+        self.event_loop_threads = Some((pollers, handlers));
+        self
+    }
+
+    /// Shut the server down once `fd`, an eventfd or the read end of a pipe
+    /// held by a supervisor, becomes readable.
+    ///
+    /// The server stops accepting connections and shuts the ones it has down,
+    /// the calls in progress can't send their responses. The fd stays owned by
+    /// the caller. [`Server::wait_shutdown_trigger`] returns once it fired, the
+    /// threads of the server are still joined by [`Server::shutdown`].
+    #[cfg(unix)]
+    pub fn shutdown_on_fd(mut self, fd: RawFd) -> Server {
+        self.shutdown_fd = Some(fd);
+        self
+    }
+
+    /// Wait until the fd set by [`Server::shutdown_on_fd`] shut the server down.
+    #[cfg(unix)]
+    pub fn wait_shutdown_trigger(&self) {
+        let (triggered, cvar) = &*self.shutdown_triggered;
+        let guard = triggered.lock().unwrap();
+        drop(cvar.wait_while(guard, |triggered| !*triggered).unwrap());
+    }
+
     pub fn start_listen(&mut self) -> Result<()> {
         let connections = self.connections.clone();
 
@@ -314,11 +419,18 @@ impl Server {
 
         self.listener_quit_flag.store(false, Ordering::SeqCst);
 
+        #[cfg(unix)]
+        if let Some((pollers, handlers)) = self.event_loop_threads {
+            self.start_event_loop(pollers, handlers)?;
+            return self.watch_shutdown_fd();
+        }
+
         let listener = self.listeners[0].clone();
         let methods = self.methods.clone();
         let default = self.thread_count_default;
         let min = self.thread_count_min;
         let max = self.thread_count_max;
+        let threads = self.threads.clone();
         let listener_quit_flag = self.listener_quit_flag.clone();
 
         let reaper_tx = match self.reaper.take() {
@@ -374,6 +486,7 @@ impl Server {
                     };
 
                     let methods = methods.clone();
+                    let threads = threads.clone();
                     let quit = Arc::new(AtomicBool::new(false));
                     let child_quit = quit.clone();
                     let reaper_tx_child = reaper_tx.clone();
@@ -387,7 +500,7 @@ impl Server {
                             let quit_res = child_quit.clone();
                             let pipe = pipe_connection_child.clone();
                             let (res_tx, res_rx): (MessageSender, MessageReceiver) = channel();
-                            let handler = thread::spawn(move || {
+                            let handler = threads.spawn(move || {
                                 for r in res_rx.iter() {
                                     trace!("response thread get {:?}", r);
                                     if let Err(e) = write_message(&pipe, r.0, r.1) {
@@ -410,7 +523,7 @@ impl Server {
                                 crossbeam::channel::unbounded();
                             let (cancel_tx, cancel_rx) = crossbeam::channel::unbounded::<()>();
                             let control_tx_reader = control_tx.clone();
-                            let reader = thread::spawn(move || {
+                            let reader = threads.spawn(move || {
                                 while !quit_reader.load(Ordering::SeqCst) {
                                     let msg = read_message(&pipe_reader);
                                     match msg {
@@ -462,6 +575,7 @@ impl Server {
                                 res_tx: &res_tx,
                                 control_tx: &control_tx,
                                 cancel_rx: &cancel_rx,
+                                threads: &threads,
                                 quit: &child_quit,
                                 default,
                                 min,
@@ -511,10 +625,119 @@ impl Server {
             .unwrap();
 
         self.handler = Some(handler);
+        #[cfg(unix)]
+        self.watch_shutdown_fd()?;
         info!("server listen started");
         Ok(())
     }
 
+    #[cfg(unix)]
+    fn watch_shutdown_fd(&mut self) -> Result<()> {
+        let fd = match self.shutdown_fd {
+            Some(fd) if self.shutdown_watcher.is_none() => fd,
+            _ => return Ok(()),
+        };
+        let (rfd, wfd) = nix::sys::socket::socketpair(
+            nix::sys::socket::AddressFamily::Unix,
+            nix::sys::socket::SockType::Stream,
+            None,
+            crate::common::SOCK_CLOEXEC,
+        )
+        .map_err(|e| Error::Socket(e.to_string()))?;
+        #[cfg(target_os = "macos")]
+        {
+            crate::common::set_fd_close_exec(rfd)?;
+            crate::common::set_fd_close_exec(wfd)?;
+        }
+
+        let listener_quit_flag = self.listener_quit_flag.clone();
+        let connections = self.connections.clone();
+        let event_loop = self.event_loop.clone();
+        let triggered = self.shutdown_triggered.clone();
+        let handle = thread::Builder::new()
+            .name("shutdown_watcher".into())
+            .spawn(move || {
+                use nix::poll::{poll, PollFd, PollFlags};
+
+                let mut fds = [
+                    PollFd::new(fd, PollFlags::POLLIN),
+                    PollFd::new(rfd, PollFlags::POLLIN),
+                ];
+                loop {
+                    match poll(&mut fds, -1) {
+                        Ok(_) => break,
+                        Err(nix::Error::EINTR) => continue,
+                        Err(e) => {
+                            error!("shutdown fd poll failed: {:?}", e);
+                            return;
+                        }
+                    }
+                }
+                if fds[0].revents().map_or(true, |r| r.is_empty()) {
+                    return;
+                }
+
+                info!("shutdown fd {} fired, shutting the server down", fd);
+                // The listener loop quits at its next poll.
+                listener_quit_flag.store(true, Ordering::SeqCst);
+                for (_fd, c) in connections.lock().unwrap().iter() {
+                    c.shutdown();
+                }
+                if let Some(event_loop) = event_loop {
+                    event_loop.shutdown_connections();
+                }
+
+                let (lock, cvar) = &*triggered;
+                *lock.lock().unwrap() = true;
+                cvar.notify_all();
+            })
+            .unwrap();
+        self.shutdown_watcher = Some(ShutdownWatcher {
+            wake: (rfd, wfd),
+            handle,
+        });
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    fn start_event_loop(&mut self, pollers: usize, handlers: usize) -> Result<()> {
+        let event_loop = match self.event_loop.as_ref() {
+            Some(event_loop) => event_loop.clone(),
+            None => {
+                let event_loop =
+                    EventLoop::new(pollers, handlers, self.methods.clone(), &self.threads)?;
+                self.event_loop.insert(Arc::new(event_loop)).clone()
+            }
+        };
+        let listener = self.listeners[0].clone();
+        let listener_quit_flag = self.listener_quit_flag.clone();
+
+        let handler = thread::Builder::new()
+            .name("listener_loop".into())
+            .spawn(move || {
+                loop {
+                    trace!("listening...");
+                    match listener.accept(&listener_quit_flag) {
+                        Ok(None) => {}
+                        Ok(Some(conn)) => event_loop.add(conn),
+                        Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {
+                            error!("got interruption {:?}.  Continue...", e);
+                        }
+                        Err(e) => {
+                            error!("listener accept got {:?}", e);
+                            break;
+                        }
+                    }
+                }
+                info!("ttrpc server listener stopped");
+            })
+            .unwrap();
+
+        self.handler = Some(handler);
+        info!("server listen started in event-loop mode");
+        Ok(())
+    }
+
     pub fn start(&mut self) -> Result<()> {
         if self.thread_count_default >= self.thread_count_max {
             return Err(Error::Others(
@@ -547,6 +770,11 @@ impl Server {
     }
 
     pub fn disconnect(mut self) {
+        #[cfg(unix)]
+        if let Some(watcher) = self.shutdown_watcher.take() {
+            watcher.stop();
+        }
+
         info!("begin to shutdown connection");
         let connections = self.connections.lock().unwrap();
 
@@ -556,6 +784,10 @@ impl Server {
         // release connections's lock, since the following handler.join()
         // would wait on the other thread's exit in which would take the lock.
         drop(connections);
+        #[cfg(unix)]
+        if let Some(event_loop) = self.event_loop.take() {
+            event_loop.shutdown();
+        }
         info!("connections closed");
 
         if let Some(r) = self.reaper.take() {
@@ -593,3 +825,100 @@ fn quit_connection(quit: Arc<AtomicBool>, control_tx: SyncSender<()>) {
         .send(())
         .unwrap_or_else(|err| debug!("Failed to send {:?}", err));
 }
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    use std::os::unix::io::IntoRawFd;
+    use std::os::unix::net::UnixListener;
+
+    use crate::sync::Client;
+
+    struct Echo;
+
+    impl MethodHandler for Echo {
+        fn handler(&self, ctx: TtrpcContext, req: Request) -> Result<()> {
+            let res = Response {
+                payload: req.payload,
+                ..Default::default()
+            };
+            response_to_channel(ctx.mh.stream_id, res, ctx.res_tx)
+        }
+    }
+
+    fn request(payload: &[u8]) -> Request {
+        Request {
+            service: "test.Test".to_string(),
+            method: "Echo".to_string(),
+            payload: payload.to_vec(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_event_loop() {
+        let path =
+            std::env::temp_dir().join(format!("ttrpc-event-loop-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        let mut methods: HashMap<String, Box<dyn MethodHandler + Send + Sync>> = HashMap::new();
+        methods.insert("/test.Test/Echo".to_string(), Box::new(Echo));
+        let mut server = Server::new()
+            .add_listener(listener.into_raw_fd())
+            .unwrap()
+            .register_service(methods)
+            .set_event_loop(1, 2);
+        server.start().unwrap();
+
+        // Idle connections share the poller with the busy ones.
+        let address = format!("unix://{}", path.display());
+        let idle: Vec<Client> = (0..8).map(|_| Client::connect(&address).unwrap()).collect();
+        let callers: Vec<_> = (0..4u8)
+            .map(|i| {
+                let client = Client::connect(&address).unwrap();
+                thread::spawn(move || {
+                    for j in 0..10u8 {
+                        let res = client.request(request(&[i, j])).unwrap();
+                        assert_eq!(res.payload, [i, j]);
+                    }
+                })
+            })
+            .collect();
+        for caller in callers {
+            caller.join().unwrap();
+        }
+        assert_eq!(idle[0].request(request(b"idle")).unwrap().payload, b"idle");
+
+        server.shutdown();
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_shutdown_on_fd() {
+        let path =
+            std::env::temp_dir().join(format!("ttrpc-shutdown-fd-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        let (rfd, wfd) = nix::unistd::pipe().unwrap();
+        let mut methods: HashMap<String, Box<dyn MethodHandler + Send + Sync>> = HashMap::new();
+        methods.insert("/test.Test/Echo".to_string(), Box::new(Echo));
+        let mut server = Server::new()
+            .add_listener(listener.into_raw_fd())
+            .unwrap()
+            .register_service(methods)
+            .shutdown_on_fd(rfd);
+        server.start().unwrap();
+        let client = Client::connect(&format!("unix://{}", path.display())).unwrap();
+        assert_eq!(client.request(request(b"a")).unwrap().payload, b"a");
+
+        nix::unistd::write(wfd, &[1]).unwrap();
+        server.wait_shutdown_trigger();
+        assert!(client.request(request(b"b")).is_err());
+
+        server.shutdown();
+        nix::unistd::close(rfd).unwrap();
+        nix::unistd::close(wfd).unwrap();
+        let _ = std::fs::remove_file(&path);
+    }
+}
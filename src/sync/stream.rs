@@ -0,0 +1,563 @@
+// Copyright (c) 2023 Ant Group
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Streams of the sync client, see [`Client::new_stream`](crate::sync::Client::new_stream).
+//!
+//! Every call blocks until its message is queued for writing, or until a message
+//! of the server is received.
+
+use std::collections::HashMap;
+use std::marker::PhantomData;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use crate::context;
+use crate::error::{Error, Result};
+use crate::proto::{
+    check_oversize, Code, Codec, MessageHeader, Response, FLAG_ACK, FLAG_ACK_REQUIRED,
+    FLAG_COMPRESSED, FLAG_NO_DATA, FLAG_REMOTE_CLOSED, MESSAGE_TYPE_DATA, MESSAGE_TYPE_RESPONSE,
+};
+use crate::sync::client::{ResultReceiver, Sender};
+use crate::sync::sys::ClientConnection;
+
+/// The messages of a call of a streaming method, untyped.
+pub struct StreamInner {
+    stream_id: u32,
+    tx: Sender,
+    rx: ResultReceiver,
+    sendable: bool,
+    recveivable: bool,
+    local_closed: AtomicBool,
+    remote_closed: bool,
+    response_metadata: Option<HashMap<String, Vec<String>>>,
+    // The connection is closed once the client and its streams are dropped.
+    _connection: Arc<ClientConnection>,
+}
+
+impl StreamInner {
+    pub(crate) fn new(
+        stream_id: u32,
+        tx: Sender,
+        rx: ResultReceiver,
+        sendable: bool,
+        recveivable: bool,
+        connection: Arc<ClientConnection>,
+    ) -> Self {
+        StreamInner {
+            stream_id,
+            tx,
+            rx,
+            sendable,
+            recveivable,
+            local_closed: AtomicBool::new(false),
+            remote_closed: false,
+            response_metadata: None,
+            _connection: connection,
+        }
+    }
+
+    pub fn send(&self, buf: Vec<u8>) -> Result<()> {
+        debug_assert!(self.sendable);
+        if self.local_closed.load(Ordering::Relaxed) {
+            return Err(Error::LocalClosed);
+        }
+        check_oversize(buf.len(), false)?;
+        let mh = MessageHeader::new_data(self.stream_id, buf.len() as u32);
+        self.write(mh, buf)
+    }
+
+    pub fn close_send(&self) -> Result<()> {
+        debug_assert!(self.sendable);
+        if self.local_closed.load(Ordering::Relaxed) {
+            return Err(Error::LocalClosed);
+        }
+        let mut mh = MessageHeader::new_data(self.stream_id, 0);
+        mh.set_flags(FLAG_REMOTE_CLOSED | FLAG_NO_DATA);
+        self.write(mh, Vec::new())?;
+        self.local_closed.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn write(&self, mh: MessageHeader, buf: Vec<u8>) -> Result<()> {
+        self.tx
+            .send((mh, buf, None))
+            .map_err(err_to_others_err!(e, "Send packet to sender error "))
+    }
+
+    /// Receives the next message of the server, [`Error::Eof`] once the server
+    /// closed the stream.
+    pub fn recv(&mut self) -> Result<Vec<u8>> {
+        if self.remote_closed {
+            return Err(Error::RemoteClosed);
+        }
+        let (mh, payload) = self.rx.recv().map_err(err_to_others_err!(
+            e,
+            "Receive packet from Receiver error: "
+        ))??;
+
+        match mh.type_ {
+            MESSAGE_TYPE_RESPONSE => {
+                self.remote_closed = true;
+                let resp = Response::decode(&payload)
+                    .map_err(err_to_others_err!(e, "Unpack response error "))?;
+                self.response_metadata = Some(context::from_pb(&resp.metadata));
+                if let Some(status) = resp.status.as_ref() {
+                    if status.code() != Code::OK {
+                        return Err(Error::RpcStatus((*status).clone()));
+                    }
+                }
+                // Streaming servers only respond to close the stream.
+                if self.recveivable && resp.payload.is_empty() {
+                    return Err(Error::Eof);
+                }
+                Ok(resp.payload)
+            }
+            MESSAGE_TYPE_DATA => {
+                if !self.recveivable {
+                    self.remote_closed = true;
+                    return Err(Error::Others(
+                        "received data from non-streaming server.".to_string(),
+                    ));
+                }
+                if (mh.flags & FLAG_ACK_REQUIRED) == FLAG_ACK_REQUIRED {
+                    let mut ack = MessageHeader::new_data(self.stream_id, 0);
+                    ack.set_flags(FLAG_ACK | FLAG_NO_DATA);
+                    if let Err(e) = self.write(ack, Vec::new()) {
+                        warn!(
+                            "Failed to acknowledge data of stream {}: {:?}",
+                            self.stream_id, e
+                        );
+                    }
+                }
+                if (mh.flags & FLAG_REMOTE_CLOSED) == FLAG_REMOTE_CLOSED {
+                    self.remote_closed = true;
+                    if (mh.flags & FLAG_NO_DATA) == FLAG_NO_DATA {
+                        return Err(Error::Eof);
+                    }
+                }
+                if (mh.flags & FLAG_COMPRESSED) == FLAG_COMPRESSED {
+                    return Err(Error::Others(
+                        "received compressed data but compression is not enabled".to_string(),
+                    ));
+                }
+                Ok(payload)
+            }
+            _ => Err(Error::Others("not support".to_string())),
+        }
+    }
+
+    /// Metadata the server sent with its response, only set once the stream finished.
+    pub fn response_metadata(&self) -> Option<&HashMap<String, Vec<String>>> {
+        self.response_metadata.as_ref()
+    }
+}
+
+/// The stream of a call of a client streaming method.
+pub struct ClientStreamSender<Q, P> {
+    inner: StreamInner,
+    _send: PhantomData<Q>,
+    _recv: PhantomData<P>,
+}
+
+impl<Q, P> ClientStreamSender<Q, P>
+where
+    Q: Codec,
+    P: Codec,
+    <Q as Codec>::E: std::fmt::Display,
+    <P as Codec>::E: std::fmt::Display,
+{
+    pub fn new(inner: StreamInner) -> Self {
+        Self {
+            inner,
+            _send: PhantomData,
+            _recv: PhantomData,
+        }
+    }
+
+    pub fn send(&self, req: &Q) -> Result<()> {
+        let msg_buf = req
+            .encode()
+            .map_err(err_to_others_err!(e, "Encode message failed."))?;
+        self.inner.send(msg_buf)
+    }
+
+    pub fn close_and_recv(&mut self) -> Result<P> {
+        self.inner.close_send()?;
+        let msg_buf = self.inner.recv()?;
+        P::decode(msg_buf).map_err(err_to_others_err!(e, "Decode message failed."))
+    }
+
+    /// Metadata the server sent with its response, only set once the stream finished.
+    pub fn response_metadata(&self) -> Option<&HashMap<String, Vec<String>>> {
+        self.inner.response_metadata()
+    }
+}
+
+/// The stream of a call of a server streaming method, also an iterator over the
+/// messages of the server.
+pub struct ClientStreamReceiver<P> {
+    inner: StreamInner,
+    done: bool,
+    _recv: PhantomData<P>,
+}
+
+impl<P> ClientStreamReceiver<P>
+where
+    P: Codec,
+    <P as Codec>::E: std::fmt::Display,
+{
+    pub fn new(inner: StreamInner) -> Self {
+        Self {
+            inner,
+            done: false,
+            _recv: PhantomData,
+        }
+    }
+
+    /// Receives the next message of the server, `None` once the server closed
+    /// the stream.
+    pub fn recv(&mut self) -> Result<Option<P>> {
+        recv_message(&mut self.inner)
+    }
+
+    /// Metadata the server sent with its response, only set once the stream finished.
+    pub fn response_metadata(&self) -> Option<&HashMap<String, Vec<String>>> {
+        self.inner.response_metadata()
+    }
+}
+
+/// Ends after the last message of the server, or after the first error.
+impl<P> Iterator for ClientStreamReceiver<P>
+where
+    P: Codec,
+    <P as Codec>::E: std::fmt::Display,
+{
+    type Item = Result<P>;
+
+    fn next(&mut self) -> Option<Result<P>> {
+        if self.done {
+            return None;
+        }
+        let res = self.recv().transpose();
+        self.done = !matches!(res, Some(Ok(_)));
+        res
+    }
+}
+
+/// The stream of a call of a duplex streaming method.
+pub struct ClientStream<Q, P> {
+    inner: StreamInner,
+    _send: PhantomData<Q>,
+    _recv: PhantomData<P>,
+}
+
+impl<Q, P> ClientStream<Q, P>
+where
+    Q: Codec,
+    P: Codec,
+    <Q as Codec>::E: std::fmt::Display,
+    <P as Codec>::E: std::fmt::Display,
+{
+    pub fn new(inner: StreamInner) -> Self {
+        Self {
+            inner,
+            _send: PhantomData,
+            _recv: PhantomData,
+        }
+    }
+
+    pub fn send(&self, req: &Q) -> Result<()> {
+        let msg_buf = req
+            .encode()
+            .map_err(err_to_others_err!(e, "Encode message failed."))?;
+        self.inner.send(msg_buf)
+    }
+
+    pub fn close_send(&self) -> Result<()> {
+        self.inner.close_send()
+    }
+
+    /// Receives the next message of the server, `None` once the server closed
+    /// the stream.
+    pub fn recv(&mut self) -> Result<Option<P>> {
+        recv_message(&mut self.inner)
+    }
+
+    /// Metadata the server sent with its response, only set once the stream finished.
+    pub fn response_metadata(&self) -> Option<&HashMap<String, Vec<String>>> {
+        self.inner.response_metadata()
+    }
+}
+
+fn recv_message<P>(inner: &mut StreamInner) -> Result<Option<P>>
+where
+    P: Codec,
+    <P as Codec>::E: std::fmt::Display,
+{
+    let res = inner.recv();
+    if matches!(res, Err(Error::Eof)) {
+        return Ok(None);
+    }
+    let msg_buf = res?;
+    P::decode(msg_buf)
+        .map_err(err_to_others_err!(e, "Decode message failed."))
+        .map(Some)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::os::unix::io::IntoRawFd;
+    use std::os::unix::net::UnixListener;
+    use std::path::Path;
+
+    use protobuf::well_known_types::wrappers::StringValue;
+
+    use crate::error::get_status;
+    use crate::sync::{response_to_channel, Client, MethodHandler, Server, TtrpcContext};
+    use crate::Request;
+
+    fn value(s: &str) -> StringValue {
+        StringValue {
+            value: s.to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn request(method: &str, payload: Vec<u8>) -> Request {
+        Request {
+            service: "test.Test".to_string(),
+            method: method.to_string(),
+            payload,
+            ..Default::default()
+        }
+    }
+
+    fn assert_status(res: Result<impl std::fmt::Debug>, code: Code) {
+        match res {
+            Err(Error::RpcStatus(status)) => assert_eq!(status.code(), code),
+            res => panic!("unexpected {:?}", res),
+        }
+    }
+
+    /// Streams the words of the request back, fails for a request without any.
+    struct Words;
+
+    impl MethodHandler for Words {
+        fn handler(&self, ctx: TtrpcContext, req: Request) -> Result<()> {
+            let words = StringValue::decode(req.payload).unwrap();
+            if words.value.is_empty() {
+                let mut res = Response::new();
+                res.set_status(get_status(Code::NOT_FOUND, "no words"));
+                return response_to_channel(ctx.mh.stream_id, res, ctx.res_tx);
+            }
+            for word in words.value.split(' ') {
+                let buf = value(word).encode().unwrap();
+                let mh = MessageHeader::new_data(ctx.mh.stream_id, buf.len() as u32);
+                ctx.res_tx.send((mh, buf)).unwrap();
+            }
+            let mut mh = MessageHeader::new_data(ctx.mh.stream_id, 0);
+            mh.set_flags(FLAG_REMOTE_CLOSED | FLAG_NO_DATA);
+            // The client may be gone already.
+            let _ = ctx.res_tx.send((mh, Vec::new()));
+            Ok(())
+        }
+    }
+
+    fn start_server(path: &Path) -> Server {
+        let _ = std::fs::remove_file(path);
+        let listener = UnixListener::bind(path).unwrap();
+        let mut methods: HashMap<String, Box<dyn MethodHandler + Send + Sync>> = HashMap::new();
+        methods.insert("/test.Test/Words".to_string(), Box::new(Words));
+        let mut server = Server::new()
+            .add_listener(listener.into_raw_fd())
+            .unwrap()
+            .register_service(methods);
+        server.start().unwrap();
+        server
+    }
+
+    #[test]
+    fn test_server_stream() {
+        let path =
+            std::env::temp_dir().join(format!("ttrpc-sync-stream-{}.sock", std::process::id()));
+        let server = start_server(&path);
+        let client = Client::connect(&format!("unix://{}", path.display())).unwrap();
+        let words = |s: &str| {
+            let req = request("Words", value(s).encode().unwrap());
+            ClientStreamReceiver::<StringValue>::new(client.new_stream(req, false, true).unwrap())
+        };
+
+        let received: Vec<String> = words("a b c").map(|w| w.unwrap().value).collect();
+        assert_eq!(received, ["a", "b", "c"]);
+
+        // The status of the server ends the stream.
+        let mut stream = words("");
+        assert_status(stream.next().unwrap(), Code::NOT_FOUND);
+        assert!(stream.next().is_none());
+
+        // Dropped before the server is done, the connection is still usable.
+        let mut stream = words("a b c d e f");
+        assert_eq!(stream.recv().unwrap().unwrap().value, "a");
+        drop(stream);
+        assert_eq!(words("g").count(), 1);
+
+        server.shutdown();
+        let _ = std::fs::remove_file(&path);
+    }
+
+    /// The sync server only serves unary methods, the streams of clients are
+    /// served by an async server.
+    #[cfg(feature = "async")]
+    mod client_streams {
+        use super::*;
+
+        use std::sync::mpsc;
+        use std::thread;
+
+        use async_trait::async_trait;
+        use tokio::sync::oneshot;
+
+        use crate::r#async::{self, testing, Service, StreamHandler};
+
+        /// Joins the words sent by the client, fails on `bad`.
+        struct Join;
+
+        #[async_trait]
+        impl StreamHandler for Join {
+            async fn handler(
+                &self,
+                _ctx: r#async::TtrpcContext,
+                mut stream: r#async::StreamInner,
+            ) -> Result<Option<Response>> {
+                let mut words = Vec::new();
+                loop {
+                    match stream.recv().await {
+                        Ok(buf) => words.push(StringValue::decode(buf).unwrap().value),
+                        Err(Error::Eof) => break,
+                        Err(e) => return Err(e),
+                    }
+                    if words.last().unwrap() == "bad" {
+                        let mut res = Response::new();
+                        res.set_status(get_status(Code::INVALID_ARGUMENT, "bad word"));
+                        return Ok(Some(res));
+                    }
+                }
+                Ok(Some(Response {
+                    payload: value(&words.join(" ")).encode().unwrap(),
+                    ..Default::default()
+                }))
+            }
+        }
+
+        /// Echoes the messages of the client until `stop`.
+        struct Echo;
+
+        #[async_trait]
+        impl StreamHandler for Echo {
+            async fn handler(
+                &self,
+                _ctx: r#async::TtrpcContext,
+                mut stream: r#async::StreamInner,
+            ) -> Result<Option<Response>> {
+                loop {
+                    let buf = match stream.recv().await {
+                        Ok(buf) => buf,
+                        Err(Error::Eof) => return Ok(Some(Response::default())),
+                        Err(e) => return Err(e),
+                    };
+                    if StringValue::decode(&buf).unwrap().value == "stop" {
+                        return Ok(Some(Response::default()));
+                    }
+                    stream.send(buf).await?;
+                }
+            }
+        }
+
+        /// Serves until the returned sender is dropped.
+        fn start_async_server() -> (String, oneshot::Sender<()>) {
+            let mut streams: HashMap<String, Arc<dyn StreamHandler + Send + Sync>> = HashMap::new();
+            streams.insert("Join".to_string(), Arc::new(Join));
+            streams.insert("Echo".to_string(), Arc::new(Echo));
+            let mut services = HashMap::new();
+            services.insert(
+                "test.Test".to_string(),
+                Service {
+                    methods: HashMap::new(),
+                    streams,
+                },
+            );
+
+            let (address_tx, address_rx) = mpsc::channel();
+            let (stop_tx, stop_rx) = oneshot::channel();
+            thread::spawn(move || {
+                let rt = tokio::runtime::Builder::new_current_thread()
+                    .enable_all()
+                    .build()
+                    .unwrap();
+                rt.block_on(async move {
+                    let server = r#async::Server::new().register_service(services);
+                    let guard = testing::start(server).await.unwrap();
+                    address_tx.send(guard.address()).unwrap();
+                    let _ = stop_rx.await;
+                    guard.shutdown().await.unwrap();
+                });
+            });
+            (address_rx.recv().unwrap(), stop_tx)
+        }
+
+        #[test]
+        fn test_client_stream() {
+            let (address, _stop) = start_async_server();
+            let client = Client::connect(&address).unwrap();
+            let join = || {
+                let stream = client.new_stream(request("Join", vec![]), true, false);
+                ClientStreamSender::<StringValue, StringValue>::new(stream.unwrap())
+            };
+
+            let mut stream = join();
+            stream.send(&value("a")).unwrap();
+            stream.send(&value("b")).unwrap();
+            assert_eq!(stream.close_and_recv().unwrap().value, "a b");
+
+            // Closed without a message.
+            assert_eq!(join().close_and_recv().unwrap().value, "");
+
+            let mut stream = join();
+            stream.send(&value("bad")).unwrap();
+            assert_status(stream.close_and_recv(), Code::INVALID_ARGUMENT);
+            // Closing again is refused locally.
+            assert!(matches!(stream.close_and_recv(), Err(Error::LocalClosed)));
+        }
+
+        #[test]
+        fn test_bidi_stream() {
+            let (address, _stop) = start_async_server();
+            let client = Client::connect(&address).unwrap();
+            let echo = || {
+                let stream = client.new_stream(request("Echo", vec![]), true, true);
+                ClientStream::<StringValue, StringValue>::new(stream.unwrap())
+            };
+
+            let mut stream = echo();
+            for word in ["a", "b"] {
+                stream.send(&value(word)).unwrap();
+                assert_eq!(stream.recv().unwrap().unwrap().value, word);
+            }
+            stream.close_send().unwrap();
+            assert!(stream.recv().unwrap().is_none());
+            assert!(matches!(stream.recv(), Err(Error::RemoteClosed)));
+            assert!(matches!(stream.send(&value("c")), Err(Error::LocalClosed)));
+
+            // The server closes the stream while the client is still sending.
+            let mut stream = echo();
+            stream.send(&value("a")).unwrap();
+            stream.send(&value("stop")).unwrap();
+            assert_eq!(stream.recv().unwrap().unwrap().value, "a");
+            assert!(stream.recv().unwrap().is_none());
+        }
+    }
+}
@@ -178,8 +178,27 @@ pub struct PipeConnection {
     fd: RawFd,
 }
 
+// A write to a connection closed by the peer fails with EPIPE instead of raising
+// SIGPIPE, which kills the processes that don't ignore it.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+const SEND_FLAGS: MsgFlags = MsgFlags::MSG_NOSIGNAL;
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+const SEND_FLAGS: MsgFlags = MsgFlags::empty();
+
 impl PipeConnection {
     pub(crate) fn new(fd: RawFd) -> PipeConnection {
+        // MSG_NOSIGNAL is not supported, the socket itself must not raise SIGPIPE.
+        #[cfg(target_os = "macos")]
+        unsafe {
+            let on: libc::c_int = 1;
+            libc::setsockopt(
+                fd,
+                libc::SOL_SOCKET,
+                libc::SO_NOSIGPIPE,
+                &on as *const _ as *const libc::c_void,
+                std::mem::size_of_val(&on) as libc::socklen_t,
+            );
+        }
         PipeConnection { fd }
     }
 
@@ -204,12 +223,15 @@ impl PipeConnection {
 
     pub fn write(&self, buf: &[u8]) -> Result<usize> {
         loop {
-            match send(self.fd, buf, MsgFlags::empty()) {
+            match send(self.fd, buf, SEND_FLAGS) {
                 Ok(l) => return Ok(l),
                 Err(e) if retryable(e) => {
                     // Should retry
                     continue;
                 }
+                Err(Error::EPIPE) | Err(Error::ECONNRESET) => {
+                    return Err(crate::Error::PeerClosed);
+                }
                 Err(e) => {
                     return Err(crate::Error::Nix(e));
                 }
@@ -239,6 +261,7 @@ pub struct ClientConnection {
 
 impl ClientConnection {
     pub fn client_connect(sockaddr: &str)-> Result<ClientConnection>   {
+        common::check_not_unixpacket(sockaddr)?;
         let fd = unsafe { client_connect(sockaddr)? };
         Ok(ClientConnection::new(fd))
     }
@@ -0,0 +1,56 @@
+// Copyright (c) 2023 Ant Group
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Naming and stack size of the worker threads of the sync server and client.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+#[derive(Clone, Debug, Default)]
+pub(crate) struct ThreadConfig {
+    pub(crate) name_prefix: Option<String>,
+    pub(crate) stack_size: Option<usize>,
+    next_index: Arc<AtomicUsize>,
+}
+
+impl ThreadConfig {
+    /// Spawns a thread, named `{prefix}-{index}` if a prefix is set.
+    pub(crate) fn spawn<F, T>(&self, f: F) -> JoinHandle<T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let mut builder = thread::Builder::new();
+        if let Some(prefix) = self.name_prefix.as_ref() {
+            let index = self.next_index.fetch_add(1, Ordering::Relaxed);
+            builder = builder.name(format!("{prefix}-{index}"));
+        }
+        if let Some(size) = self.stack_size {
+            builder = builder.stack_size(size);
+        }
+        builder.spawn(f).expect("failed to spawn thread")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_spawn() {
+        let config = ThreadConfig {
+            name_prefix: Some("worker".to_string()),
+            ..Default::default()
+        };
+        let name = || thread::current().name().map(str::to_string);
+        assert_eq!(config.spawn(name).join().unwrap().unwrap(), "worker-0");
+        assert_eq!(
+            config.clone().spawn(name).join().unwrap().unwrap(),
+            "worker-1"
+        );
+        assert_eq!(ThreadConfig::default().spawn(name).join().unwrap(), None);
+    }
+}
@@ -47,34 +47,37 @@ pub fn response_error_to_channel(
 #[macro_export]
 macro_rules! request_handler {
     ($class: ident, $ctx: ident, $req: ident, $server: ident, $req_type: ident, $req_fn: ident) => {
+        $crate::request_handler!($class, $ctx, $req, super::$server::$req_type, $req_fn);
+    };
+    ($class: ident, $ctx: ident, $req: ident, $req_type: ty, $req_fn: ident) => {
         let mut s = CodedInputStream::from_bytes(&$req.payload);
-        let mut req = super::$server::$req_type::new();
+        let mut req = <$req_type>::new();
         req.merge_from(&mut s)
-            .map_err(::ttrpc::err_to_others!(e, ""))?;
+            .map_err($crate::err_to_others!(e, ""))?;
 
-        let mut res = ::ttrpc::Response::new();
+        let mut res = $crate::Response::new();
         match $class.service.$req_fn(&$ctx, req) {
             Ok(rep) => {
-                res.set_status(::ttrpc::get_status(::ttrpc::Code::OK, "".to_string()));
+                res.set_status($crate::get_status($crate::Code::OK, "".to_string()));
                 res.payload.reserve(rep.compute_size() as usize);
                 let mut s = protobuf::CodedOutputStream::vec(&mut res.payload);
                 rep.write_to(&mut s)
-                    .map_err(::ttrpc::err_to_others!(e, ""))?;
-                s.flush().map_err(::ttrpc::err_to_others!(e, ""))?;
+                    .map_err($crate::err_to_others!(e, ""))?;
+                s.flush().map_err($crate::err_to_others!(e, ""))?;
             }
             Err(x) => match x {
-                ::ttrpc::Error::RpcStatus(s) => {
+                $crate::Error::RpcStatus(s) => {
                     res.set_status(s);
                 }
                 _ => {
-                    res.set_status(::ttrpc::get_status(
-                        ::ttrpc::Code::UNKNOWN,
+                    res.set_status($crate::get_status(
+                        $crate::Code::UNKNOWN,
                         format!("{:?}", x),
                     ));
                 }
             },
         }
-        ::ttrpc::response_to_channel($ctx.mh.stream_id, res, $ctx.res_tx)?
+        $crate::response_to_channel($ctx.mh.stream_id, res, $ctx.res_tx)?
     };
 }
 
@@ -82,17 +85,17 @@ macro_rules! request_handler {
 #[macro_export]
 macro_rules! client_request {
     ($self: ident, $ctx: ident, $req: ident, $server: expr, $method: expr, $cres: ident) => {
-        let mut creq = ::ttrpc::Request::new();
+        let mut creq = $crate::Request::new();
         creq.set_service($server.to_string());
         creq.set_method($method.to_string());
         creq.set_timeout_nano($ctx.timeout_nano);
-        let md = ::ttrpc::context::to_pb($ctx.metadata);
+        let md = $crate::context::to_pb($ctx.metadata);
         creq.set_metadata(md);
         creq.payload.reserve($req.compute_size() as usize);
         let mut s = CodedOutputStream::vec(&mut creq.payload);
         $req.write_to(&mut s)
-            .map_err(::ttrpc::err_to_others!(e, ""))?;
-        s.flush().map_err(::ttrpc::err_to_others!(e, ""))?;
+            .map_err($crate::err_to_others!(e, ""))?;
+        s.flush().map_err($crate::err_to_others!(e, ""))?;
 
         drop(s);
 
@@ -100,12 +103,76 @@ macro_rules! client_request {
         let mut s = CodedInputStream::from_bytes(&res.payload);
         $cres
             .merge_from(&mut s)
-            .map_err(::ttrpc::err_to_others!(e, "Unpack get error "))?;
+            .map_err($crate::err_to_others!(e, "Unpack get error "))?;
+    };
+}
+
+/// Start a client streaming call through sync client.
+#[macro_export]
+macro_rules! client_stream_send {
+    ($self: ident, $ctx: ident, $server: expr, $method: expr) => {
+        let mut creq = $crate::Request::new();
+        creq.set_service($server.to_string());
+        creq.set_method($method.to_string());
+        creq.set_timeout_nano($ctx.timeout_nano);
+        let md = $crate::context::to_pb($ctx.metadata);
+        creq.set_metadata(md);
+
+        let inner = $self.client.new_stream(creq, true, false)?;
+        let stream = $crate::sync::ClientStreamSender::new(inner);
+
+        return Ok(stream);
+    };
+}
+
+/// Start a server streaming call through sync client.
+#[macro_export]
+macro_rules! client_stream_receive {
+    ($self: ident, $ctx: ident, $req: ident, $server: expr, $method: expr) => {
+        let mut creq = $crate::Request::new();
+        creq.set_service($server.to_string());
+        creq.set_method($method.to_string());
+        creq.set_timeout_nano($ctx.timeout_nano);
+        let md = $crate::context::to_pb($ctx.metadata);
+        creq.set_metadata(md);
+        creq.payload.reserve($req.compute_size() as usize);
+        {
+            let mut s = CodedOutputStream::vec(&mut creq.payload);
+            $req.write_to(&mut s)
+                .map_err($crate::err_to_others!(e, ""))?;
+            s.flush().map_err($crate::err_to_others!(e, ""))?;
+        }
+
+        let inner = $self.client.new_stream(creq, false, true)?;
+        let stream = $crate::sync::ClientStreamReceiver::new(inner);
+
+        return Ok(stream);
+    };
+}
+
+/// Start a duplex streaming call through sync client.
+#[macro_export]
+macro_rules! client_stream {
+    ($self: ident, $ctx: ident, $server: expr, $method: expr) => {
+        let mut creq = $crate::Request::new();
+        creq.set_service($server.to_string());
+        creq.set_method($method.to_string());
+        creq.set_timeout_nano($ctx.timeout_nano);
+        let md = $crate::context::to_pb($ctx.metadata);
+        creq.set_metadata(md);
+
+        let inner = $self.client.new_stream(creq, true, true)?;
+        let stream = $crate::sync::ClientStream::new(inner);
+
+        return Ok(stream);
     };
 }
 
 /// The context of ttrpc (sync).
+///
+/// Only the server creates contexts, so adding a field isn't a breaking change.
 #[derive(Debug)]
+#[non_exhaustive]
 pub struct TtrpcContext {
     #[cfg(unix)]
     pub fd: std::os::unix::io::RawFd,
@@ -116,6 +183,24 @@ pub struct TtrpcContext {
     pub res_tx: std::sync::mpsc::Sender<(MessageHeader, Vec<u8>)>,
     pub metadata: HashMap<String, Vec<String>>,
     pub timeout_nano: i64,
+    /// When the call times out, `None` if the client set no timeout. Calls made on
+    /// behalf of this one inherit it with
+    /// [`Context::with_parent_deadline`](crate::context::Context::with_parent_deadline).
+    pub deadline: Option<std::time::Instant>,
+}
+
+impl TtrpcContext {
+    /// The time left until the call times out, `None` if it has no timeout.
+    pub fn remaining(&self) -> Option<std::time::Duration> {
+        self.deadline
+            .map(|deadline| deadline.saturating_duration_since(std::time::Instant::now()))
+    }
+}
+
+impl crate::context::Deadline for TtrpcContext {
+    fn deadline(&self) -> Option<std::time::Instant> {
+        self.deadline
+    }
 }
 
 /// Trait that implements handler which is a proxy to the desired method (sync).
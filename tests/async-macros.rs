@@ -0,0 +1,179 @@
+#![cfg(feature = "async")]
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use protobuf::well_known_types::wrappers::StringValue;
+use protobuf::{CodedInputStream, CodedOutputStream, Message};
+use ttrpc::context::{self, Context};
+use ttrpc::r#async::{CallOptions, Client, EncodedResponse, MethodHandler, Service, TtrpcContext};
+use ttrpc::{testing, Request, Response};
+
+/// Answers with the payload and the metadata of its request.
+struct Metadata;
+
+#[async_trait]
+impl MethodHandler for Metadata {
+    async fn handler(&self, _ctx: TtrpcContext, req: Request) -> ttrpc::Result<Response> {
+        Ok(Response {
+            payload: req.payload,
+            metadata: req.metadata,
+            ..Default::default()
+        })
+    }
+}
+
+/// Calls like the generated clients do.
+struct TestClient {
+    client: Client,
+}
+
+impl TestClient {
+    async fn echo(
+        &self,
+        ctx: Context,
+        req: &StringValue,
+        opts: &CallOptions,
+    ) -> ttrpc::Result<StringValue> {
+        let mut cres = StringValue::new();
+        ttrpc::async_client_request!(self, ctx, req, "test.Test", "Metadata", cres, opts);
+    }
+
+    async fn get(
+        &self,
+        ctx: Context,
+        req: &StringValue,
+        opts: &CallOptions,
+    ) -> ttrpc::Result<StringValue> {
+        let mut cres = StringValue::new();
+        ttrpc::async_client_request!(self, ctx, req, "test.Test", "Get", cres, opts);
+    }
+
+    async fn echo_with_metadata(
+        &self,
+        ctx: Context,
+        req: &StringValue,
+        opts: &CallOptions,
+    ) -> ttrpc::Result<(StringValue, HashMap<String, Vec<String>>)> {
+        let mut cres = StringValue::new();
+        ttrpc::async_client_request_with_metadata!(
+            self,
+            ctx,
+            req,
+            "test.Test",
+            "Metadata",
+            cres,
+            opts
+        );
+    }
+}
+
+/// A service generated with `encoded_responses`, which answers from its cache if
+/// it can.
+struct Cached {
+    cache: HashMap<String, Vec<u8>>,
+}
+
+impl Cached {
+    async fn get(
+        &self,
+        _ctx: &TtrpcContext,
+        req: StringValue,
+    ) -> ttrpc::Result<EncodedResponse<StringValue>> {
+        match self.cache.get(&req.value) {
+            Some(payload) => Ok(EncodedResponse::from_encoded(payload.clone())),
+            None => Ok(req.into()),
+        }
+    }
+}
+
+/// Handles the calls like the generated method handlers do.
+struct GetMethod {
+    service: Arc<Cached>,
+}
+
+#[async_trait]
+impl MethodHandler for GetMethod {
+    async fn handler(&self, ctx: TtrpcContext, req: Request) -> ttrpc::Result<Response> {
+        ttrpc::async_request_handler!(self, ctx, req, StringValue, get);
+    }
+}
+
+fn services(
+    methods: HashMap<String, Box<dyn MethodHandler + Send + Sync>>,
+) -> HashMap<String, Service> {
+    let mut services = HashMap::new();
+    services.insert(
+        "test.Test".to_string(),
+        Service {
+            methods,
+            streams: HashMap::new(),
+        },
+    );
+    services
+}
+
+fn string_value(value: &str) -> StringValue {
+    let mut msg = StringValue::new();
+    msg.value = value.to_string();
+    msg
+}
+
+#[tokio::test]
+async fn test_encoded_response() {
+    let cached = EncodedResponse::encode(&string_value("cached")).unwrap();
+    assert!(cached.encoded().is_some());
+    assert!(EncodedResponse::from(string_value("hit"))
+        .encoded()
+        .is_none());
+    let mut cache = HashMap::new();
+    cache.insert("hit".to_string(), cached.encoded().unwrap().to_vec());
+    let mut methods: HashMap<String, Box<dyn MethodHandler + Send + Sync>> = HashMap::new();
+    methods.insert(
+        "Get".to_string(),
+        Box::new(GetMethod {
+            service: Arc::new(Cached { cache }),
+        }),
+    );
+    let (client, _server) = testing::serve(services(methods)).await.unwrap();
+    let client = TestClient { client };
+    let opts = CallOptions::new();
+
+    // The encoded payload is sent as it is, the message is encoded.
+    let res = client
+        .get(context::with_timeout(0), &string_value("hit"), &opts)
+        .await
+        .unwrap();
+    assert_eq!(res.value, "cached");
+    let res = client
+        .get(context::with_timeout(0), &string_value("miss"), &opts)
+        .await
+        .unwrap();
+    assert_eq!(res.value, "miss");
+}
+
+#[tokio::test]
+async fn test_request_options() {
+    let mut methods: HashMap<String, Box<dyn MethodHandler + Send + Sync>> = HashMap::new();
+    methods.insert("Metadata".to_string(), Box::new(Metadata));
+    let (client, _server) = testing::serve(services(methods)).await.unwrap();
+    let client = TestClient { client };
+    let mut req = StringValue::new();
+    req.value = "hello".to_string();
+    let opts = CallOptions::new().with_metadata("key", "value");
+
+    let res = client
+        .echo(context::with_timeout(0), &req, &opts)
+        .await
+        .unwrap();
+    assert_eq!(res.value, "hello");
+
+    // The metadata of the options reaches the server, and comes back.
+    let (res, metadata) = client
+        .echo_with_metadata(context::with_timeout(0), &req, &opts)
+        .await
+        .unwrap();
+    assert_eq!(res.value, "hello");
+    assert_eq!(metadata["key"], ["value"]);
+}
@@ -0,0 +1,125 @@
+#![cfg(feature = "capture")]
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use log::{Log, Metadata, Record};
+use ttrpc::r#async::{
+    Capture, ClientBuilder, ErrorRedactor, MethodHandler, Server, Service, TtrpcContext,
+};
+use ttrpc::{get_status, testing, Code, Error, Request, Response, Status};
+
+const SECRET: &str = "/run/host/secret.sock";
+
+/// Records every log line.
+struct Recorder(Mutex<Vec<String>>);
+
+impl Log for Recorder {
+    fn enabled(&self, _metadata: &Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &Record) {
+        self.0.lock().unwrap().push(record.args().to_string());
+    }
+
+    fn flush(&self) {}
+}
+
+/// Fails like the generated method handlers do with the status of the service.
+struct Leaky;
+
+#[async_trait]
+impl MethodHandler for Leaky {
+    async fn handler(&self, _ctx: TtrpcContext, _req: Request) -> ttrpc::Result<Response> {
+        let mut res = Response::new();
+        res.set_status(get_status(
+            Code::INTERNAL,
+            format!("failed to dial {}", SECRET),
+        ));
+        Ok(res)
+    }
+}
+
+struct Redactor;
+
+impl ErrorRedactor for Redactor {
+    fn redact(&self, status: &mut Status) {
+        status.message = status.message.replace(SECRET, "<redacted>");
+    }
+}
+
+#[tokio::test]
+async fn test_redacted_status() {
+    let recorder: &'static Recorder = Box::leak(Box::new(Recorder(Mutex::new(Vec::new()))));
+    log::set_logger(recorder).unwrap();
+    log::set_max_level(log::LevelFilter::Trace);
+
+    let dir = std::env::temp_dir();
+    let server_capture = dir.join(format!(
+        "ttrpc-redaction-server-{}.pcapng",
+        std::process::id()
+    ));
+    let client_capture = dir.join(format!(
+        "ttrpc-redaction-client-{}.pcapng",
+        std::process::id()
+    ));
+
+    let mut methods: HashMap<String, Box<dyn MethodHandler + Send + Sync>> = HashMap::new();
+    methods.insert("Leaky".to_string(), Box::new(Leaky));
+    let mut services = HashMap::new();
+    services.insert(
+        "test.Test".to_string(),
+        Service {
+            methods,
+            streams: HashMap::new(),
+        },
+    );
+    let server = testing::start(
+        Server::new()
+            .register_service(services)
+            .set_error_redactor(Arc::new(Redactor))
+            .set_capture(Capture::create(&server_capture).unwrap()),
+    )
+    .await
+    .unwrap();
+    let client = ClientBuilder::new(&server.address())
+        .capture(Capture::create(&client_capture).unwrap())
+        .build()
+        .unwrap();
+
+    let req = Request {
+        service: "test.Test".to_string(),
+        method: "Leaky".to_string(),
+        ..Default::default()
+    };
+    match client.request(req).await {
+        Err(Error::RpcStatus(status)) => {
+            assert_eq!(status.code(), Code::INTERNAL);
+            assert_eq!(status.message, "failed to dial <redacted>");
+        }
+        res => panic!("unexpected result {:?}", res),
+    }
+
+    // The captures are written once their last clone is dropped.
+    drop(client);
+    server.shutdown().await.unwrap();
+    let contains = |packets: &[u8], s: &str| packets.windows(s.len()).any(|w| w == s.as_bytes());
+    for path in [&server_capture, &client_capture] {
+        let mut packets = std::fs::read(path).unwrap();
+        for _ in 0..500 {
+            if contains(&packets, "<redacted>") {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            packets = std::fs::read(path).unwrap();
+        }
+        assert!(contains(&packets, "<redacted>"));
+        assert!(!contains(&packets, SECRET));
+        let _ = std::fs::remove_file(path);
+    }
+    let logs = recorder.0.lock().unwrap();
+    assert!(!logs.is_empty());
+    assert!(logs.iter().all(|line| !line.contains(SECRET)));
+}
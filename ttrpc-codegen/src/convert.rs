@@ -670,6 +670,10 @@ impl<'a> Resolver<'a> {
             output.set_oneof_index(oneof_index);
         }
 
+        if input.proto3_optional {
+            output.set_proto3_optional(true);
+        }
+
         Ok(output)
     }
 
@@ -135,6 +135,8 @@ pub struct Field {
     pub number: i32,
     /// Non-builtin options
     pub options: Vec<ProtobufOption>,
+    /// Whether it is a proto3 field with an explicit `optional` label
+    pub proto3_optional: bool,
 }
 
 /// Extension range
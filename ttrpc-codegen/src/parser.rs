@@ -1288,6 +1288,8 @@ impl<'a> Parser<'a> {
     // field = label type fieldName "=" fieldNumber [ "[" fieldOptions "]" ] ";"
     // group = label "group" groupName "=" fieldNumber messageBody
     fn next_field(&mut self, mode: MessageBodyParseMode) -> ParserResult<Field> {
+        let proto3_optional =
+            self.syntax == Syntax::Proto3 && self.clone().next_ident_if_eq("optional")?;
         let rule = if self.clone().next_ident_if_eq("map")? {
             if !mode.map_allowed() {
                 return Err(ParserError::MapFieldNotAllowed);
@@ -1314,6 +1316,7 @@ impl<'a> Parser<'a> {
                 typ: FieldType::Group(fields),
                 number,
                 options: Vec::new(),
+                proto3_optional,
             })
         } else {
             let typ = self.next_field_type()?;
@@ -1336,6 +1339,7 @@ impl<'a> Parser<'a> {
                 typ,
                 number,
                 options,
+                proto3_optional,
             })
         }
     }
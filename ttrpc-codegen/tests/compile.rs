@@ -0,0 +1,376 @@
+// Copyright (c) 2023 Ant Group
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+
+//! Checks that the generated code compiles and works, by testing a crate of it
+//! against the ttrpc crate of this repository.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use ttrpc_codegen::{Codegen, Customize};
+
+/// Writes `protos`, as paths and contents, to a new crate named `name`, generates
+/// its code with `customize` and runs the tests of the crate. `lib` is the `lib.rs`
+/// of the crate, given the directory the code was generated into.
+fn check(name: &str, protos: &[(&str, &str)], customize: Customize, lib: impl Fn(&Path) -> String) {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let dir = tempfile::tempdir().unwrap();
+    let protos_dir = dir.path().join("protos");
+    let out_dir = dir.path().join("src").join("generated");
+    fs::create_dir_all(&out_dir).unwrap();
+
+    let mut inputs = Vec::new();
+    for (path, content) in protos {
+        let path = protos_dir.join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, content).unwrap();
+        inputs.push(path);
+    }
+
+    Codegen::new()
+        .out_dir(&out_dir)
+        .inputs(&inputs)
+        .include(&protos_dir)
+        .rust_protobuf()
+        .customize(customize.clone())
+        .run()
+        .unwrap();
+
+    // The ttrpc crate is renamed after the crate path of the generated code.
+    let ttrpc = customize
+        .crate_path
+        .as_deref()
+        .map_or("ttrpc", |path| path.trim_start_matches("::"));
+    fs::write(
+        dir.path().join("Cargo.toml"),
+        format!(
+            r#"[package]
+name = "{name}"
+version = "0.0.0"
+edition = "2018"
+
+[dependencies]
+{ttrpc} = {{ package = "ttrpc", path = "{path}", features = ["async"] }}
+protobuf = "3.1.0"
+async-trait = "0.1.42"
+tokio = {{ version = "1", features = ["macros", "rt"] }}
+tower = "0.4"
+
+[workspace]
+"#,
+            path = root.parent().unwrap().display(),
+        ),
+    )
+    .unwrap();
+    fs::write(dir.path().join("src").join("lib.rs"), lib(&out_dir)).unwrap();
+
+    // Share the dependencies built between runs.
+    let target_dir: PathBuf = root.join("target").join("compile-test");
+    let output = Command::new(env!("CARGO"))
+        .arg("test")
+        .arg("--quiet")
+        .env("CARGO_TARGET_DIR", &target_dir)
+        .current_dir(dir.path())
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "{}{}",
+        String::from_utf8_lossy(&output.stdout),
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
+
+const ECHO: &str = r#"
+syntax = "proto3";
+
+package echo;
+
+message EchoRequest {
+    string msg = 1;
+}
+
+message EchoResponse {
+    string msg = 1;
+}
+
+service Echo {
+    rpc Echo(EchoRequest) returns (EchoResponse);
+    rpc EchoStream(stream EchoRequest) returns (stream EchoResponse);
+}
+"#;
+
+#[test]
+fn test_tower_client() {
+    check(
+        "tower_client",
+        &[("echo.proto", ECHO)],
+        Customize {
+            async_all: true,
+            tower_client: true,
+            ..Default::default()
+        },
+        |_| {
+            r#"
+pub mod generated {
+    pub mod echo;
+    pub mod echo_ttrpc;
+}
+
+use generated::echo::{EchoRequest, EchoResponse};
+use generated::echo_ttrpc::{EchoClient, EchoEchoService};
+
+/// The generated service is a `tower::Service`.
+pub fn service(client: EchoClient) -> impl tower::Service<EchoRequest, Response = EchoResponse, Error = ttrpc::Error> {
+    EchoEchoService::new(client, ttrpc::context::with_timeout(0))
+}
+"#
+            .to_string()
+        },
+    );
+}
+
+const TYPES: &str = r#"
+syntax = "proto3";
+
+package x.y;
+
+message Request {
+    string msg = 1;
+}
+"#;
+
+const SERVICE: &str = r#"
+syntax = "proto3";
+
+package a.b.c;
+
+import "x/y/types.proto";
+
+message Response {
+    string msg = 1;
+}
+
+service Echo {
+    rpc Echo(x.y.Request) returns (Response);
+    rpc EchoStream(stream x.y.Request) returns (stream Response);
+}
+"#;
+
+#[test]
+fn test_package_modules() {
+    check(
+        "package_modules",
+        &[("x/y/types.proto", TYPES), ("a/b/c/service.proto", SERVICE)],
+        Customize {
+            async_all: true,
+            package_modules: true,
+            ..Default::default()
+        },
+        |out_dir| {
+            // rust-protobuf writes all files into `out_dir` and refers to the files
+            // imported as siblings: lay them out like the ttrpc files, and re-export
+            // the imported ones.
+            format!(
+                r#"
+pub mod a {{
+    pub mod b {{
+        pub mod c {{
+            use crate::x::y::types;
+
+            #[path = "{out}/service.rs"]
+            pub mod service;
+            #[path = "{out}/a/b/c/service_ttrpc.rs"]
+            pub mod service_ttrpc;
+        }}
+    }}
+}}
+
+pub mod x {{
+    pub mod y {{
+        #[path = "{out}/types.rs"]
+        pub mod types;
+    }}
+}}
+
+pub use a::b::c::service_ttrpc::{{create_echo, Echo, EchoClient}};
+"#,
+                out = out_dir.display()
+            )
+        },
+    );
+}
+
+#[test]
+fn test_crate_path() {
+    check(
+        "crate_path",
+        &[("echo.proto", ECHO)],
+        Customize {
+            async_all: true,
+            crate_path: Some("::my_ttrpc".to_string()),
+            ..Default::default()
+        },
+        |_| {
+            r#"
+pub mod generated {
+    pub mod echo;
+    pub mod echo_ttrpc;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use async_trait::async_trait;
+    use my_ttrpc::r#async::{Client, Server, TtrpcContext};
+
+    use super::generated::echo::{EchoRequest, EchoResponse};
+    use super::generated::echo_ttrpc::{create_echo, Echo, EchoClient};
+
+    struct EchoService;
+
+    #[async_trait]
+    impl Echo for EchoService {
+        async fn echo(&self, _ctx: &TtrpcContext, req: EchoRequest) -> my_ttrpc::Result<EchoResponse> {
+            let mut res = EchoResponse::new();
+            res.msg = req.msg;
+            Ok(res)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_echo() {
+        let service = Arc::new(Box::new(EchoService) as Box<dyn Echo + Send + Sync>);
+        let server = my_ttrpc::testing::start(Server::new().register_service(create_echo(service)))
+            .await
+            .unwrap();
+        let client = EchoClient::new(Client::connect(&server.address()).unwrap());
+        let mut req = EchoRequest::new();
+        req.msg = "hello".to_string();
+        let res = client.echo(my_ttrpc::context::with_timeout(0), &req).await.unwrap();
+        assert_eq!(res.msg, "hello");
+    }
+}
+"#
+            .to_string()
+        },
+    );
+}
+
+#[test]
+fn test_registry() {
+    check(
+        "registry",
+        &[("echo.proto", ECHO)],
+        Customize {
+            async_all: true,
+            ..Default::default()
+        },
+        |_| {
+            r#"
+pub mod generated {
+    pub mod echo;
+    pub mod echo_ttrpc;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use async_trait::async_trait;
+    use ttrpc::r#async::{Authorizer, Client, Server, TtrpcContext};
+    use ttrpc::{Code, Error, MethodType};
+
+    use super::generated::echo::{EchoRequest, EchoResponse};
+    use super::generated::echo_ttrpc::{create_echo, Echo, EchoClient, METHODS};
+
+    struct EchoService;
+
+    #[async_trait]
+    impl Echo for EchoService {
+        async fn echo(&self, _ctx: &TtrpcContext, req: EchoRequest) -> ttrpc::Result<EchoResponse> {
+            let mut res = EchoResponse::new();
+            res.msg = req.msg;
+            Ok(res)
+        }
+    }
+
+    /// Admins may make every call, readers only the unary ones.
+    struct Policy;
+
+    impl Authorizer for Policy {
+        fn authorize(&self, ctx: &TtrpcContext, service: &str, method: &str) -> bool {
+            let user = ctx.metadata.get("user").and_then(|users| users.first());
+            match user.map(String::as_str) {
+                Some("admin") => true,
+                Some("reader") => METHODS.iter().any(|info| {
+                    info.service == service
+                        && info.method == method
+                        && info.method_type == MethodType::Unary
+                }),
+                _ => false,
+            }
+        }
+    }
+
+    fn context(user: &str) -> ttrpc::context::Context {
+        let mut ctx = ttrpc::context::with_timeout(0);
+        ctx.add("user".to_string(), user.to_string());
+        ctx
+    }
+
+    fn code(res: ttrpc::Result<EchoResponse>) -> Code {
+        match res {
+            Err(Error::RpcStatus(s)) => s.code(),
+            res => panic!("unexpected {:?}", res),
+        }
+    }
+
+    #[test]
+    fn test_methods() {
+        let methods: Vec<_> = METHODS
+            .iter()
+            .map(|info| (info.service, info.method, info.method_type))
+            .collect();
+        assert_eq!(
+            methods,
+            [
+                ("echo.Echo", "Echo", MethodType::Unary),
+                ("echo.Echo", "EchoStream", MethodType::Duplex),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_policy() {
+        let service = Arc::new(Box::new(EchoService) as Box<dyn Echo + Send + Sync>);
+        let server = ttrpc::testing::start(
+            Server::new()
+                .register_service(create_echo(service))
+                .set_authorizer(Arc::new(Policy)),
+        )
+        .await
+        .unwrap();
+        let client = EchoClient::new(Client::connect(&server.address()).unwrap());
+        let req = EchoRequest::new();
+
+        client.echo(context("reader"), &req).await.unwrap();
+        client.echo(context("admin"), &req).await.unwrap();
+        assert_eq!(code(client.echo(context("guest"), &req).await), Code::PERMISSION_DENIED);
+
+        let mut denied = client.echo_stream(context("reader")).await.unwrap();
+        assert_eq!(code(denied.recv().await), Code::PERMISSION_DENIED);
+        // Let through, to the default handler of the service.
+        let mut allowed = client.echo_stream(context("admin")).await.unwrap();
+        assert_eq!(code(allowed.recv().await), Code::NOT_FOUND);
+    }
+}
+"#
+            .to_string()
+        },
+    );
+}